@@ -0,0 +1,1047 @@
+use clap::{Args, Parser};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::fhir::{parse_header, FetchLimits, HttpClientSettings, JsonFormat};
+
+const DEFAULT_TTL_SECS: u64 = 30;
+const DEFAULT_CACHE_DURATION_SECS: u64 = 5;
+const DEFAULT_UID: u32 = 501;
+const DEFAULT_GID: u32 = 20;
+const DEFAULT_BATCH_WINDOW_MS: u64 = 200;
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Mount a FHIR server as a filesystem
+#[derive(Debug, Parser)]
+#[command(name = "fhir-fuse", version, about)]
+pub struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "FHIR_FUSE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Named server profile from the config file ([profiles.<name>])
+    #[arg(short, long, env = "FHIR_FUSE_PROFILE")]
+    pub profile: Option<String>,
+
+    #[command(flatten)]
+    pub settings: Settings,
+}
+
+/// Every setting can come from a flag, an environment variable, the selected
+/// profile or the top level of the config file (in that order of precedence)
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Directory to mount the filesystem at
+    #[arg(env = "MOUNT_POINT")]
+    pub mountpoint: Option<PathBuf>,
+
+    /// FHIR server base URL, e.g. http://localhost:8080/fhir
+    #[arg(env = "FHIR_SERVER_URL")]
+    pub fhir_base_url: Option<String>,
+
+    /// Authentication mode: none, client-credentials or smart-backend
+    #[arg(long = "auth", env = "FHIR_AUTH_MODE")]
+    pub auth_mode: Option<String>,
+
+    /// OAuth2 client id
+    #[arg(long, env = "FHIR_CLIENT_ID")]
+    pub client_id: Option<String>,
+
+    /// OAuth2 client secret (client-credentials mode)
+    #[arg(long, env = "FHIR_CLIENT_SECRET", hide_env_values = true)]
+    pub client_secret: Option<String>,
+
+    /// OAuth2 token endpoint (discovered from SMART configuration if omitted)
+    #[arg(long, env = "FHIR_TOKEN_URL")]
+    pub token_url: Option<String>,
+
+    /// Requested scopes, e.g. "system/Patient.read"
+    #[arg(long, env = "FHIR_SCOPE")]
+    pub scope: Option<String>,
+
+    /// PEM private key used to sign client assertions (smart-backend mode)
+    #[arg(long, env = "FHIR_PRIVATE_KEY")]
+    pub private_key: Option<PathBuf>,
+
+    /// Client assertion algorithm: RS384 or ES384
+    #[arg(long, env = "FHIR_JWT_ALG")]
+    pub jwt_alg: Option<String>,
+
+    /// Key id (kid) placed in the client assertion header
+    #[arg(long, env = "FHIR_JWT_KID")]
+    pub jwt_kid: Option<String>,
+
+    /// HTTP Basic auth user
+    #[arg(long, env = "FHIR_BASIC_AUTH_USER")]
+    pub basic_auth_user: Option<String>,
+
+    /// HTTP Basic auth password
+    #[arg(long, env = "FHIR_BASIC_AUTH_PASSWORD", hide_env_values = true)]
+    pub basic_auth_password: Option<String>,
+
+    /// Fixed bearer token
+    #[arg(long, env = "FHIR_BEARER_TOKEN", hide_env_values = true)]
+    pub bearer_token: Option<String>,
+
+    /// File containing a fixed bearer token
+    #[arg(long, env = "FHIR_BEARER_TOKEN_FILE")]
+    pub bearer_token_file: Option<PathBuf>,
+
+    /// Extra request header "Name: value" (repeatable; ';'-separated in the environment)
+    #[arg(long = "header", env = "FHIR_HEADERS", value_delimiter = ';')]
+    pub headers: Option<Vec<String>>,
+
+    /// PEM bundle with additional trusted CA certificates
+    #[arg(long, env = "FHIR_CA_BUNDLE")]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Client certificate for mutual TLS (PEM)
+    #[arg(long, env = "FHIR_CLIENT_CERT")]
+    pub client_cert: Option<PathBuf>,
+
+    /// Client private key for mutual TLS (PEM)
+    #[arg(long, env = "FHIR_CLIENT_KEY")]
+    pub client_key: Option<PathBuf>,
+
+    /// Kernel attribute/entry cache lifetime in seconds
+    #[arg(long, env = "FHIR_FUSE_TTL")]
+    pub ttl: Option<u64>,
+
+    /// How long fetched resources, searches and history stay cached, in seconds
+    #[arg(long, env = "FHIR_FUSE_CACHE_DURATION")]
+    pub cache_duration: Option<u64>,
+
+    /// Maximum resources loaded per resource type
+    #[arg(long, env = "FHIR_FUSE_MAX_RESOURCES")]
+    pub max_resources: Option<usize>,
+
+    /// Resources per page (_count)
+    #[arg(long, env = "FHIR_FUSE_PAGE_SIZE")]
+    pub page_size: Option<usize>,
+
+    /// Maximum concurrent page fetches
+    #[arg(long, env = "FHIR_FUSE_MAX_CONCURRENT_FETCHES")]
+    pub max_concurrent_fetches: Option<usize>,
+
+    /// Allow other users to access the mount
+    #[arg(long, env = "FHIR_FUSE_ALLOW_OTHER", num_args = 0..=1, default_missing_value = "true")]
+    pub allow_other: Option<bool>,
+
+    /// Bypass the kernel page cache
+    #[arg(long, env = "FHIR_FUSE_DIRECT_IO", num_args = 0..=1, default_missing_value = "true")]
+    pub direct_io: Option<bool>,
+
+    /// Mount read-only
+    #[arg(long, env = "FHIR_FUSE_READ_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    pub read_only: Option<bool>,
+
+    /// Owner uid reported for all files
+    #[arg(long, env = "FHIR_FUSE_UID")]
+    pub uid: Option<u32>,
+
+    /// Owner gid reported for all files
+    #[arg(long, env = "FHIR_FUSE_GID")]
+    pub gid: Option<u32>,
+
+    /// Only expose these resource types (comma-separated)
+    #[arg(long, env = "FHIR_RESOURCE_TYPES", value_delimiter = ',')]
+    pub resource_types: Option<Vec<String>>,
+
+    /// Only allow writes to these resource types (comma-separated); all
+    /// others are read-only
+    #[arg(long, env = "FHIR_WRITABLE_TYPES", value_delimiter = ',')]
+    pub writable_types: Option<Vec<String>>,
+
+    /// Replace an `id` that doesn't match the filename instead of rejecting
+    /// the write
+    #[arg(long, env = "FHIR_FUSE_REWRITE_IDS", num_args = 0..=1, default_missing_value = "true")]
+    pub rewrite_ids: Option<bool>,
+
+    /// What renaming a resource file does about resources that reference
+    /// it: keep, rewrite or refuse
+    #[arg(long, env = "FHIR_FUSE_RENAME_REFERENCES")]
+    pub rename_references: Option<String>,
+
+    /// Resource types searched for references to a renamed resource
+    /// (comma-separated); all types by default
+    #[arg(long, env = "FHIR_FUSE_REFERENCING_TYPES", value_delimiter = ',')]
+    pub referencing_types: Option<Vec<String>>,
+
+    /// What a write does about references to resources that don't exist on
+    /// the server: off, warn or block
+    #[arg(long, env = "FHIR_FUSE_CHECK_REFERENCES")]
+    pub check_references: Option<String>,
+
+    /// Keep writes in `.staging/` until they are committed as one
+    /// transaction
+    #[arg(long, env = "FHIR_FUSE_STAGING", num_args = 0..=1, default_missing_value = "true")]
+    pub staging: Option<bool>,
+
+    /// Send closed files in batch Bundles in the background instead of one
+    /// PUT per file
+    #[arg(long, env = "FHIR_FUSE_WRITE_BEHIND", num_args = 0..=1, default_missing_value = "true")]
+    pub write_behind: Option<bool>,
+
+    /// How long write-behind gathers writes before sending them, in milliseconds
+    #[arg(long, env = "FHIR_FUSE_BATCH_WINDOW_MS")]
+    pub batch_window_ms: Option<u64>,
+
+    /// Entries per write-behind batch Bundle
+    #[arg(long, env = "FHIR_FUSE_BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+
+    /// File keeping writes and deletes the server couldn't be reached for,
+    /// replayed in order once it can
+    #[arg(long, env = "FHIR_FUSE_JOURNAL")]
+    pub journal: Option<PathBuf>,
+
+    /// How resources are rendered: lossless (the server's key order) or
+    /// canonical (FHIR element order, sorted extensions)
+    #[arg(long, env = "FHIR_FUSE_JSON_FORMAT")]
+    pub json_format: Option<String>,
+
+    /// Check resources before they are sent: local (JSON schema and
+    /// StructureDefinitions), server ($validate) or off
+    #[arg(long, env = "FHIR_FUSE_VALIDATE")]
+    pub validate: Option<String>,
+
+    /// FHIR JSON schema (fhir.schema.json) checked by --validate local
+    #[arg(long, env = "FHIR_FUSE_FHIR_SCHEMA")]
+    pub fhir_schema: Option<PathBuf>,
+
+    /// FHIR package directories whose StructureDefinitions are checked by
+    /// --validate local and build the `_template.json` files
+    /// (comma-separated)
+    #[arg(long, env = "FHIR_FUSE_PACKAGES", value_delimiter = ',')]
+    pub packages: Option<Vec<PathBuf>>,
+
+    /// Profiles (canonical urls, comma-separated) the `_template.json` of
+    /// their resource type is built from instead of the base definition
+    #[arg(long, env = "FHIR_FUSE_TEMPLATE_PROFILES", value_delimiter = ',')]
+    pub template_profiles: Option<Vec<String>>,
+}
+
+macro_rules! merge_fields {
+    ($target:expr, $fallback:expr, $($field:ident),* $(,)?) => {
+        $(
+            if $target.$field.is_none() {
+                $target.$field = $fallback.$field;
+            }
+        )*
+    };
+}
+
+impl Settings {
+    /// Fill every unset field from `fallback`
+    pub fn merge(mut self, fallback: Settings) -> Settings {
+        merge_fields!(
+            self,
+            fallback,
+            mountpoint,
+            fhir_base_url,
+            auth_mode,
+            client_id,
+            client_secret,
+            token_url,
+            scope,
+            private_key,
+            jwt_alg,
+            jwt_kid,
+            basic_auth_user,
+            basic_auth_password,
+            bearer_token,
+            bearer_token_file,
+            headers,
+            ca_bundle,
+            client_cert,
+            client_key,
+            ttl,
+            cache_duration,
+            max_resources,
+            page_size,
+            max_concurrent_fetches,
+            allow_other,
+            direct_io,
+            read_only,
+            uid,
+            gid,
+            resource_types,
+            writable_types,
+            rewrite_ids,
+            rename_references,
+            referencing_types,
+            check_references,
+            staging,
+            write_behind,
+            batch_window_ms,
+            batch_size,
+            journal,
+            json_format,
+            validate,
+            fhir_schema,
+            packages,
+            template_profiles,
+        );
+        self
+    }
+}
+
+/// Layout of the TOML config file: top-level defaults plus named profiles
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    pub defaults: Settings,
+    pub profiles: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        // `profiles` is split off by hand: serde's `flatten` would silently
+        // accept misspelled keys
+        let mut table: toml::Table = toml::from_str(text)?;
+        let profiles = match table.remove("profiles") {
+            Some(profiles) => profiles.try_into()?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            defaults: table.try_into()?,
+            profiles,
+        })
+    }
+
+    /// Settings of the given profile, falling back to the top-level defaults
+    pub fn settings(mut self, profile: Option<&str>) -> anyhow::Result<Settings> {
+        match profile {
+            Some(name) => {
+                let selected = self.profiles.remove(name).ok_or_else(|| {
+                    let mut known: Vec<_> = self.profiles.keys().cloned().collect();
+                    known.sort();
+                    anyhow::anyhow!(
+                        "Unknown profile {:?} (available: {})",
+                        name,
+                        known.join(", ")
+                    )
+                })?;
+                Ok(selected.merge(self.defaults))
+            }
+            None => Ok(self.defaults),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    ClientCredentials,
+    SmartBackend,
+}
+
+/// OAuth2 settings before the key is loaded and the token endpoint discovered
+#[derive(Debug, Clone)]
+pub struct AuthSettings {
+    pub mode: AuthMode,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub token_url: Option<String>,
+    pub scope: Option<String>,
+    pub private_key: Option<PathBuf>,
+    pub jwt_alg: String,
+    pub jwt_kid: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MountSettings {
+    pub allow_other: bool,
+    pub direct_io: bool,
+    pub read_only: bool,
+    pub writable_types: Option<Vec<String>>,
+}
+
+impl MountSettings {
+    /// Whether resources of this type may be created, changed or deleted
+    pub fn is_writable(&self, resource_type: &str) -> bool {
+        !self.read_only
+            && self
+                .writable_types
+                .as_ref()
+                .map(|types| types.iter().any(|t| t == resource_type))
+                .unwrap_or(true)
+    }
+}
+
+/// What a rename does about references to the renamed resource
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RenameReferences {
+    /// Leave referencing resources alone
+    #[default]
+    Keep,
+    /// Point references at the new id
+    Rewrite,
+    /// Fail the rename while anything references the resource
+    Refuse,
+}
+
+/// What a write does about relative references to missing resources
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CheckReferences {
+    /// Send the resource without looking
+    #[default]
+    Off,
+    /// Send it, and log the missing targets
+    Warn,
+    /// Don't send it, and list the missing targets in the `.error` file
+    Block,
+}
+
+/// How file contents are turned into writes
+#[derive(Debug, Clone, Default)]
+pub struct WriteSettings {
+    pub rewrite_ids: bool,
+    pub rename_references: RenameReferences,
+    /// Types searched for inbound references; `None` searches all
+    pub referencing_types: Option<Vec<String>>,
+    pub check_references: CheckReferences,
+    /// Writes wait in `.staging/` for an explicit commit
+    pub staging: bool,
+    /// Writes are gathered into batch Bundles, with `--write-behind`
+    pub write_behind: Option<BatchSettings>,
+    /// Where writes wait while the server is unreachable, with `--journal`
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSettings {
+    /// Writes arriving within this time of the first one go out together
+    pub window: Duration,
+    /// Entries per Bundle; the Bundles of a window are sent concurrently
+    pub batch_size: usize,
+}
+
+/// Where resources are validated before they are sent
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ValidationMode {
+    /// Leave it to the server's write
+    #[default]
+    Off,
+    /// Against the JSON schema and StructureDefinitions on this machine
+    Local,
+    /// With the server's `$validate` operation
+    Server,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationSettings {
+    pub mode: ValidationMode,
+    pub fhir_schema: Option<PathBuf>,
+    pub packages: Vec<PathBuf>,
+}
+
+/// Fully resolved configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mountpoint: PathBuf,
+    pub fhir_base_url: String,
+    pub auth: Option<AuthSettings>,
+    pub http: HttpClientSettings,
+    pub ttl: Duration,
+    pub cache_duration: Duration,
+    pub fetch_limits: FetchLimits,
+    pub mount: MountSettings,
+    pub write: WriteSettings,
+    pub json_format: JsonFormat,
+    pub validation: ValidationSettings,
+    /// Profiles to build `_template.json` from, by canonical url
+    pub template_profiles: Vec<String>,
+    pub uid: u32,
+    pub gid: u32,
+    pub resource_types: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Parse the command line, then layer the environment and config file under it
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> anyhow::Result<Self> {
+        let settings = match &cli.config {
+            Some(path) => {
+                let file = ConfigFile::load(path)?;
+                cli.settings.merge(file.settings(cli.profile.as_deref())?)
+            }
+            None if cli.profile.is_some() => {
+                return Err(anyhow::anyhow!(
+                    "--profile requires a config file (--config)"
+                ));
+            }
+            None => cli.settings,
+        };
+        Self::resolve(settings)
+    }
+
+    pub fn resolve(settings: Settings) -> anyhow::Result<Self> {
+        let mountpoint = settings
+            .mountpoint
+            .ok_or_else(|| anyhow::anyhow!("No mount point given (argument or MOUNT_POINT)"))?;
+        let fhir_base_url = settings
+            .fhir_base_url
+            .ok_or_else(|| {
+                anyhow::anyhow!("No FHIR server URL given (argument or FHIR_SERVER_URL)")
+            })?
+            .trim_end_matches('/')
+            .to_string();
+
+        let mode = match (settings.auth_mode.as_deref(), &settings.client_id) {
+            (None, None) | (Some("none"), _) => None,
+            (None, Some(_)) | (Some("client-credentials"), _) => Some(AuthMode::ClientCredentials),
+            (Some("smart-backend"), _) => Some(AuthMode::SmartBackend),
+            (Some(other), _) => return Err(anyhow::anyhow!("Unknown auth mode: {}", other)),
+        };
+
+        let rename_references = match settings.rename_references.as_deref() {
+            None | Some("keep") => RenameReferences::Keep,
+            Some("rewrite") => RenameReferences::Rewrite,
+            Some("refuse") => RenameReferences::Refuse,
+            Some(other) => {
+                return Err(anyhow::anyhow!(
+                    "Unknown rename-references mode: {} (keep, rewrite or refuse)",
+                    other
+                ))
+            }
+        };
+
+        let check_references = match settings.check_references.as_deref() {
+            None | Some("off") => CheckReferences::Off,
+            Some("warn") => CheckReferences::Warn,
+            Some("block") => CheckReferences::Block,
+            Some(other) => {
+                return Err(anyhow::anyhow!(
+                    "Unknown check-references mode: {} (off, warn or block)",
+                    other
+                ))
+            }
+        };
+
+        let staging = settings.staging.unwrap_or(false);
+        let write_behind = settings
+            .write_behind
+            .unwrap_or(false)
+            .then(|| BatchSettings {
+                window: Duration::from_millis(
+                    settings.batch_window_ms.unwrap_or(DEFAULT_BATCH_WINDOW_MS),
+                ),
+                batch_size: settings.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+            });
+        if staging && write_behind.is_some() {
+            return Err(anyhow::anyhow!(
+                "--staging and --write-behind cannot be combined"
+            ));
+        }
+        if settings.journal.is_some() && (staging || write_behind.is_some()) {
+            return Err(anyhow::anyhow!(
+                "--journal cannot be combined with --staging or --write-behind"
+            ));
+        }
+
+        let json_format = match settings.json_format.as_deref() {
+            None | Some("lossless") => JsonFormat::Lossless,
+            Some("canonical") => JsonFormat::Canonical,
+            Some(other) => {
+                return Err(anyhow::anyhow!(
+                    "Unknown JSON format: {} (lossless or canonical)",
+                    other
+                ))
+            }
+        };
+
+        let validation = ValidationSettings {
+            mode: match settings.validate.as_deref() {
+                None | Some("off") => ValidationMode::Off,
+                Some("local") => ValidationMode::Local,
+                Some("server") => ValidationMode::Server,
+                Some(other) => {
+                    return Err(anyhow::anyhow!(
+                        "Unknown validation mode: {} (local, server or off)",
+                        other
+                    ))
+                }
+            },
+            fhir_schema: settings.fhir_schema,
+            packages: settings.packages.unwrap_or_default(),
+        };
+        if validation.mode == ValidationMode::Local
+            && validation.fhir_schema.is_none()
+            && validation.packages.is_empty()
+        {
+            return Err(anyhow::anyhow!(
+                "--validate local needs --fhir-schema or --packages"
+            ));
+        }
+
+        let auth = match mode {
+            Some(mode) => {
+                let client_id = settings
+                    .client_id
+                    .ok_or_else(|| anyhow::anyhow!("--client-id is required for OAuth2 auth"))?;
+                match mode {
+                    AuthMode::ClientCredentials => {
+                        if settings.client_secret.is_none() {
+                            return Err(anyhow::anyhow!(
+                                "--client-secret is required for client-credentials auth"
+                            ));
+                        }
+                        if settings.token_url.is_none() {
+                            return Err(anyhow::anyhow!(
+                                "--token-url is required for client-credentials auth"
+                            ));
+                        }
+                    }
+                    AuthMode::SmartBackend => {
+                        if settings.private_key.is_none() {
+                            return Err(anyhow::anyhow!(
+                                "--private-key is required for smart-backend auth"
+                            ));
+                        }
+                    }
+                }
+                Some(AuthSettings {
+                    mode,
+                    client_id,
+                    client_secret: settings.client_secret,
+                    token_url: settings.token_url,
+                    scope: settings.scope,
+                    private_key: settings.private_key,
+                    jwt_alg: settings.jwt_alg.unwrap_or_else(|| "RS384".to_string()),
+                    jwt_kid: settings.jwt_kid,
+                })
+            }
+            None => None,
+        };
+
+        let http = resolve_http_settings(
+            settings.basic_auth_user,
+            settings.basic_auth_password,
+            settings.bearer_token,
+            settings.bearer_token_file,
+            settings.headers.unwrap_or_default(),
+            settings.ca_bundle,
+            settings.client_cert,
+            settings.client_key,
+        )?;
+
+        if auth.is_some() && (http.basic_auth.is_some() || http.bearer_token.is_some()) {
+            return Err(anyhow::anyhow!(
+                "Static credentials cannot be combined with OAuth2 authentication"
+            ));
+        }
+
+        let defaults = FetchLimits::default();
+        let fetch_limits = FetchLimits {
+            max_resources: settings.max_resources.unwrap_or(defaults.max_resources),
+            page_size: settings.page_size.unwrap_or(defaults.page_size).max(1),
+            max_concurrent_fetches: settings
+                .max_concurrent_fetches
+                .unwrap_or(defaults.max_concurrent_fetches)
+                .max(1),
+        };
+
+        Ok(Config {
+            mountpoint,
+            fhir_base_url,
+            auth,
+            http,
+            ttl: Duration::from_secs(settings.ttl.unwrap_or(DEFAULT_TTL_SECS)),
+            cache_duration: Duration::from_secs(
+                settings
+                    .cache_duration
+                    .unwrap_or(DEFAULT_CACHE_DURATION_SECS),
+            ),
+            fetch_limits,
+            mount: MountSettings {
+                allow_other: settings.allow_other.unwrap_or(true),
+                direct_io: settings.direct_io.unwrap_or(true),
+                read_only: settings.read_only.unwrap_or(false),
+                writable_types: settings.writable_types,
+            },
+            write: WriteSettings {
+                rewrite_ids: settings.rewrite_ids.unwrap_or(false),
+                rename_references,
+                referencing_types: settings.referencing_types,
+                check_references,
+                staging,
+                write_behind,
+                journal: settings.journal,
+            },
+            json_format,
+            validation,
+            template_profiles: settings.template_profiles.unwrap_or_default(),
+            uid: settings.uid.unwrap_or(DEFAULT_UID),
+            gid: settings.gid.unwrap_or(DEFAULT_GID),
+            resource_types: settings.resource_types,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn resolve_http_settings(
+    basic_auth_user: Option<String>,
+    basic_auth_password: Option<String>,
+    bearer_token: Option<String>,
+    bearer_token_file: Option<PathBuf>,
+    headers: Vec<String>,
+    ca_bundle: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+) -> anyhow::Result<HttpClientSettings> {
+    let bearer_token = match bearer_token_file {
+        Some(path) => Some(
+            std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?
+                .trim()
+                .to_string(),
+        ),
+        None => bearer_token,
+    };
+
+    let basic_auth = basic_auth_user.map(|user| (user, basic_auth_password.unwrap_or_default()));
+
+    if basic_auth.is_some() && bearer_token.is_some() {
+        return Err(anyhow::anyhow!(
+            "Basic auth and a bearer token cannot be used together"
+        ));
+    }
+
+    let headers = headers
+        .iter()
+        .filter(|spec| !spec.trim().is_empty())
+        .map(|spec| parse_header(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(HttpClientSettings {
+        basic_auth,
+        bearer_token,
+        headers,
+        ca_bundle,
+        client_cert,
+        client_key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        mountpoint = "/mnt/fhir"
+        page_size = 50
+        resource_types = ["Patient", "Observation"]
+
+        [profiles.dev]
+        fhir_base_url = "http://localhost:8080/fhir"
+
+        [profiles.staging]
+        fhir_base_url = "https://staging.example.org/fhir/"
+        client_id = "fuse"
+        client_secret = "secret"
+        token_url = "https://staging.example.org/auth/token"
+        read_only = true
+        page_size = 200
+    "#;
+
+    fn settings(base_url: &str) -> Settings {
+        Settings {
+            mountpoint: Some("/tmp/fhir".into()),
+            fhir_base_url: Some(base_url.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::resolve(settings("http://localhost:8080/fhir")).unwrap();
+
+        assert_eq!(config.ttl, Duration::from_secs(30));
+        assert_eq!(config.cache_duration, Duration::from_secs(5));
+        assert_eq!(config.fetch_limits.max_resources, 1000);
+        assert_eq!(config.fetch_limits.page_size, 100);
+        assert_eq!(config.fetch_limits.max_concurrent_fetches, 10);
+        assert!(config.mount.allow_other);
+        assert!(!config.mount.read_only);
+        assert!(!config.write.rewrite_ids);
+        assert_eq!(config.write.rename_references, RenameReferences::Keep);
+        assert_eq!(config.write.check_references, CheckReferences::Off);
+        assert!(!config.write.staging);
+        assert_eq!(config.write.write_behind, None);
+        assert_eq!(config.write.journal, None);
+        assert_eq!(config.json_format, JsonFormat::Lossless);
+        assert_eq!(config.validation.mode, ValidationMode::Off);
+        assert_eq!((config.uid, config.gid), (501, 20));
+        assert!(config.auth.is_none());
+        assert!(config.resource_types.is_none());
+    }
+
+    #[test]
+    fn test_profile_overrides_defaults() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let config = Config::resolve(file.settings(Some("staging")).unwrap()).unwrap();
+
+        assert_eq!(config.fhir_base_url, "https://staging.example.org/fhir");
+        assert_eq!(config.mountpoint, PathBuf::from("/mnt/fhir"));
+        assert_eq!(config.fetch_limits.page_size, 200);
+        assert!(config.mount.read_only);
+        assert_eq!(
+            config.resource_types,
+            Some(vec!["Patient".to_string(), "Observation".to_string()])
+        );
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.mode, AuthMode::ClientCredentials);
+        assert_eq!(auth.client_id, "fuse");
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/other",
+            "--page-size",
+            "10",
+            "--read-only=false",
+        ])
+        .unwrap();
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let merged = cli.settings.merge(file.settings(Some("staging")).unwrap());
+        let config = Config::resolve(merged).unwrap();
+
+        assert_eq!(config.mountpoint, PathBuf::from("/tmp/other"));
+        assert_eq!(config.fetch_limits.page_size, 10);
+        assert!(!config.mount.read_only);
+        assert_eq!(config.fhir_base_url, "https://staging.example.org/fhir");
+    }
+
+    #[test]
+    fn test_writable_types() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--writable-types",
+            "Patient,Observation",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings).unwrap();
+
+        assert!(config.mount.is_writable("Patient"));
+        assert!(config.mount.is_writable("Observation"));
+        assert!(!config.mount.is_writable("Encounter"));
+
+        let read_only = MountSettings {
+            read_only: true,
+            ..config.mount
+        };
+        assert!(!read_only.is_writable("Patient"));
+    }
+
+    #[test]
+    fn test_rename_references() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--rename-references",
+            "rewrite",
+            "--referencing-types",
+            "Observation,Encounter",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings).unwrap();
+        assert_eq!(config.write.rename_references, RenameReferences::Rewrite);
+        assert_eq!(
+            config.write.referencing_types,
+            Some(vec!["Observation".to_string(), "Encounter".to_string()])
+        );
+
+        let mut unknown = settings("http://localhost:8080/fhir");
+        unknown.rename_references = Some("move".to_string());
+        assert!(Config::resolve(unknown).is_err());
+    }
+
+    #[test]
+    fn test_check_references() {
+        let mut settings = settings("http://localhost:8080/fhir");
+        settings.check_references = Some("block".to_string());
+        let config = Config::resolve(settings.clone()).unwrap();
+        assert_eq!(config.write.check_references, CheckReferences::Block);
+
+        settings.check_references = Some("fail".to_string());
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let mut settings = settings("http://localhost:8080/fhir");
+        settings.write_behind = Some(true);
+        settings.batch_size = Some(0);
+        let config = Config::resolve(settings.clone()).unwrap();
+        assert_eq!(
+            config.write.write_behind,
+            Some(BatchSettings {
+                window: Duration::from_millis(200),
+                batch_size: 1,
+            })
+        );
+
+        settings.staging = Some(true);
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--journal",
+            "/var/tmp/fhir-journal.jsonl",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings.clone()).unwrap();
+        assert_eq!(
+            config.write.journal,
+            Some(PathBuf::from("/var/tmp/fhir-journal.jsonl"))
+        );
+
+        let mut settings = cli.settings;
+        settings.write_behind = Some(true);
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_json_format() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--json-format",
+            "canonical",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings).unwrap();
+        assert_eq!(config.json_format, JsonFormat::Canonical);
+
+        let mut unknown = settings("http://localhost:8080/fhir");
+        unknown.json_format = Some("compact".to_string());
+        assert!(Config::resolve(unknown).is_err());
+    }
+
+    #[test]
+    fn test_validation() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--validate",
+            "local",
+            "--packages",
+            "/opt/fhir/r4.core,/opt/fhir/us.core",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings).unwrap();
+        assert_eq!(config.validation.mode, ValidationMode::Local);
+        assert_eq!(config.validation.packages.len(), 2);
+
+        // Local validation needs something to validate against
+        let mut settings = settings("http://localhost:8080/fhir");
+        settings.validate = Some("local".to_string());
+        assert!(Config::resolve(settings.clone()).is_err());
+        settings.validate = Some("server".to_string());
+        assert!(Config::resolve(settings.clone()).is_ok());
+        settings.validate = Some("strict".to_string());
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_template_profiles() {
+        let cli = Cli::try_parse_from([
+            "fhir-fuse",
+            "/tmp/fhir",
+            "http://localhost:8080/fhir",
+            "--template-profiles",
+            "http://hl7.org/fhir/us/core/StructureDefinition/us-core-patient",
+        ])
+        .unwrap();
+        let config = Config::resolve(cli.settings).unwrap();
+        assert_eq!(
+            config.template_profiles,
+            vec!["http://hl7.org/fhir/us/core/StructureDefinition/us-core-patient"]
+        );
+        let config = Config::resolve(settings("http://localhost:8080/fhir")).unwrap();
+        assert!(config.template_profiles.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let err = file.settings(Some("prod")).unwrap_err();
+        assert!(err.to_string().contains("dev, staging"));
+    }
+
+    #[test]
+    fn test_unknown_config_key_is_rejected() {
+        assert!(ConfigFile::parse("page_sise = 10").is_err());
+    }
+
+    #[test]
+    fn test_missing_base_url() {
+        let settings = Settings {
+            mountpoint: Some("/tmp/fhir".into()),
+            ..Default::default()
+        };
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_smart_backend_requires_private_key() {
+        let mut settings = settings("http://localhost:8080/fhir");
+        settings.auth_mode = Some("smart-backend".to_string());
+        settings.client_id = Some("backend".to_string());
+        assert!(Config::resolve(settings.clone()).is_err());
+
+        settings.private_key = Some("key.pem".into());
+        let auth = Config::resolve(settings).unwrap().auth.unwrap();
+        assert_eq!(auth.mode, AuthMode::SmartBackend);
+        assert_eq!(auth.jwt_alg, "RS384");
+    }
+
+    #[test]
+    fn test_headers_and_static_credentials() {
+        let mut settings = settings("http://localhost:8080/fhir");
+        settings.headers = Some(vec!["X-Tenant-Id: acme".to_string()]);
+        settings.basic_auth_user = Some("user".to_string());
+        let config = Config::resolve(settings.clone()).unwrap();
+        assert_eq!(
+            config.http.headers,
+            vec![("X-Tenant-Id".to_string(), "acme".to_string())]
+        );
+        assert_eq!(
+            config.http.basic_auth,
+            Some(("user".to_string(), String::new()))
+        );
+
+        settings.bearer_token = Some("token".to_string());
+        assert!(Config::resolve(settings).is_err());
+    }
+
+    #[test]
+    fn test_example_config_parses() {
+        let file = ConfigFile::parse(include_str!("../examples/fhir-fuse.toml")).unwrap();
+        let config = Config::resolve(file.settings(Some("dev")).unwrap()).unwrap();
+        assert_eq!(config.fhir_base_url, "http://localhost:8080/fhir");
+    }
+}
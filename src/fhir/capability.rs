@@ -1,8 +1,8 @@
+use super::http::FhirClient;
 use futures::stream::{self, StreamExt};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 // Maximum resources to fetch per resource type
 const MAX_RESOURCES: usize = 1000;
@@ -11,6 +11,24 @@ const PAGE_SIZE: usize = 100;
 // Maximum concurrent page fetches
 const MAX_CONCURRENT_FETCHES: usize = 10;
 
+/// Limits applied when loading all resources of a type
+#[derive(Debug, Clone)]
+pub struct FetchLimits {
+    pub max_resources: usize,
+    pub page_size: usize,
+    pub max_concurrent_fetches: usize,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            max_resources: MAX_RESOURCES,
+            page_size: PAGE_SIZE,
+            max_concurrent_fetches: MAX_CONCURRENT_FETCHES,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CapabilityStatement {
     #[serde(rename = "resourceType")]
@@ -30,6 +48,10 @@ struct ResourceDefinition {
     resource_type: String,
     profile: Option<String>,
     interaction: Option<Vec<Interaction>>,
+    #[serde(rename = "conditionalCreate")]
+    conditional_create: Option<bool>,
+    #[serde(rename = "conditionalUpdate")]
+    conditional_update: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -37,10 +59,65 @@ struct Interaction {
     code: String,
 }
 
+/// Write interactions a server declares for a resource type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourcePermissions {
+    pub update: bool,
+    pub create: bool,
+    pub delete: bool,
+    pub history: bool,
+    /// Create with `If-None-Exist`
+    pub conditional_create: bool,
+    /// `PUT <type>?<query>`
+    pub conditional_update: bool,
+}
+
+impl ResourcePermissions {
+    /// Used for types the server declares no interactions for
+    pub const ALL: Self = Self {
+        update: true,
+        create: true,
+        delete: true,
+        history: true,
+        conditional_create: true,
+        conditional_update: true,
+    };
+
+    /// Conditional interactions are assumed to come with their plain ones
+    /// unless the server says otherwise
+    fn from_definition(interactions: &[Interaction], resource: &ResourceDefinition) -> Self {
+        let has = |code: &str| interactions.iter().any(|i| i.code == code);
+        Self {
+            update: has("update"),
+            create: has("create"),
+            delete: has("delete"),
+            history: has("history-instance"),
+            conditional_create: has("create") && resource.conditional_create.unwrap_or(true),
+            conditional_update: has("update") && resource.conditional_update.unwrap_or(true),
+        }
+    }
+
+    /// Permission bits for resource files of this type
+    pub fn file_mode(&self) -> u16 {
+        if self.update {
+            0o644
+        } else {
+            0o444
+        }
+    }
+}
+
+impl Default for ResourcePermissions {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerCapabilities {
     pub resources: Vec<String>,
     pub searchable_resources: HashSet<String>,
+    pub permissions: HashMap<String, ResourcePermissions>,
 }
 
 impl ServerCapabilities {
@@ -48,6 +125,7 @@ impl ServerCapabilities {
         Self {
             resources: Vec::new(),
             searchable_resources: HashSet::new(),
+            permissions: HashMap::new(),
         }
     }
 
@@ -63,6 +141,13 @@ impl ServerCapabilities {
                                 capabilities.resources.push(resource.resource_type.clone());
 
                                 if let Some(interactions) = &resource.interaction {
+                                    capabilities.permissions.insert(
+                                        resource.resource_type.clone(),
+                                        ResourcePermissions::from_definition(
+                                            interactions,
+                                            &resource,
+                                        ),
+                                    );
                                     for interaction in interactions {
                                         if interaction.code == "search-type" {
                                             capabilities
@@ -84,16 +169,25 @@ impl ServerCapabilities {
         capabilities.resources.sort();
         capabilities
     }
+
+    /// Permissions for a resource type; everything is allowed when the
+    /// server is silent about the type
+    pub fn permissions(&self, resource_type: &str) -> ResourcePermissions {
+        self.permissions
+            .get(resource_type)
+            .copied()
+            .unwrap_or_default()
+    }
 }
 
 pub async fn fetch_capability_statement(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
 ) -> anyhow::Result<ServerCapabilities> {
     let url = format!("{}/metadata", fhir_base_url);
     println!("[FHIR] Fetching capability statement...");
 
-    let response = client.get(&url).send().await?;
+    let response = client.send(client.get(&url)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -122,8 +216,11 @@ pub async fn fetch_capability_statement(
 }
 
 /// Fetch a single page of resources and return (resources, next_page_url)
-async fn fetch_page(client: &Client, url: &str) -> anyhow::Result<(Vec<Value>, Option<String>)> {
-    let response = client.get(url).send().await?;
+async fn fetch_page(
+    client: &FhirClient,
+    url: &str,
+) -> anyhow::Result<(Vec<Value>, Option<String>)> {
+    let response = client.send(client.get(url)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -164,7 +261,7 @@ async fn fetch_page(client: &Client, url: &str) -> anyhow::Result<(Vec<Value>, O
 /// Sequential version - kept for reference/fallback
 #[allow(dead_code)]
 async fn fetch_resources(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
 ) -> anyhow::Result<Vec<Value>> {
@@ -201,7 +298,7 @@ async fn fetch_resources(
 
     while next_urls.len() < max_pages {
         // Fetch just to get the next URL
-        let response = client.get(&current_url).send().await?;
+        let response = client.send(client.get(&current_url)).await?;
         if !response.status().is_success() {
             break;
         }
@@ -283,8 +380,8 @@ fn extract_last_page(bundle: &Value) -> Option<usize> {
 }
 
 /// Fetch a single page by URL
-async fn fetch_page_by_url(client: &Client, url: &str) -> anyhow::Result<Vec<Value>> {
-    let response = client.get(url).send().await?;
+async fn fetch_page_by_url(client: &FhirClient, url: &str) -> anyhow::Result<Vec<Value>> {
+    let response = client.send(client.get(url)).await?;
 
     if !response.status().is_success() {
         let status = response.status();
@@ -315,14 +412,18 @@ async fn fetch_page_by_url(client: &Client, url: &str) -> anyhow::Result<Vec<Val
 
 /// Fetch resources with parallel page fetching using link-based pagination
 pub async fn fetch_resources_parallel(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
+    limits: &FetchLimits,
 ) -> anyhow::Result<Vec<Value>> {
-    let initial_url = format!("{}/{}?_count={}", fhir_base_url, resource_type, PAGE_SIZE);
+    let initial_url = format!(
+        "{}/{}?_count={}",
+        fhir_base_url, resource_type, limits.page_size
+    );
 
     // First, fetch initial page to discover pagination structure
-    let response = client.get(&initial_url).send().await?;
+    let response = client.send(client.get(&initial_url)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -350,9 +451,9 @@ pub async fn fetch_resources_parallel(
     // Get total count from bundle
     let total = bundle["total"].as_u64().unwrap_or(0) as usize;
     let total_to_fetch = if total > 0 {
-        total.min(MAX_RESOURCES)
+        total.min(limits.max_resources)
     } else {
-        MAX_RESOURCES
+        limits.max_resources
     };
 
     // Check if we have all resources already
@@ -369,11 +470,14 @@ pub async fn fetch_resources_parallel(
     // If we have last page info and _page pattern, we can fetch in parallel
     if let Some(last_page_num) = last_page {
         // Calculate max pages based on resource limit
-        let max_pages = (MAX_RESOURCES + PAGE_SIZE - 1) / PAGE_SIZE;
+        let max_pages = limits.max_resources.div_ceil(limits.page_size);
         let pages_to_fetch = last_page_num.min(max_pages);
 
         // Generate all page URLs (pages 2 to last)
-        let base_url = format!("{}/{}?_count={}", fhir_base_url, resource_type, PAGE_SIZE);
+        let base_url = format!(
+            "{}/{}?_count={}",
+            fhir_base_url, resource_type, limits.page_size
+        );
         let page_urls: Vec<String> = (2..=pages_to_fetch)
             .map(|page| format!("{}&_page={}", base_url, page))
             .collect();
@@ -384,7 +488,7 @@ pub async fn fetch_resources_parallel(
                 let client = client.clone();
                 async move { fetch_page_by_url(&client, &url).await }
             })
-            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .buffer_unordered(limits.max_concurrent_fetches)
             .collect()
             .await;
 
@@ -419,7 +523,7 @@ pub async fn fetch_resources_parallel(
             break;
         }
 
-        let response = client.get(&url).send().await?;
+        let response = client.send(client.get(&url)).await?;
         if !response.status().is_success() {
             break;
         }
@@ -492,4 +596,65 @@ mod tests {
         assert_eq!(capabilities.searchable_resources.len(), 1);
         assert!(capabilities.searchable_resources.contains("Patient"));
     }
+
+    #[test]
+    fn test_permissions_from_interactions() {
+        let json = r#"{
+            "resourceType": "CapabilityStatement",
+            "rest": [
+                {
+                    "mode": "server",
+                    "resource": [
+                        {
+                            "type": "Patient",
+                            "interaction": [
+                                {"code": "read"},
+                                {"code": "update"},
+                                {"code": "create"},
+                                {"code": "delete"},
+                                {"code": "history-instance"}
+                            ]
+                        },
+                        {
+                            "type": "AuditEvent",
+                            "conditionalCreate": false,
+                            "interaction": [
+                                {"code": "read"},
+                                {"code": "create"},
+                                {"code": "search-type"}
+                            ]
+                        },
+                        {
+                            "type": "Binary"
+                        }
+                    ]
+                }
+            ]
+        }"#;
+
+        let statement: CapabilityStatement = serde_json::from_str(json).unwrap();
+        let capabilities = ServerCapabilities::from_capability_statement(statement);
+
+        assert_eq!(
+            capabilities.permissions("Patient"),
+            ResourcePermissions::ALL
+        );
+        assert_eq!(capabilities.permissions("Patient").file_mode(), 0o644);
+
+        let audit = capabilities.permissions("AuditEvent");
+        assert!(audit.create);
+        assert!(!audit.update);
+        assert!(!audit.delete);
+        assert!(!audit.history);
+        assert!(!audit.conditional_create);
+        assert!(!audit.conditional_update);
+        assert_eq!(audit.file_mode(), 0o444);
+
+        // No declared interactions, or not mentioned at all
+        assert_eq!(capabilities.permissions("Binary"), ResourcePermissions::ALL);
+        assert_eq!(
+            capabilities.permissions("ViewDefinition"),
+            ResourcePermissions::ALL
+        );
+    }
 }
@@ -1,9 +1,77 @@
-use reqwest::Client;
+use super::http::FhirClient;
+use super::patch::PatchFormat;
+use reqwest::header::{ETAG, LOCATION};
+use reqwest::StatusCode;
 use serde_json::json;
 
-#[allow(dead_code)]
+/// Non-success response from the FHIR server. Kept as a typed error so
+/// callers can react to the status (e.g. 412 on a version conflict).
+#[derive(Debug)]
+pub struct HttpError {
+    pub action: String,
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to {}: HTTP {} - {}",
+            self.action, self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl HttpError {
+    /// 409 Conflict or 412 Precondition Failed: the resource changed on the
+    /// server since we loaded it
+    pub fn is_conflict(&self) -> bool {
+        self.status == StatusCode::CONFLICT || self.status == StatusCode::PRECONDITION_FAILED
+    }
+}
+
+/// Result of a successful create or update
+#[derive(Debug, Clone, Default)]
+pub struct WriteResponse {
+    /// The stored resource, when the server returned it
+    pub resource: Option<serde_json::Value>,
+    /// New `meta.versionId`, from the ETag or the returned resource
+    pub version_id: Option<String>,
+    /// Id the resource is stored under, from the returned resource or the
+    /// `Location` header
+    pub resource_id: Option<String>,
+}
+
+/// `W/"3"` -> `3`
+fn version_from_etag(etag: &str) -> Option<String> {
+    let version = etag.trim().trim_start_matches("W/").trim_matches('"');
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// `<base>/Patient/123/_history/1` -> `123`
+fn id_from_location(location: &str, resource_type: &str) -> Option<String> {
+    let mut segments = location.split('/');
+    segments.find(|segment| *segment == resource_type)?;
+    segments
+        .next()
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+}
+
+/// `meta.versionId` of a resource
+pub fn version_id_of(resource: &serde_json::Value) -> Option<String> {
+    resource
+        .get("meta")
+        .and_then(|meta| meta.get("versionId"))
+        .and_then(|version| version.as_str())
+        .map(String::from)
+}
+
 pub async fn get_from_fhir_server(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     resource_id: &str,
@@ -14,9 +82,7 @@ pub async fn get_from_fhir_server(
     );
 
     let response = client
-        .get(&url)
-        .header("Accept", "application/fhir+json")
-        .send()
+        .send(client.get(&url).header("Accept", "application/fhir+json"))
         .await?;
 
     let status = response.status();
@@ -25,55 +91,379 @@ pub async fn get_from_fhir_server(
     if status.is_success() {
         Ok(response_text)
     } else {
-        Err(anyhow::anyhow!(
-            "Failed to GET resource from FHIR server: HTTP {} - {}",
+        Err(HttpError {
+            action: "GET resource from FHIR server".to_string(),
             status,
-            response_text
-        ))
+            body: response_text,
+        }
+        .into())
     }
 }
 
+/// Create or update a resource. With `version_id` the update is sent with
+/// `If-Match`, so the server rejects it if someone else changed the resource.
+/// Asks for the stored resource back so the file can show what the server
+/// actually saved.
 pub async fn put_to_fhir_server(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     filename: &str,
     content: &str,
-) -> anyhow::Result<String> {
+    version_id: Option<&str>,
+) -> anyhow::Result<WriteResponse> {
     let resource_id = filename.trim_end_matches(".json");
     let url = format!("{}/{}/{}", fhir_base_url, resource_type, resource_id);
 
-    let response = client
+    let mut request = client
         .put(&url)
         .header("Content-Type", "application/fhir+json")
-        .body(content.to_string())
-        .send()
-        .await?;
+        .header("Prefer", "return=representation")
+        .body(content.to_string());
+    if let Some(version_id) = version_id {
+        request = request.header("If-Match", format!("W/\"{}\"", version_id));
+    }
+
+    let response = client.send(request).await?;
+    read_write_response(response, resource_type, "PUT resource to FHIR server").await
+}
+
+/// Create a resource with an id chosen by the server. With `if_none_exist`
+/// (a search query) it's a conditional create: the server only creates the
+/// resource when nothing matches, and otherwise answers with the match.
+pub async fn post_to_fhir_server(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    resource_type: &str,
+    content: &str,
+    if_none_exist: Option<&str>,
+) -> anyhow::Result<WriteResponse> {
+    let url = format!("{}/{}", fhir_base_url, resource_type);
+
+    let mut request = client
+        .post(&url)
+        .header("Content-Type", "application/fhir+json")
+        .header("Prefer", "return=representation")
+        .body(content.to_string());
+    if let Some(query) = if_none_exist {
+        request = request.header("If-None-Exist", query);
+    }
+
+    let response = client.send(request).await?;
+    read_write_response(response, resource_type, "POST resource to FHIR server").await
+}
+
+/// Conditional update: `PUT <type>?<query>` updates the single resource
+/// matching the search, or creates one when nothing matches
+pub async fn put_conditional_to_fhir_server(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    resource_type: &str,
+    query: &str,
+    content: &str,
+) -> anyhow::Result<WriteResponse> {
+    let url = format!("{}/{}?{}", fhir_base_url, resource_type, query);
+
+    let request = client
+        .put(&url)
+        .header("Content-Type", "application/fhir+json")
+        .header("Prefer", "return=representation")
+        .body(content.to_string());
 
+    let response = client.send(request).await?;
+    read_write_response(response, resource_type, "conditional PUT to FHIR server").await
+}
+
+/// Apply a JSON Patch or FHIRPath Patch to a resource on the server. No
+/// `If-Match` is sent: the server applies the patch to whatever version it
+/// has, which is what makes patches safe to run next to other writers.
+pub async fn patch_fhir_resource(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    resource_type: &str,
+    resource_id: &str,
+    content: &str,
+    format: PatchFormat,
+) -> anyhow::Result<WriteResponse> {
+    let url = format!("{}/{}/{}", fhir_base_url, resource_type, resource_id);
+
+    let request = client
+        .patch(&url)
+        .header("Content-Type", format.content_type())
+        .header("Prefer", "return=representation")
+        .body(content.to_string());
+
+    let response = client.send(request).await?;
+    read_write_response(response, resource_type, "PATCH resource on FHIR server").await
+}
+
+/// POST a `transaction` Bundle to the base url. The server applies all of
+/// its entries or none; the result has one `WriteResponse` per entry of the
+/// response Bundle, in order (deletes have neither resource nor version).
+pub async fn post_transaction(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    bundle: &serde_json::Value,
+) -> anyhow::Result<Vec<WriteResponse>> {
+    let entries = post_bundle(client, fhir_base_url, bundle, "transaction").await?;
+    Ok(entries.iter().map(entry_response).collect())
+}
+
+/// POST a `batch` Bundle to the base url. Each entry succeeds or fails on
+/// its own: the result has one outcome per entry of the response Bundle, in
+/// order, failed entries as an `HttpError` with the entry's status and
+/// OperationOutcome.
+pub async fn post_batch(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    bundle: &serde_json::Value,
+) -> anyhow::Result<Vec<Result<WriteResponse, HttpError>>> {
+    let entries = post_bundle(client, fhir_base_url, bundle, "batch").await?;
+    Ok(entries
+        .iter()
+        .map(|entry| {
+            let response = &entry["response"];
+            // "201 Created"
+            let status = response["status"]
+                .as_str()
+                .and_then(|status| status.split_whitespace().next())
+                .and_then(|code| code.parse::<u16>().ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            if status.is_success() {
+                return Ok(entry_response(entry));
+            }
+            Err(HttpError {
+                action: format!(
+                    "{} in batch",
+                    response["location"].as_str().unwrap_or("write")
+                ),
+                status,
+                body: response
+                    .get("outcome")
+                    .map(|outcome| outcome.to_string())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// Send a Bundle and return the entries of the response Bundle
+async fn post_bundle(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    bundle: &serde_json::Value,
+    kind: &str,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let request = client
+        .post(fhir_base_url)
+        .header("Content-Type", "application/fhir+json")
+        .header("Prefer", "return=representation")
+        .body(bundle.to_string());
+
+    let response = client.send(request).await?;
     let status = response.status();
     let response_text = response.text().await?;
+    if !status.is_success() {
+        return Err(HttpError {
+            action: format!("POST {} Bundle to FHIR server", kind),
+            status,
+            body: response_text,
+        }
+        .into());
+    }
+
+    let result: serde_json::Value = serde_json::from_str(&response_text).unwrap_or_default();
+    Ok(result["entry"].as_array().cloned().unwrap_or_default())
+}
+
+/// What a successful entry of a transaction or batch response stored
+fn entry_response(entry: &serde_json::Value) -> WriteResponse {
+    let resource = entry
+        .get("resource")
+        .filter(|resource| resource["resourceType"].is_string())
+        .cloned();
+    let version_id = entry["response"]["etag"]
+        .as_str()
+        .and_then(version_from_etag)
+        .or_else(|| resource.as_ref().and_then(version_id_of));
+    let resource_id = resource
+        .as_ref()
+        .and_then(|resource| resource["id"].as_str())
+        .map(String::from);
+    WriteResponse {
+        resource,
+        version_id,
+        resource_id,
+    }
+}
+
+/// Ask the server to `$validate` a resource without storing it and return
+/// its OperationOutcome. Servers answer 200 for valid and invalid resources
+/// alike, some 400 or 422 for invalid ones.
+pub async fn validate_on_server(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    resource_type: &str,
+    content: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let url = format!("{}/{}/$validate", fhir_base_url, resource_type);
+    let resource: serde_json::Value = serde_json::from_str(content)?;
+    let parameters = json!({
+        "resourceType": "Parameters",
+        "parameter": [{"name": "resource", "resource": resource}]
+    });
+
+    let request = client
+        .post(&url)
+        .header("Content-Type", "application/fhir+json")
+        .body(parameters.to_string());
+    let response = client.send(request).await?;
+
+    let status = response.status();
+    let response_text = response.text().await?;
+    let answered = status.is_success()
+        || status == StatusCode::BAD_REQUEST
+        || status == StatusCode::UNPROCESSABLE_ENTITY;
+    match serde_json::from_str::<serde_json::Value>(&response_text) {
+        Ok(outcome) if answered && outcome["resourceType"] == "OperationOutcome" => Ok(outcome),
+        _ => Err(HttpError {
+            action: format!("$validate {}", resource_type),
+            status,
+            body: response_text,
+        }
+        .into()),
+    }
+}
+
+/// Whether `<type>/<id>` exists on the server: a `HEAD`, or a `GET` where
+/// the server doesn't support `HEAD`. Deleted resources (410) don't exist.
+pub async fn resource_exists(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    reference: &str,
+) -> anyhow::Result<bool> {
+    let url = format!("{}/{}", fhir_base_url, reference);
+    let mut status = client
+        .send(client.head(&url).header("Accept", "application/fhir+json"))
+        .await?
+        .status();
+    if status == StatusCode::METHOD_NOT_ALLOWED || status == StatusCode::NOT_IMPLEMENTED {
+        status = client
+            .send(client.get(&url).header("Accept", "application/fhir+json"))
+            .await?
+            .status();
+    }
+
+    match status {
+        status if status.is_success() => Ok(true),
+        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(false),
+        status => Err(HttpError {
+            action: format!("look up {}", reference),
+            status,
+            body: String::new(),
+        }
+        .into()),
+    }
+}
+
+/// A StructureDefinition by canonical url (`StructureDefinition?url=`).
+/// `None` when the server has no definition with that url.
+pub async fn fetch_structure_definition(
+    client: &FhirClient,
+    fhir_base_url: &str,
+    url: &str,
+) -> anyhow::Result<Option<serde_json::Value>> {
+    let request = client
+        .get(&format!("{}/StructureDefinition", fhir_base_url))
+        .query(&[("url", url)])
+        .header("Accept", "application/fhir+json");
+    let response = client.send(request).await?;
+
+    let status = response.status();
+    let response_text = response.text().await?;
+    if !status.is_success() {
+        return Err(HttpError {
+            action: "search StructureDefinition".to_string(),
+            status,
+            body: response_text,
+        }
+        .into());
+    }
+
+    let bundle: serde_json::Value = serde_json::from_str(&response_text)?;
+    let definition = bundle["entry"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|entry| &entry["resource"])
+        .find(|resource| resource["url"] == url);
+    Ok(definition.cloned())
+}
+
+async fn read_write_response(
+    response: reqwest::Response,
+    resource_type: &str,
+    action: &str,
+) -> anyhow::Result<WriteResponse> {
+    let status = response.status();
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let etag = header(ETAG).and_then(|etag| version_from_etag(&etag));
+    let location = header(LOCATION);
+    let response_text = response.text().await?;
 
     if status.is_success() {
-        Ok(response_text)
+        let resource = serde_json::from_str::<serde_json::Value>(&response_text)
+            .ok()
+            .filter(|resource| resource["resourceType"] == resource_type);
+        let version_id = etag.or_else(|| resource.as_ref().and_then(version_id_of));
+        let resource_id = resource
+            .as_ref()
+            .and_then(|resource| resource["id"].as_str())
+            .map(String::from)
+            .or_else(|| {
+                location
+                    .as_deref()
+                    .and_then(|location| id_from_location(location, resource_type))
+            });
+        Ok(WriteResponse {
+            resource,
+            version_id,
+            resource_id,
+        })
     } else {
-        Err(anyhow::anyhow!(
-            "Failed to PUT resource to FHIR server: HTTP {} - {}",
+        Err(HttpError {
+            action: action.to_string(),
             status,
-            response_text
-        ))
+            body: response_text,
+        }
+        .into())
     }
 }
 
+/// Delete a resource. With `version_id` the delete is sent with `If-Match`,
+/// so the server refuses it if someone else changed the resource.
 pub async fn delete_from_fhir_server(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     filename: &str,
+    version_id: Option<&str>,
 ) -> anyhow::Result<()> {
     let resource_id = filename.trim_end_matches(".json");
     let url = format!("{}/{}/{}", fhir_base_url, resource_type, resource_id);
 
-    let response = client.delete(&url).send().await?;
+    let mut request = client.delete(&url);
+    if let Some(version_id) = version_id {
+        request = request.header("If-Match", format!("W/\"{}\"", version_id));
+    }
+    let response = client.send(request).await?;
 
     let status = response.status();
     let response_text = response.text().await?;
@@ -81,16 +471,17 @@ pub async fn delete_from_fhir_server(
     if status.is_success() || status.as_u16() == 404 {
         Ok(())
     } else {
-        Err(anyhow::anyhow!(
-            "Failed to DELETE resource from FHIR server: HTTP {} - {}",
+        Err(HttpError {
+            action: "DELETE resource from FHIR server".to_string(),
             status,
-            response_text
-        ))
+            body: response_text,
+        }
+        .into())
     }
 }
 
 pub async fn execute_operation(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     resource_id: &str,
@@ -119,11 +510,13 @@ pub async fn execute_operation(
     };
 
     let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .header("Accept", accept_header)
-        .body(parameters.to_string())
-        .send()
+        .send(
+            client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Accept", accept_header)
+                .body(parameters.to_string()),
+        )
         .await?;
 
     let status = response.status();
@@ -159,7 +552,7 @@ pub async fn execute_operation(
 /// Search for resources using FHIR search API
 /// Returns resources grouped by resource type (important for _include/_revinclude)
 pub async fn search_fhir_resources(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     query: &str,
@@ -170,9 +563,7 @@ pub async fn search_fhir_resources(
     println!("[FHIR] Search: {}", url);
 
     let response = client
-        .get(&url)
-        .header("Accept", "application/fhir+json")
-        .send()
+        .send(client.get(&url).header("Accept", "application/fhir+json"))
         .await?;
 
     let status = response.status();
@@ -218,7 +609,7 @@ pub async fn search_fhir_resources(
 
 /// Fetch history of a specific resource
 pub async fn fetch_resource_history(
-    client: &Client,
+    client: &FhirClient,
     fhir_base_url: &str,
     resource_type: &str,
     resource_id: &str,
@@ -231,9 +622,7 @@ pub async fn fetch_resource_history(
     println!("[FHIR] Fetching history: {}", url);
 
     let response = client
-        .get(&url)
-        .header("Accept", "application/fhir+json")
-        .send()
+        .send(client.get(&url).header("Accept", "application/fhir+json"))
         .await?;
 
     let status = response.status();
@@ -268,3 +657,337 @@ pub async fn fetch_resource_history(
 
     Ok(versions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, MockServer};
+
+    #[test]
+    fn test_version_from_etag() {
+        assert_eq!(version_from_etag("W/\"3\""), Some("3".to_string()));
+        assert_eq!(version_from_etag("\"abc\""), Some("abc".to_string()));
+        assert_eq!(version_from_etag("W/\"\""), None);
+    }
+
+    #[tokio::test]
+    async fn test_put_sends_if_match_and_returns_new_version() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(200, r#"{"resourceType":"Patient","id":"pt-1"}"#)
+                .with_header("ETag", "W/\"4\"")
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let response = put_to_fhir_server(
+            &client,
+            &server.url("/fhir"),
+            "Patient",
+            "pt-1.json",
+            r#"{"resourceType":"Patient","id":"pt-1"}"#,
+            Some("3"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.version_id, Some("4".to_string()));
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/fhir/Patient/pt-1");
+        assert_eq!(request.header("if-match"), Some("W/\"3\""));
+        assert_eq!(request.header("prefer"), Some("return=representation"));
+        assert_eq!(response.resource.unwrap()["id"], "pt-1");
+    }
+
+    #[tokio::test]
+    async fn test_validate_on_server() {
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/fhir/Patient/$validate" => MockResponse::json(
+                422,
+                r#"{"resourceType":"OperationOutcome","issue":[{"severity":"error"}]}"#,
+            ),
+            _ => MockResponse::json(404, r#"{"resourceType":"OperationOutcome"}"#),
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+        let patient = r#"{"resourceType":"Patient","id":"pt-1"}"#;
+
+        let outcome = validate_on_server(&client, &server.url("/fhir"), "Patient", patient)
+            .await
+            .unwrap();
+        assert_eq!(outcome["issue"][0]["severity"], "error");
+        let parameters: serde_json::Value =
+            serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(parameters["parameter"][0]["resource"]["id"], "pt-1");
+
+        // A server without $validate doesn't make the resource invalid
+        assert!(
+            validate_on_server(&client, &server.url("/fhir"), "Group", patient)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resource_exists() {
+        let server = MockServer::start(|req| match (req.method.as_str(), req.path.as_str()) {
+            ("HEAD", "/fhir/Patient/a") => MockResponse::json(200, ""),
+            ("HEAD", "/fhir/Patient/gone") => MockResponse::json(410, ""),
+            // No HEAD for practitioners
+            ("HEAD", path) if path.starts_with("/fhir/Practitioner/") => {
+                MockResponse::json(405, "")
+            }
+            ("GET", "/fhir/Practitioner/b") => {
+                MockResponse::json(200, r#"{"resourceType":"Practitioner","id":"b"}"#)
+            }
+            ("HEAD", "/fhir/Group/g") => MockResponse::json(500, ""),
+            _ => MockResponse::json(404, ""),
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+        let base = server.url("/fhir");
+
+        assert!(resource_exists(&client, &base, "Patient/a").await.unwrap());
+        assert!(!resource_exists(&client, &base, "Patient/b").await.unwrap());
+        assert!(!resource_exists(&client, &base, "Patient/gone")
+            .await
+            .unwrap());
+        assert!(resource_exists(&client, &base, "Practitioner/b")
+            .await
+            .unwrap());
+        assert!(!resource_exists(&client, &base, "Practitioner/c")
+            .await
+            .unwrap());
+        // Not knowing isn't the same as missing
+        assert!(resource_exists(&client, &base, "Group/g").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_transaction() {
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/fhir" if req.body.contains("\"Patient/a\"") => MockResponse::json(
+                200,
+                r#"{"resourceType":"Bundle","type":"transaction-response","entry":[
+                    {"resource":{"resourceType":"Patient","id":"a","meta":{"versionId":"2"}},
+                     "response":{"status":"200 OK","etag":"W/\"2\""}},
+                    {"response":{"status":"204 No Content"}}
+                ]}"#,
+            ),
+            _ => MockResponse::json(409, r#"{"resourceType":"OperationOutcome"}"#),
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+        let base = server.url("/fhir");
+        let bundle = json!({"resourceType": "Bundle", "type": "transaction", "entry": [
+            {"request": {"method": "PUT", "url": "Patient/a"}},
+            {"request": {"method": "DELETE", "url": "Patient/b"}}
+        ]});
+
+        let responses = post_transaction(&client, &base, &bundle).await.unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].version_id.as_deref(), Some("2"));
+        assert_eq!(responses[0].resource_id.as_deref(), Some("a"));
+        assert!(responses[1].resource.is_none());
+        assert_eq!(
+            server.requests()[0].header("Prefer"),
+            Some("return=representation")
+        );
+
+        let rejected = post_transaction(&client, &base, &json!({"entry": []})).await;
+        let error = rejected.unwrap_err();
+        assert!(error.downcast_ref::<HttpError>().unwrap().is_conflict());
+    }
+
+    #[tokio::test]
+    async fn test_post_batch() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                200,
+                r#"{"resourceType":"Bundle","type":"batch-response","entry":[
+                    {"resource":{"resourceType":"Patient","id":"a","meta":{"versionId":"1"}},
+                     "response":{"status":"201 Created"}},
+                    {"response":{"status":"412 Precondition Failed","location":"Patient/b",
+                     "outcome":{"resourceType":"OperationOutcome"}}}
+                ]}"#,
+            )
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+        let bundle = json!({"resourceType": "Bundle", "type": "batch", "entry": []});
+
+        let outcomes = post_batch(&client, &server.url("/fhir"), &bundle)
+            .await
+            .unwrap();
+        let stored = outcomes[0].as_ref().unwrap();
+        assert_eq!(stored.version_id.as_deref(), Some("1"));
+        let rejected = outcomes[1].as_ref().unwrap_err();
+        assert!(rejected.is_conflict());
+        assert_eq!(rejected.body, r#"{"resourceType":"OperationOutcome"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_structure_definition() {
+        let server = MockServer::start(|req| {
+            match req.path.as_str() {
+            "/fhir/StructureDefinition?url=http%3A%2F%2Fhl7.org%2Ffhir%2FStructureDefinition%2FPatient" => {
+                MockResponse::json(
+                    200,
+                    r#"{"resourceType":"Bundle","entry":[{"resource":{
+                    "resourceType":"StructureDefinition",
+                    "url":"http://hl7.org/fhir/StructureDefinition/Patient","type":"Patient"}}]}"#,
+                )
+            }
+            _ => MockResponse::json(200, r#"{"resourceType":"Bundle","total":0}"#),
+        }
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let definition = fetch_structure_definition(
+            &client,
+            &server.url("/fhir"),
+            "http://hl7.org/fhir/StructureDefinition/Patient",
+        )
+        .await
+        .unwrap();
+        assert_eq!(definition.unwrap()["type"], "Patient");
+
+        let definition = fetch_structure_definition(
+            &client,
+            &server.url("/fhir"),
+            "http://example.org/StructureDefinition/unknown",
+        )
+        .await
+        .unwrap();
+        assert!(definition.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_put_ignores_operation_outcome_body() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(200, r#"{"resourceType":"OperationOutcome"}"#)
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let response = put_to_fhir_server(
+            &client,
+            &server.url("/fhir"),
+            "Patient",
+            "pt-1.json",
+            "{}",
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(response.resource.is_none());
+        assert!(response.version_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_put_without_version_is_unconditional() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                201,
+                r#"{"resourceType":"Patient","id":"pt-1","meta":{"versionId":"1"}}"#,
+            )
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let response = put_to_fhir_server(
+            &client,
+            &server.url("/fhir"),
+            "Patient",
+            "pt-1.json",
+            "{}",
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.version_id, Some("1".to_string()));
+        assert_eq!(server.requests()[0].header("if-match"), None);
+    }
+
+    #[tokio::test]
+    async fn test_put_version_conflict() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(412, r#"{"resourceType":"OperationOutcome"}"#)
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let error = put_to_fhir_server(
+            &client,
+            &server.url("/fhir"),
+            "Patient",
+            "pt-1.json",
+            "{}",
+            Some("3"),
+        )
+        .await
+        .unwrap_err();
+
+        let http_error = error.downcast_ref::<HttpError>().unwrap();
+        assert!(http_error.is_conflict());
+        assert_eq!(http_error.body, r#"{"resourceType":"OperationOutcome"}"#);
+    }
+
+    #[test]
+    fn test_id_from_location() {
+        assert_eq!(
+            id_from_location("http://x/fhir/Patient/123/_history/1", "Patient"),
+            Some("123".to_string())
+        );
+        assert_eq!(
+            id_from_location("Patient/abc", "Patient"),
+            Some("abc".to_string())
+        );
+        assert_eq!(id_from_location("http://x/fhir/Patient/", "Patient"), None);
+    }
+
+    #[tokio::test]
+    async fn test_post_takes_id_from_location() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(201, "")
+                .with_header("Location", "/fhir/Patient/srv-7/_history/1")
+                .with_header("ETag", "W/\"1\"")
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+
+        let response = post_to_fhir_server(
+            &client,
+            &server.url("/fhir"),
+            "Patient",
+            r#"{"resourceType":"Patient"}"#,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/fhir/Patient");
+        assert_eq!(request.header("If-None-Exist"), None);
+        assert_eq!(response.resource_id, Some("srv-7".to_string()));
+        assert_eq!(response.version_id, Some("1".to_string()));
+        assert!(response.resource.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_conditional_writes() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(200, r#"{"resourceType":"Patient","id":"pt-9"}"#)
+        });
+        let client = FhirClient::new(reqwest::Client::new());
+        let base_url = server.url("/fhir");
+        let query = "identifier=http://mrn|123";
+
+        let created = post_to_fhir_server(&client, &base_url, "Patient", "{}", Some(query))
+            .await
+            .unwrap();
+        let updated = put_conditional_to_fhir_server(&client, &base_url, "Patient", query, "{}")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("If-None-Exist"), Some(query));
+        assert_eq!(requests[1].method, "PUT");
+        assert_eq!(requests[1].path, "/fhir/Patient?identifier=http://mrn|123");
+        assert_eq!(created.resource_id, Some("pt-9".to_string()));
+        assert_eq!(updated.resource_id, Some("pt-9".to_string()));
+    }
+}
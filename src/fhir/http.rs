@@ -0,0 +1,285 @@
+use super::auth::TokenManager;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Client, Identity, RequestBuilder, Response, StatusCode};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Connection settings applied once when the `reqwest::Client` is built:
+/// static credentials, extra headers and TLS configuration
+#[derive(Debug, Clone, Default)]
+pub struct HttpClientSettings {
+    pub basic_auth: Option<(String, String)>,
+    pub bearer_token: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub ca_bundle: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl HttpClientSettings {
+    /// Same TLS setup and headers, but without static credentials
+    /// (used for token endpoints, which authenticate the client themselves)
+    pub fn without_credentials(&self) -> Self {
+        Self {
+            basic_auth: None,
+            bearer_token: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn build(&self) -> anyhow::Result<Client> {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Invalid header name {:?}: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| anyhow::anyhow!("Invalid value for header {}: {}", name, e))?;
+            headers.insert(name, value);
+        }
+
+        if let Some((user, password)) = &self.basic_auth {
+            let credentials = BASE64_STANDARD.encode(format!("{}:{}", user, password));
+            headers.insert(AUTHORIZATION, sensitive(&format!("Basic {}", credentials))?);
+        }
+
+        if let Some(token) = &self.bearer_token {
+            headers.insert(AUTHORIZATION, sensitive(&format!("Bearer {}", token))?);
+        }
+
+        let mut builder = Client::builder().default_headers(headers);
+
+        if let Some(path) = &self.ca_bundle {
+            let pem = read_file(path)?;
+            for certificate in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = read_file(cert)?;
+                pem.push(b'\n');
+                pem.extend(read_file(key)?);
+                builder = builder.identity(Identity::from_pem(&pem)?);
+            }
+            (Some(cert), None) => {
+                // Certificate and key in one PEM file
+                builder = builder.identity(Identity::from_pem(&read_file(cert)?)?);
+            }
+            (None, Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "A client key was given without a client certificate"
+                ));
+            }
+            (None, None) => {}
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// Parse a `Name: value` header specification
+pub fn parse_header(spec: &str) -> anyhow::Result<(String, String)> {
+    match spec.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(anyhow::anyhow!(
+            "Invalid header {:?}, expected \"Name: value\"",
+            spec
+        )),
+    }
+}
+
+fn sensitive(value: &str) -> anyhow::Result<HeaderValue> {
+    let mut value = HeaderValue::from_str(value)?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))
+}
+
+/// HTTP client used for every request to the FHIR server.
+/// Attaches the access token when authentication is configured and retries
+/// once with a fresh token when the server answers 401.
+#[derive(Debug, Clone)]
+pub struct FhirClient {
+    http_client: Client,
+    token_manager: Option<Arc<TokenManager>>,
+}
+
+impl FhirClient {
+    pub fn new(http_client: Client) -> Self {
+        Self {
+            http_client,
+            token_manager: None,
+        }
+    }
+
+    pub fn with_token_manager(mut self, token_manager: TokenManager) -> Self {
+        self.token_manager = Some(Arc::new(token_manager));
+        self
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.http_client.get(url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.http_client.head(url)
+    }
+
+    pub fn put(&self, url: &str) -> RequestBuilder {
+        self.http_client.put(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.http_client.post(url)
+    }
+
+    pub fn patch(&self, url: &str) -> RequestBuilder {
+        self.http_client.patch(url)
+    }
+
+    pub fn delete(&self, url: &str) -> RequestBuilder {
+        self.http_client.delete(url)
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let token_manager = match &self.token_manager {
+            Some(manager) => manager,
+            None => return Ok(request.send().await?),
+        };
+
+        let retry = request.try_clone();
+        let token = token_manager.access_token().await?;
+        let response = request.bearer_auth(token).send().await?;
+
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        match retry {
+            Some(retry) => {
+                println!("[Auth] Server answered 401, refreshing access token");
+                token_manager.invalidate().await;
+                let token = token_manager.access_token().await?;
+                Ok(retry.bearer_auth(token).send().await?)
+            }
+            None => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::auth::{ClientAuthentication, ClientCredentials};
+    use crate::test_support::{MockResponse, MockServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("X-Tenant-Id: acme").unwrap(),
+            ("X-Tenant-Id".to_string(), "acme".to_string())
+        );
+        assert_eq!(
+            parse_header("X-Api-Key:a:b").unwrap(),
+            ("X-Api-Key".to_string(), "a:b".to_string())
+        );
+        assert!(parse_header("no-colon").is_err());
+        assert!(parse_header(": value").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_settings_add_headers_to_every_request() {
+        let server = MockServer::start(|_| MockResponse::json(200, "{}"));
+        let settings = HttpClientSettings {
+            basic_auth: Some(("user".to_string(), "pass".to_string())),
+            headers: vec![("X-Tenant-Id".to_string(), "acme".to_string())],
+            ..Default::default()
+        };
+        let client = FhirClient::new(settings.build().unwrap());
+
+        let url = server.url("/fhir/Patient");
+        client.send(client.get(&url)).await.unwrap();
+        client.send(client.delete(&url)).await.unwrap();
+
+        for request in server.requests() {
+            assert_eq!(request.header("x-tenant-id"), Some("acme"));
+            assert_eq!(request.header("authorization"), Some("Basic dXNlcjpwYXNz"));
+        }
+    }
+
+    #[test]
+    fn test_without_credentials_keeps_headers() {
+        let settings = HttpClientSettings {
+            bearer_token: Some("token".to_string()),
+            headers: vec![("X-Tenant-Id".to_string(), "acme".to_string())],
+            ..Default::default()
+        };
+        let stripped = settings.without_credentials();
+        assert!(stripped.bearer_token.is_none());
+        assert_eq!(stripped.headers, settings.headers);
+    }
+
+    #[tokio::test]
+    async fn test_send_without_auth() {
+        let server = MockServer::start(|_| MockResponse::json(200, "{}"));
+        let client = FhirClient::new(Client::new());
+
+        let url = server.url("/fhir/metadata");
+        let response = client.send(client.get(&url)).await.unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(server.requests()[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_with_fresh_token_after_401() {
+        let issued = AtomicUsize::new(0);
+        let server = MockServer::start(move |request| {
+            if request.path == "/auth/token" {
+                let n = issued.fetch_add(1, Ordering::SeqCst) + 1;
+                return MockResponse::json(
+                    200,
+                    &format!(r#"{{"access_token":"token-{}","expires_in":3600}}"#, n),
+                );
+            }
+            // The first token has been revoked on the server side
+            match request.header("authorization") {
+                Some("Bearer token-2") => MockResponse::json(200, "{}"),
+                _ => MockResponse::json(401, "{}"),
+            }
+        });
+
+        let credentials = ClientCredentials {
+            token_url: server.url("/auth/token"),
+            client_id: "root".to_string(),
+            authentication: ClientAuthentication::ClientSecret("secret".to_string()),
+            scope: None,
+        };
+        let client = FhirClient::new(Client::new())
+            .with_token_manager(TokenManager::new(Client::new(), credentials));
+
+        let url = server.url("/fhir/Patient");
+        let response = client.send(client.get(&url)).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/auth/token",
+                "/fhir/Patient",
+                "/auth/token",
+                "/fhir/Patient"
+            ]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use serde_json::{Map, Value};
+
+/// Elements every resource and datatype starts with, in FHIR's order
+/// (Resource, DomainResource, then Element/BackboneElement)
+const LEADING_ELEMENTS: [&str; 9] = [
+    "resourceType",
+    "id",
+    "meta",
+    "implicitRules",
+    "language",
+    "text",
+    "contained",
+    "extension",
+    "modifierExtension",
+];
+
+/// How resources are rendered as file content. Both keep every digit of a
+/// number as the server wrote it (`1.50` stays `1.50`); only an exponent is
+/// spelled out as `e+3` or `e-3`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum JsonFormat {
+    /// The server's key order, pretty-printed
+    #[default]
+    Lossless,
+    /// Stable for diffs: base elements first in FHIR order, `_name`
+    /// primitive extensions right after `name`, extensions sorted by `url`,
+    /// two-space indentation and a final newline
+    Canonical,
+}
+
+impl JsonFormat {
+    pub fn render(self, resource: &Value) -> String {
+        match self {
+            JsonFormat::Lossless => serde_json::to_string_pretty(resource).unwrap_or_default(),
+            JsonFormat::Canonical => {
+                let mut content =
+                    serde_json::to_string_pretty(&canonical(resource)).unwrap_or_default();
+                content.push('\n');
+                content
+            }
+        }
+    }
+}
+
+fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut keys: Vec<&String> = object
+                .keys()
+                .filter(|key| !key.starts_with('_') || !object.contains_key(&key[1..]))
+                .collect();
+            // Stable, so everything else keeps the order the server sent
+            keys.sort_by_key(|key| {
+                LEADING_ELEMENTS
+                    .iter()
+                    .position(|leading| leading == key)
+                    .unwrap_or(LEADING_ELEMENTS.len())
+            });
+
+            let mut ordered = Map::new();
+            for key in keys {
+                ordered.insert(key.clone(), canonical_member(key, &object[key]));
+                let extension = format!("_{}", key);
+                if let Some(value) = object.get(&extension) {
+                    ordered.insert(extension, canonical(value));
+                }
+            }
+            Value::Object(ordered)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(canonical).collect()),
+        _ => value.clone(),
+    }
+}
+
+fn canonical_member(key: &str, value: &Value) -> Value {
+    let mut value = canonical(value);
+    if key == "extension" || key == "modifierExtension" {
+        if let Some(extensions) = value.as_array_mut() {
+            extensions.sort_by(|a, b| {
+                let url = |extension: &Value| extension["url"].as_str().unwrap_or("").to_string();
+                url(a).cmp(&url(b))
+            });
+        }
+    }
+    value
+}
+
+/// Turn a hand-written file into strict JSON. `//` and `/* */` comments and
+/// trailing commas are dropped; everything else is kept as written. Syntax
+/// errors name the line and column in the file as written.
+pub fn strict_json(content: &str) -> Result<String, String> {
+    // Comments and commas are blanked out for the parser, so positions in its
+    // errors still match the file, and left out of the result
+    let mut bytes = content.as_bytes().to_vec();
+    let mut dropped = vec![false; bytes.len()];
+    let mut relaxed = false;
+    let mut in_string = false;
+    let mut last_comma = None;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let byte = bytes[pos];
+        if in_string {
+            match byte {
+                b'\\' => pos += 1,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            pos += 1;
+            continue;
+        }
+        match (byte, bytes.get(pos + 1).copied()) {
+            (b'/', Some(b'/')) => {
+                let end = content[pos..].find('\n').map_or(bytes.len(), |n| pos + n);
+                blank(&mut bytes, &mut dropped, pos..end);
+                relaxed = true;
+                pos = end;
+                continue;
+            }
+            (b'/', Some(b'*')) => {
+                let end = match content[pos + 2..].find("*/") {
+                    Some(n) => pos + 2 + n + 2,
+                    None => {
+                        let (line, column) = position(content, pos);
+                        return Err(syntax_error(
+                            content,
+                            line,
+                            column,
+                            "the /* comment is never closed",
+                        ));
+                    }
+                };
+                blank(&mut bytes, &mut dropped, pos..end);
+                relaxed = true;
+                pos = end;
+                continue;
+            }
+            (b'"', _) => {
+                in_string = true;
+                last_comma = None;
+            }
+            (b',', _) => last_comma = Some(pos),
+            (b'}' | b']', _) => {
+                if let Some(comma) = last_comma.take() {
+                    blank(&mut bytes, &mut dropped, comma..comma + 1);
+                    relaxed = true;
+                }
+            }
+            (byte, _) if byte.is_ascii_whitespace() => {}
+            _ => last_comma = None,
+        }
+        pos += 1;
+    }
+
+    // Only ASCII bytes and whole characters inside comments were replaced
+    let blanked = String::from_utf8(bytes).unwrap_or_default();
+    if let Err(e) = serde_json::from_str::<serde::de::IgnoredAny>(&blanked) {
+        let message = e.to_string();
+        let location = format!(" at line {} column {}", e.line(), e.column());
+        let message = message.strip_suffix(&location).unwrap_or(&message);
+        return Err(syntax_error(content, e.line(), e.column(), message));
+    }
+    if !relaxed {
+        return Ok(content.to_string());
+    }
+
+    let kept: Vec<u8> = content
+        .bytes()
+        .zip(&dropped)
+        .filter(|(_, dropped)| !**dropped)
+        .map(|(byte, _)| byte)
+        .collect();
+    let kept = String::from_utf8(kept).unwrap_or_default();
+
+    // Newlines are kept, so lines still pair up with the file's. Drop what
+    // the comments leave behind: trailing spaces and emptied lines.
+    let mut lines = Vec::new();
+    for (kept_line, line) in kept.lines().zip(content.lines()) {
+        if kept_line == line {
+            lines.push(kept_line);
+        } else if !kept_line.trim().is_empty() {
+            lines.push(kept_line.trim_end());
+        }
+    }
+    let mut strict = lines.join("\n");
+    if content.ends_with('\n') {
+        strict.push('\n');
+    }
+    Ok(strict)
+}
+
+fn blank(bytes: &mut [u8], dropped: &mut [bool], range: std::ops::Range<usize>) {
+    for pos in range {
+        if bytes[pos] != b'\n' {
+            bytes[pos] = b' ';
+            dropped[pos] = true;
+        }
+    }
+}
+
+/// 1-based line and column (in bytes, as the parser counts) of an offset
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+    (before.matches('\n').count() + 1, offset - line_start + 1)
+}
+
+/// `line 3, column 13: <message>` followed by the line and a caret under
+/// the column. Columns are counted in characters, as editors do.
+fn syntax_error(content: &str, line: usize, column: usize, message: &str) -> String {
+    let text = content.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    let before = column.saturating_sub(1);
+    let indent = text
+        .get(..before)
+        .map_or(before, |prefix| prefix.chars().count());
+    format!(
+        "line {}, column {}: {}\n{}\n{}^",
+        line,
+        indent + 1,
+        message,
+        text,
+        " ".repeat(indent)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Resources in `tests/corpus`, as the server would send them
+    fn corpus() -> Vec<(String, String)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let mut files: Vec<(String, String)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| !path.to_string_lossy().ends_with(".canonical.json"))
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+        files
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        for (name, content) in corpus() {
+            let resource: Value = serde_json::from_str(&content).unwrap();
+            assert_eq!(
+                JsonFormat::Lossless.render(&resource),
+                content.trim_end(),
+                "{} changed",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_canonical_is_stable() {
+        for (name, content) in corpus() {
+            let resource: Value = serde_json::from_str(&content).unwrap();
+            let rendered = JsonFormat::Canonical.render(&resource);
+            let reparsed: Value = serde_json::from_str(&rendered).unwrap();
+            // Members and extensions move, but nothing is added or lost
+            let members = |value: &Value| {
+                let mut lines: Vec<String> = JsonFormat::Lossless
+                    .render(value)
+                    .lines()
+                    .map(|line| line.trim_end_matches(',').to_string())
+                    .collect();
+                lines.sort();
+                lines
+            };
+            assert_eq!(members(&reparsed), members(&resource), "{} lost data", name);
+            assert_eq!(
+                JsonFormat::Canonical.render(&reparsed),
+                rendered,
+                "{} is not stable",
+                name
+            );
+
+            let expected = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/corpus")
+                .join(name.replace(".json", ".canonical.json"));
+            if let Ok(expected) = std::fs::read_to_string(expected) {
+                assert_eq!(rendered, expected, "{} canonical rendering", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strict_json() {
+        let relaxed = "{\n  // drafted by hand\n  \"resourceType\": \"Patient\", // required\n  \
+                       \"name\": [{\"text\": \"a // b, /* c */\"},],\n  /* not yet:\n  \
+                       \"active\": true\n  */\n  \"gender\": \"other\",\n}\n";
+        assert_eq!(
+            strict_json(relaxed).unwrap(),
+            "{\n  \"resourceType\": \"Patient\",\n  \
+             \"name\": [{\"text\": \"a // b, /* c */\"}],\n  \"gender\": \"other\"\n}\n"
+        );
+
+        // Strict JSON is passed through untouched
+        let strict = r#"{"resourceType":"Patient","x":1.50}"#;
+        assert_eq!(strict_json(strict).unwrap(), strict);
+    }
+
+    #[test]
+    fn test_strict_json_syntax_errors() {
+        let error = strict_json("{\n  // the id\n  \"id\" \"a\",\n}").unwrap_err();
+        assert_eq!(
+            error,
+            "line 3, column 8: expected `:`\n  \"id\" \"a\",\n       ^"
+        );
+
+        let error = strict_json("{\"id\": \"ä\", x}").unwrap_err();
+        assert!(error.starts_with("line 1, column 13: key must be a string"));
+        assert!(error.ends_with("\n            ^"));
+
+        let error = strict_json("{\"id\": \"a\"} /* end").unwrap_err();
+        assert!(error.starts_with("line 1, column 13: the /* comment is never closed"));
+    }
+
+    #[test]
+    fn test_decimals_keep_their_precision() {
+        let resource: Value =
+            serde_json::from_str(r#"{"valueQuantity":{"value":1.50},"x":[0.10,1E3,3]}"#).unwrap();
+        for format in [JsonFormat::Lossless, JsonFormat::Canonical] {
+            let rendered = format.render(&resource);
+            assert!(rendered.contains("1.50"));
+            assert!(rendered.contains("0.10"));
+            assert!(rendered.contains("1e+3"));
+        }
+    }
+}
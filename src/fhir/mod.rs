@@ -1,11 +1,27 @@
+pub mod auth;
 pub mod capability;
 pub mod client;
+pub mod http;
+pub mod json;
+pub mod patch;
+pub mod reconcile;
+pub mod references;
 
-pub use capability::{fetch_capability_statement, fetch_resources_parallel};
+pub use auth::{
+    discover_token_endpoint, AssertionSigner, ClientAuthentication, ClientCredentials, TokenManager,
+};
+pub use capability::{
+    fetch_capability_statement, fetch_resources_parallel, FetchLimits, ResourcePermissions,
+    ServerCapabilities,
+};
 pub use client::{
-    delete_from_fhir_server, execute_operation, fetch_resource_history, put_to_fhir_server,
-    search_fhir_resources,
+    delete_from_fhir_server, execute_operation, fetch_resource_history, fetch_structure_definition,
+    get_from_fhir_server, patch_fhir_resource, post_batch, post_to_fhir_server, post_transaction,
+    put_conditional_to_fhir_server, put_to_fhir_server, resource_exists, search_fhir_resources,
+    validate_on_server, version_id_of, HttpError, WriteResponse,
 };
-
-#[allow(unused_imports)]
-pub use client::get_from_fhir_server;
+pub use http::{parse_header, FhirClient, HttpClientSettings};
+pub use json::JsonFormat;
+pub use patch::PatchFormat;
+pub use reconcile::{reconcile_resource, same_resource, InvalidResource};
+pub use references::{local_references, references, revinclude_query, rewrite_references};
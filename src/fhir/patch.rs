@@ -0,0 +1,114 @@
+use super::json::strict_json;
+use super::reconcile::{reconcile_resource, InvalidResource};
+use serde_json::Value;
+
+/// Partial updates, written next to a resource as `<id>.json-patch` or
+/// `<id>.fhirpath-patch`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchFormat {
+    /// RFC 6902 JSON Patch: an array of operations
+    JsonPatch,
+    /// FHIRPath Patch: a `Parameters` resource of `operation`s
+    FhirPathPatch,
+}
+
+impl PatchFormat {
+    /// `pt-1.json-patch` -> (`pt-1`, JSON Patch)
+    pub fn from_filename(name: &str) -> Option<(&str, PatchFormat)> {
+        let (resource_id, format) = if let Some(id) = name.strip_suffix(".json-patch") {
+            (id, PatchFormat::JsonPatch)
+        } else if let Some(id) = name.strip_suffix(".fhirpath-patch") {
+            (id, PatchFormat::FhirPathPatch)
+        } else {
+            return None;
+        };
+        if resource_id.is_empty() || resource_id.starts_with('.') {
+            return None;
+        }
+        Some((resource_id, format))
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PatchFormat::JsonPatch => "application/json-patch+json",
+            PatchFormat::FhirPathPatch => "application/fhir+json",
+        }
+    }
+
+    /// Reject documents that can't be a patch of this format before they
+    /// are sent, and return the strict JSON to send
+    pub fn check(self, content: &str) -> Result<String, InvalidResource> {
+        match self {
+            PatchFormat::JsonPatch => {
+                let not_json =
+                    |e: String| InvalidResource(format!("The patch is not valid JSON: {}", e));
+                let content = strict_json(content).map_err(not_json)?;
+                let patch: Value =
+                    serde_json::from_str(&content).map_err(|e| not_json(e.to_string()))?;
+                let operations = patch.as_array().ok_or_else(|| {
+                    InvalidResource(
+                        "A JSON Patch is an array of operations, e.g. \
+                         [{\"op\": \"replace\", \"path\": \"/active\", \"value\": false}]"
+                            .to_string(),
+                    )
+                })?;
+                match operations.iter().position(|op| !op["op"].is_string()) {
+                    Some(index) => Err(InvalidResource(format!(
+                        "Operation {} of the JSON Patch has no \"op\"",
+                        index
+                    ))),
+                    None => Ok(content),
+                }
+            }
+            PatchFormat::FhirPathPatch => reconcile_resource(content, "Parameters", None, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_filename() {
+        assert_eq!(
+            PatchFormat::from_filename("pt-1.json-patch"),
+            Some(("pt-1", PatchFormat::JsonPatch))
+        );
+        assert_eq!(
+            PatchFormat::from_filename("pt-1.fhirpath-patch"),
+            Some(("pt-1", PatchFormat::FhirPathPatch))
+        );
+        assert_eq!(PatchFormat::from_filename("pt-1.json"), None);
+        assert_eq!(PatchFormat::from_filename(".json-patch"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let json_patch = PatchFormat::JsonPatch;
+        assert_eq!(
+            json_patch
+                .check("[{\"op\":\"replace\",\"path\":\"/active\",\"value\":false},] // deceased")
+                .unwrap(),
+            r#"[{"op":"replace","path":"/active","value":false}]"#
+        );
+        assert!(json_patch
+            .check(r#"{"op":"replace"}"#)
+            .unwrap_err()
+            .0
+            .contains("array"));
+        assert!(json_patch
+            .check(r#"[{"path":"/active"}]"#)
+            .unwrap_err()
+            .0
+            .contains("Operation 0"));
+
+        let fhirpath_patch = PatchFormat::FhirPathPatch;
+        assert!(fhirpath_patch
+            .check(r#"{"resourceType":"Parameters","parameter":[]}"#)
+            .is_ok());
+        assert!(fhirpath_patch
+            .check(r#"{"resourceType":"Patient"}"#)
+            .is_err());
+    }
+}
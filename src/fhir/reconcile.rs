@@ -0,0 +1,302 @@
+use super::json::strict_json;
+use serde_json::Value;
+use std::ops::Range;
+
+/// A file that can't be sent as the resource its path names. The message is
+/// meant for the person who wrote the file.
+#[derive(Debug)]
+pub struct InvalidResource(pub String);
+
+impl std::fmt::Display for InvalidResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidResource {}
+
+/// Check a file's content against where it was written before sending it:
+/// `resourceType` must be the directory's type, and `id` the filename's
+/// (`resource_id` is `None` for creates where the server picks the id).
+/// A missing `id` is filled in; a different one is rejected, or replaced
+/// when `rewrite_id` is set. Comments and trailing commas are dropped. Edits
+/// are made in the text itself, so formatting, key order and numbers are
+/// sent as written.
+pub fn reconcile_resource(
+    content: &str,
+    resource_type: &str,
+    resource_id: Option<&str>,
+    rewrite_id: bool,
+) -> Result<String, InvalidResource> {
+    let not_json = |e: String| InvalidResource(format!("The file is not valid JSON: {}", e));
+    let content = &strict_json(content).map_err(not_json)?;
+    let value: Value = serde_json::from_str(content).map_err(|e| not_json(e.to_string()))?;
+    let object = value.as_object().ok_or_else(|| {
+        InvalidResource(format!(
+            "The file must contain a JSON object with \"resourceType\": \"{}\"",
+            resource_type
+        ))
+    })?;
+
+    match object.get("resourceType") {
+        Some(Value::String(found)) if found == resource_type => {}
+        Some(found) => {
+            return Err(InvalidResource(format!(
+                "resourceType is {} but the file is in {}/; move it to the {} directory \
+                 or set \"resourceType\": \"{}\"",
+                found,
+                resource_type,
+                found.as_str().unwrap_or("matching"),
+                resource_type
+            )))
+        }
+        None => {
+            return Err(InvalidResource(format!(
+                "resourceType is missing; add \"resourceType\": \"{}\"",
+                resource_type
+            )))
+        }
+    }
+
+    let resource_id = match resource_id {
+        Some(resource_id) => resource_id,
+        None => return Ok(content.to_string()),
+    };
+    let id = Value::String(resource_id.to_string()).to_string();
+
+    match object.get("id") {
+        Some(Value::String(found)) if found == resource_id => Ok(content.to_string()),
+        None => Ok(insert_first_member(content, &format!("\"id\": {}", id))),
+        Some(found) if rewrite_id => {
+            let span = member_value(content, "id").unwrap_or(0..0);
+            println!(
+                "[FHIR] {}/{}: replacing id {} from the file",
+                resource_type, resource_id, found
+            );
+            Ok(format!(
+                "{}{}{}",
+                &content[..span.start],
+                id,
+                &content[span.end..]
+            ))
+        }
+        Some(found) => Err(InvalidResource(format!(
+            "id is {} but the file is {}.json; rename the file or set \"id\": {}",
+            found, resource_id, id
+        ))),
+    }
+}
+
+/// Whether two versions of a resource say the same thing. Formatting, key
+/// order and the server-managed `meta.versionId` and `meta.lastUpdated`
+/// don't count.
+pub fn same_resource(a: &str, b: &str) -> bool {
+    fn meaningful(content: &str) -> Option<Value> {
+        let mut resource: Value = serde_json::from_str(content).ok()?;
+        if let Some(meta) = resource.get_mut("meta").and_then(Value::as_object_mut) {
+            meta.remove("versionId");
+            meta.remove("lastUpdated");
+            if meta.is_empty() {
+                resource.as_object_mut()?.remove("meta");
+            }
+        }
+        Some(resource)
+    }
+    match (meaningful(a), meaningful(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Add a member at the start of the top-level object, using the whitespace
+/// that follows its `{` so pretty-printed files stay pretty
+fn insert_first_member(content: &str, member: &str) -> String {
+    let open = match content.find('{') {
+        Some(open) => open + 1,
+        None => return content.to_string(),
+    };
+    let rest = &content[open..];
+    let body = rest.trim_start();
+    let whitespace = &rest[..rest.len() - body.len()];
+    if body.starts_with('}') {
+        return format!("{}{}{}{}", &content[..open], whitespace, member, rest);
+    }
+    format!(
+        "{}{}{},{}{}",
+        &content[..open],
+        whitespace,
+        member,
+        whitespace,
+        body
+    )
+}
+
+/// Byte range of the value of a top-level member. Expects valid JSON.
+fn member_value(content: &str, key: &str) -> Option<Range<usize>> {
+    let bytes = content.as_bytes();
+    let mut pos = content.find('{')? + 1;
+    loop {
+        pos = skip_whitespace(bytes, pos);
+        if bytes.get(pos)? != &b'"' {
+            return None;
+        }
+        let key_end = skip_value(bytes, pos)?;
+        let name: String = serde_json::from_str(&content[pos..key_end]).ok()?;
+        pos = skip_whitespace(bytes, key_end);
+        pos = skip_whitespace(bytes, pos + 1); // ':'
+        let value_end = skip_value(bytes, pos)?;
+        if name == key {
+            return Some(pos..value_end);
+        }
+        pos = skip_whitespace(bytes, value_end);
+        if bytes.get(pos)? != &b',' {
+            return None;
+        }
+        pos += 1;
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+        pos += 1;
+    }
+    pos
+}
+
+/// End of the JSON value starting at `pos`
+fn skip_value(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    while pos < bytes.len() {
+        let byte = bytes[pos];
+        if in_string {
+            match byte {
+                b'\\' => pos += 1,
+                b'"' => {
+                    in_string = false;
+                    if depth == 0 {
+                        return Some(pos + 1);
+                    }
+                }
+                _ => {}
+            }
+        } else {
+            match byte {
+                b'"' => in_string = true,
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' if depth == 0 => return Some(pos),
+                b'}' | b']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(pos + 1);
+                    }
+                }
+                b',' if depth == 0 => return Some(pos),
+                byte if depth == 0 && byte.is_ascii_whitespace() => return Some(pos),
+                _ => {}
+            }
+        }
+        pos += 1;
+    }
+    Some(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching_resource_is_unchanged() {
+        let content = "{\"resourceType\":\"Patient\",\"id\":\"a\",\"x\":1.50}";
+        assert_eq!(
+            reconcile_resource(content, "Patient", Some("a"), false).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn test_wrong_resource_type() {
+        let error = reconcile_resource(
+            r#"{"resourceType":"Observation","id":"b"}"#,
+            "Patient",
+            Some("a"),
+            true,
+        )
+        .unwrap_err();
+        assert!(error.0.contains("resourceType is \"Observation\""));
+        assert!(error.0.contains("Patient/"));
+
+        let error = reconcile_resource(r#"{"id":"a"}"#, "Patient", Some("a"), false).unwrap_err();
+        assert!(error.0.contains("resourceType is missing"));
+
+        let error = reconcile_resource("[1]", "Patient", None, false).unwrap_err();
+        assert!(error.0.contains("JSON object"));
+    }
+
+    #[test]
+    fn test_missing_id_is_filled_in() {
+        let pretty = "{\n  \"resourceType\": \"Patient\",\n  \"active\": true\n}";
+        assert_eq!(
+            reconcile_resource(pretty, "Patient", Some("a"), false).unwrap(),
+            "{\n  \"id\": \"a\",\n  \"resourceType\": \"Patient\",\n  \"active\": true\n}"
+        );
+
+        let compact = r#"{"resourceType":"Patient"}"#;
+        assert_eq!(
+            reconcile_resource(compact, "Patient", Some("a"), false).unwrap(),
+            r#"{"id": "a","resourceType":"Patient"}"#
+        );
+
+        // Creates with a server-assigned id are left alone
+        assert_eq!(
+            reconcile_resource(compact, "Patient", None, false).unwrap(),
+            compact
+        );
+    }
+
+    #[test]
+    fn test_conflicting_id() {
+        let content = r#"{"resourceType":"Patient","name":[{"id":"x"}], "id" : "b","x":1.50}"#;
+        let error = reconcile_resource(content, "Patient", Some("a"), false).unwrap_err();
+        assert!(error.0.contains("id is \"b\" but the file is a.json"));
+
+        assert_eq!(
+            reconcile_resource(content, "Patient", Some("a"), true).unwrap(),
+            r#"{"resourceType":"Patient","name":[{"id":"x"}], "id" : "a","x":1.50}"#
+        );
+    }
+
+    #[test]
+    fn test_same_resource() {
+        let stored = r#"{"resourceType":"Patient","id":"a","active":true,
+            "meta":{"versionId":"3","lastUpdated":"2024-01-01T00:00:00Z"}}"#;
+        let edited = "{\n  \"id\": \"a\",\n  \"active\": true,\n  \"resourceType\": \"Patient\"\n}";
+        assert!(same_resource(stored, edited));
+
+        let tagged = r#"{"resourceType":"Patient","id":"a","active":true,
+            "meta":{"versionId":"3","tag":[{"code":"x"}]}}"#;
+        assert!(!same_resource(stored, tagged));
+        assert!(!same_resource(
+            stored,
+            r#"{"resourceType":"Patient","id":"a"}"#
+        ));
+        assert!(!same_resource(stored, "{"));
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let error = reconcile_resource("{", "Patient", Some("a"), false).unwrap_err();
+        assert!(error
+            .0
+            .starts_with("The file is not valid JSON: line 1, column 1: EOF"));
+    }
+
+    #[test]
+    fn test_comments_are_dropped() {
+        let drafted = "{\n  \"resourceType\": \"Patient\", // TODO: name\n  \"active\": true,\n}";
+        assert_eq!(
+            reconcile_resource(drafted, "Patient", Some("a"), false).unwrap(),
+            "{\n  \"id\": \"a\",\n  \"resourceType\": \"Patient\",\n  \"active\": true\n}"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use serde_json::Value;
+
+/// Whether `reference` points at `target` (`Type/id`) on the server at
+/// `fhir_base_url`, written relative, absolute or version-specific. Other
+/// servers' resources with the same type and id don't count.
+pub fn refers_to(reference: &str, target: &str, fhir_base_url: &str) -> bool {
+    local_target(reference, fhir_base_url).as_deref() == Some(target)
+}
+
+/// Whether any `reference` in `resource` points at `target` on the server
+/// at `fhir_base_url`
+pub fn references(resource: &Value, target: &str, fhir_base_url: &str) -> bool {
+    match resource {
+        Value::Object(object) => object.iter().any(|(key, value)| match value {
+            Value::String(reference) if key == "reference" => {
+                refers_to(reference, target, fhir_base_url)
+            }
+            value => references(value, target, fhir_base_url),
+        }),
+        Value::Array(items) => items
+            .iter()
+            .any(|item| references(item, target, fhir_base_url)),
+        _ => false,
+    }
+}
+
+/// Point every `reference` to `from` on the server at `fhir_base_url` at
+/// `to` instead. Absolute references stay absolute; version-specific ones
+/// lose their version, which belongs to the old resource. Returns how many
+/// were changed.
+pub fn rewrite_references(
+    resource: &mut Value,
+    from: &str,
+    to: &str,
+    fhir_base_url: &str,
+) -> usize {
+    match resource {
+        Value::Object(object) => object
+            .iter_mut()
+            .map(|(key, value)| match value {
+                Value::String(reference) if key == "reference" => {
+                    if !refers_to(reference, from, fhir_base_url) {
+                        return 0;
+                    }
+                    let absolute = reference
+                        .strip_prefix(fhir_base_url)
+                        .is_some_and(|path| path.starts_with('/'));
+                    *reference = match absolute {
+                        true => format!("{}/{}", fhir_base_url, to),
+                        false => to.to_string(),
+                    };
+                    1
+                }
+                value => rewrite_references(value, from, to, fhir_base_url),
+            })
+            .sum(),
+        Value::Array(items) => items
+            .iter_mut()
+            .map(|item| rewrite_references(item, from, to, fhir_base_url))
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// The `Type/id` targets of the references in a resource that can be looked
+/// up on the server at `fhir_base_url`, with the location of each reference
+/// (`Observation.performer[1]`). Relative references and absolute ones to
+/// the same server count; contained (`#id`), conditional, `urn:` and other
+/// servers' references don't. Versions are dropped.
+pub fn local_references(resource: &Value, fhir_base_url: &str) -> Vec<(String, String)> {
+    let mut found = Vec::new();
+    let location = resource["resourceType"].as_str().unwrap_or_default();
+    collect_references(resource, location, fhir_base_url, &mut found);
+    found
+}
+
+fn collect_references(
+    value: &Value,
+    location: &str,
+    fhir_base_url: &str,
+    found: &mut Vec<(String, String)>,
+) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match value {
+                    Value::String(reference) if key == "reference" => {
+                        if let Some(target) = local_target(reference, fhir_base_url) {
+                            found.push((location.to_string(), target));
+                        }
+                    }
+                    value => collect_references(
+                        value,
+                        &format!("{}.{}", location, key),
+                        fhir_base_url,
+                        found,
+                    ),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                let location = format!("{}[{}]", location, index);
+                collect_references(item, &location, fhir_base_url, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `Patient/a/_history/2` or `<base>/Patient/a` -> `Patient/a`
+fn local_target(reference: &str, fhir_base_url: &str) -> Option<String> {
+    let reference = reference
+        .strip_prefix(fhir_base_url)
+        .and_then(|path| path.strip_prefix('/'))
+        .unwrap_or(reference);
+    let path = reference.split("/_history/").next().unwrap_or(reference);
+    let (resource_type, id) = path.split_once('/')?;
+    let is_type = resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+        && resource_type.chars().all(|c| c.is_ascii_alphanumeric());
+    let is_id = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    (is_type && is_id).then(|| path.to_string())
+}
+
+/// Search query for a resource together with everything referencing it,
+/// from the given types or from any type
+pub fn revinclude_query(resource_id: &str, referencing_types: Option<&[String]>) -> String {
+    let revincludes = match referencing_types {
+        Some(types) => types
+            .iter()
+            .map(|resource_type| format!("_revinclude={}:*", resource_type))
+            .collect::<Vec<_>>()
+            .join("&"),
+        None => "_revinclude=*".to_string(),
+    };
+    format!("_id={}&{}", resource_id, revincludes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_refers_to() {
+        let base = "http://fhir.example.org/r4";
+        assert!(refers_to("Patient/a", "Patient/a", base));
+        assert!(refers_to(
+            "http://fhir.example.org/r4/Patient/a",
+            "Patient/a",
+            base
+        ));
+        assert!(refers_to("Patient/a/_history/3", "Patient/a", base));
+        assert!(!refers_to("Patient/ab", "Patient/a", base));
+        assert!(!refers_to("Group/a", "Patient/a", base));
+        assert!(!refers_to(
+            "http://elsewhere.example.org/Patient/a",
+            "Patient/a",
+            base
+        ));
+    }
+
+    #[test]
+    fn test_rewrite_references() {
+        let base = "https://fhir.example.org";
+        let mut observation = json!({
+            "resourceType": "Observation",
+            "subject": {"reference": "Patient/a"},
+            "performer": [
+                {"reference": "https://fhir.example.org/Patient/a/_history/2"},
+                {"reference": "Practitioner/a"}
+            ],
+            "note": [{"text": "Patient/a"}]
+        });
+        assert!(references(&observation, "Patient/a", base));
+
+        assert_eq!(
+            rewrite_references(&mut observation, "Patient/a", "Patient/b", base),
+            2
+        );
+        assert_eq!(observation["subject"]["reference"], "Patient/b");
+        assert_eq!(
+            observation["performer"][0]["reference"],
+            "https://fhir.example.org/Patient/b"
+        );
+        assert_eq!(observation["performer"][1]["reference"], "Practitioner/a");
+        assert_eq!(observation["note"][0]["text"], "Patient/a");
+        assert!(!references(&observation, "Patient/a", base));
+    }
+
+    #[test]
+    fn test_other_servers_references_are_left_alone() {
+        let base = "https://fhir.example.org";
+        let foreign = "https://elsewhere.example.org/fhir/Patient/a";
+        let mut observation = json!({
+            "resourceType": "Observation",
+            "subject": {"reference": "Patient/a"},
+            "focus": [{"reference": foreign}]
+        });
+
+        assert_eq!(
+            rewrite_references(&mut observation, "Patient/a", "Patient/b", base),
+            1
+        );
+        assert_eq!(observation["subject"]["reference"], "Patient/b");
+        assert_eq!(observation["focus"][0]["reference"], foreign);
+        assert!(!references(&observation, "Patient/a", base));
+    }
+
+    #[test]
+    fn test_local_references() {
+        let base = "https://fhir.example.org/r4";
+        let observation = json!({
+            "resourceType": "Observation",
+            "contained": [{"resourceType": "Practitioner", "id": "p1"}],
+            "subject": {"reference": "Patient/a"},
+            "performer": [
+                {"reference": "#p1"},
+                {"reference": "https://fhir.example.org/r4/Practitioner/b/_history/2"},
+                {"reference": "https://elsewhere.example.org/Practitioner/c"},
+                {"reference": "Organization?identifier=urn:org|1"},
+                {"reference": "urn:uuid:0c3151bd-1cbf-4d64-b04d-cd9187a4c6e0"}
+            ],
+            "hasMember": [{"display": "no reference"}]
+        });
+        assert_eq!(
+            local_references(&observation, base),
+            vec![
+                ("Observation.subject".to_string(), "Patient/a".to_string()),
+                (
+                    "Observation.performer[1]".to_string(),
+                    "Practitioner/b".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_revinclude_query() {
+        assert_eq!(revinclude_query("a", None), "_id=a&_revinclude=*");
+        let types = vec!["Observation".to_string(), "Encounter".to_string()];
+        assert_eq!(
+            revinclude_query("a", Some(&types)),
+            "_id=a&_revinclude=Observation:*&_revinclude=Encounter:*"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+/// A resource file opened for writing. Every handle edits its own copy of the
+/// content, which is uploaded when a descriptor of it is closed or fsync'ed.
+#[derive(Debug)]
+pub struct OpenFile {
+    pub ino: u64,
+    pub resource_type: String,
+    pub resource_id: String,
+    /// Version the content was read from, sent as `If-Match`
+    pub version_id: Option<String>,
+    /// The file was created through this handle and doesn't exist on the server yet
+    pub created: bool,
+    /// Content of `version_id`, to tell whether a write changes anything
+    pub base: Option<String>,
+    content: Vec<u8>,
+    append: bool,
+    dirty: bool,
+    detached: bool,
+}
+
+impl OpenFile {
+    pub fn new(
+        ino: u64,
+        resource_type: &str,
+        resource_id: &str,
+        version_id: Option<String>,
+        content: Vec<u8>,
+        flags: i32,
+    ) -> Self {
+        let truncate = flags & libc::O_TRUNC != 0;
+        Self {
+            ino,
+            resource_type: resource_type.to_string(),
+            resource_id: resource_id.to_string(),
+            version_id,
+            created: false,
+            base: Some(String::from_utf8_lossy(&content).into_owned()),
+            content: if truncate { Vec::new() } else { content },
+            append: flags & libc::O_APPEND != 0,
+            dirty: truncate,
+            detached: false,
+        }
+    }
+
+    /// A file created through `create`: empty, and only uploaded once written
+    pub fn created(ino: u64, resource_type: &str, resource_id: &str, flags: i32) -> Self {
+        let mut file = Self::new(ino, resource_type, resource_id, None, Vec::new(), flags);
+        file.created = true;
+        file.base = None;
+        file.dirty = false;
+        file
+    }
+
+    pub fn filename(&self) -> String {
+        format!("{}.json", self.resource_id)
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn read(&self, offset: i64, size: u32) -> Vec<u8> {
+        let offset = offset.max(0) as usize;
+        if offset >= self.content.len() {
+            return Vec::new();
+        }
+        let end = std::cmp::min(offset + size as usize, self.content.len());
+        self.content[offset..end].to_vec()
+    }
+
+    /// Write at `offset`, or at the end when opened with `O_APPEND`
+    pub fn write(&mut self, offset: i64, data: &[u8]) -> usize {
+        let offset = if self.append {
+            self.content.len()
+        } else {
+            offset.max(0) as usize
+        };
+        if offset + data.len() > self.content.len() {
+            self.content.resize(offset + data.len(), 0);
+        }
+        self.content[offset..offset + data.len()].copy_from_slice(data);
+        self.dirty = true;
+        data.len()
+    }
+
+    pub fn truncate(&mut self, size: u64) {
+        self.content.resize(size as usize, 0);
+        self.dirty = true;
+    }
+
+    /// Whether there are changes the server hasn't seen
+    pub fn is_dirty(&self) -> bool {
+        self.dirty && !self.detached
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// The file was deleted while open: later writes go nowhere
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+}
+
+/// Open write handles by file handle number. Handle 0 is never allocated and
+/// is what read-only opens get.
+#[derive(Debug)]
+pub struct FileHandles {
+    next_fh: u64,
+    open: HashMap<u64, OpenFile>,
+}
+
+impl FileHandles {
+    pub fn new() -> Self {
+        Self {
+            next_fh: 1,
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, file: OpenFile) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open.insert(fh, file);
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&OpenFile> {
+        self.open.get(&fh)
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut OpenFile> {
+        self.open.get_mut(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<OpenFile> {
+        self.open.remove(&fh)
+    }
+
+    /// Handles currently open on an inode
+    pub fn for_inode(&self, ino: u64) -> Vec<u64> {
+        let mut handles: Vec<u64> = self
+            .open
+            .iter()
+            .filter(|(_, file)| file.ino == ino)
+            .map(|(&fh, _)| fh)
+            .collect();
+        handles.sort_unstable();
+        handles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(content: &str, flags: i32) -> OpenFile {
+        OpenFile::new(
+            7,
+            "Patient",
+            "pt-1",
+            Some("3".to_string()),
+            content.as_bytes().to_vec(),
+            flags,
+        )
+    }
+
+    #[test]
+    fn test_write_over_existing_content() {
+        let mut file = open("{\"a\": 1}", libc::O_WRONLY);
+        assert!(!file.is_dirty());
+
+        file.write(6, b"2");
+        assert_eq!(file.content(), b"{\"a\": 2}");
+        assert!(file.is_dirty());
+        assert_eq!(file.read(1, 3), b"\"a\"");
+    }
+
+    #[test]
+    fn test_truncate_and_append_flags() {
+        let file = open("old", libc::O_WRONLY | libc::O_TRUNC);
+        assert_eq!(file.content(), b"");
+        assert_eq!(file.base.as_deref(), Some("old"));
+        assert!(file.is_dirty());
+
+        let mut file = open("ab", libc::O_WRONLY | libc::O_APPEND);
+        file.write(0, b"cd");
+        assert_eq!(file.content(), b"abcd");
+
+        file.truncate(1);
+        assert_eq!(file.content(), b"a");
+    }
+
+    #[test]
+    fn test_created_file_is_clean_until_written() {
+        let mut file = OpenFile::created(7, "Patient", "new", libc::O_WRONLY);
+        assert!(file.created);
+        assert!(!file.is_dirty());
+        file.write(0, b"{}");
+        assert!(file.is_dirty());
+        assert_eq!(file.filename(), "new.json");
+    }
+
+    #[test]
+    fn test_detached_file_is_never_dirty() {
+        let mut file = open("{}", libc::O_WRONLY);
+        file.detach();
+        file.write(0, b"[]");
+        assert!(!file.is_dirty());
+    }
+
+    #[test]
+    fn test_handles_are_per_open() {
+        let mut handles = FileHandles::new();
+        let first = handles.insert(open("{}", libc::O_WRONLY));
+        let second = handles.insert(open("{}", libc::O_WRONLY));
+        assert_ne!(first, 0);
+        assert_ne!(first, second);
+        assert_eq!(handles.for_inode(7), vec![first, second]);
+
+        handles.get_mut(first).unwrap().write(0, b"[]");
+        assert_eq!(handles.get(second).unwrap().content(), b"{}");
+
+        handles.remove(first);
+        assert_eq!(handles.for_inode(7), vec![second]);
+    }
+}
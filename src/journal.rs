@@ -0,0 +1,311 @@
+use crate::fhir::{
+    delete_from_fhir_server, put_to_fhir_server, resource_exists, FhirClient, HttpError,
+    WriteResponse,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A write or delete the server couldn't be reached for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub resource_type: String,
+    pub resource_id: String,
+    /// What the resource becomes, `None` for a delete
+    pub content: Option<String>,
+    /// Version the change was made against, sent as `If-Match`
+    pub version_id: Option<String>,
+    /// The file was new: replaying it must not replace a resource someone
+    /// else created meanwhile
+    #[serde(default)]
+    pub created: bool,
+}
+
+impl JournalEntry {
+    fn key(&self) -> (&str, &str) {
+        (&self.resource_type, &self.resource_id)
+    }
+
+    fn status(&self) -> char {
+        match (&self.content, self.created) {
+            (None, _) => 'D',
+            (Some(_), true) => 'A',
+            (Some(_), false) => 'M',
+        }
+    }
+
+    /// Send the change. `None` for a delete.
+    pub async fn replay(
+        &self,
+        client: &FhirClient,
+        fhir_base_url: &str,
+    ) -> anyhow::Result<Option<WriteResponse>> {
+        let filename = format!("{}.json", self.resource_id);
+        let Some(content) = &self.content else {
+            delete_from_fhir_server(
+                client,
+                fhir_base_url,
+                &self.resource_type,
+                &filename,
+                self.version_id.as_deref(),
+            )
+            .await?;
+            return Ok(None);
+        };
+
+        let reference = format!("{}/{}", self.resource_type, self.resource_id);
+        if self.created && resource_exists(client, fhir_base_url, &reference).await? {
+            return Err(HttpError {
+                action: format!("create {}", reference),
+                status: StatusCode::CONFLICT,
+                body: format!("{} was created on the server in the meantime", reference),
+            }
+            .into());
+        }
+        let response = put_to_fhir_server(
+            client,
+            fhir_base_url,
+            &self.resource_type,
+            &filename,
+            content,
+            self.version_id.as_deref(),
+        )
+        .await?;
+        Ok(Some(response))
+    }
+}
+
+/// Writes waiting for the server, with `--journal`. Kept in a file with one
+/// JSON entry per line, so they survive unmounting and are replayed by the
+/// next mount.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, with what an earlier mount left in it
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(text) => text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow::anyhow!("Invalid journal {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to read journal {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Pending entries, oldest first
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add an entry, on disk before this returns
+    pub fn push(&mut self, entry: JournalEntry) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        file.sync_data()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// The oldest entry reached the server, which stored `version_id`.
+    /// Later changes of the resource made against the same version now build
+    /// on the stored one.
+    pub fn replayed(&mut self, version_id: Option<String>) -> anyhow::Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+        let done = self.entries.remove(0);
+        if done.content.is_some() {
+            for entry in self.entries.iter_mut() {
+                if entry.key() == done.key()
+                    && entry.version_id == done.version_id
+                    && entry.created == done.created
+                {
+                    entry.version_id = version_id.clone();
+                    entry.created = false;
+                }
+            }
+        }
+        self.save()
+    }
+
+    /// The server rejected the oldest entry: drop it, keeping the content it
+    /// would have written as `<journal>.rejected/<type>/<id>.json` so the
+    /// change survives unmounting. Returns where it was kept.
+    pub fn reject_first(&mut self) -> anyhow::Result<Option<PathBuf>> {
+        let Some(rejected) = self.entries.first() else {
+            return Ok(None);
+        };
+        let kept = match &rejected.content {
+            Some(content) => {
+                let path = self.rejected_path(rejected);
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut file = File::create(&path)?;
+                file.write_all(content.as_bytes())?;
+                file.sync_data()?;
+                Some(path)
+            }
+            None => None,
+        };
+        self.entries.remove(0);
+        self.save()?;
+        Ok(kept)
+    }
+
+    fn rejected_path(&self, entry: &JournalEntry) -> PathBuf {
+        let mut dir = self.path.clone().into_os_string();
+        dir.push(".rejected");
+        PathBuf::from(dir)
+            .join(&entry.resource_type)
+            .join(format!("{}.json", entry.resource_id))
+    }
+
+    /// Whether a later entry changes the same resource as the oldest one
+    pub fn first_is_superseded(&self) -> bool {
+        match self.entries.split_first() {
+            Some((first, rest)) => rest.iter().any(|entry| entry.key() == first.key()),
+            None => false,
+        }
+    }
+
+    /// One line per entry in the order they are replayed, `A` (created), `M`
+    /// (modified) or `D` (deleted) and its path
+    pub fn status(&self) -> String {
+        if self.is_empty() {
+            return "Nothing pending\n".to_string();
+        }
+        self.entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} {}/{}.json\n",
+                    entry.status(),
+                    entry.resource_type,
+                    entry.resource_id
+                )
+            })
+            .collect()
+    }
+
+    /// Rewrite the file from `entries`, replacing it in one step
+    fn save(&self) -> anyhow::Result<()> {
+        let mut text = String::new();
+        for entry in &self.entries {
+            text.push_str(&serde_json::to_string(entry)?);
+            text.push('\n');
+        }
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::journal_path;
+
+    fn entry(resource_id: &str, content: Option<&str>, version_id: Option<&str>) -> JournalEntry {
+        JournalEntry {
+            resource_type: "Patient".to_string(),
+            resource_id: resource_id.to_string(),
+            content: content.map(String::from),
+            version_id: version_id.map(String::from),
+            created: false,
+        }
+    }
+
+    #[test]
+    fn test_entries_survive_reopening() {
+        let path = journal_path("reopen");
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.status(), "Nothing pending\n");
+        journal.push(entry("pt-1", Some("{}"), Some("1"))).unwrap();
+        journal
+            .push(JournalEntry {
+                created: true,
+                ..entry("pt-2", Some("{}"), None)
+            })
+            .unwrap();
+        journal.push(entry("pt-3", None, Some("4"))).unwrap();
+
+        let reopened = Journal::open(&path).unwrap();
+        assert_eq!(reopened.entries(), journal.entries());
+        assert_eq!(
+            reopened.status(),
+            "M Patient/pt-1.json\nA Patient/pt-2.json\nD Patient/pt-3.json\n"
+        );
+
+        let kept = journal.reject_first().unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&kept).unwrap(), "{}");
+        assert!(kept.ends_with("Patient/pt-1.json"));
+        assert_eq!(Journal::open(&path).unwrap().entries().len(), 2);
+        journal.reject_first().unwrap();
+        // A rejected delete has nothing to keep
+        assert_eq!(journal.reject_first().unwrap(), None);
+        std::fs::remove_dir_all(kept.parent().unwrap().parent().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replayed_entry_rebases_later_changes() {
+        let path = journal_path("rebase");
+        let mut journal = Journal::open(&path).unwrap();
+        journal.push(entry("pt-1", Some("{}"), Some("1"))).unwrap();
+        journal.push(entry("pt-2", Some("{}"), Some("1"))).unwrap();
+        journal.push(entry("pt-1", Some("{}"), Some("1"))).unwrap();
+        // Saved again after a conflict, against the server's version
+        journal.push(entry("pt-1", None, Some("5"))).unwrap();
+        assert!(journal.first_is_superseded());
+
+        journal.replayed(Some("2".to_string())).unwrap();
+        let versions: Vec<_> = journal
+            .entries()
+            .iter()
+            .map(|entry| (entry.resource_id.as_str(), entry.version_id.as_deref()))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                ("pt-2", Some("1")),
+                ("pt-1", Some("2")),
+                ("pt-1", Some("5"))
+            ]
+        );
+        assert!(!journal.first_is_superseded());
+        assert_eq!(Journal::open(&path).unwrap().entries(), journal.entries());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
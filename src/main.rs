@@ -2,12 +2,12 @@ use fuser::{
     FileAttr, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
     ReplyEmpty, ReplyEntry, ReplyWrite, Request,
 };
-use libc::{EACCES, EIO, ENODATA, ENOENT};
-use reqwest::Client;
+use libc::{
+    EACCES, EAGAIN, EBADF, EBUSY, EEXIST, EINVAL, EIO, ENODATA, ENOENT, EPERM, EROFS, ESTALE,
+};
 use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::runtime::Runtime;
 
 mod vfs;
@@ -19,25 +19,107 @@ use vfs::{
 
 mod fhir;
 use fhir::{
-    delete_from_fhir_server, execute_operation, fetch_capability_statement, fetch_resource_history,
-    fetch_resources_parallel, put_to_fhir_server, search_fhir_resources,
+    delete_from_fhir_server, discover_token_endpoint, execute_operation,
+    fetch_capability_statement, fetch_resource_history, fetch_resources_parallel,
+    fetch_structure_definition, get_from_fhir_server, local_references, patch_fhir_resource,
+    post_to_fhir_server, post_transaction, put_conditional_to_fhir_server, put_to_fhir_server,
+    reconcile_resource, references, resource_exists, revinclude_query, rewrite_references,
+    same_resource, search_fhir_resources, validate_on_server, version_id_of, AssertionSigner,
+    ClientAuthentication, ClientCredentials, FhirClient, HttpError, InvalidResource, PatchFormat,
+    ResourcePermissions, ServerCapabilities, TokenManager, WriteResponse,
 };
 
+mod config;
+use config::{AuthMode, CheckReferences, Config, RenameReferences, ValidationMode};
+
+mod file_handle;
+use file_handle::{FileHandles, OpenFile};
+
 mod inode_allocator;
 use inode_allocator::InodeAllocator;
 
-const TTL: Duration = Duration::from_secs(30);
-const CACHE_DURATION: Duration = Duration::from_secs(5);
+mod staging;
+use staging::{Change, Staging};
+
+mod journal;
+use journal::{Journal, JournalEntry};
+
+mod write_behind;
+use write_behind::{Outcome, QueuedWrite, WriteBehind};
+
+mod validation;
+use validation::{
+    base_url, outcome_issues, report, template, LocalValidator, StructureDefinitions,
+};
+
+#[cfg(test)]
+mod test_support;
 
 const README_CONTENT: &str = include_str!("../assets/README.md");
 const SEARCH_README_CONTENT: &str = include_str!("../assets/SEARCH_README.md");
+/// Files written here are POSTed, so the server assigns the id
+const NEW_DIRECTORY: &str = "_new";
+/// Files written here are created unless their name, a search, matches
+const IF_NONE_EXIST_DIRECTORY: &str = "_if-none-exist";
+/// Files written here update the resource their name, a search, matches
+const WHERE_DIRECTORY: &str = "_where";
+/// Skeleton of a new resource, built from the type's StructureDefinition
+const TEMPLATE_FILE: &str = "_template.json";
+/// Writes kept back by `--staging`, with `status`, diffs, `commit` and `abort`
+const STAGING_DIRECTORY: &str = ".staging";
+/// Lists the changes `--journal` holds for the server, in replay order
+const JOURNAL_STATUS: &str = ".pending";
+/// How long an unreachable server is left alone before the journal is
+/// replayed again
+const JOURNAL_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// How a file written into one of the directories above reaches the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CreateMode {
+    /// `POST <type>`
+    Post,
+    /// `POST <type>` with `If-None-Exist: <name>`
+    IfNoneExist,
+    /// `PUT <type>?<name>`
+    Where,
+}
+
+impl CreateMode {
+    const ALL: [CreateMode; 3] = [CreateMode::Post, CreateMode::IfNoneExist, CreateMode::Where];
+
+    fn directory(self) -> &'static str {
+        match self {
+            CreateMode::Post => NEW_DIRECTORY,
+            CreateMode::IfNoneExist => IF_NONE_EXIST_DIRECTORY,
+            CreateMode::Where => WHERE_DIRECTORY,
+        }
+    }
+
+    fn is_allowed(self, permissions: ResourcePermissions) -> bool {
+        match self {
+            CreateMode::Post => permissions.create,
+            CreateMode::IfNoneExist => permissions.conditional_create,
+            CreateMode::Where => permissions.conditional_update,
+        }
+    }
+
+    /// The search a file's name stands for; `_new/` names are just names
+    fn query(self, name: &str) -> Option<&str> {
+        match self {
+            CreateMode::Post => None,
+            _ => Some(name.trim_end_matches(".json")),
+        }
+    }
+}
 
 struct FhirFuse {
+    config: Config,
     fhir_base_url: String,
-    http_client: Client,
+    http_client: FhirClient,
     runtime: Arc<Runtime>,
     inode_index: InodeIndex,
     resource_directories: HashMap<String, u64>,
+    capabilities: ServerCapabilities,
     search_directories: HashMap<u64, u64>, // search_inode -> readme_inode
     search_query_directories: HashMap<u64, u64>, // query_inode -> search_inode
     search_result_groups: HashMap<u64, u64>, // group_inode -> query_inode
@@ -48,16 +130,38 @@ struct FhirFuse {
     history_directories: HashMap<u64, (String, String)>, // history_dir_inode -> (resource_type, resource_id)
     history_load_times: HashMap<u64, std::time::Instant>, // history_dir_inode -> last_refresh_time
     inode_allocator: InodeAllocator,
-    pending_writes: HashMap<u64, Vec<u8>>,
-    created_files: HashMap<u64, (String, String)>,
+    file_handles: FileHandles,
     temp_files: HashMap<u64, (u64, String, Vec<u8>)>,
+    unsent_temp_files: HashSet<u64>, // temp files written since they were last closed
+    moved_aside: HashMap<(u64, String), (Option<String>, String)>, // (dir_inode, filename) -> (version_id, content) of a resource an editor renamed to a backup name
+    inode_aliases: HashMap<u64, u64>, // renamed temp inode -> resource inode it was saved over
+    new_directories: HashMap<u64, (String, CreateMode)>, // _new/_if-none-exist/_where dir inode -> (resource_type, mode)
+    new_file_links: HashMap<(u64, String), String>, // (new dir inode, filename) -> id of the resource it became
     lookup_counter: u64,
     readdir_counter: u64,
     operation_manager: OperationManager,
+    validator: Option<LocalValidator>, // loaded for --validate local
+    definitions: StructureDefinitions, // from --packages, and those fetched from the server
+    missing_definitions: HashSet<String>, // canonical urls neither in the packages nor on the server
+    templated_types: HashSet<String>,     // types whose _template.json was built (or couldn't be)
+    staging: Option<Staging>,             // writes waiting for a commit, with --staging
+    staging_directories: HashMap<u64, u64>, // .staging and its type directories -> parent inode
+    write_behind: Option<WriteBehind>,    // batches closed files, with --write-behind
+    write_errors: HashMap<u64, i32>, // inode -> errno of its rejected write-behind write, reported once
+    journal: Option<Journal>,        // changes waiting for the server, with --journal
+    journal_retry: Option<std::time::Instant>, // when an unreachable server is tried again
 }
 
 impl FhirFuse {
-    fn new(fhir_base_url: String, http_client: Client, runtime: Arc<Runtime>) -> Self {
+    fn new(
+        config: Config,
+        http_client: FhirClient,
+        runtime: Arc<Runtime>,
+        validator: Option<LocalValidator>,
+        definitions: StructureDefinitions,
+        journal: Option<Journal>,
+    ) -> Self {
+        let fhir_base_url = config.fhir_base_url.clone();
         let mut inode_allocator = InodeAllocator::new(1);
 
         let root_inode = inode_allocator.root_inode;
@@ -81,18 +185,26 @@ impl FhirFuse {
 
         let mut resource_directories = HashMap::new();
         let mut search_directories = HashMap::new();
+        let mut new_directories = HashMap::new();
 
         // Fetch capabilities async and create directories for each resource type
         let caps_result =
             runtime.block_on(fetch_capability_statement(&http_client, &fhir_base_url));
 
-        match caps_result {
+        let capabilities = match caps_result {
             Ok(caps) => {
                 println!(
                     "Successfully fetched capabilities: {} resource types",
                     caps.resources.len()
                 );
-                for resource_type in &caps.resources {
+                let exposed = caps.resources.iter().filter(|resource_type| {
+                    config
+                        .resource_types
+                        .as_ref()
+                        .map(|types| types.contains(resource_type))
+                        .unwrap_or(true)
+                });
+                for resource_type in exposed {
                     let dir_inode = inode_allocator.allocate();
                     inode_index.insert_directory(Directory::new(dir_inode, resource_type.clone()));
                     inode_index.add_parent_child_relation(root_inode, dir_inode);
@@ -116,12 +228,24 @@ impl FhirFuse {
                     ));
                     inode_index.add_parent_child_relation(search_inode, search_readme_inode);
                     search_directories.insert(search_inode, search_readme_inode);
+
+                    for mode in CreateMode::ALL {
+                        if mode.is_allowed(caps.permissions(resource_type)) {
+                            let new_inode = inode_allocator.allocate();
+                            inode_index
+                                .insert_directory(Directory::new(new_inode, mode.directory()));
+                            inode_index.add_parent_child_relation(dir_inode, new_inode);
+                            new_directories.insert(new_inode, (resource_type.clone(), mode));
+                        }
+                    }
                 }
+                caps
             }
             Err(e) => {
                 eprintln!("Failed to fetch capabilities: {:#?}", e);
+                ServerCapabilities::new()
             }
-        }
+        };
 
         let mut operation_manager = OperationManager::new();
 
@@ -137,12 +261,23 @@ impl FhirFuse {
             operation_manager.add_operation_path(run_path);
         }
 
-        FhirFuse {
+        let staging = config.write.staging.then(Staging::default);
+        let write_behind = config.write.write_behind.clone().map(|settings| {
+            WriteBehind::start(
+                &runtime,
+                settings,
+                http_client.clone(),
+                fhir_base_url.clone(),
+            )
+        });
+        let mut fuse = FhirFuse {
+            config,
             fhir_base_url,
             http_client,
             runtime,
             inode_index,
             resource_directories,
+            capabilities,
             search_directories,
             search_query_directories: HashMap::new(),
             search_result_groups: HashMap::new(),
@@ -153,13 +288,30 @@ impl FhirFuse {
             history_directories: HashMap::new(),
             history_load_times: HashMap::new(),
             inode_allocator,
-            pending_writes: HashMap::new(),
-            created_files: HashMap::new(),
+            file_handles: FileHandles::new(),
             temp_files: HashMap::new(),
+            unsent_temp_files: HashSet::new(),
+            moved_aside: HashMap::new(),
+            inode_aliases: HashMap::new(),
+            new_directories,
+            new_file_links: HashMap::new(),
             lookup_counter: 0,
             readdir_counter: 0,
             operation_manager,
-        }
+            validator,
+            definitions,
+            missing_definitions: HashSet::new(),
+            templated_types: HashSet::new(),
+            staging,
+            staging_directories: HashMap::new(),
+            write_behind,
+            write_errors: HashMap::new(),
+            journal,
+            journal_retry: None,
+        };
+        fuse.show_staging();
+        fuse.show_journal();
+        fuse
     }
 
     fn ensure_resources_loaded(&mut self, resource_type: &str, force_refresh: bool) {
@@ -168,7 +320,7 @@ impl FhirFuse {
             || self
                 .resource_load_times
                 .get(resource_type)
-                .map(|t| t.elapsed() > CACHE_DURATION)
+                .map(|t| t.elapsed() > self.config.cache_duration)
                 .unwrap_or(true);
 
         if should_refresh {
@@ -183,10 +335,11 @@ impl FhirFuse {
         let client = self.http_client.clone();
         let base_url = self.fhir_base_url.clone();
         let rt = resource_type.to_string();
+        let limits = self.config.fetch_limits.clone();
 
         let result = self
             .runtime
-            .block_on(async { fetch_resources_parallel(&client, &base_url, &rt).await });
+            .block_on(async { fetch_resources_parallel(&client, &base_url, &rt, &limits).await });
 
         match result {
             Ok(resources) => {
@@ -195,41 +348,33 @@ impl FhirFuse {
 
                 // Get the directory inode for this resource type
                 let dir_inode = self.resource_directories.get(resource_type).copied();
+                // Files moved aside as backups are visible again
+                self.moved_aside
+                    .retain(|(dir, _), _| Some(*dir) != dir_inode);
 
                 let mut count = 0;
                 for resource in resources {
                     let inode = self.inode_allocator.allocate();
-                    let id = resource["id"].as_str().unwrap_or("unknown");
-                    let content = serde_json::to_string_pretty(&resource).unwrap_or_default();
-
-                    let resource_entry = FHIRResource::new(inode, resource_type, id, content);
+                    let resource_entry = FHIRResource::from_json(
+                        inode,
+                        resource_type,
+                        &resource,
+                        self.config.json_format,
+                    );
+                    let id = resource_entry.resource_id.clone();
 
                     self.inode_index.insert_resource(resource_entry);
 
                     if let Some(dir) = dir_inode {
                         self.inode_index.add_parent_child_relation(dir, inode);
-
-                        // Create hidden history directory for this resource if it doesn't exist
-                        let history_dir_name = format!(".{}", id);
-                        let history_dir_inode = if let Some(existing_inode) =
-                            self.inode_index.find_child_by_name(dir, &history_dir_name)
-                        {
-                            existing_inode
-                        } else {
-                            let new_inode = self.inode_allocator.allocate();
-                            let history_dir = Directory::new(new_inode, history_dir_name);
-                            self.inode_index.insert_directory(history_dir);
-                            self.inode_index.add_parent_child_relation(dir, new_inode);
-                            new_inode
-                        };
-                        // Always track history directory (even if it already existed)
-                        self.history_directories.insert(
-                            history_dir_inode,
-                            (resource_type.to_string(), id.to_string()),
-                        );
+                        self.ensure_history_directory(dir, resource_type, &id);
                     }
                     count += 1;
                 }
+                if let Some(dir) = dir_inode {
+                    self.show_staged_resources(resource_type, dir);
+                    self.show_pending_resources(resource_type, dir);
+                }
 
                 println!("[FHIR] Loaded {} {} resources", count, resource_type);
             }
@@ -239,802 +384,3713 @@ impl FhirFuse {
         }
     }
 
-    fn get_attrs(&self, inode: u64) -> Option<FileAttr> {
-        self.inode_index.get_attr(inode)
+    /// Put `_template.json` in the directory of a type resources can be
+    /// created in, the first time it is looked up. Building it can take a
+    /// few StructureDefinition searches, which only the types someone asks
+    /// a template of should cost.
+    fn ensure_template(&mut self, resource_type: &str) {
+        if !self.templated_types.insert(resource_type.to_string()) {
+            return;
+        }
+        if !self.config.mount.is_writable(resource_type) || !self.permissions(resource_type).create
+        {
+            return;
+        }
+        match self.build_template(resource_type) {
+            Some(content) => self.set_sidecar(resource_type, TEMPLATE_FILE, content),
+            None => println!(
+                "[Template] No StructureDefinition for {}, no {}",
+                resource_type, TEMPLATE_FILE
+            ),
+        }
     }
 
-    /// Refresh search results for a given query inode (only if cache expired)
-    fn refresh_search_query(&mut self, query_inode: u64) {
-        // Check if we need to refresh (cache duration)
-        let should_refresh = self
-            .search_query_load_times
-            .get(&query_inode)
-            .map(|t| t.elapsed() > CACHE_DURATION)
-            .unwrap_or(true);
+    /// The template of a resource type, from the `--template-profiles`
+    /// profile of that type or else the base definition
+    fn build_template(&mut self, resource_type: &str) -> Option<String> {
+        let profiles = self.config.template_profiles.clone();
+        let profile = profiles.into_iter().find(|url| {
+            self.load_definition(url)
+                && self
+                    .definitions
+                    .get(url)
+                    .is_some_and(|definition| definition["type"] == resource_type)
+        });
+        let url = profile.unwrap_or_else(|| base_url(resource_type));
+        if !self.load_definition(&url) {
+            return None;
+        }
+        let definition = self.definitions.get(&url)?.clone();
+
+        // Data type definitions not in the packages are fetched as the
+        // template turns out to need them
+        loop {
+            let template = template::build(&definition, &self.definitions);
+            let mut fetched = false;
+            for type_name in &template.missing {
+                fetched |= self.load_definition(&base_url(type_name));
+            }
+            if !fetched {
+                println!(
+                    "[Template] Built {} {} from {}",
+                    resource_type, TEMPLATE_FILE, url
+                );
+                return Some(template.content);
+            }
+        }
+    }
 
-        if !should_refresh {
-            println!(
-                "[Search] Skipping refresh for query_inode {} (cache still valid)",
-                query_inode
-            );
+    /// Make sure a StructureDefinition is loaded, fetching it from the
+    /// server if the packages don't have it
+    fn load_definition(&mut self, url: &str) -> bool {
+        if self.definitions.get(url).is_some() {
+            return true;
+        }
+        if self.missing_definitions.contains(url) {
+            return false;
+        }
+        let result = self.runtime.block_on(fetch_structure_definition(
+            &self.http_client,
+            &self.fhir_base_url,
+            url,
+        ));
+        match result {
+            Ok(Some(definition)) => self.definitions.insert(definition),
+            Ok(None) => println!("[Template] {} is not on the server", url),
+            Err(e) => println!("[Template] Failed to fetch {}: {}", url, e),
+        }
+        // Definitions without a snapshot aren't kept
+        let loaded = self.definitions.get(url).is_some();
+        if !loaded {
+            self.missing_definitions.insert(url.to_string());
+        }
+        loaded
+    }
+
+    /// Create the hidden `.<id>` history directory for a resource if it
+    /// doesn't exist yet (only when the server supports `history-instance`)
+    fn ensure_history_directory(&mut self, dir_inode: u64, resource_type: &str, id: &str) {
+        if !self.permissions(resource_type).history {
             return;
         }
 
-        let search_info = self.search_query_info.get(&query_inode).cloned();
-        if let Some((resource_type, query_string)) = search_info {
-            println!(
-                "[Search] Refreshing: {}/{}?{}",
-                resource_type, "_search", query_string
-            );
+        let history_dir_name = format!(".{}", id);
+        let history_dir_inode = if let Some(existing_inode) = self
+            .inode_index
+            .find_child_by_name(dir_inode, &history_dir_name)
+        {
+            existing_inode
+        } else {
+            let new_inode = self.inode_allocator.allocate();
+            let history_dir = Directory::new(new_inode, history_dir_name);
+            self.inode_index.insert_directory(history_dir);
+            self.inode_index
+                .add_parent_child_relation(dir_inode, new_inode);
+            new_inode
+        };
+        // Always track history directory (even if it already existed)
+        self.history_directories.insert(
+            history_dir_inode,
+            (resource_type.to_string(), id.to_string()),
+        );
+    }
 
-            let client = self.http_client.clone();
-            let base_url = self.fhir_base_url.clone();
-            let rt_clone = resource_type.clone();
-            let query_clone = query_string.clone();
+    /// Interactions the server allows for a resource type
+    fn permissions(&self, resource_type: &str) -> ResourcePermissions {
+        self.capabilities.permissions(resource_type)
+    }
 
-            let result = self.runtime.block_on(async {
-                search_fhir_resources(&client, &base_url, &rt_clone, &query_clone).await
-            });
+    /// Whether a resource file may be modified in place
+    fn is_updatable(&self, inode: u64) -> bool {
+        self.inode_index
+            .get_fhir_resource(inode)
+            .map(|resource| self.permissions(&resource.resource_type).update)
+            .unwrap_or(true)
+    }
 
-            match result {
-                Ok(grouped_resources) => {
-                    // Build a map of existing groups by resource type
-                    let mut existing_groups: HashMap<String, u64> = HashMap::new();
-                    let old_children = self.inode_index.get_children(query_inode);
-                    for child_inode in &old_children {
-                        if let Some(group) = self.inode_index.get_search_result_group(*child_inode)
-                        {
-                            existing_groups.insert(group.resource_type.clone(), *child_inode);
-                        }
-                    }
+    /// EROFS for writes the mount configuration forbids: everything when
+    /// mounted read-only, otherwise types outside `--writable-types`
+    fn check_writable(&self, resource_type: Option<&str>) -> Result<(), i32> {
+        let writable = match resource_type {
+            Some(resource_type) => self.config.mount.is_writable(resource_type),
+            None => !self.config.mount.read_only,
+        };
+        if writable {
+            Ok(())
+        } else {
+            Err(EROFS)
+        }
+    }
 
-                    // Update each group - reuse existing group inodes, only refresh resources
-                    for (res_type, resources) in grouped_resources {
-                        let group_inode = if let Some(&existing_inode) =
-                            existing_groups.get(&res_type)
-                        {
-                            // Clear old resources from this group
-                            let resource_children = self.inode_index.get_children(existing_inode);
-                            for res_inode in resource_children {
-                                self.inode_index.remove(res_inode);
-                            }
-                            self.inode_index.clear_children(existing_inode);
-                            existing_groups.remove(&res_type);
-                            existing_inode
-                        } else {
-                            // Create new group
-                            let new_inode = self.inode_allocator.allocate();
-                            let query = match self.inode_index.get_search_query(query_inode) {
-                                Some(q) => q,
-                                None => {
-                                    println!(
-                                        "[Error] SearchQuery not found for inode {}",
-                                        query_inode
-                                    );
-                                    continue;
-                                }
-                            };
-                            let group = SearchResultGroup::new(
-                                new_inode,
-                                res_type.clone(),
-                                query_inode,
-                                query,
-                            );
-                            self.inode_index.insert_search_result_group(group);
-                            self.inode_index
-                                .add_parent_child_relation(query_inode, new_inode);
-                            self.search_result_groups.insert(new_inode, query_inode);
-                            new_inode
-                        };
+    /// `check_writable` for an existing file
+    fn check_inode_writable(&self, inode: u64) -> Result<(), i32> {
+        let resource_type = self
+            .inode_index
+            .get_fhir_resource(inode)
+            .map(|resource| resource.resource_type.as_str());
+        self.check_writable(resource_type)
+    }
 
-                        // Add fresh resources to the group
-                        println!(
-                            "[Search] Adding {} {} resources to group {}",
-                            resources.len(),
-                            res_type,
-                            group_inode
-                        );
-                        for resource in &resources {
-                            let res_inode = self.inode_allocator.allocate();
-                            let id = resource["id"].as_str().unwrap_or("unknown");
-                            let content =
-                                serde_json::to_string_pretty(&resource).unwrap_or_default();
+    /// Resource type whose directory has this inode
+    fn directory_resource_type(&self, dir_inode: u64) -> Option<String> {
+        self.resource_directories
+            .iter()
+            .find(|(_, &inode)| inode == dir_inode)
+            .map(|(resource_type, _)| resource_type.clone())
+    }
 
-                            let resource_entry =
-                                FHIRResource::new(res_inode, &res_type, id, content);
-                            self.inode_index.insert_resource(resource_entry);
-                            self.inode_index
-                                .add_parent_child_relation(group_inode, res_inode);
-                        }
-                    }
+    /// Show a plain file next to the resources in a type directory, replacing
+    /// the content of an earlier file with the same name
+    fn set_sidecar(&mut self, resource_type: &str, name: &str, content: String) {
+        if let Some(&dir_inode) = self.resource_directories.get(resource_type) {
+            self.set_text_file(dir_inode, name, content);
+        }
+    }
 
-                    // Remove groups that no longer exist in results
-                    for (_res_type, old_group_inode) in existing_groups {
-                        let resource_children = self.inode_index.get_children(old_group_inode);
-                        for res_inode in resource_children {
-                            self.inode_index.remove(res_inode);
-                        }
-                        self.inode_index.clear_children(old_group_inode);
-                        self.search_result_groups.remove(&old_group_inode);
-                        self.inode_index.remove(old_group_inode);
-                    }
+    fn remove_sidecar(&mut self, resource_type: &str, name: &str) {
+        if let Some(&dir_inode) = self.resource_directories.get(resource_type) {
+            self.remove_text_file(dir_inode, name);
+        }
+    }
 
-                    // Update cache time
-                    self.search_query_load_times
-                        .insert(query_inode, std::time::Instant::now());
-                }
-                Err(e) => {
-                    println!("[Search] Refresh failed: {}", e);
-                }
+    fn set_text_file(&mut self, dir_inode: u64, name: &str, content: String) {
+        if let Some(existing) = self.inode_index.find_child_by_name(dir_inode, name) {
+            if let Some(file) = self.inode_index.get_text_file_mut(existing) {
+                file.content = content;
+                return;
             }
         }
+
+        let inode = self.inode_allocator.allocate();
+        self.inode_index
+            .insert_text_file(TextFile::new(inode, name, content));
+        self.inode_index.add_parent_child_relation(dir_inode, inode);
     }
 
-    #[allow(dead_code)]
-    fn debug_print_stats(&self) {
-        let stats: IndexStats = self.inode_index.stats();
-        println!("=== Inode Index Statistics ===");
-        println!("{}", stats);
-        println!("Loaded resource types: {:?}", self.loaded_resources);
-        println!(
-            "Resource directories: {} types",
-            self.resource_directories.len()
-        );
-        println!("Next inode: {}", self.inode_allocator.peek_next());
-        println!("==============================");
+    fn remove_text_file(&mut self, dir_inode: u64, name: &str) {
+        if let Some(inode) = self.inode_index.find_child_by_name(dir_inode, name) {
+            if self.inode_index.get_text_file(inode).is_some() {
+                self.inode_index.remove(inode);
+            }
+        }
     }
-}
 
-impl FhirFuse {
-    // Helper methods for mkdir
-    fn execute_search_query(
-        &mut self,
-        resource_type: &str,
-        query: &str,
-    ) -> Result<HashMap<String, Vec<serde_json::Value>>, Box<dyn std::error::Error>> {
+    /// Keep the server's current version as `<id>.json.conflict` after an
+    /// update was rejected, so the change can be merged by hand. The file
+    /// still refers to the version it was loaded at until the conflict is
+    /// resolved (see `merged_version` and `resolve_conflict`), so nothing
+    /// based on the stale content overwrites the server's change.
+    fn record_conflict(&mut self, resource_type: &str, resource_id: &str, error: &anyhow::Error) {
         let client = self.http_client.clone();
         let base_url = self.fhir_base_url.clone();
-        let rt_clone = resource_type.to_string();
-        let query_clone = query.to_string();
 
-        match self.runtime.block_on(async {
-            search_fhir_resources(&client, &base_url, &rt_clone, &query_clone).await
-        }) {
-            Ok(result) => Ok(result),
-            Err(e) => Err(e.to_string().into()),
-        }
+        let content = match self.runtime.block_on(get_from_fhir_server(
+            &client,
+            &base_url,
+            resource_type,
+            resource_id,
+        )) {
+            Ok(current) => current,
+            Err(e) => {
+                println!(
+                    "[FHIR] {}: {} could not fetch current version: {}",
+                    resource_type, resource_id, e
+                );
+                format!("{}\n", error)
+            }
+        };
+
+        let name = format!("{}.json.conflict", resource_id);
+        println!(
+            "[FHIR] {}: conflict, server version in {}",
+            resource_type, name
+        );
+        self.set_sidecar(resource_type, &name, content);
     }
 
-    fn create_search_query_directory(
-        &mut self,
-        query: String,
-        resource_type: String,
-        parent: u64,
-    ) -> u64 {
-        let query_inode = self.inode_allocator.allocate();
-        let search_query =
-            SearchQuery::new(query_inode, query.clone(), resource_type.clone(), parent);
-        self.inode_index.insert_search_query(search_query);
-        self.inode_index
-            .add_parent_child_relation(parent, query_inode);
-        self.search_query_directories.insert(query_inode, parent);
-        self.search_query_info
-            .insert(query_inode, (resource_type, query));
-        query_inode
+    /// The server's version in `<id>.json.conflict` when `content` says it
+    /// was merged onto it (its `meta.versionId` is that version), to send as
+    /// `If-Match` instead of the version the file was loaded at
+    fn merged_version(
+        &self,
+        resource_type: &str,
+        resource_id: &str,
+        content: &str,
+    ) -> Option<String> {
+        let &dir_inode = self.resource_directories.get(resource_type)?;
+        let inode = self
+            .inode_index
+            .find_child_by_name(dir_inode, &format!("{}.json.conflict", resource_id))?;
+        let conflict = self.inode_index.get_text_file(inode)?;
+        let server_version = version_id_of(&serde_json::from_str(&conflict.content).ok()?)?;
+        let merged_onto = version_id_of(&serde_json::from_str(content).ok()?)?;
+        (merged_onto == server_version).then_some(server_version)
     }
 
-    fn create_search_result_group(&mut self, res_type: String, query_inode: u64) -> u64 {
-        let group_inode = self.inode_allocator.allocate();
-        let query = self
+    /// `<id>.json.conflict` was removed: the conflict is resolved, and the
+    /// file and the handles open on it are saved against the server's
+    /// version from now on
+    fn resolve_conflict(&mut self, dir_inode: u64, resource_id: &str, conflict_inode: u64) {
+        let Some(server_version) = self
             .inode_index
-            .get_search_query(query_inode)
-            .expect(&format!("SearchQuery not found for inode {}", query_inode));
-        let group = SearchResultGroup::new(group_inode, res_type.clone(), query_inode, query);
-        self.inode_index.insert_search_result_group(group);
-        self.inode_index
-            .add_parent_child_relation(query_inode, group_inode);
-        self.search_result_groups.insert(group_inode, query_inode);
-        println!(
-            "[Search] Created group {} with inode {}",
-            res_type, group_inode
-        );
-        group_inode
-    }
-
-    fn add_resources_to_group(&mut self, resources: Vec<serde_json::Value>, group_inode: u64) {
-        for resource in resources {
-            if let Some(id) = resource.get("id").and_then(|v| v.as_str()) {
-                let resource_type = resource
-                    .get("resourceType")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("Unknown");
-                let resource_inode = self.inode_allocator.allocate();
-                let content = serde_json::to_string_pretty(&resource).unwrap_or_default();
-                let fhir_resource = FHIRResource::new(resource_inode, resource_type, id, content);
-                self.inode_index.insert_resource(fhir_resource);
-                self.inode_index
-                    .add_parent_child_relation(group_inode, resource_inode);
+            .get_text_file(conflict_inode)
+            .and_then(|conflict| serde_json::from_str(&conflict.content).ok())
+            .and_then(|resource| version_id_of(&resource))
+        else {
+            return;
+        };
+        let filename = format!("{}.json", resource_id);
+        let Some(inode) = self.inode_index.find_child_by_name(dir_inode, &filename) else {
+            return;
+        };
+        if let Some(resource) = self.inode_index.get_fhir_resource_mut(inode) {
+            resource.version_id = Some(server_version.clone());
+        }
+        for fh in self.file_handles.for_inode(inode) {
+            if let Some(file) = self.file_handles.get_mut(fh) {
+                file.version_id = Some(server_version.clone());
             }
         }
     }
 
-    // Helper methods for readdir
-    fn create_directory_listing(&self, ino: u64, parent: u64) -> DirectoryListing {
-        let mut listing = DirectoryListing::new();
-        listing.add_current_dir(ino);
-        listing.add_parent_dir(parent);
-        listing
+    /// Report a rejected write or delete: keep the server's explanation as
+    /// `<id>.json.error` (and the server version on conflicts), and return the
+    /// errno for the calling process
+    fn write_failed(
+        &mut self,
+        resource_type: &str,
+        resource_id: &str,
+        error: &anyhow::Error,
+    ) -> i32 {
+        let name = format!("{}.json.error", resource_id);
+        self.set_sidecar(resource_type, &name, error_report(error));
+        if is_conflict(error) {
+            self.record_conflict(resource_type, resource_id, error);
+        }
+        errno_for(error)
     }
 
-    fn add_children_to_listing(&self, listing: &mut DirectoryListing, parent_inode: u64) {
-        let children = self.inode_index.get_children(parent_inode);
-        for child_inode in children {
-            if let Some(entry) = self.inode_index.get(child_inode) {
-                match entry {
-                    VFSEntry::Directory(dir) => listing.add_dir(dir.inode, &dir.name),
-                    VFSEntry::TextFile(file) => listing.add_file(file.inode, &file.filename),
-                    VFSEntry::SearchPath(search) => listing.add_dir(search.inode, &search.path),
-                    VFSEntry::SearchQuery(query) => listing.add_dir(query.inode, &query.query),
-                    VFSEntry::SearchResultGroup(group) => {
-                        listing.add_dir(group.inode, &group.resource_type)
-                    }
-                    VFSEntry::FHIRResource(resource) => {
-                        listing.add_file(resource.inode, &resource.filename)
-                    }
-                    VFSEntry::ResourceVersion(version) => {
-                        listing.add_file(version.inode, &version.filename)
-                    }
-                    VFSEntry::OperationPath(op) => listing.add_dir(op.inode, &op.path),
-                    VFSEntry::OperationExecution(exec) => listing.add_file(exec.inode, &exec.path),
-                }
-            }
-        }
+    /// Drop the `.error` and `.conflict` sidecars after a successful write
+    fn clear_sidecars(&mut self, resource_type: &str, resource_id: &str) {
+        self.remove_sidecar(resource_type, &format!("{}.json.error", resource_id));
+        self.remove_sidecar(resource_type, &format!("{}.json.conflict", resource_id));
     }
 
-    fn add_sorted_files_to_listing(&self, listing: &mut DirectoryListing, parent_inode: u64) {
-        let children = self.inode_index.get_children(parent_inode);
-        let mut files: Vec<_> = children
-            .iter()
-            .filter_map(|&inode| {
-                self.inode_index
-                    .get_fhir_resource(inode)
-                    .map(|resource| (resource.filename.clone(), inode))
-            })
-            .collect();
-        files.sort_by_key(|(name, _)| name.clone());
-        for (name, inode) in files {
-            listing.add_file(inode, &name);
-        }
+    /// Start a write handle on a resource file, holding its current content
+    /// and version
+    fn open_for_write(&mut self, ino: u64, flags: i32) -> Result<u64, i32> {
+        let file = match self.inode_index.get(ino) {
+            Some(VFSEntry::FHIRResource(resource)) => OpenFile::new(
+                ino,
+                &resource.resource_type,
+                &resource.resource_id,
+                resource.version_id.clone(),
+                resource.content.as_bytes().to_vec(),
+                flags,
+            ),
+            Some(_) => return Err(EACCES),
+            None => return Err(ENOENT),
+        };
+        Ok(self.file_handles.insert(file))
     }
 
-    fn reply_with_listing(
+    /// What is sent for a file written as `resource_type/resource_id`: its
+    /// text, with `resourceType` and `id` reconciled with the path. An `id`
+    /// that doesn't match is replaced with `rewrite_id`, as renames do, and
+    /// rejected otherwise.
+    fn prepare_content(
         &self,
-        listing: DirectoryListing,
-        offset: i64,
-        reply: &mut ReplyDirectory,
-    ) {
-        let entries = listing.into_vec();
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(entry.0, (i + 1) as i64, entry.1, &entry.2) {
-                break;
+        bytes: Vec<u8>,
+        resource_type: &str,
+        resource_id: Option<&str>,
+        rewrite_id: bool,
+    ) -> Result<String, InvalidResource> {
+        let content = String::from_utf8(bytes)
+            .map_err(|_| InvalidResource("The file is not valid UTF-8".to_string()))?;
+        let content = reconcile_resource(&content, resource_type, resource_id, rewrite_id)?;
+        self.validate(resource_type, &content)?;
+        self.check_references(resource_type, &content)?;
+        Ok(content)
+    }
+
+    /// Look up the targets of a resource's references as
+    /// `--check-references` asks. Resources in the filesystem are known to
+    /// exist; the others are looked up on the server.
+    fn check_references(&self, resource_type: &str, content: &str) -> Result<(), InvalidResource> {
+        let mode = self.config.write.check_references;
+        if mode == CheckReferences::Off {
+            return Ok(());
+        }
+        let resource = serde_json::from_str(content)
+            .map_err(|e| InvalidResource(format!("The file is not valid JSON: {}", e)))?;
+
+        let mut exists: HashMap<String, bool> = HashMap::new();
+        let mut missing = Vec::new();
+        for (location, target) in local_references(&resource, &self.fhir_base_url) {
+            let found = match exists.get(&target) {
+                Some(&found) => found,
+                None => {
+                    let found = self.reference_exists(&target);
+                    exists.insert(target.clone(), found);
+                    found
+                }
+            };
+            if !found {
+                missing.push(format!("{}: {}", location, target));
             }
         }
-    }
+        if missing.is_empty() {
+            return Ok(());
+        }
 
-    fn handle_root_readdir(&self, offset: i64, reply: &mut ReplyDirectory) {
-        let mut listing = self.create_directory_listing(
-            self.inode_allocator.root_inode,
-            self.inode_allocator.root_inode,
+        if mode == CheckReferences::Warn {
+            for reference in &missing {
+                println!(
+                    "[References] {} is not on the server, sending anyway",
+                    reference
+                );
+            }
+            return Ok(());
+        }
+        println!(
+            "[References] {}: {} missing target(s), not sending it",
+            resource_type,
+            missing.len()
         );
-        self.add_children_to_listing(&mut listing, self.inode_allocator.root_inode);
-        self.reply_with_listing(listing, offset, reply);
+        let lines: Vec<String> = missing.iter().map(|line| format!("- {}", line)).collect();
+        Err(InvalidResource(format!(
+            "The resource references resources that don't exist on the server:\n{}",
+            lines.join("\n")
+        )))
     }
 
-    fn handle_search_readdir(&self, ino: u64, offset: i64, reply: &mut ReplyDirectory) {
-        let parent = self
+    /// Whether `Type/id` exists. When the server can't tell, it is taken to
+    /// exist, so a failing lookup never blocks a write by itself.
+    fn reference_exists(&self, target: &str) -> bool {
+        let (resource_type, id) = target.split_once('/').unwrap_or((target, ""));
+        let listed = self
             .inode_index
-            .get_search_path(ino)
-            .map(|search| search.parent_inode)
-            .unwrap_or(ino);
-
-        let mut listing = self.create_directory_listing(ino, parent);
-        self.add_children_to_listing(&mut listing, ino);
-        self.reply_with_listing(listing, offset, reply);
+            .get_resources_by_type(resource_type)
+            .iter()
+            .any(|resource| resource.resource_id == id);
+        if listed {
+            return true;
+        }
+        let result = self.runtime.block_on(resource_exists(
+            &self.http_client,
+            &self.fhir_base_url,
+            target,
+        ));
+        result.unwrap_or_else(|e| {
+            println!("[References] Can't tell whether {} exists: {}", target, e);
+            true
+        })
     }
 
-    fn handle_search_query_readdir(&mut self, ino: u64, offset: i64, reply: &mut ReplyDirectory) {
-        self.refresh_search_query(ino);
-
-        let parent = self
-            .inode_index
-            .get_search_query(ino)
-            .map(|query| query.parent_inode)
-            .unwrap_or(ino);
-
-        let mut listing = self.create_directory_listing(ino, parent);
-        self.add_children_to_listing(&mut listing, ino);
-        self.reply_with_listing(listing, offset, reply);
+    /// Check a resource as `--validate` asks before it is sent
+    fn validate(&self, resource_type: &str, content: &str) -> Result<(), InvalidResource> {
+        let issues = match self.config.validation.mode {
+            ValidationMode::Off => return Ok(()),
+            ValidationMode::Local => {
+                let resource = serde_json::from_str(content)
+                    .map_err(|e| InvalidResource(format!("The file is not valid JSON: {}", e)))?;
+                match &self.validator {
+                    Some(validator) => validator.validate(&resource, &self.definitions),
+                    None => return Ok(()),
+                }
+            }
+            ValidationMode::Server => {
+                let outcome = self.runtime.block_on(validate_on_server(
+                    &self.http_client,
+                    &self.fhir_base_url,
+                    resource_type,
+                    content,
+                ));
+                match outcome {
+                    Ok(outcome) => outcome_issues(&outcome),
+                    Err(e) => {
+                        // Without $validate the write itself is the check
+                        println!("[Validate] {}: sending unvalidated, {}", resource_type, e);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        if issues.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "[Validate] {}: {} issue(s), not sending it",
+            resource_type,
+            issues.len()
+        );
+        Err(InvalidResource(report(&issues)))
     }
 
-    fn handle_search_result_group_readdir(
-        &mut self,
-        ino: u64,
-        offset: i64,
-        reply: &mut ReplyDirectory,
-    ) {
-        // Get the parent query inode and refresh it
-        let parent = self
-            .inode_index
-            .get_search_result_group(ino)
-            .map(|group| group.parent_inode)
-            .unwrap_or(ino);
+    /// Inode currently showing a resource: the one it was opened with, or the
+    /// entry that replaced it when its type directory was refreshed meanwhile
+    fn resource_inode(&self, ino: u64, resource_type: &str, filename: &str) -> Option<u64> {
+        if self.inode_index.get_fhir_resource(ino).is_some() {
+            return Some(ino);
+        }
+        let &dir_inode = self.resource_directories.get(resource_type)?;
+        self.inode_index
+            .find_child_by_name(dir_inode, filename)
+            .filter(|&inode| self.inode_index.get_fhir_resource(inode).is_some())
+    }
 
-        // Refresh the parent search query to ensure results are up-to-date
-        if parent != ino {
+    /// PUT what was written through a handle against the version it was
+    /// opened at. The same content is never sent twice, whether or not the
+    /// server accepted it, and content that says the same as that version
+    /// isn't sent at all. With `--staging` it is staged instead, and with
+    /// `--write-behind` queued for the next batch.
+    fn upload(&mut self, fh: u64) -> Result<(), i32> {
+        let file = match self.file_handles.get_mut(fh) {
+            Some(file) if file.is_dirty() => file,
+            _ => return Ok(()),
+        };
+        file.mark_clean();
+
+        let ino = file.ino;
+        let resource_type = file.resource_type.clone();
+        let resource_id = file.resource_id.clone();
+        let filename = file.filename();
+        let version_id = file.version_id.clone();
+        let created = file.created;
+        let action = if created { "created" } else { "updated" };
+        let base = file.base.clone();
+        let bytes = file.content().to_vec();
+        let rewrite_ids = self.config.write.rewrite_ids;
+        let content =
+            match self.prepare_content(bytes, &resource_type, Some(&resource_id), rewrite_ids) {
+                Ok(content) => content,
+                Err(invalid) => {
+                    println!(
+                        "[FHIR] {}: {} {} rejected: {}",
+                        resource_type, resource_id, action, invalid
+                    );
+                    return Err(self.write_failed(&resource_type, &resource_id, &invalid.into()));
+                }
+            };
+        let version_id = self
+            .merged_version(&resource_type, &resource_id, &content)
+            .or(version_id);
+        if self.staging.is_some() {
+            // Staged by type and id even when the file's entry was replaced
+            // meanwhile; the staged content shows once the directory is
+            // loaded again
+            let inode = self.resource_inode(ino, &resource_type, &filename);
+            self.stage_put(
+                inode,
+                &resource_type,
+                &resource_id,
+                content,
+                version_id,
+                base,
+            );
+            return Ok(());
+        }
+        if let Some(base) = base.clone().filter(|base| same_resource(base, &content)) {
             println!(
-                "[Search] Refreshing parent query {} for group {}",
-                parent, ino
+                "[FHIR] {}: {} unchanged, nothing sent",
+                resource_type, resource_id
             );
-            self.refresh_search_query(parent);
+            // A file created in place of one an editor moved aside shows
+            // the version it stands for again
+            if let Some(resource) = self
+                .resource_inode(ino, &resource_type, &filename)
+                .and_then(|inode| self.inode_index.get_fhir_resource_mut(inode))
+                .filter(|resource| resource.content.is_empty())
+            {
+                resource.content = base;
+                resource.version_id = version_id;
+            }
+            return Ok(());
         }
 
-        let mut listing = self.create_directory_listing(ino, parent);
-        self.add_sorted_files_to_listing(&mut listing, ino);
-        self.reply_with_listing(listing, offset, reply);
-    }
-
-    fn load_resource_history(&mut self, history_dir_inode: u64) {
-        // Check if we need to refresh (cache duration)
-        let should_refresh = self
-            .history_load_times
-            .get(&history_dir_inode)
-            .map(|t| t.elapsed() > CACHE_DURATION)
-            .unwrap_or(true);
-
-        if !should_refresh {
-            return;
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.queue(QueuedWrite {
+                inode: ino,
+                resource_type: resource_type.clone(),
+                resource_id: resource_id.clone(),
+                content: content.clone(),
+                version_id,
+                base,
+            });
+            self.show_unsent_write(fh, ino, &resource_type, &filename, content);
+            return Ok(());
         }
-
-        // Get resource info for this history directory
-        let (resource_type, resource_id) = match self.history_directories.get(&history_dir_inode) {
-            Some(info) => info.clone(),
-            None => {
-                println!(
-                    "[History] No info found for history directory {}",
-                    history_dir_inode
-                );
-                return;
-            }
+        let entry = JournalEntry {
+            resource_type: resource_type.clone(),
+            resource_id: resource_id.clone(),
+            content: Some(content.clone()),
+            version_id: version_id.clone(),
+            created,
         };
-
-        println!(
-            "[History] Loading history for {}/{}",
-            resource_type, resource_id
-        );
+        if self.journal_pending() {
+            self.journal_push(entry)?;
+            self.show_unsent_write(fh, ino, &resource_type, &filename, content);
+            return Ok(());
+        }
 
         let client = self.http_client.clone();
         let base_url = self.fhir_base_url.clone();
-
-        let result: Result<Vec<serde_json::Value>, anyhow::Error> = self.runtime.block_on(async {
-            fetch_resource_history(&client, &base_url, &resource_type, &resource_id).await
+        let result = self.runtime.block_on(async {
+            put_to_fhir_server(
+                &client,
+                &base_url,
+                &resource_type,
+                &filename,
+                &content,
+                version_id.as_deref(),
+            )
+            .await
         });
 
         match result {
-            Ok(versions) => {
-                // Clear existing version files for this history directory
-                let children = self.inode_index.get_children(history_dir_inode);
-                for &child_inode in &children {
-                    self.inode_index.remove(child_inode);
+            Ok(response) => {
+                println!("[FHIR] {}: {} {}", resource_type, resource_id, action);
+                // Further writes through this handle build on the new version
+                if let Some(file) = self.file_handles.get_mut(fh) {
+                    file.version_id = response.version_id.clone();
+                    file.created = false;
+                    file.base = Some(content.clone());
                 }
-                self.inode_index.clear_children(history_dir_inode);
-
-                // Add version files
-                for (index, version) in versions.iter().enumerate() {
-                    let version_id = version
-                        .get("meta")
-                        .and_then(|m: &serde_json::Value| m.get("versionId"))
-                        .and_then(|v: &serde_json::Value| v.as_str())
-                        .map(String::from)
-                        .unwrap_or_else(|| format!("{}", index + 1));
-
-                    let content = serde_json::to_string_pretty(version).unwrap_or_default();
-
-                    let version_inode = self.inode_allocator.allocate();
-                    let version_entry = vfs::ResourceVersion::new(
-                        version_inode,
-                        &resource_type,
-                        &resource_id,
-                        &version_id,
-                        content,
-                    );
-
-                    self.inode_index.insert_resource_version(version_entry);
-                    self.inode_index
-                        .add_parent_child_relation(history_dir_inode, version_inode);
+                if let Some(inode) = self.resource_inode(ino, &resource_type, &filename) {
+                    self.apply_write_response(inode, response, content);
                 }
-
+                self.clear_sidecars(&resource_type, &resource_id);
+                Ok(())
+            }
+            Err(e) if self.journal.is_some() && is_unreachable(&e) => {
                 println!(
-                    "[History] Loaded {} versions for {}/{}",
-                    versions.len(),
-                    resource_type,
-                    resource_id
+                    "[FHIR] {}: {} {} failed: {}",
+                    resource_type, resource_id, action, e
                 );
-
-                // Update cache timestamp
-                self.history_load_times
-                    .insert(history_dir_inode, std::time::Instant::now());
+                self.journal_push(entry)?;
+                self.show_unsent_write(fh, ino, &resource_type, &filename, content);
+                Ok(())
             }
             Err(e) => {
                 println!(
-                    "[History] Failed to fetch history for {}/{}: {}",
-                    resource_type, resource_id, e
+                    "[FHIR] {}: {} {} failed: {}",
+                    resource_type, resource_id, action, e
                 );
+                Err(self.write_failed(&resource_type, &resource_id, &e))
             }
         }
     }
 
-    fn handle_resource_directory_readdir(
+    /// Show a write that was queued rather than sent in its file until the
+    /// server has it
+    fn show_unsent_write(
         &mut self,
+        fh: u64,
+        ino: u64,
         resource_type: &str,
-        dir_inode: u64,
-        offset: i64,
-        reply: &mut ReplyDirectory,
+        filename: &str,
+        content: String,
     ) {
-        self.ensure_resources_loaded(resource_type, false);
-
-        let mut listing = self.create_directory_listing(dir_inode, self.inode_allocator.root_inode);
+        if let Some(file) = self.file_handles.get_mut(fh) {
+            file.base = Some(content.clone());
+        }
+        if let Some(resource) = self
+            .resource_inode(ino, resource_type, filename)
+            .and_then(|inode| self.inode_index.get_fhir_resource_mut(inode))
+        {
+            resource.content = content;
+            resource.mtime = std::time::SystemTime::now();
+        }
+    }
 
-        // Add _search directory first
-        let children = self.inode_index.get_children(dir_inode);
-        for &child_inode in &children {
-            if let Some(search) = self.inode_index.get_search_path(child_inode) {
-                listing.add_dir(search.inode, &search.path);
-            }
-            if let Some(op) = self.inode_index.get_operation_path(child_inode) {
-                listing.add_dir(op.inode, &op.path);
+    /// Show what the write-behind batches answered so far stored, and the
+    /// `.error` (and `.conflict`) files of the writes they rejected. The
+    /// errno of a rejected write is kept for `take_write_error`.
+    fn apply_batch_outcomes(&mut self) {
+        let outcomes = match &self.write_behind {
+            Some(write_behind) => write_behind.take_outcomes(),
+            None => return,
+        };
+        for Outcome { write, result } in outcomes {
+            let QueuedWrite {
+                inode,
+                resource_type,
+                resource_id,
+                content,
+                base,
+                ..
+            } = write;
+            let filename = format!("{}.json", resource_id);
+            match result {
+                Ok(response) => {
+                    println!("[FHIR] {}: {} written in batch", resource_type, resource_id);
+                    // Handles still open write on top of the stored version
+                    for fh in self.file_handles.for_inode(inode) {
+                        if let Some(file) = self.file_handles.get_mut(fh) {
+                            if file.base.as_deref() == Some(content.as_str()) {
+                                file.version_id = response.version_id.clone();
+                                file.created = false;
+                            }
+                        }
+                    }
+                    if let Some(target) = self.resource_inode(inode, &resource_type, &filename) {
+                        self.apply_write_response(target, response, content);
+                    }
+                    self.clear_sidecars(&resource_type, &resource_id);
+                }
+                Err(e) => {
+                    println!(
+                        "[FHIR] {}: {} write in batch failed: {}",
+                        resource_type, resource_id, e
+                    );
+                    // Back to what the file showed before, as when a write
+                    // is rejected right away. A newer write shown since stays.
+                    for fh in self.file_handles.for_inode(inode) {
+                        if let Some(file) = self.file_handles.get_mut(fh) {
+                            if file.base.as_deref() == Some(content.as_str()) {
+                                file.base = base.clone();
+                            }
+                        }
+                    }
+                    if let Some(resource) = self
+                        .resource_inode(inode, &resource_type, &filename)
+                        .and_then(|target| self.inode_index.get_fhir_resource_mut(target))
+                        .filter(|resource| resource.content == content)
+                    {
+                        resource.content = base.unwrap_or_default();
+                        resource.mtime = std::time::SystemTime::now();
+                    }
+                    let errno = self.write_failed(&resource_type, &resource_id, &e);
+                    self.write_errors.insert(inode, errno);
+                }
             }
         }
+    }
 
-        // Add resource files and hidden history directories sorted
-        let children = self.inode_index.get_children(dir_inode);
-        let mut entries: Vec<(String, u64, bool)> = Vec::new();
+    /// Send what write-behind still holds and wait for the answers, for
+    /// `fsync` and unmounting
+    fn flush_write_behind(&mut self) {
+        if let Some(write_behind) = &self.write_behind {
+            self.runtime.block_on(write_behind.flush());
+        }
+        self.apply_batch_outcomes();
+    }
 
-        for &child_inode in &children {
-            if let Some(resource) = self.inode_index.get_fhir_resource(child_inode) {
-                entries.push((resource.filename.clone(), child_inode, false));
-            } else if let Some(directory) = self.inode_index.get_directory(child_inode) {
-                // Only include directories that start with '.' (history directories)
-                if directory.name.starts_with('.') {
-                    entries.push((directory.name.clone(), child_inode, true));
-                }
-            }
+    /// The errno of a file's write that a write-behind batch rejected since
+    /// it was closed. Reported once, by the next `open`, `flush`, `release`
+    /// or `fsync` of the file, like a failed write-back on NFS.
+    fn take_write_error(&mut self, ino: u64) -> Result<(), i32> {
+        self.apply_batch_outcomes();
+        match self.write_errors.remove(&ino) {
+            Some(errno) => Err(errno),
+            None => Ok(()),
         }
+    }
 
-        // Sort by name
-        entries.sort_by(|a, b| a.0.cmp(&b.0));
+    /// Whether changes are waiting in the journal. Later writes and deletes
+    /// wait behind them, so everything reaches the server in order.
+    fn journal_pending(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| !journal.is_empty())
+    }
 
-        for (name, inode, is_dir) in entries {
-            if is_dir {
-                listing.add_dir(inode, name);
-            } else {
-                listing.add_file(inode, name);
-            }
+    /// Keep a change in the journal until the server can be reached. Fails
+    /// with `EIO` when the journal can't be written.
+    fn journal_push(&mut self, entry: JournalEntry) -> Result<(), i32> {
+        let was_empty = !self.journal_pending();
+        let Some(journal) = self.journal.as_mut() else {
+            return Err(EIO);
+        };
+        let (resource_type, resource_id) = (entry.resource_type.clone(), entry.resource_id.clone());
+        if let Err(e) = journal.push(entry) {
+            println!(
+                "[Journal] {}: {} could not be kept: {}",
+                resource_type, resource_id, e
+            );
+            return Err(EIO);
+        }
+        println!(
+            "[Journal] {}: {} kept until the server can be reached",
+            resource_type, resource_id
+        );
+        if was_empty {
+            self.journal_retry = Some(std::time::Instant::now() + JOURNAL_RETRY_INTERVAL);
         }
+        self.show_journal();
+        Ok(())
+    }
 
-        self.reply_with_listing(listing, offset, reply);
+    /// Renames, patches and `_new/` files aren't journaled, so they must not
+    /// overtake the journal's changes they depend on (those `waits_for`
+    /// picks). The journal is replayed first; while such changes are still
+    /// waiting the call fails with `EAGAIN`.
+    fn wait_for_journal(&mut self, waits_for: impl Fn(&JournalEntry) -> bool) -> Result<(), i32> {
+        let holds = |fs: &Self| {
+            fs.journal
+                .as_ref()
+                .is_some_and(|journal| journal.entries().iter().any(&waits_for))
+        };
+        if !holds(self) {
+            return Ok(());
+        }
+        self.replay_journal_when_due();
+        if holds(self) {
+            println!("[Journal] Waiting for pending changes to reach the server first");
+            return Err(EAGAIN);
+        }
+        Ok(())
     }
-}
 
-impl Filesystem for FhirFuse {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name_str = name.to_str().unwrap_or("");
-        self.lookup_counter += 1;
+    /// Replay the journal once the server has had time to come back
+    fn replay_journal_when_due(&mut self) {
+        let due = self
+            .journal_retry
+            .is_none_or(|retry| retry <= std::time::Instant::now());
+        if self.journal_pending() && due {
+            self.replay_journal();
+        }
+    }
 
-        match parent {
-            parent if parent == self.inode_allocator.root_inode => {
-                if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str) {
-                    if let Some(attr) = self.get_attrs(child_inode) {
-                        reply.entry(&TTL, &attr, 0);
-                        return;
+    /// Send the journal's changes, oldest first, until the server can't be
+    /// reached. Each is conditional on the version it was made against: one
+    /// the server rejects is moved out of the journal into a file of its own,
+    /// with its `.error` file and, when someone else changed the resource
+    /// meanwhile, the server's version as `.conflict`, so nothing newer is
+    /// overwritten.
+    fn replay_journal(&mut self) {
+        while let Some(entry) = self
+            .journal
+            .as_ref()
+            .and_then(|journal| journal.entries().first().cloned())
+        {
+            let superseded = self
+                .journal
+                .as_ref()
+                .is_some_and(Journal::first_is_superseded);
+            let result = self
+                .runtime
+                .block_on(entry.replay(&self.http_client, &self.fhir_base_url));
+            let (resource_type, resource_id) = (&entry.resource_type, &entry.resource_id);
+            let saved = match result {
+                Err(e) if is_unreachable(&e) => {
+                    println!("[Journal] Server still unreachable: {}", e);
+                    self.journal_retry = Some(std::time::Instant::now() + JOURNAL_RETRY_INTERVAL);
+                    break;
+                }
+                Ok(response) => {
+                    println!("[Journal] {}: {} replayed", resource_type, resource_id);
+                    let version_id = response
+                        .as_ref()
+                        .and_then(|response| response.version_id.clone());
+                    self.show_replayed(&entry, response, superseded);
+                    self.journal
+                        .as_mut()
+                        .map_or(Ok(()), |journal| journal.replayed(version_id))
+                }
+                Err(e) => {
+                    println!(
+                        "[Journal] {}: {} rejected: {}",
+                        resource_type, resource_id, e
+                    );
+                    self.write_failed(resource_type, resource_id, &e);
+                    let rejected = self
+                        .journal
+                        .as_mut()
+                        .map_or(Ok(None), Journal::reject_first);
+                    if let Ok(Some(kept)) = &rejected {
+                        println!("[Journal] Rejected change kept in {}", kept.display());
                     }
+                    rejected.map(|_| ())
                 }
+            };
+            if let Err(e) = saved {
+                println!("[Journal] Failed to update the journal: {}", e);
             }
-            parent => {
-                let matching_resource = self
-                    .resource_directories
-                    .iter()
-                    .find(|(_, &dir_inode)| parent == dir_inode)
-                    .map(|(resource_type, _)| resource_type.clone());
-
-                if let Some(resource_type) = matching_resource {
-                    self.ensure_resources_loaded(&resource_type, false);
+        }
+        self.show_journal();
+    }
 
-                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
-                    {
-                        if let Some(attr) = self.get_attrs(child_inode) {
-                            reply.entry(&TTL, &attr, 0);
-                            return;
-                        }
-                    }
+    /// Show what the server stored for a replayed change. While a later
+    /// change of the resource is still waiting, the file keeps showing that
+    /// one and only takes the new version.
+    fn show_replayed(
+        &mut self,
+        entry: &JournalEntry,
+        response: Option<WriteResponse>,
+        superseded: bool,
+    ) {
+        let (Some(content), Some(response)) = (&entry.content, response) else {
+            if !superseded {
+                self.clear_sidecars(&entry.resource_type, &entry.resource_id);
+            }
+            return;
+        };
+        let filename = format!("{}.json", entry.resource_id);
+        let Some(inode) = self
+            .resource_directories
+            .get(&entry.resource_type)
+            .and_then(|&dir| self.inode_index.find_child_by_name(dir, &filename))
+            .filter(|&inode| self.inode_index.get_fhir_resource(inode).is_some())
+        else {
+            return;
+        };
+        // Handles still open write on top of the stored version
+        for fh in self.file_handles.for_inode(inode) {
+            if let Some(file) = self.file_handles.get_mut(fh) {
+                if file.version_id == entry.version_id {
+                    file.version_id = response.version_id.clone();
+                    file.created = false;
                 }
+            }
+        }
+        if superseded {
+            if let Some(resource) = self.inode_index.get_fhir_resource_mut(inode) {
+                resource.version_id = response.version_id;
+            }
+        } else {
+            self.apply_write_response(inode, response, content.clone());
+            self.clear_sidecars(&entry.resource_type, &entry.resource_id);
+        }
+    }
 
-                // Handle _search directories
-                if self.search_directories.contains_key(&parent) {
-                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
-                    {
-                        if let Some(attr) = self.get_attrs(child_inode) {
-                            reply.entry(&TTL, &attr, 0);
-                            return;
-                        }
-                    }
+    /// Bring `.pending` at the root of the mount up to date
+    fn show_journal(&mut self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let status = journal.status();
+        let root = self.inode_allocator.root_inode;
+        self.set_text_file(root, JOURNAL_STATUS, status);
+    }
+
+    /// Send what was written through a handle, or to a `_new/` file or
+    /// patch, when a descriptor of it is closed, so `close()` reports a
+    /// rejected write: the kernel ignores errors from release. A shell
+    /// redirection closes the descriptor it opened before writing through its
+    /// copy, so a still empty file waits for release.
+    fn flush_file(&mut self, ino: u64, fh: u64) -> Result<(), i32> {
+        let result = match self.file_handles.get(fh) {
+            Some(file) if file.content().is_empty() => Ok(()),
+            Some(_) => self.upload(fh),
+            None => self.send_written_temp_file(ino),
+        };
+        result.and(self.take_write_error(self.resolve_inode(ino)))
+    }
+
+    /// Upload anything still pending on a handle and forget it
+    fn release_handle(&mut self, fh: u64) -> Result<(), i32> {
+        let result = self.upload(fh);
+        self.file_handles.remove(fh);
+        result
+    }
+
+    /// Truncation through `setattr` applies to the handle doing it, or to
+    /// every handle open on the file (`truncate(1)` by path). A file nobody
+    /// has open is uploaded right away.
+    fn truncate_file(&mut self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), i32> {
+        let handles = match fh.filter(|&fh| self.file_handles.get(fh).is_some()) {
+            Some(fh) => vec![fh],
+            None => self.file_handles.for_inode(ino),
+        };
+        if !handles.is_empty() {
+            for fh in handles {
+                if let Some(file) = self.file_handles.get_mut(fh) {
+                    file.truncate(size);
                 }
+            }
+            return Ok(());
+        }
 
-                // Handle SearchQuery directories (search results grouped by type)
-                if self.search_query_directories.contains_key(&parent) {
-                    // Refresh the search results before looking up
-                    println!(
-                        "[Lookup] Refreshing search query {} for lookup of '{}'",
-                        parent, name_str
-                    );
-                    self.refresh_search_query(parent);
+        let fh = self.open_for_write(ino, libc::O_WRONLY)?;
+        if let Some(file) = self.file_handles.get_mut(fh) {
+            file.truncate(size);
+        }
+        self.release_handle(fh)
+    }
 
-                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
-                    {
-                        if let Some(attr) = self.get_attrs(child_inode) {
-                            reply.entry(&TTL, &attr, 0);
-                            return;
-                        }
-                    }
+    /// Show what the server stored (versionId, lastUpdated, defaults) in a
+    /// resource file under its current inode
+    fn apply_write_response(&mut self, inode: u64, response: WriteResponse, content: String) {
+        if let Some(resource) = self.inode_index.get_fhir_resource_mut(inode) {
+            match &response.resource {
+                Some(stored) => resource.update_from_json(stored, self.config.json_format),
+                None => {
+                    resource.content = content;
+                    resource.version_id = response.version_id;
+                    resource.mtime = std::time::SystemTime::now();
                 }
+            }
+        }
+    }
 
-                // Handle operation directories ($run)
-                if let Some(operation_path) =
-                    self.operation_manager.get_operation_path(parent).cloned()
-                {
-                    // For operation directories, check if the file already exists
-                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
-                    {
-                        if let Some(attr) = self.get_attrs(child_inode) {
-                            reply.entry(&TTL, &attr, 0);
-                            return;
-                        }
-                    }
+    /// Inode a file is known by. The kernel keeps using a temp file's inode
+    /// for the name it was renamed to, which may be an existing resource.
+    fn resolve_inode(&self, ino: u64) -> u64 {
+        self.inode_aliases.get(&ino).copied().unwrap_or(ino)
+    }
 
-                    // Parse the filename to see if it's a valid operation execution request
-                    if let Some((resource_id, format)) =
-                        OperationExecution::parse_filename(name_str)
-                    {
-                        // Execute the operation and create the file
-                        let client = self.http_client.clone();
-                        let base_url = self.fhir_base_url.clone();
-                        let rt_clone = operation_path.resource_type.clone();
-                        let rid_clone = resource_id.clone();
-                        let op_clone = format!("${}", operation_path.operation_name);
-                        let fmt_clone = format.clone();
+    /// Create a file, returning its inode and file handle. Temp files only
+    /// live in memory; `<id>.json` in a type directory is a new resource,
+    /// uploaded once it has been written.
+    fn create_file(&mut self, parent: u64, name: &str, flags: i32) -> Result<(u64, u64), i32> {
+        self.check_writable(None)?;
 
-                        let result = self.runtime.block_on(async move {
-                            execute_operation(
-                                &client, &base_url, &rt_clone, &rid_clone, &op_clone, &fmt_clone,
-                            )
-                            .await
-                        });
+        if let Some((resource_type, mode)) = self.new_directories.get(&parent).cloned() {
+            if !is_temp_file(name) {
+                return self.create_new_file(parent, name, &resource_type, mode);
+            }
+        }
 
-                        match result {
-                            Ok(content) => {
-                                // Create the operation execution entry
-                                let inode = self.inode_allocator.allocate();
-                                let mut execution = OperationExecution::new(
-                                    inode,
-                                    operation_path.resource_type.clone(),
-                                    resource_id.clone(),
-                                    operation_path.operation_name.clone(),
-                                    format.clone(),
-                                    parent,
-                                );
-                                execution.result = Some(content);
-                                execution.last_executed = Some(std::time::Instant::now());
+        // Allow temp files (like .DS_Store) in any directory for Finder compatibility
+        if is_temp_file(name) {
+            let inode = self.inode_allocator.allocate();
+            self.temp_files
+                .insert(inode, (parent, name.to_string(), Vec::new()));
+            println!("[create]: Temp file {} in parent {}", name, parent);
+            return Ok((inode, 0));
+        }
 
-                                let attr = execution.get_attr();
+        // Check if parent is an operation directory ($run)
+        if self.operation_manager.get_operation_path(parent).is_some() {
+            // Operation files are created on-demand during lookup, not via create/touch
+            println!(
+                "[create]: Operation files are virtual and created on-demand: {}",
+                name
+            );
+            return Err(EACCES);
+        }
 
-                                self.inode_index
-                                    .insert_operation_execution(execution.clone());
-                                self.inode_index.add_parent_child_relation(parent, inode);
-                                self.operation_manager.add_operation_execution(execution);
+        let resource_type = match self.directory_resource_type(parent) {
+            Some(resource_type) => resource_type,
+            None => {
+                println!(
+                    "[create]: DENIED - parent {} is not a resource directory, file={}",
+                    parent, name
+                );
+                return Err(EACCES);
+            }
+        };
 
-                                println!(
-                                    "[lookup]: Operation {}/{} executed for {}.{}",
-                                    operation_path.resource_type,
-                                    operation_path.path,
-                                    resource_id,
-                                    format
-                                );
-                                reply.entry(&TTL, &attr, 0);
-                                return;
-                            }
-                            Err(e) => {
-                                println!("[lookup]: Failed to execute operation: {}", e);
-                                reply.error(ENOENT);
-                                return;
-                            }
-                        }
-                    }
-                }
+        if let Err(errno) = self.check_writable(Some(&resource_type)) {
+            println!("[create]: DENIED - {} is read-only", resource_type);
+            return Err(errno);
+        }
 
-                // Handle SearchResultGroup directories (resources within a type group)
-                if self.search_result_groups.contains_key(&parent) {
-                    // Get the parent query inode and refresh it
-                    if let Some(&query_inode) = self.search_result_groups.get(&parent) {
-                        println!(
-                            "[Lookup] Refreshing parent query {} for group {} lookup of '{}'",
-                            query_inode, parent, name_str
-                        );
-                        self.refresh_search_query(query_inode);
-                    }
+        // Writing a new file where an editor moved the old one aside as its
+        // backup is an update of the version that was moved aside
+        let key = (parent, name.to_string());
+        let moved_aside = self.moved_aside.get(&key).cloned();
+        let permissions = self.permissions(&resource_type);
+        let allowed = match moved_aside {
+            Some(_) => permissions.update,
+            None => permissions.create,
+        };
+        if !allowed {
+            println!(
+                "[create]: DENIED - server does not allow writing {}/{}",
+                resource_type, name
+            );
+            return Err(EACCES);
+        }
+        self.moved_aside.remove(&key);
+
+        let inode = self.inode_allocator.allocate();
+        let resource_id = name.trim_end_matches(".json");
+        let mut file = OpenFile::created(inode, &resource_type, resource_id, flags);
+        if let Some((version_id, content)) = moved_aside {
+            file.created = false;
+            file.version_id = version_id;
+            file.base = Some(content);
+        }
+        let fh = self.file_handles.insert(file);
+        self.inode_index.insert_resource(FHIRResource::new(
+            inode,
+            &resource_type,
+            resource_id,
+            String::new(),
+        ));
+        self.inode_index.add_parent_child_relation(parent, inode);
+        self.ensure_history_directory(parent, &resource_type, resource_id);
+
+        println!("[create]: {}/{}", resource_type, name);
+        Ok((inode, fh))
+    }
 
-                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
-                    {
-                        if let Some(attr) = self.get_attrs(child_inode) {
-                            reply.entry(&TTL, &attr, 0);
-                            return;
-                        }
-                    }
-                }
+    fn write_file(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<usize, i32> {
+        self.check_inode_writable(ino)?;
 
-                for (&inode, (temp_parent, filename, content)) in &self.temp_files {
-                    if *temp_parent == parent && filename == name_str {
-                        let ts = std::time::SystemTime::now();
-                        let size = content.len() as u64;
-                        let blocks = (size + 511) / 512;
-                        let attr = FileAttr {
-                            ino: inode,
-                            size,
-                            blocks,
-                            atime: ts,
-                            mtime: ts,
-                            ctime: ts,
-                            crtime: ts,
-                            kind: fuser::FileType::RegularFile,
-                            perm: 0o644,
-                            nlink: 1,
-                            uid: 501,
-                            gid: 20,
-                            rdev: 0,
-                            flags: 0,
-                            blksize: 512,
-                        };
-                        reply.entry(&TTL, &attr, 0);
-                        return;
-                    }
+        if let Some((_parent, _filename, content)) = self.temp_files.get_mut(&ino) {
+            let offset = offset as usize;
+            if offset + data.len() > content.len() {
+                content.resize(offset + data.len(), 0);
+            }
+            content[offset..offset + data.len()].copy_from_slice(data);
+            self.unsent_temp_files.insert(ino);
+            return Ok(data.len());
+        }
+
+        match self.file_handles.get_mut(fh) {
+            Some(file) => Ok(file.write(offset, data)),
+            None => Err(EBADF),
+        }
+    }
+
+    fn temp_file_inode(&self, parent: u64, name: &str) -> Option<u64> {
+        self.temp_files
+            .iter()
+            .find(|(_, (p, n, _))| *p == parent && n == name)
+            .map(|(&ino, _)| ino)
+    }
+
+    fn unlink_file(&mut self, parent: u64, name: &str) -> Result<(), i32> {
+        if let Err(errno) = self.check_writable(self.directory_resource_type(parent).as_deref()) {
+            println!("[unlink]: DENIED - {} is read-only", name);
+            return Err(errno);
+        }
+
+        if let Some(inode) = self.temp_file_inode(parent, name) {
+            self.temp_files.remove(&inode);
+            return Ok(());
+        }
+
+        // Forgetting a name in `_new/` leaves the created resource alone
+        if self
+            .new_file_links
+            .remove(&(parent, name.to_string()))
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let server_delete_needed = self.resource_directories.values().any(|&dir| dir == parent);
+        let inode = self
+            .inode_index
+            .find_child_by_name(parent, name)
+            .ok_or(ENOENT)?;
+
+        if server_delete_needed && name.ends_with(".json") {
+            // A write of the file still queued would recreate it afterwards
+            self.flush_write_behind();
+            if let Some(resource) = self.inode_index.get_fhir_resource(inode) {
+                if !self.permissions(&resource.resource_type).delete {
+                    println!(
+                        "[unlink]: DENIED - server does not allow deleting {}",
+                        resource.resource_type
+                    );
+                    return Err(EPERM);
                 }
 
-                // Handle history directories (directories starting with '.')
-                if let Some(directory) = self.inode_index.get_directory(parent) {
-                    if directory.name.starts_with('.') {
-                        // This is a history directory - load history if needed
-                        if self.history_directories.contains_key(&parent) {
-                            self.load_resource_history(parent);
-                        }
+                let resource_type = resource.resource_type.clone();
+                let filename = resource.filename.clone();
+                let resource_id = resource.resource_id.clone();
+                let version_id = resource.version_id.clone();
+                let content = resource.content.clone();
+
+                if self.staging.is_some() {
+                    // A file that was never written has nothing to delete
+                    let base = Some(content).filter(|content| !content.is_empty());
+                    self.stage_delete(&resource_type, &resource_id, version_id, base);
+                } else if self.journal_pending() {
+                    self.journal_push(JournalEntry {
+                        resource_type,
+                        resource_id,
+                        content: None,
+                        version_id,
+                        created: false,
+                    })?;
+                } else {
+                    let client = self.http_client.clone();
+                    let base_url = self.fhir_base_url.clone();
 
-                        // Now try to find the child (version file)
-                        if let Some(child_inode) =
-                            self.inode_index.find_child_by_name(parent, name_str)
-                        {
-                            if let Some(attr) = self.get_attrs(child_inode) {
-                                reply.entry(&TTL, &attr, 0);
-                                return;
-                            }
+                    let result = self.runtime.block_on(async {
+                        delete_from_fhir_server(&client, &base_url, &resource_type, &filename, None)
+                            .await
+                    });
+
+                    match result {
+                        Ok(_) => {
+                            println!("[FHIR] {}: {} deleted", resource_type, resource_id);
+                            self.clear_sidecars(&resource_type, &resource_id);
+                            // Don't invalidate cache - we already removed the inode below
+                        }
+                        Err(e) if self.journal.is_some() && is_unreachable(&e) => {
+                            println!(
+                                "[FHIR] {}: {} delete failed: {}",
+                                resource_type, resource_id, e
+                            );
+                            self.journal_push(JournalEntry {
+                                resource_type,
+                                resource_id,
+                                content: None,
+                                version_id,
+                                created: false,
+                            })?;
+                        }
+                        Err(e) => {
+                            println!(
+                                "[FHIR] {}: {} delete failed: {}",
+                                resource_type, resource_id, e
+                            );
+                            return Err(self.write_failed(&resource_type, &resource_id, &e));
                         }
                     }
                 }
             }
         }
 
-        reply.error(ENOENT);
+        if let Some(resource_id) = name.strip_suffix(".json.conflict") {
+            if server_delete_needed {
+                self.resolve_conflict(parent, resource_id, inode);
+            }
+        }
+
+        for fh in self.file_handles.for_inode(inode) {
+            if let Some(file) = self.file_handles.get_mut(fh) {
+                file.detach();
+            }
+        }
+        self.inode_index.remove(inode);
+        Ok(())
+    }
+
+    /// Rename a file. Renaming a temp file onto `<id>.json` saves it (see
+    /// `save_temp_file`); renaming a resource onto a temp name is an editor
+    /// keeping a backup and never touches the server; renaming it onto
+    /// another `<id>.json` changes its id (see `rename_resource`).
+    fn rename_file(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+    ) -> Result<(), i32> {
+        let writable = self
+            .check_writable(self.directory_resource_type(parent).as_deref())
+            .and_then(|_| self.check_writable(self.directory_resource_type(newparent).as_deref()));
+        if let Err(errno) = writable {
+            println!("[rename]: DENIED - read-only");
+            return Err(errno);
+        }
+
+        if let Some(inode) = self.temp_file_inode(parent, name) {
+            return self.save_temp_file(inode, newparent, newname);
+        }
+
+        // Handle renaming regular files (not temp files)
+        let inode = self
+            .inode_index
+            .find_child_by_name(parent, name)
+            .ok_or(ENOENT)?;
+
+        if !self.resource_directories.values().any(|&dir| dir == parent) {
+            // For non-resource files, just return OK (we don't actually rename in the index)
+            return Ok(());
+        }
+
+        if parent != newparent {
+            // Renaming across directories is not supported
+            println!(
+                "[rename]: Renaming across directories not supported: {} -> {}",
+                name, newname
+            );
+            return Err(EACCES);
+        }
+
+        let resource = self.inode_index.get_fhir_resource(inode).ok_or(ENOENT)?;
+        let resource_type = resource.resource_type.clone();
+        let version_id = resource.version_id.clone();
+        let content = resource.content.clone();
+
+        if is_temp_file(newname) {
+            // Backup before writing a new file (emacs, vim): the content stays
+            // readable as a temp file under the same inode, and the version is
+            // kept for the file that replaces it
+            self.moved_aside
+                .insert((parent, name.to_string()), (version_id, content.clone()));
+            self.inode_index.remove(inode);
+            self.temp_files
+                .insert(inode, (parent, newname.to_string(), content.into_bytes()));
+            println!(
+                "[rename]: {} moved aside as {} in {}",
+                name, newname, resource_type
+            );
+            return Ok(());
+        }
+
+        if name == newname {
+            return Ok(());
+        }
+        self.rename_resource(parent, inode, newname)
+    }
+
+    /// `mv <old>.json <new>.json`: the resource is created under the new id
+    /// and the old one deleted, in one transaction. Depending on
+    /// `--rename-references`, resources referencing the old id are first
+    /// checked or pointed at the new one in the same transaction.
+    /// The file keeps its inode, and open handles follow it.
+    fn rename_resource(&mut self, parent: u64, inode: u64, newname: &str) -> Result<(), i32> {
+        if self.staging.is_some() {
+            println!("[Staging] Renaming resources can't be staged; commit or abort first");
+            return Err(EPERM);
+        }
+        // It rewrites the resources referring to it too, so everything
+        // waiting to be sent goes first
+        self.wait_for_journal(|_| true)?;
+        self.flush_write_behind();
+        let resource = self.inode_index.get_fhir_resource(inode).ok_or(ENOENT)?;
+        let resource_type = resource.resource_type.clone();
+        let old_id = resource.resource_id.clone();
+        let old_version = resource.version_id.clone();
+        let content = resource.content.clone();
+        let new_id = newname.trim_end_matches(".json").to_string();
+
+        let existing = self
+            .inode_index
+            .find_child_by_name(parent, newname)
+            .filter(|&target| self.inode_index.get_fhir_resource(target).is_some());
+        let target_version = existing
+            .and_then(|target| self.inode_index.get_fhir_resource(target))
+            .and_then(|resource| resource.version_id.clone());
+
+        let permissions = self.permissions(&resource_type);
+        let allowed = if existing.is_some() {
+            permissions.update
+        } else {
+            permissions.create
+        };
+        if !allowed || !permissions.delete {
+            println!(
+                "[rename]: DENIED - server does not allow renaming {}",
+                resource_type
+            );
+            return Err(EACCES);
+        }
+
+        let old_reference = format!("{}/{}", resource_type, old_id);
+        let new_reference = format!("{}/{}", resource_type, new_id);
+
+        // A listing that is out of date doesn't show every resource, and
+        // the PUT would replace one it doesn't show
+        if existing.is_none() {
+            let taken = self.runtime.block_on(resource_exists(
+                &self.http_client,
+                &self.fhir_base_url,
+                &new_reference,
+            ));
+            match taken {
+                Ok(false) => {}
+                Ok(true) => {
+                    println!(
+                        "[rename]: DENIED - {} already exists on the server",
+                        new_reference
+                    );
+                    self.set_sidecar(
+                        &resource_type,
+                        &format!("{}.json.error", old_id),
+                        format!(
+                            "{} can't be renamed to {}, which already exists on the server\n",
+                            old_reference, new_reference
+                        ),
+                    );
+                    return Err(EEXIST);
+                }
+                Err(e) => {
+                    println!(
+                        "[FHIR] {}: could not look up {}: {}",
+                        resource_type, new_reference, e
+                    );
+                    return Err(self.write_failed(&resource_type, &old_id, &e));
+                }
+            }
+        }
+
+        let mode = self.config.write.rename_references;
+        let referencing = if mode == RenameReferences::Keep {
+            Vec::new()
+        } else {
+            match self.inbound_references(&resource_type, &old_id) {
+                Ok(referencing) => referencing,
+                Err(e) => {
+                    println!(
+                        "[FHIR] {}: could not search references to {}: {}",
+                        resource_type, old_id, e
+                    );
+                    return Err(self.write_failed(&resource_type, &old_id, &e));
+                }
+            }
+        };
+
+        if mode == RenameReferences::Refuse && !referencing.is_empty() {
+            let referrers: Vec<String> = referencing.iter().map(reference_to).collect();
+            println!(
+                "[rename]: DENIED - {} is referenced by {}",
+                old_reference,
+                referrers.join(", ")
+            );
+            self.set_sidecar(
+                &resource_type,
+                &format!("{}.json.error", old_id),
+                format!(
+                    "{} can't be renamed while other resources reference it:\n{}\n",
+                    old_reference,
+                    referrers.join("\n")
+                ),
+            );
+            return Err(EBUSY);
+        }
+
+        // Checked like any write of the same content under the new id
+        let content =
+            match self.prepare_content(content.into_bytes(), &resource_type, Some(&new_id), true) {
+                Ok(content) => content,
+                Err(invalid) => {
+                    println!(
+                        "[FHIR] {}: rename {} -> {} rejected: {}",
+                        resource_type, old_id, new_id, invalid
+                    );
+                    return Err(self.write_failed(&resource_type, &old_id, &invalid.into()));
+                }
+            };
+
+        // One transaction, so the server applies all of it or nothing: the
+        // resource never ends up under both ids, nor references under
+        // neither. The delete is against the version that was renamed, so
+        // changes made to it meanwhile aren't deleted unseen.
+        let mut entries = vec![transaction_entry(
+            &new_reference,
+            Some(&content),
+            target_version.as_deref(),
+        )];
+        for mut referrer in referencing {
+            rewrite_references(
+                &mut referrer,
+                &old_reference,
+                &new_reference,
+                &self.fhir_base_url,
+            );
+            let referrer_version = version_id_of(&referrer);
+            entries.push(transaction_entry(
+                &reference_to(&referrer),
+                Some(&referrer.to_string()),
+                referrer_version.as_deref(),
+            ));
+        }
+        entries.push(transaction_entry(
+            &old_reference,
+            None,
+            old_version.as_deref(),
+        ));
+        let bundle = serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": entries,
+        });
+        let result = self.runtime.block_on(post_transaction(
+            &self.http_client,
+            &self.fhir_base_url,
+            &bundle,
+        ));
+        let response = match result {
+            Ok(responses) => responses.into_iter().next().unwrap_or_default(),
+            Err(e) => {
+                println!(
+                    "[FHIR] {}: rename {} -> {} failed: {}",
+                    resource_type, old_id, new_id, e
+                );
+                return Err(self.write_failed(&resource_type, &old_id, &e));
+            }
+        };
+        println!("[FHIR] {}: {} renamed to {}", resource_type, old_id, new_id);
+
+        if let Some(target) = existing {
+            for fh in self.file_handles.for_inode(target) {
+                if let Some(file) = self.file_handles.get_mut(fh) {
+                    file.detach();
+                }
+            }
+            self.inode_index.remove(target);
+        }
+        self.inode_index.remove(inode);
+        let resource_entry = match &response.resource {
+            Some(stored) => {
+                FHIRResource::from_json(inode, &resource_type, stored, self.config.json_format)
+            }
+            None => FHIRResource::new(inode, &resource_type, &new_id, content)
+                .with_version_id(response.version_id.clone()),
+        };
+        let version_id = resource_entry.version_id.clone();
+        self.inode_index.insert_resource(resource_entry);
+        self.inode_index.add_parent_child_relation(parent, inode);
+        self.ensure_history_directory(parent, &resource_type, &new_id);
+
+        for fh in self.file_handles.for_inode(inode) {
+            if let Some(file) = self.file_handles.get_mut(fh) {
+                file.resource_id = new_id.clone();
+                file.version_id = version_id.clone();
+            }
+        }
+        self.clear_sidecars(&resource_type, &old_id);
+        self.clear_sidecars(&resource_type, &new_id);
+        Ok(())
+    }
+
+    /// Resources that reference `resource_type/resource_id`, from the types
+    /// in `--referencing-types` (all types when unset)
+    fn inbound_references(
+        &self,
+        resource_type: &str,
+        resource_id: &str,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let query = revinclude_query(resource_id, self.config.write.referencing_types.as_deref());
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+        let grouped = self.runtime.block_on(async {
+            search_fhir_resources(&client, &base_url, resource_type, &query).await
+        })?;
+
+        let target = format!("{}/{}", resource_type, resource_id);
+        Ok(grouped
+            .into_values()
+            .flatten()
+            .filter(|resource| references(resource, &target, &self.fhir_base_url))
+            .collect())
+    }
+
+    /// Atomic save: a temp file renamed onto `<id>.json` in a type directory
+    /// is uploaded. An existing resource is updated against the version it
+    /// shows and keeps its inode; otherwise the resource is created under the
+    /// temp file's inode. If the server rejects it, the temp file stays.
+    fn save_temp_file(&mut self, inode: u64, newparent: u64, newname: &str) -> Result<(), i32> {
+        let content = match self.temp_files.get(&inode) {
+            Some((_, _, content)) => content.clone(),
+            None => return Err(ENOENT),
+        };
+
+        let resource_type = match self.directory_resource_type(newparent) {
+            Some(resource_type) if !is_temp_file(newname) && !content.is_empty() => resource_type,
+            _ => {
+                // For non-resource renames, just move the temp file (replacing
+                // any with that name); moving it into `_new/` creates the
+                // resource, and onto `<id>.json-patch` applies the patch
+                if let Some(replaced) = self.temp_file_inode(newparent, newname) {
+                    self.temp_files.remove(&replaced);
+                }
+                self.temp_files
+                    .insert(inode, (newparent, newname.to_string(), content));
+                return self.send_temp_file(inode);
+            }
+        };
+
+        let resource_id = newname.trim_end_matches(".json").to_string();
+        let key = (newparent, newname.to_string());
+        let existing = self
+            .inode_index
+            .find_child_by_name(newparent, newname)
+            .filter(|&target| self.inode_index.get_fhir_resource(target).is_some());
+        // Saving over an existing file is an update of its version
+        let (is_update, version_id, base) = match existing {
+            Some(target) => {
+                let resource = self.inode_index.get_fhir_resource(target);
+                (
+                    true,
+                    resource.and_then(|resource| resource.version_id.clone()),
+                    resource.map(|resource| resource.content.clone()),
+                )
+            }
+            None => match self.moved_aside.get(&key) {
+                Some((version_id, content)) => (true, version_id.clone(), Some(content.clone())),
+                None => (false, None, None),
+            },
+        };
+
+        let permissions = self.permissions(&resource_type);
+        let allowed = if is_update {
+            permissions.update
+        } else {
+            permissions.create
+        };
+        if !allowed {
+            println!(
+                "[rename]: DENIED - server does not allow writing {}",
+                newname
+            );
+            return Err(EACCES);
+        }
+
+        let action = if is_update { "updated" } else { "created" };
+        let rewrite_ids = self.config.write.rewrite_ids;
+        let content =
+            match self.prepare_content(content, &resource_type, Some(&resource_id), rewrite_ids) {
+                Ok(content) => content,
+                Err(invalid) => {
+                    println!(
+                        "[FHIR] {}: {} {} via rename rejected: {}",
+                        resource_type, resource_id, action, invalid
+                    );
+                    return Err(self.write_failed(&resource_type, &resource_id, &invalid.into()));
+                }
+            };
+        let version_id = self
+            .merged_version(&resource_type, &resource_id, &content)
+            .or(version_id);
+
+        if self.staging.is_some() {
+            self.temp_files.remove(&inode);
+            self.moved_aside.remove(&key);
+            let target = match existing {
+                Some(target) => {
+                    self.inode_aliases.insert(inode, target);
+                    target
+                }
+                None => {
+                    self.inode_index.insert_resource(
+                        FHIRResource::new(inode, &resource_type, &resource_id, String::new())
+                            .with_version_id(version_id.clone()),
+                    );
+                    self.inode_index.add_parent_child_relation(newparent, inode);
+                    self.ensure_history_directory(newparent, &resource_type, &resource_id);
+                    inode
+                }
+            };
+            self.stage_put(
+                Some(target),
+                &resource_type,
+                &resource_id,
+                content,
+                version_id,
+                base,
+            );
+            return Ok(());
+        }
+
+        if let Some(base) = base.filter(|base| same_resource(base, &content)) {
+            println!(
+                "[FHIR] {}: {} unchanged, nothing sent",
+                resource_type, resource_id
+            );
+            self.temp_files.remove(&inode);
+            self.moved_aside.remove(&key);
+            match existing {
+                Some(target) => {
+                    self.inode_aliases.insert(inode, target);
+                }
+                None => {
+                    // The file an editor moved aside is back as it was
+                    self.inode_index.insert_resource(
+                        FHIRResource::new(inode, &resource_type, &resource_id, base)
+                            .with_version_id(version_id),
+                    );
+                    self.inode_index.add_parent_child_relation(newparent, inode);
+                }
+            }
+            return Ok(());
+        }
+
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+        let result = self.runtime.block_on(async {
+            put_to_fhir_server(
+                &client,
+                &base_url,
+                &resource_type,
+                newname,
+                &content,
+                version_id.as_deref(),
+            )
+            .await
+        });
+
+        match result {
+            Ok(response) => {
+                println!(
+                    "[FHIR] {}: {} {} via rename",
+                    resource_type, resource_id, action
+                );
+                self.temp_files.remove(&inode);
+                self.moved_aside.remove(&key);
+                match existing {
+                    Some(target) => {
+                        self.apply_write_response(target, response, content);
+                        self.inode_aliases.insert(inode, target);
+                    }
+                    None => {
+                        let resource_entry = match &response.resource {
+                            Some(stored) => FHIRResource::from_json(
+                                inode,
+                                &resource_type,
+                                stored,
+                                self.config.json_format,
+                            ),
+                            None => FHIRResource::new(inode, &resource_type, &resource_id, content)
+                                .with_version_id(response.version_id),
+                        };
+                        self.inode_index.insert_resource(resource_entry);
+                        self.inode_index.add_parent_child_relation(newparent, inode);
+                        self.ensure_history_directory(newparent, &resource_type, &resource_id);
+                    }
+                }
+                self.clear_sidecars(&resource_type, &resource_id);
+                Ok(())
+            }
+            Err(e) => {
+                println!(
+                    "[FHIR] {}: {} {} via rename failed: {}",
+                    resource_type, resource_id, action, e
+                );
+                Err(self.write_failed(&resource_type, &resource_id, &e))
+            }
+        }
+    }
+
+    /// Start a file in `<type>/_new/`, `_if-none-exist/` or `_where/`. It
+    /// stays in memory until it's closed (or fsync'ed), then is sent.
+    fn create_new_file(
+        &mut self,
+        parent: u64,
+        name: &str,
+        resource_type: &str,
+        mode: CreateMode,
+    ) -> Result<(u64, u64), i32> {
+        if let Err(errno) = self.check_writable(Some(resource_type)) {
+            println!("[create]: DENIED - {} is read-only", resource_type);
+            return Err(errno);
+        }
+        if self.staging.is_some() {
+            println!(
+                "[Staging] Files in {}/{} can't be staged; write <id>.json instead",
+                resource_type,
+                mode.directory()
+            );
+            return Err(EPERM);
+        }
+        if !mode.is_allowed(self.permissions(resource_type)) {
+            println!(
+                "[create]: DENIED - server does not allow {} for {}",
+                mode.directory(),
+                resource_type
+            );
+            return Err(EACCES);
+        }
+        if mode.query(name).is_some_and(|query| !query.contains('=')) {
+            println!(
+                "[create]: {}/{}/{} is not a search like identifier=...",
+                resource_type,
+                mode.directory(),
+                name
+            );
+            return Err(EINVAL);
+        }
+
+        // Reusing a name starts another resource
+        self.new_file_links.remove(&(parent, name.to_string()));
+        let inode = self.inode_allocator.allocate();
+        self.temp_files
+            .insert(inode, (parent, name.to_string(), Vec::new()));
+        println!("[create]: {}/{}/{}", resource_type, mode.directory(), name);
+        Ok((inode, 0))
+    }
+
+    /// Send a temp file written since it was last closed, so a rejected
+    /// `_new/` file or patch fails the `close()` of the process writing it.
+    /// The same content isn't sent twice, as with resource files.
+    fn send_written_temp_file(&mut self, inode: u64) -> Result<(), i32> {
+        if self.unsent_temp_files.remove(&inode) {
+            self.send_temp_file(inode)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a finished temp file that stands for a write: a file in `_new/`
+    /// and its siblings, or a patch. Other temp files stay in memory.
+    fn send_temp_file(&mut self, inode: u64) -> Result<(), i32> {
+        let is_patch = match self.temp_files.get(&inode) {
+            Some((dir_inode, name, _)) => {
+                PatchFormat::from_filename(name).is_some()
+                    && self.directory_resource_type(*dir_inode).is_some()
+            }
+            None => return Ok(()),
+        };
+        if is_patch {
+            self.send_patch_file(inode)
+        } else {
+            self.send_new_file(inode)
+        }
+    }
+
+    /// PATCH the resource a `<id>.json-patch` or `<id>.fhirpath-patch` file
+    /// in a type directory names, and show the patched resource in
+    /// `<id>.json`. An applied patch file goes away; a rejected one stays,
+    /// with the explanation in `<id>.json.error`.
+    fn send_patch_file(&mut self, inode: u64) -> Result<(), i32> {
+        let (dir_inode, name, content) = match self.temp_files.get(&inode) {
+            Some(temp) if !temp.2.is_empty() => temp.clone(),
+            _ => return Ok(()),
+        };
+        let resource_type = self.directory_resource_type(dir_inode).ok_or(ENOENT)?;
+        let (resource_id, format) = PatchFormat::from_filename(&name).ok_or(EINVAL)?;
+        let resource_id = resource_id.to_string();
+
+        if let Err(errno) = self.check_writable(Some(&resource_type)) {
+            println!("[patch]: DENIED - {} is read-only", resource_type);
+            return Err(errno);
+        }
+        if !self.permissions(&resource_type).update {
+            println!(
+                "[patch]: DENIED - server does not allow updating {}",
+                resource_type
+            );
+            return Err(EACCES);
+        }
+        if self.staging.is_some() {
+            println!(
+                "[Staging] Patch {} can't be staged; edit the resource instead",
+                name
+            );
+            return Err(EPERM);
+        }
+        self.wait_for_journal(|entry| {
+            entry.resource_type == resource_type && entry.resource_id == resource_id
+        })?;
+
+        let checked = String::from_utf8(content)
+            .map_err(|_| InvalidResource("The patch is not valid UTF-8".to_string()))
+            .and_then(|content| format.check(&content));
+        let content = match checked {
+            Ok(content) => content,
+            Err(invalid) => {
+                println!(
+                    "[FHIR] {}: patch {} rejected: {}",
+                    resource_type, name, invalid
+                );
+                return Err(self.write_failed(&resource_type, &resource_id, &invalid.into()));
+            }
+        };
+
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+        let result = self.runtime.block_on(async {
+            patch_fhir_resource(
+                &client,
+                &base_url,
+                &resource_type,
+                &resource_id,
+                &content,
+                format,
+            )
+            .await
+        });
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                println!("[FHIR] {}: patch {} failed: {}", resource_type, name, e);
+                return Err(self.write_failed(&resource_type, &resource_id, &e));
+            }
+        };
+        println!("[FHIR] {}: {} patched", resource_type, resource_id);
+        self.temp_files.remove(&inode);
+        self.clear_sidecars(&resource_type, &resource_id);
+
+        // Servers that don't send the result back are asked for it
+        let patched = match response.resource {
+            Some(resource) => Some(resource),
+            None => self
+                .runtime
+                .block_on(async {
+                    get_from_fhir_server(&client, &base_url, &resource_type, &resource_id).await
+                })
+                .ok()
+                .and_then(|content| serde_json::from_str(&content).ok()),
+        };
+        let target = self
+            .inode_index
+            .find_child_by_name(dir_inode, &format!("{}.json", resource_id));
+        if let (Some(patched), Some(target)) = (patched, target) {
+            if let Some(resource) = self.inode_index.get_fhir_resource_mut(target) {
+                resource.update_from_json(&patched, self.config.json_format);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a file written in `<type>/_new/` (POST), `_if-none-exist/`
+    /// (conditional create) or `_where/` (conditional update). The resource
+    /// it became shows as `<type>/<id>.json`, taking over the file's inode
+    /// unless that file was already there, and the name keeps resolving to
+    /// it. A rejected file stays, with `<name>.error`.
+    fn send_new_file(&mut self, inode: u64) -> Result<(), i32> {
+        let (dir_inode, name, content) = match self.temp_files.get(&inode) {
+            Some(temp) => temp.clone(),
+            None => return Ok(()),
+        };
+        let (resource_type, mode) = match self.new_directories.get(&dir_inode) {
+            Some(directory) if !is_temp_file(&name) && !content.is_empty() => directory.clone(),
+            _ => return Ok(()),
+        };
+        let source = format!("{}/{}", mode.directory(), name);
+        let error_name = format!("{}.error", name);
+        let rewrite_ids = self.config.write.rewrite_ids;
+        let content = match self.prepare_content(content, &resource_type, None, rewrite_ids) {
+            Ok(content) => content,
+            Err(invalid) => {
+                println!(
+                    "[FHIR] {}: write from {} rejected: {}",
+                    resource_type, source, invalid
+                );
+                self.set_text_file(dir_inode, &error_name, format!("{}\n", invalid));
+                return Err(EINVAL);
+            }
+        };
+        // A conditional write can land on any resource of the type
+        self.wait_for_journal(|entry| entry.resource_type == resource_type)?;
+
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+        let query = mode.query(&name).map(String::from);
+        let result = self.runtime.block_on(async {
+            match mode {
+                CreateMode::Where => {
+                    let query = query.as_deref().unwrap_or_default();
+                    put_conditional_to_fhir_server(
+                        &client,
+                        &base_url,
+                        &resource_type,
+                        query,
+                        &content,
+                    )
+                    .await
+                }
+                _ => {
+                    post_to_fhir_server(
+                        &client,
+                        &base_url,
+                        &resource_type,
+                        &content,
+                        query.as_deref(),
+                    )
+                    .await
+                }
+            }
+        });
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                println!(
+                    "[FHIR] {}: write from {} failed: {}",
+                    resource_type, source, e
+                );
+                self.set_text_file(dir_inode, &error_name, error_report(&e));
+                return Err(errno_for(&e));
+            }
+        };
+
+        self.temp_files.remove(&inode);
+        self.remove_text_file(dir_inode, &error_name);
+        let resource_id = match response.resource_id.clone() {
+            Some(resource_id) => resource_id,
+            None => {
+                // Stored, but we can't tell where; it shows up on the next refresh
+                println!(
+                    "[FHIR] {}: written from {}, server returned no id",
+                    resource_type, source
+                );
+                return Ok(());
+            }
+        };
+        println!(
+            "[FHIR] {}: {} written from {}",
+            resource_type, resource_id, source
+        );
+
+        let type_dir = self.resource_directories.get(&resource_type).copied();
+        let existing = type_dir.and_then(|dir| {
+            self.inode_index
+                .find_child_by_name(dir, &format!("{}.json", resource_id))
+        });
+        match existing {
+            Some(target) => {
+                // A conditional create that found a match may not send it
+                // back; what was written isn't what the server has then
+                if response.resource.is_some() || mode != CreateMode::IfNoneExist {
+                    self.apply_write_response(target, response, content);
+                }
+                self.inode_aliases.insert(inode, target);
+            }
+            None => {
+                let resource_entry = match &response.resource {
+                    Some(stored) => FHIRResource::from_json(
+                        inode,
+                        &resource_type,
+                        stored,
+                        self.config.json_format,
+                    ),
+                    None => FHIRResource::new(inode, &resource_type, &resource_id, content)
+                        .with_version_id(response.version_id),
+                };
+                self.inode_index.insert_resource(resource_entry);
+                if let Some(type_dir) = type_dir {
+                    self.inode_index.add_parent_child_relation(type_dir, inode);
+                    self.ensure_history_directory(type_dir, &resource_type, &resource_id);
+                }
+            }
+        }
+        self.clear_sidecars(&resource_type, &resource_id);
+        self.new_file_links.insert((dir_inode, name), resource_id);
+        Ok(())
+    }
+
+    /// Keep a write in `.staging/` instead of sending it. The file shows the
+    /// staged content until the staging area is committed or aborted.
+    fn stage_put(
+        &mut self,
+        inode: Option<u64>,
+        resource_type: &str,
+        resource_id: &str,
+        content: String,
+        version_id: Option<String>,
+        base: Option<String>,
+    ) {
+        // Rendered like the server's resources, so the diff shows what changed
+        let content = serde_json::from_str(&content)
+            .map(|resource| self.config.json_format.render(&resource))
+            .unwrap_or(content);
+        if let Some(staging) = self.staging.as_mut() {
+            staging.put(
+                resource_type,
+                resource_id,
+                content.clone(),
+                version_id,
+                base,
+            );
+        }
+        if let Some(resource) =
+            inode.and_then(|inode| self.inode_index.get_fhir_resource_mut(inode))
+        {
+            resource.content = content;
+            resource.mtime = std::time::SystemTime::now();
+        }
+        println!("[Staging] {}: {} staged", resource_type, resource_id);
+        self.show_staging();
+    }
+
+    fn stage_delete(
+        &mut self,
+        resource_type: &str,
+        resource_id: &str,
+        version_id: Option<String>,
+        base: Option<String>,
+    ) {
+        if let Some(staging) = self.staging.as_mut() {
+            staging.delete(resource_type, resource_id, version_id, base);
+        }
+        println!("[Staging] {}: {} delete staged", resource_type, resource_id);
+        self.show_staging();
+    }
+
+    /// Show the staged changes of a type over what was just loaded from the
+    /// server: staged content in place of the server's, staged creates as
+    /// files, staged deletes gone
+    fn show_staged_resources(&mut self, resource_type: &str, dir_inode: u64) {
+        let changes: Vec<(String, Option<String>)> = match &self.staging {
+            Some(staging) => staging
+                .changes()
+                .filter(|(staged_type, _, _)| *staged_type == resource_type)
+                .map(|(_, id, change)| (id.to_string(), change.content.clone()))
+                .collect(),
+            None => return,
+        };
+        self.show_local_changes(resource_type, dir_inode, changes);
+    }
+
+    /// Show the changes of a type still in the journal over what was just
+    /// loaded from the server, like staged changes. The files keep the
+    /// version the changes were made against, so editing them again can't
+    /// overwrite what someone else stored meanwhile.
+    fn show_pending_resources(&mut self, resource_type: &str, dir_inode: u64) {
+        let entries: Vec<JournalEntry> = match &self.journal {
+            Some(journal) => journal
+                .entries()
+                .iter()
+                .filter(|entry| entry.resource_type == resource_type)
+                .cloned()
+                .collect(),
+            None => return,
+        };
+        let changes = entries
+            .iter()
+            .map(|entry| (entry.resource_id.clone(), entry.content.clone()))
+            .collect();
+        self.show_local_changes(resource_type, dir_inode, changes);
+        for entry in entries {
+            if let Some(resource) = self
+                .inode_index
+                .find_child_by_name(dir_inode, &format!("{}.json", entry.resource_id))
+                .and_then(|inode| self.inode_index.get_fhir_resource_mut(inode))
+            {
+                resource.version_id = entry.version_id;
+            }
+        }
+    }
+
+    /// Apply `(id, content)` changes, in order, to the files of a type
+    /// directory: `None` removes the file
+    fn show_local_changes(
+        &mut self,
+        resource_type: &str,
+        dir_inode: u64,
+        changes: Vec<(String, Option<String>)>,
+    ) {
+        for (resource_id, content) in changes {
+            let existing = self
+                .inode_index
+                .find_child_by_name(dir_inode, &format!("{}.json", resource_id))
+                .filter(|&inode| self.inode_index.get_fhir_resource(inode).is_some());
+            match (existing, content) {
+                (Some(inode), Some(content)) => {
+                    if let Some(resource) = self.inode_index.get_fhir_resource_mut(inode) {
+                        resource.content = content;
+                    }
+                }
+                (None, Some(content)) => {
+                    let inode = self.inode_allocator.allocate();
+                    self.inode_index.insert_resource(FHIRResource::new(
+                        inode,
+                        resource_type,
+                        &resource_id,
+                        content,
+                    ));
+                    self.inode_index.add_parent_child_relation(dir_inode, inode);
+                    self.ensure_history_directory(dir_inode, resource_type, &resource_id);
+                }
+                (Some(inode), None) => {
+                    self.inode_index.remove(inode);
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    /// Bring `.staging/` up to date: `status`, and `<type>/<id>.json.diff`
+    /// against the server for every staged resource
+    fn show_staging(&mut self) {
+        let Some(staging) = &self.staging else {
+            return;
+        };
+        let status = staging.status();
+        let diffs: Vec<(String, String, String)> = staging
+            .changes()
+            .map(|(resource_type, id, change)| {
+                let path = format!("{}/{}.json", resource_type, id);
+                (
+                    resource_type.to_string(),
+                    format!("{}.json.diff", id),
+                    change.diff(&path),
+                )
+            })
+            .collect();
+
+        let root = self.inode_allocator.root_inode;
+        let staging_dir = match self.inode_index.find_child_by_name(root, STAGING_DIRECTORY) {
+            Some(inode) => inode,
+            None => {
+                let inode = self.staging_subdirectory(root, STAGING_DIRECTORY);
+                self.set_text_file(inode, "commit", String::new());
+                self.set_text_file(inode, "abort", String::new());
+                inode
+            }
+        };
+        self.set_text_file(staging_dir, "status", status);
+
+        // Diffs of resources no longer staged go away, and so do type
+        // directories left empty
+        for dir in self.inode_index.get_children(staging_dir) {
+            let Some(directory) = self.inode_index.get_directory(dir) else {
+                continue;
+            };
+            let resource_type = directory.name.clone();
+            for file in self.inode_index.get_children(dir) {
+                let staged = self.inode_index.get_text_file(file).is_some_and(|file| {
+                    diffs
+                        .iter()
+                        .any(|(t, name, _)| *t == resource_type && *name == file.filename)
+                });
+                if !staged {
+                    self.inode_index.remove(file);
+                }
+            }
+            if self.inode_index.get_children(dir).is_empty() {
+                self.inode_index.remove(dir);
+                self.staging_directories.remove(&dir);
+            }
+        }
+        for (resource_type, name, diff) in diffs {
+            let dir = match self
+                .inode_index
+                .find_child_by_name(staging_dir, &resource_type)
+            {
+                Some(dir) => dir,
+                None => self.staging_subdirectory(staging_dir, &resource_type),
+            };
+            self.set_text_file(dir, &name, diff);
+        }
+    }
+
+    fn staging_subdirectory(&mut self, parent: u64, name: &str) -> u64 {
+        let inode = self.inode_allocator.allocate();
+        self.inode_index
+            .insert_directory(Directory::new(inode, name));
+        self.inode_index.add_parent_child_relation(parent, inode);
+        self.staging_directories.insert(inode, parent);
+        inode
+    }
+
+    /// `touch .staging/commit` and `touch .staging/abort`: opening either
+    /// file for writing runs it. `None` for any other file.
+    fn run_staging_command(&mut self, ino: u64) -> Option<Result<(), i32>> {
+        let root = self.inode_allocator.root_inode;
+        let staging_dir = self
+            .inode_index
+            .find_child_by_name(root, STAGING_DIRECTORY)?;
+        if !self.inode_index.get_children(staging_dir).contains(&ino) {
+            return None;
+        }
+        match self.inode_index.get_text_file(ino)?.filename.as_str() {
+            "commit" => Some(self.commit_staged(staging_dir)),
+            "abort" => {
+                self.abort_staged(staging_dir);
+                Some(Ok(()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Send everything staged as one `transaction` Bundle. The server applies
+    /// all of it or nothing: on success the files show what it stored and the
+    /// staging area is empty; otherwise everything stays staged and
+    /// `.staging/commit.error` has the server's explanation.
+    fn commit_staged(&mut self, staging_dir: u64) -> Result<(), i32> {
+        let Some(staging) = self.staging.as_ref().filter(|staging| !staging.is_empty()) else {
+            println!("[Staging] Nothing to commit");
+            return Ok(());
+        };
+        let bundle = staging.transaction(&self.fhir_base_url);
+        let staged: Vec<(String, String, Option<String>)> = staging
+            .changes()
+            .map(|(resource_type, id, change)| {
+                (
+                    resource_type.to_string(),
+                    id.to_string(),
+                    change.content.clone(),
+                )
+            })
+            .collect();
+
+        let result = self.runtime.block_on(post_transaction(
+            &self.http_client,
+            &self.fhir_base_url,
+            &bundle,
+        ));
+        let responses = match result {
+            Ok(responses) => responses,
+            Err(e) => {
+                println!(
+                    "[Staging] Commit of {} change(s) failed: {}",
+                    staged.len(),
+                    e
+                );
+                self.set_text_file(staging_dir, "commit.error", error_report(&e));
+                return Err(errno_for(&e));
+            }
+        };
+        println!("[Staging] Committed {} change(s)", staged.len());
+
+        for (index, (resource_type, resource_id, content)) in staged.into_iter().enumerate() {
+            self.clear_sidecars(&resource_type, &resource_id);
+            let Some(content) = content else {
+                continue;
+            };
+            let filename = format!("{}.json", resource_id);
+            let Some(inode) = self
+                .resource_directories
+                .get(&resource_type)
+                .and_then(|&dir| self.inode_index.find_child_by_name(dir, &filename))
+            else {
+                continue;
+            };
+            match responses.get(index) {
+                Some(response) => {
+                    // Handles still open write on top of the committed version
+                    for fh in self.file_handles.for_inode(inode) {
+                        if let Some(file) = self.file_handles.get_mut(fh) {
+                            file.version_id = response.version_id.clone();
+                            file.created = false;
+                            file.base = Some(content.clone());
+                        }
+                    }
+                    self.apply_write_response(inode, response.clone(), content);
+                }
+                // Without the server's answer the type is loaded again
+                None => {
+                    self.loaded_resources.remove(&resource_type);
+                }
+            }
+        }
+        if let Some(staging) = self.staging.as_mut() {
+            staging.clear();
+        }
+        self.remove_text_file(staging_dir, "commit.error");
+        self.show_staging();
+        Ok(())
+    }
+
+    /// Forget everything staged: the files show the server's resources as
+    /// they were when they were first staged
+    fn abort_staged(&mut self, staging_dir: u64) {
+        let Some(staging) = self.staging.as_mut() else {
+            return;
+        };
+        let staged: Vec<(String, String, Change)> = staging
+            .changes()
+            .map(|(resource_type, id, change)| {
+                (resource_type.to_string(), id.to_string(), change.clone())
+            })
+            .collect();
+        staging.clear();
+        println!("[Staging] Discarded {} change(s)", staged.len());
+
+        for (resource_type, resource_id, change) in staged {
+            let Some(&dir) = self.resource_directories.get(&resource_type) else {
+                continue;
+            };
+            let existing = self
+                .inode_index
+                .find_child_by_name(dir, &format!("{}.json", resource_id))
+                .filter(|&inode| self.inode_index.get_fhir_resource(inode).is_some());
+            match (existing, change.base) {
+                (Some(inode), Some(base)) => {
+                    if let Some(resource) = self.inode_index.get_fhir_resource_mut(inode) {
+                        resource.content = base;
+                        resource.version_id = change.version_id;
+                        resource.mtime = std::time::SystemTime::now();
+                    }
+                }
+                (None, Some(base)) => {
+                    let inode = self.inode_allocator.allocate();
+                    self.inode_index.insert_resource(
+                        FHIRResource::new(inode, &resource_type, &resource_id, base)
+                            .with_version_id(change.version_id),
+                    );
+                    self.inode_index.add_parent_child_relation(dir, inode);
+                }
+                (Some(inode), None) => {
+                    for fh in self.file_handles.for_inode(inode) {
+                        if let Some(file) = self.file_handles.get_mut(fh) {
+                            file.detach();
+                        }
+                    }
+                    self.inode_index.remove(inode);
+                }
+                (None, None) => {}
+            }
+        }
+        self.remove_text_file(staging_dir, "commit.error");
+        self.show_staging();
+    }
+
+    /// Resource a name in `<type>/_new/`, `_if-none-exist/` or `_where/`
+    /// resolves to after it was sent
+    fn new_file_target(&self, dir_inode: u64, name: &str) -> Option<u64> {
+        let resource_id = self.new_file_links.get(&(dir_inode, name.to_string()))?;
+        let (resource_type, _) = self.new_directories.get(&dir_inode)?;
+        let &type_dir = self.resource_directories.get(resource_type)?;
+        self.inode_index
+            .find_child_by_name(type_dir, &format!("{}.json", resource_id))
+    }
+
+    /// Closing the last descriptor of an open file: create or upload what was
+    /// written through it
+    fn release_file(&mut self, ino: u64, fh: u64, flags: i32) -> Result<(), i32> {
+        let posted = if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            self.send_written_temp_file(ino)
+        } else {
+            Ok(())
+        };
+        let uploaded = self.release_handle(fh);
+        posted.and(uploaded)
+    }
+
+    fn get_attrs(&self, inode: u64) -> Option<FileAttr> {
+        let mut attr = self.with_owner(self.inode_index.get_attr(inode)?);
+        if let Some(resource) = self.inode_index.get_fhir_resource(inode) {
+            attr.perm = if self.config.mount.is_writable(&resource.resource_type) {
+                self.permissions(&resource.resource_type).file_mode()
+            } else {
+                0o444
+            };
+        }
+        Some(attr)
+    }
+
+    /// Report every file as owned by the configured uid/gid
+    fn with_owner(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = self.config.uid;
+        attr.gid = self.config.gid;
+        attr
+    }
+
+    /// Attributes for files that only exist locally (temp files, new resources)
+    fn new_file_attr(&self, ino: u64, size: u64) -> FileAttr {
+        let ts = std::time::SystemTime::now();
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: ts,
+            mtime: ts,
+            ctime: ts,
+            crtime: ts,
+            kind: fuser::FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: self.config.uid,
+            gid: self.config.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
+    /// Refresh search results for a given query inode (only if cache expired)
+    fn refresh_search_query(&mut self, query_inode: u64) {
+        // Check if we need to refresh (cache duration)
+        let should_refresh = self
+            .search_query_load_times
+            .get(&query_inode)
+            .map(|t| t.elapsed() > self.config.cache_duration)
+            .unwrap_or(true);
+
+        if !should_refresh {
+            println!(
+                "[Search] Skipping refresh for query_inode {} (cache still valid)",
+                query_inode
+            );
+            return;
+        }
+
+        let search_info = self.search_query_info.get(&query_inode).cloned();
+        if let Some((resource_type, query_string)) = search_info {
+            println!(
+                "[Search] Refreshing: {}/{}?{}",
+                resource_type, "_search", query_string
+            );
+
+            let client = self.http_client.clone();
+            let base_url = self.fhir_base_url.clone();
+            let rt_clone = resource_type.clone();
+            let query_clone = query_string.clone();
+
+            let result = self.runtime.block_on(async {
+                search_fhir_resources(&client, &base_url, &rt_clone, &query_clone).await
+            });
+
+            match result {
+                Ok(grouped_resources) => {
+                    // Build a map of existing groups by resource type
+                    let mut existing_groups: HashMap<String, u64> = HashMap::new();
+                    let old_children = self.inode_index.get_children(query_inode);
+                    for child_inode in &old_children {
+                        if let Some(group) = self.inode_index.get_search_result_group(*child_inode)
+                        {
+                            existing_groups.insert(group.resource_type.clone(), *child_inode);
+                        }
+                    }
+
+                    // Update each group - reuse existing group inodes, only refresh resources
+                    for (res_type, resources) in grouped_resources {
+                        let group_inode = if let Some(&existing_inode) =
+                            existing_groups.get(&res_type)
+                        {
+                            // Clear old resources from this group
+                            let resource_children = self.inode_index.get_children(existing_inode);
+                            for res_inode in resource_children {
+                                self.inode_index.remove(res_inode);
+                            }
+                            self.inode_index.clear_children(existing_inode);
+                            existing_groups.remove(&res_type);
+                            existing_inode
+                        } else {
+                            // Create new group
+                            let new_inode = self.inode_allocator.allocate();
+                            let query = match self.inode_index.get_search_query(query_inode) {
+                                Some(q) => q,
+                                None => {
+                                    println!(
+                                        "[Error] SearchQuery not found for inode {}",
+                                        query_inode
+                                    );
+                                    continue;
+                                }
+                            };
+                            let group = SearchResultGroup::new(
+                                new_inode,
+                                res_type.clone(),
+                                query_inode,
+                                query,
+                            );
+                            self.inode_index.insert_search_result_group(group);
+                            self.inode_index
+                                .add_parent_child_relation(query_inode, new_inode);
+                            self.search_result_groups.insert(new_inode, query_inode);
+                            new_inode
+                        };
+
+                        // Add fresh resources to the group
+                        println!(
+                            "[Search] Adding {} {} resources to group {}",
+                            resources.len(),
+                            res_type,
+                            group_inode
+                        );
+                        for resource in &resources {
+                            let res_inode = self.inode_allocator.allocate();
+                            let resource_entry = FHIRResource::from_json(
+                                res_inode,
+                                &res_type,
+                                resource,
+                                self.config.json_format,
+                            );
+                            self.inode_index.insert_resource(resource_entry);
+                            self.inode_index
+                                .add_parent_child_relation(group_inode, res_inode);
+                        }
+                    }
+
+                    // Remove groups that no longer exist in results
+                    for (_res_type, old_group_inode) in existing_groups {
+                        let resource_children = self.inode_index.get_children(old_group_inode);
+                        for res_inode in resource_children {
+                            self.inode_index.remove(res_inode);
+                        }
+                        self.inode_index.clear_children(old_group_inode);
+                        self.search_result_groups.remove(&old_group_inode);
+                        self.inode_index.remove(old_group_inode);
+                    }
+
+                    // Update cache time
+                    self.search_query_load_times
+                        .insert(query_inode, std::time::Instant::now());
+                }
+                Err(e) => {
+                    println!("[Search] Refresh failed: {}", e);
+                }
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    fn debug_print_stats(&self) {
+        let stats: IndexStats = self.inode_index.stats();
+        println!("=== Inode Index Statistics ===");
+        println!("{}", stats);
+        println!("Loaded resource types: {:?}", self.loaded_resources);
+        println!(
+            "Resource directories: {} types",
+            self.resource_directories.len()
+        );
+        println!("Next inode: {}", self.inode_allocator.peek_next());
+        println!("==============================");
+    }
+}
+
+impl FhirFuse {
+    // Helper methods for mkdir
+    fn execute_search_query(
+        &mut self,
+        resource_type: &str,
+        query: &str,
+    ) -> Result<HashMap<String, Vec<serde_json::Value>>, Box<dyn std::error::Error>> {
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+        let rt_clone = resource_type.to_string();
+        let query_clone = query.to_string();
+
+        match self.runtime.block_on(async {
+            search_fhir_resources(&client, &base_url, &rt_clone, &query_clone).await
+        }) {
+            Ok(result) => Ok(result),
+            Err(e) => Err(e.to_string().into()),
+        }
+    }
+
+    fn create_search_query_directory(
+        &mut self,
+        query: String,
+        resource_type: String,
+        parent: u64,
+    ) -> u64 {
+        let query_inode = self.inode_allocator.allocate();
+        let search_query =
+            SearchQuery::new(query_inode, query.clone(), resource_type.clone(), parent);
+        self.inode_index.insert_search_query(search_query);
+        self.inode_index
+            .add_parent_child_relation(parent, query_inode);
+        self.search_query_directories.insert(query_inode, parent);
+        self.search_query_info
+            .insert(query_inode, (resource_type, query));
+        query_inode
+    }
+
+    fn create_search_result_group(&mut self, res_type: String, query_inode: u64) -> u64 {
+        let group_inode = self.inode_allocator.allocate();
+        let query = self
+            .inode_index
+            .get_search_query(query_inode)
+            .expect(&format!("SearchQuery not found for inode {}", query_inode));
+        let group = SearchResultGroup::new(group_inode, res_type.clone(), query_inode, query);
+        self.inode_index.insert_search_result_group(group);
+        self.inode_index
+            .add_parent_child_relation(query_inode, group_inode);
+        self.search_result_groups.insert(group_inode, query_inode);
+        println!(
+            "[Search] Created group {} with inode {}",
+            res_type, group_inode
+        );
+        group_inode
+    }
+
+    fn add_resources_to_group(&mut self, resources: Vec<serde_json::Value>, group_inode: u64) {
+        for resource in resources {
+            if resource.get("id").and_then(|v| v.as_str()).is_some() {
+                let resource_type = resource
+                    .get("resourceType")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("Unknown");
+                let resource_inode = self.inode_allocator.allocate();
+                let fhir_resource = FHIRResource::from_json(
+                    resource_inode,
+                    resource_type,
+                    &resource,
+                    self.config.json_format,
+                );
+                self.inode_index.insert_resource(fhir_resource);
+                self.inode_index
+                    .add_parent_child_relation(group_inode, resource_inode);
+            }
+        }
+    }
+
+    // Helper methods for readdir
+    fn create_directory_listing(&self, ino: u64, parent: u64) -> DirectoryListing {
+        let mut listing = DirectoryListing::new();
+        listing.add_current_dir(ino);
+        listing.add_parent_dir(parent);
+        listing
+    }
+
+    fn add_children_to_listing(&self, listing: &mut DirectoryListing, parent_inode: u64) {
+        let children = self.inode_index.get_children(parent_inode);
+        for child_inode in children {
+            if let Some(entry) = self.inode_index.get(child_inode) {
+                match entry {
+                    VFSEntry::Directory(dir) => listing.add_dir(dir.inode, &dir.name),
+                    VFSEntry::TextFile(file) => listing.add_file(file.inode, &file.filename),
+                    VFSEntry::SearchPath(search) => listing.add_dir(search.inode, &search.path),
+                    VFSEntry::SearchQuery(query) => listing.add_dir(query.inode, &query.query),
+                    VFSEntry::SearchResultGroup(group) => {
+                        listing.add_dir(group.inode, &group.resource_type)
+                    }
+                    VFSEntry::FHIRResource(resource) => {
+                        listing.add_file(resource.inode, &resource.filename)
+                    }
+                    VFSEntry::ResourceVersion(version) => {
+                        listing.add_file(version.inode, &version.filename)
+                    }
+                    VFSEntry::OperationPath(op) => listing.add_dir(op.inode, &op.path),
+                    VFSEntry::OperationExecution(exec) => listing.add_file(exec.inode, &exec.path),
+                }
+            }
+        }
+    }
+
+    fn add_sorted_files_to_listing(&self, listing: &mut DirectoryListing, parent_inode: u64) {
+        let children = self.inode_index.get_children(parent_inode);
+        let mut files: Vec<_> = children
+            .iter()
+            .filter_map(|&inode| {
+                self.inode_index
+                    .get_fhir_resource(inode)
+                    .map(|resource| (resource.filename.clone(), inode))
+            })
+            .collect();
+        files.sort_by_key(|(name, _)| name.clone());
+        for (name, inode) in files {
+            listing.add_file(inode, &name);
+        }
+    }
+
+    fn reply_with_listing(
+        &self,
+        listing: DirectoryListing,
+        offset: i64,
+        reply: &mut ReplyDirectory,
+    ) {
+        let entries = listing.into_vec();
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(entry.0, (i + 1) as i64, entry.1, &entry.2) {
+                break;
+            }
+        }
+    }
+
+    fn handle_root_readdir(&self, offset: i64, reply: &mut ReplyDirectory) {
+        let mut listing = self.create_directory_listing(
+            self.inode_allocator.root_inode,
+            self.inode_allocator.root_inode,
+        );
+        self.add_children_to_listing(&mut listing, self.inode_allocator.root_inode);
+        self.reply_with_listing(listing, offset, reply);
+    }
+
+    fn handle_search_readdir(&self, ino: u64, offset: i64, reply: &mut ReplyDirectory) {
+        let parent = self
+            .inode_index
+            .get_search_path(ino)
+            .map(|search| search.parent_inode)
+            .unwrap_or(ino);
+
+        let mut listing = self.create_directory_listing(ino, parent);
+        self.add_children_to_listing(&mut listing, ino);
+        self.reply_with_listing(listing, offset, reply);
+    }
+
+    fn handle_search_query_readdir(&mut self, ino: u64, offset: i64, reply: &mut ReplyDirectory) {
+        self.refresh_search_query(ino);
+
+        let parent = self
+            .inode_index
+            .get_search_query(ino)
+            .map(|query| query.parent_inode)
+            .unwrap_or(ino);
+
+        let mut listing = self.create_directory_listing(ino, parent);
+        self.add_children_to_listing(&mut listing, ino);
+        self.reply_with_listing(listing, offset, reply);
+    }
+
+    fn handle_search_result_group_readdir(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        reply: &mut ReplyDirectory,
+    ) {
+        // Get the parent query inode and refresh it
+        let parent = self
+            .inode_index
+            .get_search_result_group(ino)
+            .map(|group| group.parent_inode)
+            .unwrap_or(ino);
+
+        // Refresh the parent search query to ensure results are up-to-date
+        if parent != ino {
+            println!(
+                "[Search] Refreshing parent query {} for group {}",
+                parent, ino
+            );
+            self.refresh_search_query(parent);
+        }
+
+        let mut listing = self.create_directory_listing(ino, parent);
+        self.add_sorted_files_to_listing(&mut listing, ino);
+        self.reply_with_listing(listing, offset, reply);
+    }
+
+    fn load_resource_history(&mut self, history_dir_inode: u64) {
+        // Check if we need to refresh (cache duration)
+        let should_refresh = self
+            .history_load_times
+            .get(&history_dir_inode)
+            .map(|t| t.elapsed() > self.config.cache_duration)
+            .unwrap_or(true);
+
+        if !should_refresh {
+            return;
+        }
+
+        // Get resource info for this history directory
+        let (resource_type, resource_id) = match self.history_directories.get(&history_dir_inode) {
+            Some(info) => info.clone(),
+            None => {
+                println!(
+                    "[History] No info found for history directory {}",
+                    history_dir_inode
+                );
+                return;
+            }
+        };
+
+        println!(
+            "[History] Loading history for {}/{}",
+            resource_type, resource_id
+        );
+
+        let client = self.http_client.clone();
+        let base_url = self.fhir_base_url.clone();
+
+        let result: Result<Vec<serde_json::Value>, anyhow::Error> = self.runtime.block_on(async {
+            fetch_resource_history(&client, &base_url, &resource_type, &resource_id).await
+        });
+
+        match result {
+            Ok(versions) => {
+                // Clear existing version files for this history directory
+                let children = self.inode_index.get_children(history_dir_inode);
+                for &child_inode in &children {
+                    self.inode_index.remove(child_inode);
+                }
+                self.inode_index.clear_children(history_dir_inode);
+
+                // Add version files
+                for (index, version) in versions.iter().enumerate() {
+                    let version_id = version
+                        .get("meta")
+                        .and_then(|m: &serde_json::Value| m.get("versionId"))
+                        .and_then(|v: &serde_json::Value| v.as_str())
+                        .map(String::from)
+                        .unwrap_or_else(|| format!("{}", index + 1));
+
+                    let content = self.config.json_format.render(version);
+
+                    let version_inode = self.inode_allocator.allocate();
+                    let version_entry = vfs::ResourceVersion::new(
+                        version_inode,
+                        &resource_type,
+                        &resource_id,
+                        &version_id,
+                        content,
+                    );
+
+                    self.inode_index.insert_resource_version(version_entry);
+                    self.inode_index
+                        .add_parent_child_relation(history_dir_inode, version_inode);
+                }
+
+                println!(
+                    "[History] Loaded {} versions for {}/{}",
+                    versions.len(),
+                    resource_type,
+                    resource_id
+                );
+
+                // Update cache timestamp
+                self.history_load_times
+                    .insert(history_dir_inode, std::time::Instant::now());
+            }
+            Err(e) => {
+                println!(
+                    "[History] Failed to fetch history for {}/{}: {}",
+                    resource_type, resource_id, e
+                );
+            }
+        }
+    }
+
+    fn handle_resource_directory_readdir(
+        &mut self,
+        resource_type: &str,
+        dir_inode: u64,
+        offset: i64,
+        reply: &mut ReplyDirectory,
+    ) {
+        self.ensure_resources_loaded(resource_type, false);
+
+        let mut listing = self.create_directory_listing(dir_inode, self.inode_allocator.root_inode);
+
+        // Add _search directory first
+        let children = self.inode_index.get_children(dir_inode);
+        for &child_inode in &children {
+            if let Some(search) = self.inode_index.get_search_path(child_inode) {
+                listing.add_dir(search.inode, &search.path);
+            }
+            if let Some(op) = self.inode_index.get_operation_path(child_inode) {
+                listing.add_dir(op.inode, &op.path);
+            }
+            if let Some((_, mode)) = self.new_directories.get(&child_inode) {
+                listing.add_dir(child_inode, mode.directory());
+            }
+        }
+
+        // Add resource files and hidden history directories sorted
+        let children = self.inode_index.get_children(dir_inode);
+        let mut entries: Vec<(String, u64, bool)> = Vec::new();
+
+        for &child_inode in &children {
+            if let Some(resource) = self.inode_index.get_fhir_resource(child_inode) {
+                entries.push((resource.filename.clone(), child_inode, false));
+            } else if let Some(file) = self.inode_index.get_text_file(child_inode) {
+                // Sidecar files such as `<id>.json.conflict`
+                entries.push((file.filename.clone(), child_inode, false));
+            } else if let Some(directory) = self.inode_index.get_directory(child_inode) {
+                // Only include directories that start with '.' (history directories)
+                if directory.name.starts_with('.') {
+                    entries.push((directory.name.clone(), child_inode, true));
+                }
+            }
+        }
+
+        // Sort by name
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, inode, is_dir) in entries {
+            if is_dir {
+                listing.add_dir(inode, name);
+            } else {
+                listing.add_file(inode, name);
+            }
+        }
+
+        self.reply_with_listing(listing, offset, reply);
+    }
+}
+
+impl Filesystem for FhirFuse {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name_str = name.to_str().unwrap_or("");
+        self.lookup_counter += 1;
+        self.apply_batch_outcomes();
+        self.replay_journal_when_due();
+
+        match parent {
+            parent if parent == self.inode_allocator.root_inode => {
+                if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str) {
+                    if let Some(attr) = self.get_attrs(child_inode) {
+                        reply.entry(&self.config.ttl, &attr, 0);
+                        return;
+                    }
+                }
+            }
+            parent => {
+                let matching_resource = self
+                    .resource_directories
+                    .iter()
+                    .find(|(_, &dir_inode)| parent == dir_inode)
+                    .map(|(resource_type, _)| resource_type.clone());
+
+                if let Some(resource_type) = matching_resource {
+                    self.ensure_resources_loaded(&resource_type, false);
+                    if name_str == TEMPLATE_FILE {
+                        self.ensure_template(&resource_type);
+                    }
+
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+                }
+
+                // Handle _search directories
+                if self.search_directories.contains_key(&parent) {
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+                }
+
+                // Handle SearchQuery directories (search results grouped by type)
+                if self.search_query_directories.contains_key(&parent) {
+                    // Refresh the search results before looking up
+                    println!(
+                        "[Lookup] Refreshing search query {} for lookup of '{}'",
+                        parent, name_str
+                    );
+                    self.refresh_search_query(parent);
+
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+                }
+
+                // Handle operation directories ($run)
+                if let Some(operation_path) =
+                    self.operation_manager.get_operation_path(parent).cloned()
+                {
+                    // For operation directories, check if the file already exists
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+
+                    // Parse the filename to see if it's a valid operation execution request
+                    if let Some((resource_id, format)) =
+                        OperationExecution::parse_filename(name_str)
+                    {
+                        // Execute the operation and create the file
+                        let client = self.http_client.clone();
+                        let base_url = self.fhir_base_url.clone();
+                        let rt_clone = operation_path.resource_type.clone();
+                        let rid_clone = resource_id.clone();
+                        let op_clone = format!("${}", operation_path.operation_name);
+                        let fmt_clone = format.clone();
+
+                        let result = self.runtime.block_on(async move {
+                            execute_operation(
+                                &client, &base_url, &rt_clone, &rid_clone, &op_clone, &fmt_clone,
+                            )
+                            .await
+                        });
+
+                        match result {
+                            Ok(content) => {
+                                // Create the operation execution entry
+                                let inode = self.inode_allocator.allocate();
+                                let mut execution = OperationExecution::new(
+                                    inode,
+                                    operation_path.resource_type.clone(),
+                                    resource_id.clone(),
+                                    operation_path.operation_name.clone(),
+                                    format.clone(),
+                                    parent,
+                                );
+                                execution.result = Some(content);
+                                execution.last_executed = Some(std::time::Instant::now());
+
+                                let attr = self.with_owner(execution.get_attr());
+
+                                self.inode_index
+                                    .insert_operation_execution(execution.clone());
+                                self.inode_index.add_parent_child_relation(parent, inode);
+                                self.operation_manager.add_operation_execution(execution);
+
+                                println!(
+                                    "[lookup]: Operation {}/{} executed for {}.{}",
+                                    operation_path.resource_type,
+                                    operation_path.path,
+                                    resource_id,
+                                    format
+                                );
+                                reply.entry(&self.config.ttl, &attr, 0);
+                                return;
+                            }
+                            Err(e) => {
+                                println!("[lookup]: Failed to execute operation: {}", e);
+                                reply.error(ENOENT);
+                                return;
+                            }
+                        }
+                    }
+                }
+
+                // Handle SearchResultGroup directories (resources within a type group)
+                if self.search_result_groups.contains_key(&parent) {
+                    // Get the parent query inode and refresh it
+                    if let Some(&query_inode) = self.search_result_groups.get(&parent) {
+                        println!(
+                            "[Lookup] Refreshing parent query {} for group {} lookup of '{}'",
+                            query_inode, parent, name_str
+                        );
+                        self.refresh_search_query(query_inode);
+                    }
+
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+                }
+
+                for (&inode, (temp_parent, filename, content)) in &self.temp_files {
+                    if *temp_parent == parent && filename == name_str {
+                        let attr = self.new_file_attr(inode, content.len() as u64);
+                        reply.entry(&self.config.ttl, &attr, 0);
+                        return;
+                    }
+                }
+
+                if self.new_directories.contains_key(&parent) {
+                    let target = self
+                        .new_file_target(parent, name_str)
+                        .or_else(|| self.inode_index.find_child_by_name(parent, name_str));
+                    if let Some(attr) = target.and_then(|inode| self.get_attrs(inode)) {
+                        reply.entry(&self.config.ttl, &attr, 0);
+                        return;
+                    }
+                }
+
+                if self.staging_directories.contains_key(&parent) {
+                    if let Some(child_inode) = self.inode_index.find_child_by_name(parent, name_str)
+                    {
+                        if let Some(attr) = self.get_attrs(child_inode) {
+                            reply.entry(&self.config.ttl, &attr, 0);
+                            return;
+                        }
+                    }
+                }
+
+                // Handle history directories (directories starting with '.')
+                if let Some(directory) = self.inode_index.get_directory(parent) {
+                    if directory.name.starts_with('.') {
+                        // This is a history directory - load history if needed
+                        if self.history_directories.contains_key(&parent) {
+                            self.load_resource_history(parent);
+                        }
+
+                        // Now try to find the child (version file)
+                        if let Some(child_inode) =
+                            self.inode_index.find_child_by_name(parent, name_str)
+                        {
+                            if let Some(attr) = self.get_attrs(child_inode) {
+                                reply.entry(&self.config.ttl, &attr, 0);
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        reply.error(ENOENT);
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        let ino = self.resolve_inode(ino);
+        self.apply_batch_outcomes();
+        self.replay_journal_when_due();
+        match self.inode_index.get(ino) {
+            Some(VFSEntry::FHIRResource(_))
+            | Some(VFSEntry::Directory(_))
+            | Some(VFSEntry::TextFile(_))
+            | Some(VFSEntry::ResourceVersion(_))
+            | Some(VFSEntry::SearchPath(_))
+            | Some(VFSEntry::SearchQuery(_))
+            | Some(VFSEntry::SearchResultGroup(_))
+            | Some(VFSEntry::OperationPath(_)) => {
+                if let Some(mut attr) = self.get_attrs(ino) {
+                    // A handle sees the size of what it has written so far
+                    if let Some(file) = fh.and_then(|fh| self.file_handles.get(fh)) {
+                        attr.size = file.content().len() as u64;
+                        attr.blocks = attr.size.div_ceil(512);
+                    }
+                    reply.attr(&self.config.ttl, &attr);
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+            Some(VFSEntry::OperationExecution(exec)) => {
+                // Ensure operation is executed if not cached
+                if exec.result.is_none() {
+                    let client = self.http_client.clone();
+                    let base_url = self.fhir_base_url.clone();
+                    let rt = exec.resource_type.clone();
+                    let rid = exec.resource_id.clone();
+                    let op = format!("${}", exec.operation_name);
+                    let fmt = exec.format.clone();
+
+                    let result = self.runtime.block_on(async move {
+                        execute_operation(&client, &base_url, &rt, &rid, &op, &fmt).await
+                    });
+
+                    if let Ok(content) = result {
+                        // Cache the result
+                        if let Some(exec_mut) =
+                            self.operation_manager.get_operation_execution_mut(ino)
+                        {
+                            exec_mut.result = Some(content);
+                            exec_mut.last_executed = Some(std::time::Instant::now());
+                        }
+                    }
+                }
+
+                if let Some(attr) = self.get_attrs(ino) {
+                    reply.attr(&self.config.ttl, &attr);
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+            None => {
+                if let Some((_, _, content)) = self.temp_files.get(&ino) {
+                    let attr = self.new_file_attr(ino, content.len() as u64);
+                    reply.attr(&self.config.ttl, &attr);
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let ino = self.resolve_inode(ino);
+        if let Some(file) = self.file_handles.get(fh) {
+            reply.data(&file.read(offset, size));
+            return;
+        }
+
+        match self.inode_index.get(ino) {
+            Some(VFSEntry::TextFile(text_file)) => {
+                let data = text_file.read(offset, size);
+                reply.data(&data);
+            }
+            Some(VFSEntry::FHIRResource(resource)) => {
+                let data = resource.read(offset, size);
+                reply.data(&data);
+            }
+            Some(VFSEntry::OperationExecution(exec)) => {
+                // Execute operation if not cached
+                let result = if let Some(ref cached_result) = exec.result {
+                    cached_result.clone()
+                } else {
+                    // Execute the operation
+                    let client = self.http_client.clone();
+                    let base_url = self.fhir_base_url.clone();
+                    let rt = exec.resource_type.clone();
+                    let rid = exec.resource_id.clone();
+                    let op = format!("${}", exec.operation_name);
+                    let fmt = exec.format.clone();
+
+                    let exec_result = self.runtime.block_on(async move {
+                        execute_operation(&client, &base_url, &rt, &rid, &op, &fmt).await
+                    });
+
+                    match exec_result {
+                        Ok(content) => {
+                            // Cache the result
+                            if let Some(exec_mut) =
+                                self.operation_manager.get_operation_execution_mut(ino)
+                            {
+                                exec_mut.result = Some(content.clone());
+                                exec_mut.last_executed = Some(std::time::Instant::now());
+                            }
+                            content
+                        }
+                        Err(e) => {
+                            println!("[read] Failed to execute operation: {}", e);
+                            reply.error(EIO);
+                            return;
+                        }
+                    }
+                };
+
+                let offset = offset as usize;
+                let size = size as usize;
+                let data = if offset < result.len() {
+                    let end = std::cmp::min(offset + size, result.len());
+                    result[offset..end].as_bytes().to_vec()
+                } else {
+                    vec![]
+                };
+                reply.data(&data);
+            }
+            Some(VFSEntry::OperationPath(_))
+            | Some(VFSEntry::Directory(_))
+            | Some(VFSEntry::SearchPath(_))
+            | Some(VFSEntry::SearchQuery(_))
+            | Some(VFSEntry::SearchResultGroup(_)) => {
+                reply.error(libc::EISDIR);
+            }
+            Some(VFSEntry::ResourceVersion(version)) => {
+                let offset = offset as usize;
+                let size = size as usize;
+                let data = if offset < version.content.len() {
+                    let end = std::cmp::min(offset + size, version.content.len());
+                    version.content[offset..end].as_bytes().to_vec()
+                } else {
+                    vec![]
+                };
+                reply.data(&data);
+            }
+            None => {
+                if let Some((_, _filename, content)) = self.temp_files.get(&ino) {
+                    let offset = offset as usize;
+                    let size = size as usize;
+                    let data = if offset < content.len() {
+                        let end = std::cmp::min(offset + size, content.len());
+                        content[offset..end].to_vec()
+                    } else {
+                        vec![]
+                    };
+                    reply.data(&data);
+                    return;
+                }
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.readdir_counter += 1;
+        self.apply_batch_outcomes();
+        self.replay_journal_when_due();
+
+        if ino == self.inode_allocator.root_inode {
+            self.handle_root_readdir(offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if self.search_directories.contains_key(&ino) {
+            self.handle_search_readdir(ino, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if self.search_query_directories.contains_key(&ino) {
+            self.handle_search_query_readdir(ino, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if self.search_result_groups.contains_key(&ino) {
+            self.handle_search_result_group_readdir(ino, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        // Handle operation directories ($run)
+        if let Some(operation_path) = self.operation_manager.get_operation_path(ino) {
+            // Find parent from the index
+            let parent = self
+                .resource_directories
+                .get(&operation_path.resource_type)
+                .copied()
+                .unwrap_or(self.inode_allocator.root_inode);
+
+            let mut listing = self.create_directory_listing(ino, parent);
+
+            // Add existing operation execution files
+            let children = self.inode_index.get_children(ino);
+            for &child_inode in &children {
+                if let Some(exec) = self.inode_index.get_operation_execution(child_inode) {
+                    listing.add_file(exec.inode, &exec.path);
+                }
+            }
+
+            self.reply_with_listing(listing, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if let Some((resource_type, dir_inode)) = self
+            .resource_directories
+            .iter()
+            .find(|(_, &dir_inode)| ino == dir_inode)
+            .map(|(resource_type, &dir_inode)| (resource_type.clone(), dir_inode))
+        {
+            self.handle_resource_directory_readdir(&resource_type, dir_inode, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if let Some((resource_type, _)) = self.new_directories.get(&ino) {
+            let parent = self.resource_directories[resource_type];
+            let mut listing = self.create_directory_listing(ino, parent);
+            let mut entries: Vec<(String, u64)> = self
+                .temp_files
+                .iter()
+                .filter(|(_, (dir, name, _))| *dir == ino && !is_temp_file(name))
+                .map(|(&inode, (_, name, _))| (name.clone(), inode))
+                .collect();
+            for (dir, name) in self.new_file_links.keys() {
+                if let Some(inode) = self.new_file_target(*dir, name).filter(|_| *dir == ino) {
+                    entries.push((name.clone(), inode));
+                }
+            }
+            for child_inode in self.inode_index.get_children(ino) {
+                if let Some(file) = self.inode_index.get_text_file(child_inode) {
+                    entries.push((file.filename.clone(), child_inode));
+                }
+            }
+            entries.sort();
+            for (name, inode) in entries {
+                listing.add_file(inode, name);
+            }
+            self.reply_with_listing(listing, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        if let Some(&parent) = self.staging_directories.get(&ino) {
+            let mut listing = self.create_directory_listing(ino, parent);
+            self.add_children_to_listing(&mut listing, ino);
+            self.reply_with_listing(listing, offset, &mut reply);
+            reply.ok();
+            return;
+        }
+
+        // Check if this is a history directory (starts with '.')
+        if let Some(directory) = self.inode_index.get_directory(ino) {
+            if directory.name.starts_with('.') {
+                // Load history if needed
+                if self.history_directories.contains_key(&ino) {
+                    self.load_resource_history(ino);
+                }
+
+                // Find parent directory to get proper parent inode
+                let parent = self
+                    .resource_directories
+                    .values()
+                    .find(|&&dir_inode| self.inode_index.get_children(dir_inode).contains(&ino))
+                    .copied()
+                    .unwrap_or(self.inode_allocator.root_inode);
+
+                let mut listing = self.create_directory_listing(ino, parent);
+
+                // Add version files
+                let children = self.inode_index.get_children(ino);
+                for &child_inode in &children {
+                    if let Some(version) = self.inode_index.get_resource_version(child_inode) {
+                        listing.add_file(version.inode, &version.filename);
+                    }
+                }
+
+                self.reply_with_listing(listing, offset, &mut reply);
+                reply.ok();
+                return;
+            }
+        }
+
+        reply.error(ENOENT);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let name_str = name.to_str().unwrap_or("");
+        println!(
+            "[create]: name={}, parent={}, mode={:o}, flags={}",
+            name_str, parent, mode, flags
+        );
+
+        match self.create_file(parent, name_str, flags) {
+            Ok((inode, fh)) => {
+                let attr = self.new_file_attr(inode, 0);
+                reply.created(&self.config.ttl, &attr, 0, fh, 0);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let ino = self.resolve_inode(ino);
+        match self.write_file(ino, fh, offset, data) {
+            Ok(written) => reply.written(written as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.flush_file(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let result = self.release_file(ino, fh, flags);
+        let reported = self.take_write_error(self.resolve_inode(ino));
+        match result.and(reported) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let result = self
+            .send_written_temp_file(ino)
+            .and_then(|_| self.upload(fh));
+        // With write-behind the upload was only queued
+        self.flush_write_behind();
+        match result.and(self.take_write_error(self.resolve_inode(ino))) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn destroy(&mut self) {
+        self.flush_write_behind();
+        // What the server still can't take stays for the next mount
+        if self.journal_pending() {
+            self.replay_journal();
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: fuser::ReplyXattr) {
+        reply.size(0);
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _name: &OsStr,
+        _size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        reply.error(ENODATA);
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let ino = self.resolve_inode(ino);
+        if size.is_some() {
+            if let Err(errno) = self.check_inode_writable(ino) {
+                reply.error(errno);
+                return;
+            }
+        }
+
+        if let Some((_, _, content)) = self.temp_files.get_mut(&ino) {
+            if let Some(new_size) = size {
+                content.resize(new_size as usize, 0);
+                self.unsent_temp_files.insert(ino);
+            }
+            let content_len = content.len() as u64;
+            let mut attr = self.new_file_attr(ino, content_len);
+            if let Some(mode) = mode {
+                attr.perm = mode as u16;
+            }
+            reply.attr(&self.config.ttl, &attr);
+            return;
+        }
+
+        if let Some(mut attr) = self.get_attrs(ino) {
+            if let Some(new_size) = size {
+                if !self.is_updatable(ino) {
+                    reply.error(EACCES);
+                    return;
+                }
+                if let Err(errno) = self.truncate_file(ino, fh, new_size) {
+                    reply.error(errno);
+                    return;
+                }
+                attr.size = new_size;
+            }
+
+            if let Some(new_mode) = mode {
+                attr.perm = new_mode as u16;
+            }
+
+            reply.attr(&self.config.ttl, &attr);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn access(&mut self, _req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let ino = self.resolve_inode(ino);
+        let write_check = if mask & libc::W_OK != 0 {
+            self.check_inode_writable(ino)
+        } else {
+            Ok(())
+        };
+
+        if let Err(errno) = write_check {
+            reply.error(errno);
+        } else if mask & libc::W_OK != 0 && !self.is_updatable(ino) {
+            reply.error(EACCES);
+        } else if self.inode_index.get(ino).is_some() || self.temp_files.contains_key(&ino) {
+            reply.ok();
+        } else {
+            println!("[access]: ENOENT for ino={}", ino);
+            reply.error(ENOENT);
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        // Report 5 GB total, 4.5 GB free to allow drag-and-drop in Finder
+        let block_size: u32 = 4096;
+        let total_size: u64 = 5 * 1024 * 1024 * 1024; // 5 GB
+
+        // Nothing is free on a read-only mount (the kernel reports ST_RDONLY
+        // from the `ro` mount option)
+        let free_size: u64 = if self.config.mount.read_only {
+            0
+        } else {
+            4_500_000_000 // 4.5 GB free
+        };
+        let total_blocks = total_size / block_size as u64;
+        let free_blocks = free_size / block_size as u64;
+        let free_inodes = if self.config.mount.read_only {
+            0
+        } else {
+            999_000
+        };
+        reply.statfs(
+            total_blocks, // total blocks
+            free_blocks,  // free blocks
+            free_blocks,  // available blocks (same as free for our purposes)
+            1_000_000,    // total inodes
+            free_inodes,  // free inodes
+            block_size,   // block size
+            255,          // max name length
+            block_size,   // fragment size
+        );
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         match self.inode_index.get(ino) {
-            Some(VFSEntry::FHIRResource(_))
-            | Some(VFSEntry::Directory(_))
-            | Some(VFSEntry::TextFile(_))
-            | Some(VFSEntry::ResourceVersion(_))
-            | Some(VFSEntry::SearchPath(_))
-            | Some(VFSEntry::SearchQuery(_))
-            | Some(VFSEntry::SearchResultGroup(_))
-            | Some(VFSEntry::OperationPath(_)) => {
-                if let Some(attr) = self.get_attrs(ino) {
-                    reply.attr(&TTL, &attr);
-                } else {
-                    reply.error(ENOENT);
-                }
+            Some(VFSEntry::Directory(dir)) => {
+                println!("[opendir]: inode: {}, path: {}", ino, dir.name);
+                reply.opened(0, 0);
             }
-            Some(VFSEntry::OperationExecution(exec)) => {
-                // Ensure operation is executed if not cached
-                if exec.result.is_none() {
-                    let client = self.http_client.clone();
-                    let base_url = self.fhir_base_url.clone();
-                    let rt = exec.resource_type.clone();
-                    let rid = exec.resource_id.clone();
-                    let op = format!("${}", exec.operation_name);
-                    let fmt = exec.format.clone();
-
-                    let result = self.runtime.block_on(async move {
-                        execute_operation(&client, &base_url, &rt, &rid, &op, &fmt).await
-                    });
+            Some(VFSEntry::SearchPath(search)) => {
+                println!("[opendir]: inode: {}, path: {}", ino, search.path);
+                reply.opened(0, 0);
+            }
+            Some(VFSEntry::SearchQuery(query)) => {
+                println!("[opendir]: inode: {}, path: {}", ino, query.path);
+                reply.opened(0, 0);
+            }
+            Some(VFSEntry::SearchResultGroup(group)) => {
+                println!("[opendir]: inode: {}, path: {}", ino, group.path);
+                reply.opened(0, 0);
+            }
+            Some(VFSEntry::OperationPath(op)) => {
+                println!("[opendir]: inode: {}, path: {}", ino, op.path);
+                reply.opened(0, 0);
+            }
+            Some(_) => {
+                println!("[opendir]: inode: {}, error: not a directory", ino);
+                reply.error(libc::ENOTDIR);
+            }
+            None => {
+                println!("[opendir]: inode: {}, error: not found", ino);
+                reply.error(ENOENT);
+            }
+        }
+    }
 
-                    if let Ok(content) = result {
-                        // Cache the result
-                        if let Some(exec_mut) =
-                            self.operation_manager.get_operation_execution_mut(ino)
-                        {
-                            exec_mut.result = Some(content);
-                            exec_mut.last_executed = Some(std::time::Instant::now());
-                        }
-                    }
-                }
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let ino = self.resolve_inode(ino);
+        if let Err(errno) = self.take_write_error(ino) {
+            reply.error(errno);
+            return;
+        }
+        if self.temp_files.contains_key(&ino) {
+            reply.opened(0, 0);
+            return;
+        }
 
-                if let Some(attr) = self.get_attrs(ino) {
-                    reply.attr(&TTL, &attr);
-                } else {
-                    reply.error(ENOENT);
-                }
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            if let Err(errno) = self.check_inode_writable(ino) {
+                reply.error(errno);
+                return;
             }
-            None => {
-                if let Some((_, _, content)) = self.temp_files.get(&ino) {
-                    let ts = std::time::SystemTime::now();
-                    let size = content.len() as u64;
-                    let blocks = (size + 511) / 512;
-                    let attr = FileAttr {
-                        ino,
-                        size,
-                        blocks,
-                        atime: ts,
-                        mtime: ts,
-                        ctime: ts,
-                        crtime: ts,
-                        kind: fuser::FileType::RegularFile,
-                        perm: 0o644,
-                        nlink: 1,
-                        uid: 501,
-                        gid: 20,
-                        rdev: 0,
-                        flags: 0,
-                        blksize: 512,
-                    };
-                    reply.attr(&TTL, &attr);
-                } else {
-                    reply.error(ENOENT);
+            if let Some(result) = self.run_staging_command(ino) {
+                match result {
+                    Ok(()) => reply.opened(0, 0),
+                    Err(errno) => reply.error(errno),
                 }
+                return;
+            }
+            if !self.is_updatable(ino) {
+                println!("[open]: DENIED - inode {} is read-only", ino);
+                reply.error(EACCES);
+                return;
+            }
+            match self.open_for_write(ino, flags) {
+                Ok(fh) => reply.opened(fh, 0),
+                Err(errno) => reply.error(errno),
             }
+            return;
         }
-    }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
         match self.inode_index.get(ino) {
-            Some(VFSEntry::TextFile(text_file)) => {
-                let data = text_file.read(offset, size);
-                reply.data(&data);
-            }
-            Some(VFSEntry::FHIRResource(resource)) => {
-                let data = resource.read(offset, size);
-                reply.data(&data);
+            Some(VFSEntry::TextFile(_))
+            | Some(VFSEntry::FHIRResource(_))
+            | Some(VFSEntry::ResourceVersion(_)) => {
+                reply.opened(0, 0);
             }
             Some(VFSEntry::OperationExecution(exec)) => {
-                // Execute operation if not cached
-                let result = if let Some(ref cached_result) = exec.result {
-                    cached_result.clone()
-                } else {
-                    // Execute the operation
+                // Ensure operation is executed if not cached
+                if exec.result.is_none() {
                     let client = self.http_client.clone();
                     let base_url = self.fhir_base_url.clone();
                     let rt = exec.resource_type.clone();
@@ -1042,963 +4098,2237 @@ impl Filesystem for FhirFuse {
                     let op = format!("${}", exec.operation_name);
                     let fmt = exec.format.clone();
 
-                    let exec_result = self.runtime.block_on(async move {
+                    let result = self.runtime.block_on(async move {
                         execute_operation(&client, &base_url, &rt, &rid, &op, &fmt).await
                     });
 
-                    match exec_result {
+                    match result {
                         Ok(content) => {
                             // Cache the result
                             if let Some(exec_mut) =
                                 self.operation_manager.get_operation_execution_mut(ino)
                             {
-                                exec_mut.result = Some(content.clone());
+                                exec_mut.result = Some(content);
                                 exec_mut.last_executed = Some(std::time::Instant::now());
                             }
-                            content
+                            reply.opened(0, 0);
                         }
                         Err(e) => {
-                            println!("[read] Failed to execute operation: {}", e);
-                            reply.error(EIO);
-                            return;
-                        }
-                    }
-                };
-
-                let offset = offset as usize;
-                let size = size as usize;
-                let data = if offset < result.len() {
-                    let end = std::cmp::min(offset + size, result.len());
-                    result[offset..end].as_bytes().to_vec()
-                } else {
-                    vec![]
-                };
-                reply.data(&data);
-            }
-            Some(VFSEntry::OperationPath(_))
-            | Some(VFSEntry::Directory(_))
-            | Some(VFSEntry::SearchPath(_))
-            | Some(VFSEntry::SearchQuery(_))
-            | Some(VFSEntry::SearchResultGroup(_)) => {
-                reply.error(libc::EISDIR);
-            }
-            Some(VFSEntry::ResourceVersion(version)) => {
-                let offset = offset as usize;
-                let size = size as usize;
-                let data = if offset < version.content.len() {
-                    let end = std::cmp::min(offset + size, version.content.len());
-                    version.content[offset..end].as_bytes().to_vec()
-                } else {
-                    vec![]
-                };
-                reply.data(&data);
-            }
-            None => {
-                if let Some((_, _filename, content)) = self.temp_files.get(&ino) {
-                    let offset = offset as usize;
-                    let size = size as usize;
-                    let data = if offset < content.len() {
-                        let end = std::cmp::min(offset + size, content.len());
-                        content[offset..end].to_vec()
-                    } else {
-                        vec![]
-                    };
-                    reply.data(&data);
-                    return;
-                }
-                reply.error(ENOENT);
-            }
-        }
-    }
-
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        self.readdir_counter += 1;
-
-        if ino == self.inode_allocator.root_inode {
-            self.handle_root_readdir(offset, &mut reply);
-            reply.ok();
-            return;
-        }
-
-        if self.search_directories.contains_key(&ino) {
-            self.handle_search_readdir(ino, offset, &mut reply);
-            reply.ok();
-            return;
-        }
-
-        if self.search_query_directories.contains_key(&ino) {
-            self.handle_search_query_readdir(ino, offset, &mut reply);
-            reply.ok();
-            return;
-        }
-
-        if self.search_result_groups.contains_key(&ino) {
-            self.handle_search_result_group_readdir(ino, offset, &mut reply);
-            reply.ok();
-            return;
-        }
-
-        // Handle operation directories ($run)
-        if let Some(operation_path) = self.operation_manager.get_operation_path(ino) {
-            // Find parent from the index
-            let parent = self
-                .resource_directories
-                .get(&operation_path.resource_type)
-                .copied()
-                .unwrap_or(self.inode_allocator.root_inode);
-
-            let mut listing = self.create_directory_listing(ino, parent);
-
-            // Add existing operation execution files
-            let children = self.inode_index.get_children(ino);
-            for &child_inode in &children {
-                if let Some(exec) = self.inode_index.get_operation_execution(child_inode) {
-                    listing.add_file(exec.inode, &exec.path);
+                            println!("[open] Failed to execute operation: {}", e);
+                            reply.error(EIO);
+                        }
+                    }
+                } else {
+                    reply.opened(0, 0);
                 }
             }
-
-            self.reply_with_listing(listing, offset, &mut reply);
-            reply.ok();
-            return;
+            Some(_) => {
+                reply.error(libc::EISDIR);
+            }
+            None => {
+                reply.error(ENOENT);
+            }
         }
+    }
 
-        if let Some((resource_type, dir_inode)) = self
-            .resource_directories
-            .iter()
-            .find(|(_, &dir_inode)| ino == dir_inode)
-            .map(|(resource_type, &dir_inode)| (resource_type.clone(), dir_inode))
-        {
-            self.handle_resource_directory_readdir(&resource_type, dir_inode, offset, &mut reply);
-            reply.ok();
-            return;
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name_str = name.to_str().unwrap_or("");
+        match self.unlink_file(parent, name_str) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
+    }
 
-        // Check if this is a history directory (starts with '.')
-        if let Some(directory) = self.inode_index.get_directory(ino) {
-            if directory.name.starts_with('.') {
-                // Load history if needed
-                if self.history_directories.contains_key(&ino) {
-                    self.load_resource_history(ino);
-                }
-
-                // Find parent directory to get proper parent inode
-                let parent = self
-                    .resource_directories
-                    .values()
-                    .find(|&&dir_inode| self.inode_index.get_children(dir_inode).contains(&ino))
-                    .copied()
-                    .unwrap_or(self.inode_allocator.root_inode);
-
-                let mut listing = self.create_directory_listing(ino, parent);
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name_str = name.to_str().unwrap_or("");
+        let newname_str = newname.to_str().unwrap_or("");
 
-                // Add version files
-                let children = self.inode_index.get_children(ino);
-                for &child_inode in &children {
-                    if let Some(version) = self.inode_index.get_resource_version(child_inode) {
-                        listing.add_file(version.inode, &version.filename);
-                    }
-                }
+        println!(
+            "[rename]: parent={}, name={}, newparent={}, newname={}",
+            parent, name_str, newparent, newname_str
+        );
 
-                self.reply_with_listing(listing, offset, &mut reply);
-                reply.ok();
-                return;
-            }
+        match self.rename_file(parent, name_str, newparent, newname_str) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
+    }
 
-        reply.error(ENOENT);
+    fn forget(&mut self, _req: &Request, ino: u64, _nlookup: u64) {
+        self.inode_aliases.remove(&ino);
     }
 
-    fn create(
+    fn mkdir(
         &mut self,
         _req: &Request,
         parent: u64,
         name: &OsStr,
-        mode: u32,
+        _mode: u32,
         _umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
+        reply: ReplyEntry,
     ) {
         let name_str = name.to_str().unwrap_or("");
-        println!(
-            "[create]: name={}, parent={}, mode={:o}, flags={}",
-            name_str, parent, mode, flags
-        );
 
-        let is_temp_file = |name: &str| -> bool {
-            name.starts_with('.') && name.ends_with(".swp")
-                || name.starts_with('.') && name.ends_with(".swo")
-                || name.starts_with('.') && name.contains(".sw")
-                || name == "4913"
-                || name.starts_with(".")
-                || name.ends_with("~")
-        };
+        if let Some((resource_type, _search_inode)) = self.inode_index.get_search_path_info(parent)
+        {
+            let query = name_str.to_string();
 
-        // Allow temp files (like .DS_Store) in any directory for Finder compatibility
-        if is_temp_file(name_str) {
-            let inode = self.inode_allocator.allocate();
-            self.temp_files
-                .insert(inode, (parent, name_str.to_string(), Vec::new()));
-
-            let ts = std::time::SystemTime::now();
-            let attr = FileAttr {
-                ino: inode,
-                size: 0,
-                blocks: 0,
-                atime: ts,
-                mtime: ts,
-                ctime: ts,
-                crtime: ts,
-                kind: fuser::FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+            println!("[mkdir] {}/_search/{}", resource_type, query);
+
+            match self.execute_search_query(&resource_type, &query) {
+                Ok(grouped_resources) => {
+                    let query_inode = self.create_search_query_directory(
+                        query.clone(),
+                        resource_type.clone(),
+                        parent,
+                    );
+
+                    // Create SearchResultGroup for each resource type in results
+                    for (res_type, resources) in grouped_resources {
+                        let group_inode =
+                            self.create_search_result_group(res_type.clone(), query_inode);
+
+                        // Add resources to the group
+                        self.add_resources_to_group(resources, group_inode);
+
+                        println!(
+                            "[Search] Added resources to group {} (inode {})",
+                            res_type, group_inode
+                        );
+                    }
 
-            println!("[create]: Temp file {} in parent {}", name_str, parent);
-            reply.created(&TTL, &attr, 0, inode, 0);
+                    if let Some(attr) = self.get_attrs(query_inode) {
+                        reply.entry(&self.config.ttl, &attr, 0);
+                    } else {
+                        reply.error(EIO);
+                    }
+                }
+                Err(e) => {
+                    println!("[Search] Failed: {}", e);
+                    reply.error(EIO);
+                }
+            }
             return;
         }
 
-        // Check if parent is an operation directory ($run)
-        if self.operation_manager.get_operation_path(parent).is_some() {
-            // Operation files are created on-demand during lookup, not via create/touch
-            println!(
-                "[create]: Operation files are virtual and created on-demand: {}",
-                name_str
-            );
-            reply.error(EACCES);
-            return;
+        // mkdir not allowed elsewhere
+        reply.error(self.check_writable(None).err().unwrap_or(EACCES));
+    }
+}
+
+/// Names tools give the files they write while saving. Temp files live only
+/// in memory and are uploaded when renamed onto `<id>.json` in a type
+/// directory, where any other name is a temp file (patch files included,
+/// which are sent once written). That covers:
+/// - vim: `.x.json.swp` swap files, the `4913` write probe, `x.json~` backups
+/// - emacs: `#x.json#` auto-saves, `x.json~` backups
+/// - VS Code and most other tools: `x.json.tmp`, `.x.json.tmp-1234`
+/// - `sed -i`: `sedAbC123`
+/// - Finder: `.DS_Store`, `._x.json`
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') || !name.ends_with(".json")
+}
+
+/// Whether a failed write was rejected because the resource changed on the server
+fn is_conflict(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<HttpError>()
+        .map(HttpError::is_conflict)
+        .unwrap_or(false)
+}
+
+/// Whether a write failed because the server couldn't be reached rather
+/// than refusing it: the connection failed or timed out, or a gateway in
+/// between answered for the server
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    if let Some(http_error) = error.downcast_ref::<HttpError>() {
+        return matches!(http_error.status.as_u16(), 502..=504);
+    }
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_connect() || e.is_timeout())
+    })
+}
+
+/// errno reported to the process whose write the server rejected
+fn errno_for(error: &anyhow::Error) -> i32 {
+    if error.downcast_ref::<InvalidResource>().is_some() {
+        return EINVAL;
+    }
+    let status = match error.downcast_ref::<HttpError>() {
+        Some(http_error) => http_error.status.as_u16(),
+        // Network failures, invalid responses
+        None => return EIO,
+    };
+    match status {
+        400 | 422 => EINVAL,
+        401 | 403 => EACCES,
+        404 | 410 => ENOENT,
+        409 => EBUSY,
+        412 => ESTALE,
+        _ => EIO,
+    }
+}
+
+/// Content of the `.error` sidecar: the server's OperationOutcome when it
+/// sent one, otherwise the error message
+fn error_report(error: &anyhow::Error) -> String {
+    error
+        .downcast_ref::<HttpError>()
+        .and_then(|http_error| serde_json::from_str::<serde_json::Value>(&http_error.body).ok())
+        .and_then(|outcome| serde_json::to_string_pretty(&outcome).ok())
+        .unwrap_or_else(|| format!("{}\n", error))
+}
+
+/// `Type/id` of a resource
+fn reference_to(resource: &serde_json::Value) -> String {
+    format!(
+        "{}/{}",
+        resource["resourceType"].as_str().unwrap_or_default(),
+        resource["id"].as_str().unwrap_or_default()
+    )
+}
+
+/// A `transaction` entry that puts `content` at `Type/id`, or deletes it
+/// when there is none, conditional on `version_id`
+fn transaction_entry(
+    reference: &str,
+    content: Option<&str>,
+    version_id: Option<&str>,
+) -> serde_json::Value {
+    let mut request = match content {
+        Some(_) => serde_json::json!({"method": "PUT", "url": reference}),
+        None => serde_json::json!({"method": "DELETE", "url": reference}),
+    };
+    if let Some(version_id) = version_id {
+        request["ifMatch"] = serde_json::json!(format!("W/\"{}\"", version_id));
+    }
+    let mut entry = serde_json::json!({"request": request});
+    if let Some(content) = content {
+        entry["resource"] = serde_json::from_str(content).unwrap_or_default();
+    }
+    entry
+}
+
+/// Load what `--validate local` checks resources against
+fn build_validator(config: &Config) -> anyhow::Result<Option<LocalValidator>> {
+    match config.validation.mode {
+        ValidationMode::Local => LocalValidator::load(&config.validation).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Build the HTTP client, attaching an OAuth2 token manager when
+/// authentication is configured
+fn build_fhir_client(runtime: &Runtime, config: &Config) -> anyhow::Result<FhirClient> {
+    let http_client = config.http.build()?;
+    let auth = match &config.auth {
+        Some(auth) => auth,
+        None => return Ok(FhirClient::new(http_client)),
+    };
+    let token_client = config.http.without_credentials().build()?;
+
+    let authentication = match auth.mode {
+        AuthMode::ClientCredentials => {
+            // Presence is checked when the configuration is resolved
+            ClientAuthentication::ClientSecret(auth.client_secret.clone().unwrap_or_default())
         }
+        AuthMode::SmartBackend => {
+            let key_path = auth.private_key.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("A private key is required for smart-backend auth")
+            })?;
+            let pem = std::fs::read(key_path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", key_path.display(), e))?;
+            let signer = AssertionSigner::from_pem(&auth.jwt_alg, &pem, auth.jwt_kid.clone())?;
+            ClientAuthentication::PrivateKeyJwt(signer)
+        }
+    };
+
+    let token_url = match &auth.token_url {
+        Some(token_url) => token_url.clone(),
+        None => runtime.block_on(discover_token_endpoint(
+            &token_client,
+            &config.fhir_base_url,
+        ))?,
+    };
+
+    let credentials = ClientCredentials {
+        token_url,
+        client_id: auth.client_id.clone(),
+        authentication,
+        scope: auth.scope.clone(),
+    };
+    println!(
+        "OAuth2 client credentials: client_id={} auth={:?} token_url={}",
+        credentials.client_id, credentials.authentication, credentials.token_url
+    );
+    let token_manager = TokenManager::new(token_client, credentials);
+    Ok(FhirClient::new(http_client).with_token_manager(token_manager))
+}
 
-        let matching_resource = self
-            .resource_directories
-            .iter()
-            .find(|(_, &dir_inode)| parent == dir_inode)
-            .map(|(resource_type, _)| resource_type.clone());
+fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-        if let Some(resource_type) = matching_resource {
-            let inode = self.inode_allocator.allocate();
-            self.pending_writes.insert(inode, Vec::new());
-            self.created_files
-                .insert(inode, (resource_type.clone(), name_str.to_string()));
-            let resource_entry = FHIRResource::new(
-                inode,
-                &resource_type,
-                name_str.trim_end_matches(".json"),
-                String::new(),
-            );
-            self.inode_index.insert_resource(resource_entry);
+    println!(
+        "Mounting FHIR filesystem at: {}",
+        config.mountpoint.display()
+    );
+    println!("FHIR server: {}", config.fhir_base_url);
+
+    // Create tokio runtime
+    let runtime = Arc::new(
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .expect("Failed to create Tokio runtime"),
+    );
 
-            if let Some(&dir_inode) = self.resource_directories.get(&resource_type) {
-                self.inode_index.add_parent_child_relation(dir_inode, inode);
+    // Create HTTP client
+    let http_client = match build_fhir_client(&runtime, &config) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mountpoint = config.mountpoint.clone();
+    let mount = config.mount.clone();
+    let validator = match build_validator(&config) {
+        Ok(validator) => validator,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let definitions = match StructureDefinitions::load(&config.validation.packages) {
+        Ok(definitions) => definitions,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let journal = match config
+        .write
+        .journal
+        .as_deref()
+        .map(Journal::open)
+        .transpose()
+    {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let fs = FhirFuse::new(
+        config,
+        http_client,
+        runtime,
+        validator,
+        definitions,
+        journal,
+    );
 
-                // Create hidden history directory for newly created resource if it doesn't exist
-                let resource_id = name_str.trim_end_matches(".json");
-                let history_dir_name = format!(".{}", resource_id);
-                let history_dir_inode = if let Some(existing_inode) = self
-                    .inode_index
-                    .find_child_by_name(dir_inode, &history_dir_name)
-                {
-                    existing_inode
-                } else {
-                    let new_inode = self.inode_allocator.allocate();
-                    let history_dir = Directory::new(new_inode, history_dir_name);
-                    self.inode_index.insert_directory(history_dir);
-                    self.inode_index
-                        .add_parent_child_relation(dir_inode, new_inode);
-                    new_inode
+    let mut options = vec![
+        if mount.read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+        MountOption::CUSTOM("max_readahead=0".to_string()),
+        MountOption::CUSTOM("sync_read".to_string()),
+        MountOption::Sync,
+        MountOption::DirSync,
+        MountOption::FSName("fhir-fuse".to_string()),
+        MountOption::CUSTOM("noappledouble".to_string()),
+        MountOption::CUSTOM("noapplexattr".to_string()),
+    ];
+    if mount.direct_io {
+        options.push(MountOption::CUSTOM("direct_io".to_string()));
+    }
+    if mount.allow_other {
+        options.push(MountOption::AllowOther); // Allow other users/apps to access
+    }
+
+    match fuser::mount2(fs, &mountpoint, &options) {
+        Ok(_) => println!("Filesystem unmounted"),
+        Err(e) => eprintln!("Failed to mount filesystem: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{journal_path, MockRequest, MockResponse, MockServer};
+    use reqwest::StatusCode;
+
+    const PATIENT: &str = r#"{"resourceType":"Patient","id":"pt-1","meta":{"versionId":"1"}}"#;
+
+    /// A FHIR server that declares Patient, stores every PUT as version 2,
+    /// assigns `srv-1` to every POST, matches `pt-1` for every conditional
+    /// write, answers every PATCH with version 3, has `Observation/obs-1`
+    /// referencing `Patient/pt-1`, finds resources without a gender invalid
+    /// and applies every transaction except one touching `Patient/locked`,
+    /// and every batch entry except `Patient/bad`. `Patient/pt-7` is at
+    /// version 8, so writes against version 7 conflict.
+    fn fhir_server() -> MockServer {
+        MockServer::start(|req: &MockRequest| match req.method.as_str() {
+            "POST" if req.path.ends_with("/$validate") => match req.body.contains("gender") {
+                true => MockResponse::json(
+                    200,
+                    r#"{"resourceType":"OperationOutcome","issue":[
+                    {"severity":"information","code":"informational","diagnostics":"All OK"}]}"#,
+                ),
+                false => MockResponse::json(
+                    200,
+                    r#"{"resourceType":"OperationOutcome","issue":[
+                    {"severity":"error","code":"required","expression":["Patient.gender"],
+                     "diagnostics":"gender is required"}]}"#,
+                ),
+            },
+            "GET" if req.path == "/metadata" => MockResponse::json(
+                200,
+                r#"{"resourceType":"CapabilityStatement","rest":[{"mode":"server","resource":[{"type":"Patient"}]}]}"#,
+            ),
+            "GET" if req.path.starts_with("/Patient?_id=pt-1&") => MockResponse::json(
+                200,
+                r#"{"resourceType":"Bundle","entry":[
+                    {"resource":{"resourceType":"Patient","id":"pt-1"}},
+                    {"resource":{"resourceType":"Observation","id":"obs-1","meta":{"versionId":"4"},
+                     "subject":{"reference":"Patient/pt-1"}}}]}"#,
+            ),
+            "GET" if req.path.starts_with("/StructureDefinition?url=") => {
+                let url = req.path.rsplit("%2F").next().unwrap_or_default();
+                let mut definition = match url {
+                    "Patient" => serde_json::json!({"type": "Patient", "snapshot": {"element": [
+                        {"path": "Patient", "min": 0, "max": "*"},
+                        {"path": "Patient.gender", "min": 1, "max": "1",
+                         "type": [{"code": "code"}], "short": "male | female | other | unknown"},
+                        {"path": "Patient.name", "min": 0, "max": "*", "isSummary": true,
+                         "type": [{"code": "HumanName"}], "short": "A name"}
+                    ]}}),
+                    "HumanName" => {
+                        serde_json::json!({"type": "HumanName", "snapshot": {"element": [
+                            {"path": "HumanName", "min": 0, "max": "*"},
+                            {"path": "HumanName.family", "min": 0, "max": "1", "isSummary": true,
+                             "type": [{"code": "string"}], "short": "Family name"}
+                        ]}})
+                    }
+                    _ => return MockResponse::json(200, r#"{"resourceType":"Bundle"}"#),
                 };
-                // Always track history directory (even if it already existed)
-                self.history_directories.insert(
-                    history_dir_inode,
-                    (resource_type.clone(), resource_id.to_string()),
-                );
+                definition["resourceType"] = serde_json::json!("StructureDefinition");
+                definition["url"] =
+                    serde_json::json!(format!("http://hl7.org/fhir/StructureDefinition/{}", url));
+                let bundle = serde_json::json!({"resourceType": "Bundle", "entry": [{"resource": definition}]});
+                MockResponse::json(200, &bundle.to_string())
+            }
+            "GET" if req.path.starts_with("/Patient/pt-7?") => MockResponse::json(
+                200,
+                r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"8"}}"#,
+            ),
+            "HEAD" if req.path == "/Organization/org-1" || req.path == "/Patient/taken" => {
+                MockResponse::json(200, "")
+            }
+            "DELETE" if req.header("If-Match") == Some("W/\"7\"") => MockResponse::json(
+                412,
+                r#"{"resourceType":"OperationOutcome","issue":[
+                {"severity":"error","code":"conflict","diagnostics":"Version 8 is current"}]}"#,
+            ),
+            "DELETE" => MockResponse::json(200, "{}"),
+            "PATCH" => MockResponse::json(
+                200,
+                r#"{"resourceType":"Patient","id":"pt-1","active":false,"meta":{"versionId":"3"}}"#,
+            ),
+            "PUT" if req.header("If-Match") == Some("W/\"7\"") => MockResponse::json(
+                412,
+                r#"{"resourceType":"OperationOutcome","issue":[
+                {"severity":"error","code":"conflict","diagnostics":"Version 8 is current"}]}"#,
+            ),
+            "PUT" => match serde_json::from_str::<serde_json::Value>(&req.body) {
+                Ok(mut resource) => {
+                    // A conditional update matches pt-1
+                    if req.path.starts_with("/Patient?") {
+                        resource["id"] = serde_json::json!("pt-1");
+                    }
+                    resource["meta"] = serde_json::json!({"versionId": "2"});
+                    MockResponse::json(200, &resource.to_string()).with_header("ETag", "W/\"2\"")
+                }
+                Err(_) => MockResponse::json(400, r#"{"resourceType":"OperationOutcome"}"#),
+            },
+            "POST" if req.path == "/" && req.body.contains(r#""ifMatch":"W/\"7\"""#) => {
+                MockResponse::json(
+                    412,
+                    r#"{"resourceType":"OperationOutcome","issue":[
+                    {"severity":"error","code":"conflict","diagnostics":"Version 8 is current"}]}"#,
+                )
+            }
+            "POST" if req.path == "/" && req.body.contains("\"Patient/locked\"") => {
+                MockResponse::json(
+                    409,
+                    r#"{"resourceType":"OperationOutcome","issue":[
+                    {"severity":"error","code":"conflict","diagnostics":"Patient/locked is locked"}]}"#,
+                )
+            }
+            "POST" if req.path == "/" => {
+                let bundle: serde_json::Value = serde_json::from_str(&req.body).unwrap_or_default();
+                let entries: Vec<serde_json::Value> = bundle["entry"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| match entry.get("resource") {
+                        Some(resource) if resource["id"] == "bad" => serde_json::json!({
+                            "response": {"status": "422 Unprocessable Entity", "outcome": {
+                                "resourceType": "OperationOutcome", "issue": [{"severity": "error",
+                                "code": "invalid", "diagnostics": "bad is bad"}]}}}),
+                        Some(resource) => {
+                            let mut resource = resource.clone();
+                            resource["meta"] = serde_json::json!({"versionId": "2"});
+                            serde_json::json!({"resource": resource,
+                                "response": {"status": "200 OK", "etag": "W/\"2\""}})
+                        }
+                        None => serde_json::json!({"response": {"status": "204 No Content"}}),
+                    })
+                    .collect();
+                let result = serde_json::json!({"resourceType": "Bundle",
+                    "type": "transaction-response", "entry": entries});
+                MockResponse::json(200, &result.to_string())
             }
+            // A conditional create matches pt-1
+            "POST" if req.header("If-None-Exist").is_some() => MockResponse::json(200, PATIENT),
+            "POST" => match serde_json::from_str::<serde_json::Value>(&req.body) {
+                Ok(mut resource) => {
+                    resource["id"] = serde_json::json!("srv-1");
+                    resource["meta"] = serde_json::json!({"versionId": "1"});
+                    MockResponse::json(201, &resource.to_string())
+                }
+                Err(_) => MockResponse::json(400, r#"{"resourceType":"OperationOutcome"}"#),
+            },
+            _ => MockResponse::json(404, "{}"),
+        })
+    }
 
-            let ts = std::time::SystemTime::now();
-            let attr = FileAttr {
-                ino: inode,
-                size: 0,
-                blocks: 0,
-                atime: ts,
-                mtime: ts,
-                ctime: ts,
-                crtime: ts,
-                kind: fuser::FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+    fn mount(server: &MockServer) -> FhirFuse {
+        mount_with(server, config::Settings::default())
+    }
 
-            println!("[create]: {}/{}", resource_type, name_str);
-            reply.created(&TTL, &attr, 0, inode, 0);
-        } else {
-            println!(
-                "[create]: DENIED - parent {} is not a resource directory, file={}",
-                parent, name_str
-            );
-            reply.error(EACCES);
-        }
+    fn mount_with(server: &MockServer, settings: config::Settings) -> FhirFuse {
+        let config = Config::resolve(config::Settings {
+            mountpoint: Some("/tmp/fhir".into()),
+            fhir_base_url: Some(server.url("")),
+            ..settings
+        })
+        .unwrap();
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let http_client = build_fhir_client(&runtime, &config).unwrap();
+        let validator = build_validator(&config).unwrap();
+        let definitions = StructureDefinitions::load(&config.validation.packages).unwrap();
+        let journal = config
+            .write
+            .journal
+            .as_deref()
+            .map(Journal::open)
+            .transpose()
+            .unwrap();
+        FhirFuse::new(
+            config,
+            http_client,
+            runtime,
+            validator,
+            definitions,
+            journal,
+        )
     }
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        if let Some((_parent, _filename, content)) = self.temp_files.get_mut(&ino) {
-            let offset = offset as usize;
-            if offset + data.len() > content.len() {
-                content.resize(offset + data.len(), 0);
-            }
-            content[offset..offset + data.len()].copy_from_slice(data);
-            reply.written(data.len() as u32);
-            return;
-        }
+    fn add_patient(fs: &mut FhirFuse, content: &str) -> u64 {
+        let inode = fs.inode_allocator.allocate();
+        let resource = serde_json::from_str(content).unwrap();
+        fs.inode_index.insert_resource(FHIRResource::from_json(
+            inode,
+            "Patient",
+            &resource,
+            fs.config.json_format,
+        ));
+        fs.inode_index
+            .add_parent_child_relation(fs.resource_directories["Patient"], inode);
+        inode
+    }
 
-        if !self.pending_writes.contains_key(&ino) {
-            if let Some(resource) = self.inode_index.get_fhir_resource(ino) {
-                self.pending_writes
-                    .insert(ino, resource.content.as_bytes().to_vec());
-            } else {
-                self.pending_writes.insert(ino, Vec::new());
-            }
+    /// Requests after the capability statement, only those with `method`
+    /// if given
+    fn requests(server: &MockServer, method: Option<&str>) -> Vec<MockRequest> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|req| req.path != "/metadata")
+            .filter(|req| method.is_none_or(|method| req.method == method))
+            .collect()
+    }
+
+    /// Content of the generated text file at `path` below `dir`
+    fn text_file(fs: &FhirFuse, dir: u64, path: &str) -> Option<String> {
+        let inode = inode_at(fs, dir, path)?;
+        Some(fs.inode_index.get_text_file(inode)?.content.clone())
+    }
+
+    /// Inode of the '/'-separated `path` below `dir`
+    fn inode_at(fs: &FhirFuse, dir: u64, path: &str) -> Option<u64> {
+        path.split('/').try_fold(dir, |inode, name| {
+            fs.inode_index.find_child_by_name(inode, name)
+        })
+    }
+
+    fn http_error(status: u16, body: &str) -> anyhow::Error {
+        HttpError {
+            action: "PUT resource to FHIR server".to_string(),
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
         }
+        .into()
+    }
 
-        if let Some(content) = self.pending_writes.get_mut(&ino) {
-            let offset = offset as usize;
+    #[test]
+    fn test_errno_for_http_status() {
+        assert_eq!(errno_for(&http_error(400, "")), EINVAL);
+        assert_eq!(errno_for(&http_error(422, "")), EINVAL);
+        assert_eq!(errno_for(&http_error(403, "")), EACCES);
+        assert_eq!(errno_for(&http_error(404, "")), ENOENT);
+        assert_eq!(errno_for(&http_error(409, "")), EBUSY);
+        assert_eq!(errno_for(&http_error(412, "")), ESTALE);
+        assert_eq!(errno_for(&http_error(500, "")), EIO);
+        assert_eq!(errno_for(&http_error(503, "")), EIO);
+        assert_eq!(errno_for(&anyhow::anyhow!("connection refused")), EIO);
+    }
 
-            if offset + data.len() > content.len() {
-                content.resize(offset + data.len(), 0);
-            }
+    #[test]
+    fn test_error_report_prefers_operation_outcome() {
+        let outcome = r#"{"resourceType":"OperationOutcome","issue":[{"severity":"error","code":"invalid","diagnostics":"Patient.gender: unknown code"}]}"#;
+        let report = error_report(&http_error(422, outcome));
+        let parsed: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(parsed["issue"][0]["code"], "invalid");
+        assert!(report.contains("\n  \"issue\""));
+
+        let report = error_report(&http_error(502, "Bad Gateway"));
+        assert!(report.contains("HTTP 502"));
+        assert!(report.contains("Bad Gateway"));
+    }
 
-            content[offset..offset + data.len()].copy_from_slice(data);
+    #[test]
+    fn test_handles_buffer_writes_separately() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let first = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+        let second = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+        fs.truncate_file(ino, Some(first), 0).unwrap();
+        fs.truncate_file(ino, Some(second), 0).unwrap();
+        let a = r#"{"resourceType":"Patient","id":"pt-1","active":true}"#;
+        let b = r#"{"resourceType":"Patient","id":"pt-1","active":false}"#;
+        fs.file_handles
+            .get_mut(first)
+            .unwrap()
+            .write(0, a.as_bytes());
+        fs.file_handles
+            .get_mut(second)
+            .unwrap()
+            .write(0, b.as_bytes());
+        assert!(requests(&server, Some("PUT")).is_empty());
+
+        fs.release_handle(first).unwrap();
+        fs.release_handle(second).unwrap();
+
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 2);
+        assert_eq!(puts[0].body, a);
+        assert_eq!(puts[1].body, b);
+        // Both were based on the version that was open
+        assert_eq!(puts[1].header("If-Match"), Some("W/\"1\""));
+    }
 
-            reply.written(data.len() as u32);
-        } else {
-            reply.error(ENOENT);
-        }
+    #[test]
+    fn test_single_put_per_handle() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        let content = r#"{"resourceType":"Patient","id":"pt-1","active":true}"#;
+        fs.file_handles
+            .get_mut(fh)
+            .unwrap()
+            .write(0, content.as_bytes());
+        fs.upload(fh).unwrap();
+        fs.release_handle(fh).unwrap();
+
+        assert_eq!(requests(&server, Some("PUT")).len(), 1);
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("2"));
+        assert!(resource.content.contains("\"active\": true"));
+
+        // Opening and closing without writing sends nothing
+        let fh = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+        fs.release_handle(fh).unwrap();
+        assert_eq!(requests(&server, Some("PUT")).len(), 1);
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        if self.temp_files.contains_key(&ino) {
-            reply.ok();
-            return;
-        }
+    #[test]
+    fn test_close_reports_rejected_write() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"7"}}"#,
+        );
 
-        if let Some(content) = self.pending_writes.get(&ino) {
-            if let Some(resource) = self.inode_index.get_fhir_resource(ino) {
-                if let Ok(text) = std::str::from_utf8(content) {
-                    let is_new_file = self.created_files.contains_key(&ino);
-                    let action = if is_new_file { "created" } else { "updated" };
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        // A shell redirection closes its first descriptor before writing
+        assert_eq!(fs.flush_file(ino, fh), Ok(()));
+        assert!(requests(&server, Some("PUT")).is_empty());
+
+        let content = r#"{"resourceType":"Patient","id":"pt-7","active":true}"#;
+        fs.write_file(ino, fh, 0, content.as_bytes()).unwrap();
+        assert_eq!(fs.flush_file(ino, fh), Err(ESTALE));
+        assert!(text_file(&fs, dir, "pt-7.json.error").is_some());
+
+        // Nothing new was written, so the release doesn't send it again
+        fs.release_file(ino, fh, libc::O_WRONLY).unwrap();
+        assert_eq!(requests(&server, Some("PUT")).len(), 1);
+    }
 
-                    let client = self.http_client.clone();
-                    let base_url = self.fhir_base_url.clone();
-                    let resource_type = resource.resource_type.clone();
-                    let filename = resource.filename.clone();
-                    let resource_id = resource.resource_id.clone();
-                    let content_str = text.to_string();
+    /// Write `content` over a resource file through a new handle and close it
+    fn save(fs: &mut FhirFuse, ino: u64, content: &str) -> Result<(), i32> {
+        let fh = fs.open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)?;
+        fs.write_file(ino, fh, 0, content.as_bytes())?;
+        fs.release_handle(fh)
+    }
 
-                    let result = self.runtime.block_on(async {
-                        put_to_fhir_server(
-                            &client,
-                            &base_url,
-                            &resource_type,
-                            &filename,
-                            &content_str,
-                        )
-                        .await
-                    });
+    #[test]
+    fn test_conflict_holds_the_version_until_merged() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"7"}}"#,
+        );
 
-                    match result {
-                        Ok(_response) => {
-                            println!("[FHIR] {}: {} {}", resource_type, resource_id, action);
-                            // Don't invalidate cache for newly created files - the inode is
-                            // already in our index and invalidating would cause Finder to
-                            // get ENOENT for the file it just created, leading to delete/retry cycles.
-                            // For updates, also skip invalidation to keep the current inode valid.
-                            // The resource will be refreshed on the next manual refresh or remount.
-                        }
-                        Err(e) => {
-                            println!(
-                                "[FHIR] {}: {} {} failed: {}",
-                                resource_type, resource_id, action, e
-                            );
-                        }
-                    }
-                }
-            }
-        }
-        reply.ok();
+        let changed = r#"{"resourceType":"Patient","id":"pt-7","active":true}"#;
+        assert_eq!(save(&mut fs, ino, changed), Err(ESTALE));
+        // Saving the stale content again doesn't overwrite the server's change
+        assert_eq!(save(&mut fs, ino, changed), Err(ESTALE));
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("7"));
+
+        let merged =
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"8"},"active":true}"#;
+        save(&mut fs, ino, merged).unwrap();
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 3);
+        assert_eq!(puts[2].header("If-Match"), Some("W/\"8\""));
+        assert!(text_file(&fs, dir, "pt-7.json.conflict").is_none());
     }
 
-    fn release(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        self.pending_writes.remove(&ino);
-        self.created_files.remove(&ino);
-        reply.ok();
+    #[test]
+    fn test_removing_the_conflict_file_resolves_it() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"7"}}"#,
+        );
+        let dir = fs.resource_directories["Patient"];
+
+        let changed = r#"{"resourceType":"Patient","id":"pt-7","active":true}"#;
+        assert_eq!(save(&mut fs, ino, changed), Err(ESTALE));
+        fs.unlink_file(dir, "pt-7.json.conflict").unwrap();
+        assert!(requests(&server, Some("DELETE")).is_empty());
+
+        save(&mut fs, ino, changed).unwrap();
+        assert_eq!(
+            requests(&server, Some("PUT"))[1].header("If-Match"),
+            Some("W/\"8\"")
+        );
     }
 
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: fuser::ReplyXattr) {
-        reply.size(0);
+    #[test]
+    fn test_canonical_json_format() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                json_format: Some("canonical".to_string()),
+                ..Default::default()
+            },
+        );
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+        let content = r#"{"active":true,"extension":[
+            {"url":"http://example.org/b","valueDecimal":1.50},
+            {"url":"http://example.org/a","valueDecimal":0.100}],
+            "id":"pt-1","resourceType":"Patient"}"#;
+        fs.file_handles
+            .get_mut(fh)
+            .unwrap()
+            .write(0, content.as_bytes());
+        fs.release_handle(fh).unwrap();
+
+        // The server's answer is shown in FHIR order, decimals as written
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert!(resource
+            .content
+            .starts_with("{\n  \"resourceType\": \"Patient\",\n  \"id\": \"pt-1\","));
+        assert!(resource.content.ends_with("}\n"));
+        let a = resource.content.find("example.org/a").unwrap();
+        assert!(a < resource.content.find("example.org/b").unwrap());
+        assert!(resource.content.contains("\"valueDecimal\": 1.50"));
+        assert!(resource.content.contains("\"valueDecimal\": 0.100"));
     }
 
-    fn getxattr(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
-        reply: fuser::ReplyXattr,
-    ) {
-        reply.error(ENODATA);
+    #[test]
+    fn test_append_and_truncate_without_handle() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, r#"{"resourceType":"Patient","id":"pt-1"}"#);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_APPEND)
+            .unwrap();
+        fs.file_handles.get_mut(fh).unwrap().write(0, b" ");
+        let written = fs.file_handles.get(fh).unwrap().content().to_vec();
+        assert!(written.ends_with(b"} "));
+        // Only whitespace changed, so there is nothing to send
+        fs.release_handle(fh).unwrap();
+        assert!(requests(&server, Some("PUT")).is_empty());
+
+        // truncate(1) on a file nobody has open is uploaded at once; an
+        // empty file isn't a resource, so it never reaches the server
+        assert_eq!(fs.truncate_file(ino, None, 0), Err(EINVAL));
+        assert!(requests(&server, Some("PUT")).is_empty());
     }
 
-    fn setxattr(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
-        _flags: i32,
-        _position: u32,
-        reply: ReplyEmpty,
-    ) {
-        reply.ok();
+    #[test]
+    fn test_writes_to_deleted_file_are_dropped() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+        fs.file_handles.get_mut(fh).unwrap().detach();
+        fs.file_handles.get_mut(fh).unwrap().write(0, b"{}");
+        fs.release_handle(fh).unwrap();
+        assert!(requests(&server, Some("PUT")).is_empty());
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        if let Some((_, _, content)) = self.temp_files.get_mut(&ino) {
-            if let Some(new_size) = size {
-                content.resize(new_size as usize, 0);
-            }
-            let ts = std::time::SystemTime::now();
-            let attr = FileAttr {
-                ino,
-                size: content.len() as u64,
-                blocks: 1,
-                atime: ts,
-                mtime: ts,
-                ctime: ts,
-                crtime: ts,
-                kind: fuser::FileType::RegularFile,
-                perm: mode.map(|m| m as u16).unwrap_or(0o644),
-                nlink: 1,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
-            reply.attr(&TTL, &attr);
-            return;
+    const SAVED: &str = r#"{"resourceType":"Patient","id":"pt-1","active":true}"#;
+
+    /// Write a temp file through the calls an editor makes
+    fn write_temp_file(fs: &mut FhirFuse, dir: u64, name: &str, content: &str) -> u64 {
+        let (ino, fh) = fs
+            .create_file(dir, name, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL)
+            .unwrap();
+        fs.write_file(ino, fh, 0, content.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+        ino
+    }
+
+    /// The save reached the server as one update of version 1, and the type
+    /// directory shows a single `pt-1.json` with the stored content.
+    /// Returns its inode.
+    fn assert_saved_once(fs: &FhirFuse, server: &MockServer) -> u64 {
+        let writes: Vec<MockRequest> = requests(server, None)
+            .into_iter()
+            .filter(|req| req.method != "GET")
+            .collect();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].method, "PUT");
+        assert_eq!(writes[0].path, "/Patient/pt-1");
+        assert_eq!(writes[0].header("If-Match"), Some("W/\"1\""));
+        assert_eq!(writes[0].body, SAVED);
+
+        let dir = fs.resource_directories["Patient"];
+        let files: Vec<u64> = fs
+            .inode_index
+            .get_children(dir)
+            .into_iter()
+            .filter(|&ino| fs.inode_index.get_fhir_resource(ino).is_some())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let resource = fs.inode_index.get_fhir_resource(files[0]).unwrap();
+        assert_eq!(resource.filename, "pt-1.json");
+        assert_eq!(resource.version_id.as_deref(), Some("2"));
+        assert!(resource.content.contains("\"active\": true"));
+        files[0]
+    }
+
+    #[test]
+    fn test_is_temp_file() {
+        for name in [
+            ".pt-1.json.swp",
+            "4913",
+            "pt-1.json~",
+            "#pt-1.json#",
+            "pt-1.json.tmp",
+            ".pt-1.json.tmp-1234",
+            "sedAbC123",
+            ".DS_Store",
+        ] {
+            assert!(is_temp_file(name), "{}", name);
         }
+        assert!(!is_temp_file("pt-1.json"));
+    }
 
-        if let Some(mut attr) = self.get_attrs(ino) {
-            if let Some(new_size) = size {
-                attr.size = new_size;
+    #[test]
+    fn test_vim_save() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        write_temp_file(&mut fs, dir, ".pt-1.json.swp", "swap");
+        // Probe whether the directory is writable
+        write_temp_file(&mut fs, dir, "4913", "");
+        fs.unlink_file(dir, "4913").unwrap();
+        // Keep the original as a backup and write a new file in its place
+        fs.rename_file(dir, "pt-1.json", dir, "pt-1.json~").unwrap();
+        assert_eq!(fs.temp_file_inode(dir, "pt-1.json~"), Some(ino));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json")
+            .is_none());
+        let (new, fh) = fs
+            .create_file(
+                dir,
+                "pt-1.json",
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            )
+            .unwrap();
+        fs.write_file(new, fh, 0, SAVED.as_bytes()).unwrap();
+        fs.upload(fh).unwrap();
+        fs.release_handle(fh).unwrap();
+        fs.unlink_file(dir, "pt-1.json~").unwrap();
+        fs.unlink_file(dir, ".pt-1.json.swp").unwrap();
+
+        assert_saved_once(&fs, &server);
+        assert!(fs.temp_files.is_empty());
+    }
 
-                if let Some(content) = self.pending_writes.get_mut(&ino) {
-                    content.resize(new_size as usize, 0);
-                }
-            }
+    #[test]
+    fn test_emacs_save() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        write_temp_file(&mut fs, dir, "#pt-1.json#", SAVED);
+        fs.rename_file(dir, "pt-1.json", dir, "pt-1.json~").unwrap();
+        let (new, fh) = fs
+            .create_file(
+                dir,
+                "pt-1.json",
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            )
+            .unwrap();
+        fs.write_file(new, fh, 0, SAVED.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+        fs.unlink_file(dir, "#pt-1.json#").unwrap();
+
+        assert_saved_once(&fs, &server);
+        // The backup stays readable until it's removed
+        let backup = fs.temp_file_inode(dir, "pt-1.json~").unwrap();
+        assert!(fs.temp_files[&backup].2.starts_with(b"{"));
+    }
 
-            if let Some(new_mode) = mode {
-                attr.perm = new_mode as u16;
-            }
+    #[test]
+    fn test_unchanged_saves_are_skipped() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+        let reformatted = "{\n  \"id\": \"pt-1\",\n  \"resourceType\": \"Patient\",\n  \"meta\": {\"versionId\": \"7\", \"lastUpdated\": \"2024-01-01T00:00:00Z\"}\n}\n";
+
+        let temp = write_temp_file(&mut fs, dir, "pt-1.json.tmp", reformatted);
+        fs.rename_file(dir, "pt-1.json.tmp", dir, "pt-1.json")
+            .unwrap();
+        assert_eq!(fs.resolve_inode(temp), ino);
+        assert!(fs.temp_files.is_empty());
+
+        // emacs: the file moved aside comes back when the same thing is saved
+        fs.rename_file(dir, "pt-1.json", dir, "pt-1.json~").unwrap();
+        let (new, fh) = fs
+            .create_file(dir, "pt-1.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(new, fh, 0, reformatted.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+        assert!(requests(&server, Some("PUT")).is_empty());
+        let resource = fs.inode_index.get_fhir_resource(new).unwrap();
+        assert!(resource.content.starts_with('{'));
+        assert_eq!(resource.version_id.as_deref(), Some("1"));
+
+        let fh = fs.open_for_write(new, libc::O_WRONLY).unwrap();
+        fs.truncate_file(new, Some(fh), 0).unwrap();
+        fs.write_file(new, fh, 0, SAVED.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+        assert_eq!(requests(&server, Some("PUT")).len(), 1);
+    }
 
-            reply.attr(&TTL, &attr);
-        } else {
-            reply.error(ENOENT);
-        }
+    #[test]
+    fn test_vscode_save() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        let temp = write_temp_file(&mut fs, dir, "pt-1.json.tmp", SAVED);
+        fs.rename_file(dir, "pt-1.json.tmp", dir, "pt-1.json")
+            .unwrap();
+
+        assert_eq!(assert_saved_once(&fs, &server), ino);
+        // The kernel knows the saved file by the temp file's inode
+        assert_eq!(fs.resolve_inode(temp), ino);
+        assert!(fs.temp_files.is_empty());
     }
 
-    fn access(&mut self, _req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
-        if self.inode_index.get(ino).is_some() || self.temp_files.contains_key(&ino) {
-            reply.ok();
-        } else {
-            println!("[access]: ENOENT for ino={}", ino);
-            reply.error(ENOENT);
-        }
+    #[test]
+    fn test_sed_in_place() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        write_temp_file(&mut fs, dir, "sedAbC123", SAVED);
+        fs.rename_file(dir, "sedAbC123", dir, "pt-1.json").unwrap();
+
+        assert_eq!(assert_saved_once(&fs, &server), ino);
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
-        // Report 5 GB total, 4.5 GB free to allow drag-and-drop in Finder
-        let block_size: u32 = 4096;
-        let total_size: u64 = 5 * 1024 * 1024 * 1024; // 5 GB
-        let free_size: u64 = 4_500_000_000; // 4.5 GB free
-        let total_blocks = total_size / block_size as u64;
-        let free_blocks = free_size / block_size as u64;
-        reply.statfs(
-            total_blocks, // total blocks
-            free_blocks,  // free blocks
-            free_blocks,  // available blocks (same as free for our purposes)
-            1_000_000,    // total inodes
-            999_000,      // free inodes
-            block_size,   // block size
-            255,          // max name length
-            block_size,   // fragment size
+    #[test]
+    fn test_rename_creates_new_resource() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+
+        let content = r#"{"resourceType":"Patient","id":"pt-2"}"#;
+        let temp = write_temp_file(&mut fs, dir, "pt-2.json.tmp", content);
+        fs.rename_file(dir, "pt-2.json.tmp", dir, "pt-2.json")
+            .unwrap();
+
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 1);
+        assert_eq!(puts[0].header("If-Match"), None);
+        assert_eq!(
+            fs.inode_index.find_child_by_name(dir, "pt-2.json"),
+            Some(temp)
         );
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        match self.inode_index.get(ino) {
-            Some(VFSEntry::Directory(dir)) => {
-                println!("[opendir]: inode: {}, path: {}", ino, dir.name);
-                reply.opened(0, 0);
-            }
-            Some(VFSEntry::SearchPath(search)) => {
-                println!("[opendir]: inode: {}, path: {}", ino, search.path);
-                reply.opened(0, 0);
-            }
-            Some(VFSEntry::SearchQuery(query)) => {
-                println!("[opendir]: inode: {}, path: {}", ino, query.path);
-                reply.opened(0, 0);
-            }
-            Some(VFSEntry::SearchResultGroup(group)) => {
-                println!("[opendir]: inode: {}, path: {}", ino, group.path);
-                reply.opened(0, 0);
-            }
-            Some(VFSEntry::OperationPath(op)) => {
-                println!("[opendir]: inode: {}, path: {}", ino, op.path);
-                reply.opened(0, 0);
-            }
-            Some(_) => {
-                println!("[opendir]: inode: {}, error: not a directory", ino);
-                reply.error(libc::ENOTDIR);
-            }
-            None => {
-                println!("[opendir]: inode: {}, error: not found", ino);
-                reply.error(ENOENT);
-            }
-        }
+    #[test]
+    fn test_rejected_save_keeps_temp_file() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        let temp = write_temp_file(&mut fs, dir, "pt-1.json.tmp", "{");
+        assert_eq!(
+            fs.rename_file(dir, "pt-1.json.tmp", dir, "pt-1.json"),
+            Err(EINVAL)
+        );
+        assert_eq!(fs.temp_file_inode(dir, "pt-1.json.tmp"), Some(temp));
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("1"));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json.error")
+            .is_some());
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        if self.temp_files.contains_key(&ino) {
-            reply.opened(0, 0);
-            return;
-        }
+    #[test]
+    fn test_resource_type_and_id_are_checked() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Observation"}"#)
+            .unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        let error = text_file(&fs, dir, "pt-1.json.error").unwrap();
+        assert!(error.contains("resourceType is \"Observation\""));
+
+        fs.truncate_file(ino, Some(fh), 0).unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Patient","id":"other"}"#)
+            .unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        assert!(requests(&server, Some("PUT")).is_empty());
+
+        // A missing id is taken from the filename
+        fs.truncate_file(ino, Some(fh), 0).unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Patient","active":true}"#)
+            .unwrap();
+        fs.release_handle(fh).unwrap();
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 1);
+        assert_eq!(
+            puts[0].body,
+            r#"{"id": "pt-1","resourceType":"Patient","active":true}"#
+        );
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json.error")
+            .is_none());
+    }
 
-        match self.inode_index.get(ino) {
-            Some(VFSEntry::TextFile(_))
-            | Some(VFSEntry::FHIRResource(_))
-            | Some(VFSEntry::ResourceVersion(_)) => {
-                reply.opened(0, 0);
-            }
-            Some(VFSEntry::OperationExecution(exec)) => {
-                // Ensure operation is executed if not cached
-                if exec.result.is_none() {
-                    let client = self.http_client.clone();
-                    let base_url = self.fhir_base_url.clone();
-                    let rt = exec.resource_type.clone();
-                    let rid = exec.resource_id.clone();
-                    let op = format!("${}", exec.operation_name);
-                    let fmt = exec.format.clone();
+    #[test]
+    fn test_relaxed_json_is_sent_strict() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(
+            ino,
+            fh,
+            0,
+            b"{\n  \"resourceType\": \"Patient\",\n  \"active\" true\n}",
+        )
+        .unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        let error = text_file(&fs, dir, "pt-1.json.error").unwrap();
+        assert!(error.contains("line 3, column 12: expected `:`"));
+
+        fs.truncate_file(ino, Some(fh), 0).unwrap();
+        let drafted = "{\n  \"resourceType\": \"Patient\",\n  \"id\": \"pt-1\",\n  \
+                       // waiting for the discharge letter\n  \"active\": true,\n}\n";
+        fs.write_file(ino, fh, 0, drafted.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 1);
+        assert_eq!(
+            puts[0].body,
+            "{\n  \"resourceType\": \"Patient\",\n  \"id\": \"pt-1\",\n  \"active\": true\n}\n"
+        );
+    }
 
-                    let result = self.runtime.block_on(async move {
-                        execute_operation(&client, &base_url, &rt, &rid, &op, &fmt).await
-                    });
+    #[test]
+    fn test_validation_on_server() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                validate: Some("server".to_string()),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Patient","active":true}"#)
+            .unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        assert!(requests(&server, Some("PUT")).is_empty());
+        assert_eq!(
+            text_file(&fs, dir, "pt-1.json.error").unwrap(),
+            "The resource is not valid:\n- Patient.gender: gender is required\n"
+        );
 
-                    match result {
-                        Ok(content) => {
-                            // Cache the result
-                            if let Some(exec_mut) =
-                                self.operation_manager.get_operation_execution_mut(ino)
-                            {
-                                exec_mut.result = Some(content);
-                                exec_mut.last_executed = Some(std::time::Instant::now());
-                            }
-                            reply.opened(0, 0);
-                        }
-                        Err(e) => {
-                            println!("[open] Failed to execute operation: {}", e);
-                            reply.error(EIO);
-                        }
-                    }
-                } else {
-                    reply.opened(0, 0);
-                }
-            }
-            Some(_) => {
-                reply.error(libc::EISDIR);
-            }
-            None => {
-                reply.error(ENOENT);
-            }
-        }
+        fs.truncate_file(ino, Some(fh), 0).unwrap();
+        fs.write_file(
+            ino,
+            fh,
+            0,
+            br#"{"resourceType":"Patient","gender":"other"}"#,
+        )
+        .unwrap();
+        fs.release_handle(fh).unwrap();
+        assert_eq!(
+            requests(&server, None),
+            vec![
+                "POST /Patient/$validate",
+                "POST /Patient/$validate",
+                "PUT /Patient/pt-1"
+            ]
+        );
+        assert!(text_file(&fs, dir, "pt-1.json.error").is_none());
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name_str = name.to_str().unwrap_or("");
+    #[test]
+    fn test_local_validation() {
+        let packages =
+            std::env::temp_dir().join(format!("fhir-fuse-packages-{}", std::process::id()));
+        std::fs::create_dir_all(packages.join("package")).unwrap();
+        let element = |path: &str, min: u64, code: &str| serde_json::json!({"id": path, "path": path, "min": min, "max": "1", "type": [{"code": code}]});
+        let patient = serde_json::json!({
+            "resourceType": "StructureDefinition",
+            "url": "http://hl7.org/fhir/StructureDefinition/Patient",
+            "type": "Patient",
+            "snapshot": {"element": [
+                {"id": "Patient", "path": "Patient", "min": 0, "max": "*"},
+                element("Patient.id", 0, "http://hl7.org/fhirpath/System.String"),
+                element("Patient.meta", 0, "Meta"),
+                element("Patient.active", 0, "boolean"),
+                element("Patient.gender", 1, "code")
+            ]}
+        });
+        std::fs::write(
+            packages.join("package/StructureDefinition-Patient.json"),
+            patient.to_string(),
+        )
+        .unwrap();
+
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                validate: Some("local".to_string()),
+                packages: Some(vec![packages.clone()]),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        std::fs::remove_dir_all(&packages).unwrap();
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Patient","active":"yes"}"#)
+            .unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        assert_eq!(
+            text_file(&fs, dir, "pt-1.json.error").unwrap(),
+            "The resource is not valid:\n\
+             - Patient.active: must be true or false (boolean), found a string\n\
+             - Patient.gender: is required\n"
+        );
+        // Nothing was sent, not even for validation
+        assert!(requests(&server, None).is_empty());
+
+        fs.truncate_file(ino, Some(fh), 0).unwrap();
+        fs.write_file(
+            ino,
+            fh,
+            0,
+            br#"{"resourceType":"Patient","gender":"other"}"#,
+        )
+        .unwrap();
+        fs.release_handle(fh).unwrap();
+        assert_eq!(requests(&server, None), vec!["PUT /Patient/pt-1"]);
+    }
+
+    /// A patient referencing itself, an organization on the server and a
+    /// practitioner that doesn't exist
+    const REFERENCING_PATIENT: &[u8] = br#"{"resourceType":"Patient",
+        "link":[{"other":{"reference":"Patient/pt-1"},"type":"seealso"}],
+        "managingOrganization":{"reference":"Organization/org-1"},
+        "generalPractitioner":[{"reference":"Practitioner/gone"}]}"#;
+
+    #[test]
+    fn test_dangling_references_block() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                check_references: Some("block".to_string()),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, REFERENCING_PATIENT).unwrap();
+        assert_eq!(fs.upload(fh), Err(EINVAL));
+        assert_eq!(
+            text_file(&fs, dir, "pt-1.json.error").unwrap(),
+            "The resource references resources that don't exist on the server:\n\
+             - Patient.generalPractitioner[0]: Practitioner/gone\n"
+        );
+        // Patient/pt-1 is in the filesystem, so only the others were looked up
+        assert_eq!(
+            requests(&server, None),
+            vec!["HEAD /Organization/org-1", "HEAD /Practitioner/gone"]
+        );
+    }
+
+    #[test]
+    fn test_dangling_references_warn() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                check_references: Some("warn".to_string()),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, REFERENCING_PATIENT).unwrap();
+        fs.release_handle(fh).unwrap();
+        assert_eq!(
+            requests(&server, None),
+            vec![
+                "HEAD /Organization/org-1",
+                "HEAD /Practitioner/gone",
+                "PUT /Patient/pt-1"
+            ]
+        );
+        assert_eq!(text_file(&fs, dir, "pt-1.json.error"), None);
+    }
 
-        let temp_inode = self
-            .temp_files
+    #[test]
+    fn test_template_from_server_definitions() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+        // Listing the directory doesn't build it
+        fs.ensure_resources_loaded("Patient", false);
+        assert!(requests(&server, None)
             .iter()
-            .find(|(_, (p, n, _))| *p == parent && n == name_str)
-            .map(|(&ino, _)| ino);
+            .all(|request| !request.path.contains("StructureDefinition")));
+
+        fs.ensure_template("Patient");
+        assert_eq!(
+            text_file(&fs, dir, TEMPLATE_FILE).unwrap(),
+            "{\n  \"resourceType\": \"Patient\",\n  \
+             \"gender\": \"\", // 1..1 male | female | other | unknown\n  \
+             \"name\": [ // 0..* A name\n    {\n      \
+             \"family\": \"\" // 0..1 Family name\n    }\n  ]\n}\n"
+        );
+        let fetched = requests(&server, None)
+            .iter()
+            .filter(|request| request.path.contains("StructureDefinition"))
+            .count();
+        assert_eq!(fetched, 2);
+
+        // Built once, not on every lookup
+        fs.ensure_template("Patient");
+        assert_eq!(
+            requests(&server, None)
+                .iter()
+                .filter(|request| request.path.contains("StructureDefinition"))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_rename_checks_content_like_a_write() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                check_references: Some("block".to_string()),
+                ..Default::default()
+            },
+        );
+        let mut patient: serde_json::Value = serde_json::from_slice(REFERENCING_PATIENT).unwrap();
+        patient["id"] = serde_json::json!("pt-3");
+        add_patient(&mut fs, &patient.to_string());
+        let dir = fs.resource_directories["Patient"];
+
+        assert_eq!(
+            fs.rename_file(dir, "pt-3.json", dir, "pt-4.json"),
+            Err(EINVAL)
+        );
+        assert!(text_file(&fs, dir, "pt-3.json.error")
+            .unwrap()
+            .contains("Practitioner/gone"));
+        assert!(requests(&server, Some("PUT")).is_empty());
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-3.json")
+            .is_some());
+    }
 
-        if let Some(inode) = temp_inode {
-            self.temp_files.remove(&inode);
-            reply.ok();
-            return;
-        }
+    #[test]
+    fn test_rename_moves_resource_to_new_id() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+        let fh = fs.open_for_write(ino, libc::O_WRONLY).unwrap();
+
+        fs.rename_file(dir, "pt-1.json", dir, "pt-2.json").unwrap();
+        assert_eq!(
+            requests(&server, None),
+            vec!["HEAD /Patient/pt-2", "POST /"]
+        );
+        let bundle: serde_json::Value =
+            serde_json::from_str(&server.requests().pop().unwrap().body).unwrap();
+        assert_eq!(bundle["type"], "transaction");
+        assert_eq!(
+            bundle["entry"][0]["request"],
+            serde_json::json!({"method": "PUT", "url": "Patient/pt-2"})
+        );
+        assert_eq!(bundle["entry"][0]["resource"]["id"], "pt-2");
+        assert_eq!(
+            bundle["entry"][1]["request"],
+            serde_json::json!({"method": "DELETE", "url": "Patient/pt-1", "ifMatch": "W/\"1\""})
+        );
 
-        let mut server_delete_needed = false;
+        assert_eq!(
+            fs.inode_index.find_child_by_name(dir, "pt-2.json"),
+            Some(ino)
+        );
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json")
+            .is_none());
+        // A handle open across the rename now writes the new resource
+        assert_eq!(fs.file_handles.get(fh).unwrap().resource_id, "pt-2");
+    }
 
-        for (_res_type, &dir_inode) in &self.resource_directories {
-            if dir_inode == parent {
-                server_delete_needed = true;
-                break;
-            }
-        }
+    #[test]
+    fn test_rename_refuses_an_id_taken_on_the_server() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        // Not listed here, but on the server
+        assert_eq!(
+            fs.rename_file(dir, "pt-1.json", dir, "taken.json"),
+            Err(EEXIST)
+        );
+        assert_eq!(requests(&server, None), vec!["HEAD /Patient/taken"]);
+        assert!(text_file(&fs, dir, "pt-1.json.error")
+            .unwrap()
+            .contains("already exists"));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json")
+            .is_some());
+    }
 
-        let file_inode = self.inode_index.find_child_by_name(parent, name_str);
+    #[test]
+    fn test_rename_keeps_a_resource_changed_meanwhile() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"7"}}"#,
+        );
+        let dir = fs.resource_directories["Patient"];
 
-        if let Some(inode) = file_inode {
-            if server_delete_needed && name_str.ends_with(".json") {
-                if let Some(resource) = self.inode_index.get_fhir_resource(inode) {
-                    let resource_type = resource.resource_type.clone();
-                    let filename = resource.filename.clone();
-                    let resource_id = resource.resource_id.clone();
+        assert_eq!(
+            fs.rename_file(dir, "pt-7.json", dir, "pt-8.json"),
+            Err(ESTALE)
+        );
+        assert!(text_file(&fs, dir, "pt-7.json.conflict")
+            .unwrap()
+            .contains("\"versionId\":\"8\""));
+        // Nothing was applied, so there is no pt-8 left behind
+        assert_eq!(
+            requests(&server, None),
+            vec![
+                "HEAD /Patient/pt-8",
+                "POST /",
+                "GET /Patient/pt-7?_pretty=true"
+            ]
+        );
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-8.json")
+            .is_none());
 
-                    let client = self.http_client.clone();
-                    let base_url = self.fhir_base_url.clone();
+        // Trying again doesn't delete the server's newer version
+        assert_eq!(
+            fs.rename_file(dir, "pt-7.json", dir, "pt-8.json"),
+            Err(ESTALE)
+        );
+        let transaction = requests(&server, Some("POST")).remove(1);
+        assert!(transaction.body.contains(r#""ifMatch":"W/\"7\"""#));
+    }
 
-                    let result = self.runtime.block_on(async {
-                        delete_from_fhir_server(&client, &base_url, &resource_type, &filename).await
-                    });
+    #[test]
+    fn test_rename_refuses_referenced_resource() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                rename_references: Some("refuse".to_string()),
+                ..Default::default()
+            },
+        );
+        add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
 
-                    match result {
-                        Ok(_) => {
-                            println!("[FHIR] {}: {} deleted", resource_type, resource_id);
-                            // Don't invalidate cache - we already removed the inode below
-                        }
-                        Err(e) => {
-                            println!(
-                                "[FHIR] {}: {} delete failed: {}",
-                                resource_type, resource_id, e
-                            );
-                            reply.error(EIO);
-                            return;
-                        }
-                    }
-                }
-            }
+        assert_eq!(
+            fs.rename_file(dir, "pt-1.json", dir, "pt-2.json"),
+            Err(EBUSY)
+        );
+        assert_eq!(
+            requests(&server, None),
+            vec!["HEAD /Patient/pt-2", "GET /Patient?_id=pt-1&_revinclude=*"]
+        );
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json.error")
+            .is_some());
+    }
 
-            self.pending_writes.remove(&inode);
-            self.created_files.remove(&inode);
-            self.inode_index.remove(inode);
+    #[test]
+    fn test_rename_rewrites_references() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                rename_references: Some("rewrite".to_string()),
+                referencing_types: Some(vec!["Observation".to_string()]),
+                ..Default::default()
+            },
+        );
+        add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        fs.rename_file(dir, "pt-1.json", dir, "pt-2.json").unwrap();
+        assert_eq!(
+            requests(&server, None),
+            vec![
+                "HEAD /Patient/pt-2",
+                "GET /Patient?_id=pt-1&_revinclude=Observation:*",
+                "POST /",
+            ]
+        );
+        let bundle: serde_json::Value =
+            serde_json::from_str(&server.requests().pop().unwrap().body).unwrap();
+        let urls: Vec<&str> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["request"]["url"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            urls,
+            vec!["Patient/pt-2", "Observation/obs-1", "Patient/pt-1"]
+        );
+        let observation = &bundle["entry"][1];
+        assert_eq!(observation["request"]["ifMatch"], "W/\"4\"");
+        assert_eq!(
+            observation["resource"]["subject"]["reference"],
+            "Patient/pt-2"
+        );
+    }
 
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
-        }
+    fn new_directory(fs: &FhirFuse) -> u64 {
+        mode_directory(fs, CreateMode::Post)
     }
 
-    fn rename(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        let name_str = name.to_str().unwrap_or("");
-        let newname_str = newname.to_str().unwrap_or("");
+    fn mode_directory(fs: &FhirFuse, mode: CreateMode) -> u64 {
+        let dir = fs.resource_directories["Patient"];
+        fs.inode_index
+            .find_child_by_name(dir, mode.directory())
+            .unwrap()
+    }
 
-        println!(
-            "[rename]: parent={}, name={}, newparent={}, newname={}",
-            parent, name_str, newparent, newname_str
+    #[test]
+    fn test_new_file_is_posted() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let new_dir = new_directory(&fs);
+
+        let (ino, fh) = fs
+            .create_file(new_dir, "import-1.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(ino, fh, 0, br#"{"resourceType":"Patient"}"#)
+            .unwrap();
+        assert!(server.requests().iter().all(|req| req.method == "GET"));
+        fs.release_file(ino, fh, libc::O_WRONLY).unwrap();
+
+        let posts = requests(&server, Some("POST"));
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].path, "/Patient");
+
+        let dir = fs.resource_directories["Patient"];
+        assert_eq!(
+            fs.inode_index.find_child_by_name(dir, "srv-1.json"),
+            Some(ino)
         );
+        assert_eq!(fs.new_file_target(new_dir, "import-1.json"), Some(ino));
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("1"));
 
-        // Check if this is a temp file being renamed
-        let temp_inode = self
-            .temp_files
-            .iter()
-            .find(|(_, (p, n, _))| *p == parent && n == name_str)
-            .map(|(&ino, _)| ino);
-
-        if let Some(inode) = temp_inode {
-            // Get the temp file content
-            if let Some((_, _, content)) = self.temp_files.remove(&inode) {
-                // Check if new parent is a resource directory
-                let mut target_resource_type: Option<String> = None;
-                for (res_type, &dir_inode) in &self.resource_directories {
-                    if dir_inode == newparent {
-                        target_resource_type = Some(res_type.clone());
-                        break;
-                    }
-                }
+        // Closing it again doesn't create another resource
+        fs.release_file(ino, 0, libc::O_WRONLY).unwrap();
+        assert_eq!(server.requests().len(), posts.len() + 1);
+    }
 
-                if let Some(resource_type) = target_resource_type {
-                    // This is a temp file being renamed to a FHIR resource file
-                    if newname_str.ends_with(".json") && !content.is_empty() {
-                        // Create the resource on the FHIR server
-                        let client = self.http_client.clone();
-                        let base_url = self.fhir_base_url.clone();
+    #[test]
+    fn test_rejected_new_file_keeps_error() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let new_dir = new_directory(&fs);
+
+        let (ino, fh) = fs
+            .create_file(new_dir, "broken.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(ino, fh, 0, b"{").unwrap();
+        // `cp` sees the rejection when it closes the file
+        assert_eq!(fs.flush_file(ino, fh), Err(EINVAL));
+        // Nothing was written since, so the release doesn't send it again
+        assert_eq!(fs.release_file(ino, fh, libc::O_WRONLY), Ok(()));
+
+        assert_eq!(fs.temp_file_inode(new_dir, "broken.json"), Some(ino));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(new_dir, "broken.json.error")
+            .is_some());
+    }
 
-                        if let Ok(content_str) = std::str::from_utf8(&content) {
-                            let result = self.runtime.block_on(async {
-                                put_to_fhir_server(
-                                    &client,
-                                    &base_url,
-                                    &resource_type,
-                                    newname_str,
-                                    content_str,
-                                )
-                                .await
-                            });
-
-                            match result {
-                                Ok(_) => {
-                                    println!(
-                                        "[FHIR] {}: {} created via rename",
-                                        resource_type, newname_str
-                                    );
-                                    // Add the resource to our inode index so it's visible
-                                    let new_inode = self.inode_allocator.allocate();
-                                    let resource_id = newname_str.trim_end_matches(".json");
-                                    let resource_entry = FHIRResource::new(
-                                        new_inode,
-                                        &resource_type,
-                                        resource_id,
-                                        content_str.to_string(),
-                                    );
-                                    self.inode_index.insert_resource(resource_entry);
-                                    self.inode_index
-                                        .add_parent_child_relation(newparent, new_inode);
-                                    // Don't invalidate cache - keep the new inode valid
-                                    reply.ok();
-                                    return;
-                                }
-                                Err(e) => {
-                                    println!(
-                                        "[FHIR] {}: {} create via rename failed: {}",
-                                        resource_type, newname_str, e
-                                    );
-                                    reply.error(EIO);
-                                    return;
-                                }
-                            }
-                        }
-                    }
-                }
+    #[test]
+    fn test_rename_into_new_directory_posts() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let dir = fs.resource_directories["Patient"];
+        let new_dir = new_directory(&fs);
+
+        let temp = write_temp_file(
+            &mut fs,
+            new_dir,
+            "a.json.tmp",
+            r#"{"resourceType":"Patient"}"#,
+        );
+        assert!(server.requests().iter().all(|req| req.method == "GET"));
+        fs.rename_file(new_dir, "a.json.tmp", new_dir, "a.json")
+            .unwrap();
 
-                // For non-resource renames, just move the temp file
-                self.temp_files
-                    .insert(inode, (newparent, newname_str.to_string(), content));
-                reply.ok();
-                return;
-            }
-        }
+        assert_eq!(
+            fs.inode_index.find_child_by_name(dir, "srv-1.json"),
+            Some(temp)
+        );
+    }
 
-        // Handle renaming regular files (not temp files)
-        if let Some(inode) = self.inode_index.find_child_by_name(parent, name_str) {
-            // Check if source is in a resource directory
-            let source_is_resource = self.resource_directories.values().any(|&dir| dir == parent);
-
-            if source_is_resource {
-                // Check if renaming within the same directory
-                if parent != newparent {
-                    // Renaming across directories is not supported
-                    println!(
-                        "[rename]: Renaming across directories not supported: {} -> {}",
-                        name_str, newname_str
-                    );
-                    reply.error(EACCES);
-                    return;
-                }
+    /// Write `content` to a new file in `dir` and close it
+    fn write_new_file(fs: &mut FhirFuse, dir: u64, name: &str, content: &str) -> u64 {
+        let (ino, fh) = fs
+            .create_file(dir, name, libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(ino, fh, 0, content.as_bytes()).unwrap();
+        fs.release_file(ino, fh, libc::O_WRONLY).unwrap();
+        ino
+    }
 
-                // Renaming within the same resource directory
-                // Get the resource and update its name in the index
-                if let Some(entry) = self.inode_index.get(inode) {
-                    if let VFSEntry::FHIRResource(resource) = entry {
-                        let resource_type = resource.resource_type.clone();
-                        let content = resource.content.clone();
-                        let new_id = newname_str.trim_end_matches(".json").to_string();
+    #[test]
+    fn test_conditional_create_finds_existing_resource() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let existing = add_patient(&mut fs, PATIENT);
+        let dir = mode_directory(&fs, CreateMode::IfNoneExist);
 
-                        // Remove old entry and insert new one with updated name
-                        self.inode_index.remove(inode);
+        let name = "identifier=urn:mrn|123.json";
+        write_new_file(&mut fs, dir, name, r#"{"resourceType":"Patient"}"#);
 
-                        let new_resource =
-                            FHIRResource::new(inode, &resource_type, &new_id, content);
+        let post = requests(&server, Some("POST")).remove(0);
+        assert_eq!(post.path, "/Patient");
+        assert_eq!(post.header("If-None-Exist"), Some("identifier=urn:mrn|123"));
+        assert_eq!(fs.new_file_target(dir, name), Some(existing));
 
-                        self.inode_index.insert_resource(new_resource);
-                        self.inode_index.add_parent_child_relation(parent, inode);
+        assert_eq!(
+            fs.create_file(dir, "pt-2.json", libc::O_WRONLY | libc::O_CREAT),
+            Err(EINVAL)
+        );
+    }
 
-                        println!(
-                            "[rename]: {} -> {} in {}",
-                            name_str, newname_str, resource_type
-                        );
+    #[test]
+    fn test_conditional_update_maps_onto_resource() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let existing = add_patient(&mut fs, PATIENT);
+        let dir = mode_directory(&fs, CreateMode::Where);
+
+        let name = "identifier=urn:mrn|123.json";
+        write_new_file(
+            &mut fs,
+            dir,
+            name,
+            r#"{"resourceType":"Patient","active":true}"#,
+        );
 
-                        reply.ok();
-                        return;
-                    }
-                }
+        let puts = requests(&server, Some("PUT"));
+        assert_eq!(puts.len(), 1);
+        assert_eq!(puts[0].path, "/Patient?identifier=urn:mrn|123");
+        assert_eq!(fs.new_file_target(dir, name), Some(existing));
+        let resource = fs.inode_index.get_fhir_resource(existing).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("2"));
+        assert!(resource.content.contains("\"active\": true"));
+    }
 
-                reply.error(ENOENT);
-                return;
-            }
+    #[test]
+    fn test_patch_file_patches_resource() {
+        let server = fhir_server();
+        let mut fs = mount(&server);
+        let ino = add_patient(&mut fs, PATIENT);
+        let dir = fs.resource_directories["Patient"];
+
+        // Not a JSON Patch: rejected before anything is sent
+        let (temp, fh) = fs
+            .create_file(dir, "pt-1.json-patch", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(temp, fh, 0, br#"{"op":"add"}"#).unwrap();
+        assert_eq!(fs.flush_file(temp, fh), Err(EINVAL));
+        assert_eq!(fs.release_file(temp, fh, libc::O_WRONLY), Ok(()));
+        assert_eq!(fs.temp_file_inode(dir, "pt-1.json-patch"), Some(temp));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json.error")
+            .is_some());
+
+        write_temp_file(
+            &mut fs,
+            dir,
+            "pt-1.json-patch.tmp",
+            r#"[{"op":"add","path":"/active","value":false}]"#,
+        );
+        fs.rename_file(dir, "pt-1.json-patch.tmp", dir, "pt-1.json-patch")
+            .unwrap();
+
+        let patches = requests(&server, Some("PATCH"));
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].path, "/Patient/pt-1");
+        assert_eq!(
+            patches[0].header("Content-Type"),
+            Some("application/json-patch+json")
+        );
+        assert!(patches[0].header("If-Match").is_none());
 
-            // For non-resource files, just return OK (we don't actually rename in the index)
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
-        }
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("3"));
+        assert!(resource.content.contains("\"active\": false"));
+        assert!(fs.temp_file_inode(dir, "pt-1.json-patch").is_none());
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-1.json.error")
+            .is_none());
     }
 
-    fn mkdir(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        let name_str = name.to_str().unwrap_or("");
+    fn edit(fs: &mut FhirFuse, ino: u64, content: &str) {
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, content.as_bytes()).unwrap();
+        fs.release_handle(fh).unwrap();
+    }
 
-        if let Some((resource_type, _search_inode)) = self.inode_index.get_search_path_info(parent)
-        {
-            let query = name_str.to_string();
+    #[test]
+    fn test_write_is_staged_after_its_entry_was_replaced() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                staging: Some(true),
+                ..Default::default()
+            },
+        );
+        let staging = fs
+            .inode_index
+            .find_child_by_name(fs.inode_allocator.root_inode, STAGING_DIRECTORY)
+            .unwrap();
+        let ino = add_patient(&mut fs, PATIENT);
+        let fh = fs
+            .open_for_write(ino, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap();
+        fs.write_file(ino, fh, 0, SAVED.as_bytes()).unwrap();
+        // The type directory is reloaded while the file is open
+        fs.inode_index.clear_resources_by_type("Patient");
+
+        fs.release_handle(fh).unwrap();
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "M Patient/pt-1.json\n"
+        );
+        assert!(requests(&server, None).is_empty());
+    }
 
-            println!("[mkdir] {}/_search/{}", resource_type, query);
+    #[test]
+    fn test_staged_writes_commit_as_one_transaction() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                staging: Some(true),
+                ..Default::default()
+            },
+        );
+        let staging = fs
+            .inode_index
+            .find_child_by_name(fs.inode_allocator.root_inode, STAGING_DIRECTORY)
+            .unwrap();
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+        add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-3","meta":{"versionId":"5"}}"#,
+        );
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "Nothing staged\n"
+        );
 
-            match self.execute_search_query(&resource_type, &query) {
-                Ok(grouped_resources) => {
-                    let query_inode = self.create_search_query_directory(
-                        query.clone(),
-                        resource_type.clone(),
-                        parent,
-                    );
+        edit(&mut fs, ino, SAVED);
+        let (new, fh) = fs
+            .create_file(dir, "pt-2.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(new, fh, 0, br#"{"resourceType":"Patient"}"#)
+            .unwrap();
+        fs.release_handle(fh).unwrap();
+        fs.unlink_file(dir, "pt-3.json").unwrap();
+
+        // Nothing reaches the server, but the files show the staged changes
+        assert!(requests(&server, None).is_empty());
+        assert!(fs
+            .inode_index
+            .get_fhir_resource(ino)
+            .unwrap()
+            .content
+            .contains("\"active\""));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-3.json")
+            .is_none());
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "M Patient/pt-1.json\nA Patient/pt-2.json\nD Patient/pt-3.json\n"
+        );
+        let diff = text_file(&fs, staging, "Patient/pt-1.json.diff").unwrap();
+        assert!(diff.starts_with("--- a/Patient/pt-1.json\n+++ b/Patient/pt-1.json\n"));
+        assert!(diff.contains("\n+  \"active\": true"));
+
+        let commit = inode_at(&fs, staging, "commit").unwrap();
+        assert_eq!(fs.run_staging_command(commit), Some(Ok(())));
+        assert_eq!(requests(&server, None), vec!["POST /"]);
+        let bundle: serde_json::Value =
+            serde_json::from_str(&server.requests().last().unwrap().body).unwrap();
+        assert_eq!(bundle["type"], "transaction");
+        let requests: Vec<&serde_json::Value> = bundle["entry"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| &entry["request"])
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                &serde_json::json!({"method": "PUT", "url": "Patient/pt-1", "ifMatch": "W/\"1\""}),
+                &serde_json::json!({"method": "PUT", "url": "Patient/pt-2"}),
+                &serde_json::json!({"method": "DELETE", "url": "Patient/pt-3", "ifMatch": "W/\"5\""}),
+            ]
+        );
 
-                    // Create SearchResultGroup for each resource type in results
-                    for (res_type, resources) in grouped_resources {
-                        let group_inode =
-                            self.create_search_result_group(res_type.clone(), query_inode);
+        // The files now refer to the committed versions
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "Nothing staged\n"
+        );
+        assert!(inode_at(&fs, staging, "Patient").is_none());
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("2"));
+        let created = fs.inode_index.find_child_by_name(dir, "pt-2.json").unwrap();
+        let created = fs.inode_index.get_fhir_resource(created).unwrap();
+        assert_eq!(created.version_id.as_deref(), Some("2"));
+    }
 
-                        // Add resources to the group
-                        self.add_resources_to_group(resources, group_inode);
+    #[test]
+    fn test_rejected_commit_stays_staged_until_aborted() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                staging: Some(true),
+                ..Default::default()
+            },
+        );
+        let staging = fs
+            .inode_index
+            .find_child_by_name(fs.inode_allocator.root_inode, STAGING_DIRECTORY)
+            .unwrap();
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+        let original = fs
+            .inode_index
+            .get_fhir_resource(ino)
+            .unwrap()
+            .content
+            .clone();
+        let locked = add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"locked","meta":{"versionId":"1"}}"#,
+        );
+        edit(&mut fs, ino, SAVED);
+        edit(
+            &mut fs,
+            locked,
+            r#"{"resourceType":"Patient","id":"locked","active":true}"#,
+        );
+        // Writes that can't be part of a transaction are refused
+        assert_eq!(
+            fs.rename_file(dir, "pt-1.json", dir, "pt-9.json"),
+            Err(EPERM)
+        );
 
-                        println!(
-                            "[Search] Added resources to group {} (inode {})",
-                            res_type, group_inode
-                        );
-                    }
+        let commit = inode_at(&fs, staging, "commit").unwrap();
+        assert_eq!(fs.run_staging_command(commit), Some(Err(EBUSY)));
+        assert!(text_file(&fs, staging, "commit.error")
+            .unwrap()
+            .contains("Patient/locked is locked"));
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "M Patient/locked.json\nM Patient/pt-1.json\n"
+        );
 
-                    if let Some(attr) = self.inode_index.get_attr(query_inode) {
-                        reply.entry(&TTL, &attr, 0);
-                    } else {
-                        reply.error(EIO);
-                    }
-                }
-                Err(e) => {
-                    println!("[Search] Failed: {}", e);
-                    reply.error(EIO);
-                }
-            }
-            return;
-        }
+        let abort = inode_at(&fs, staging, "abort").unwrap();
+        assert_eq!(fs.run_staging_command(abort), Some(Ok(())));
+        assert_eq!(requests(&server, None), vec!["POST /"]);
+        assert_eq!(
+            text_file(&fs, staging, "status").unwrap(),
+            "Nothing staged\n"
+        );
+        assert_eq!(text_file(&fs, staging, "commit.error"), None);
+        let restored = fs.inode_index.find_child_by_name(dir, "pt-1.json").unwrap();
+        let restored = fs.inode_index.get_fhir_resource(restored).unwrap();
+        assert_eq!(restored.content, original);
+        assert_eq!(restored.version_id.as_deref(), Some("1"));
+    }
 
-        // mkdir not allowed elsewhere
-        reply.error(EACCES);
+    #[test]
+    fn test_write_behind_sends_batches() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                write_behind: Some(true),
+                batch_window_ms: Some(60_000),
+                batch_size: Some(2),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+        edit(&mut fs, ino, SAVED);
+        let (bad, fh) = fs
+            .create_file(dir, "bad.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(bad, fh, 0, br#"{"resourceType":"Patient"}"#)
+            .unwrap();
+        fs.release_handle(fh).unwrap();
+        let (new, fh) = fs
+            .create_file(dir, "pt-2.json", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        fs.write_file(new, fh, 0, br#"{"resourceType":"Patient"}"#)
+            .unwrap();
+        fs.upload(fh).unwrap();
+
+        // Closing the files only queued them
+        assert!(requests(&server, None).is_empty());
+        assert!(!fs
+            .inode_index
+            .get_fhir_resource(bad)
+            .unwrap()
+            .content
+            .is_empty());
+        assert!(fs
+            .inode_index
+            .get_fhir_resource(ino)
+            .unwrap()
+            .content
+            .contains("\"active\":true"));
+
+        // What fsync does: everything goes out, two entries per Bundle
+        fs.flush_write_behind();
+        assert_eq!(requests(&server, None), vec!["POST /", "POST /"]);
+        let entries: Vec<serde_json::Value> = requests(&server, Some("POST"))
+            .iter()
+            .flat_map(|req| {
+                let bundle: serde_json::Value = serde_json::from_str(&req.body).unwrap();
+                assert_eq!(bundle["type"], "batch");
+                bundle["entry"].as_array().unwrap().clone()
+            })
+            .collect();
+        // The Bundles are sent concurrently, so they arrive in any order
+        assert_eq!(entries.len(), 3);
+        let updated = entries
+            .iter()
+            .find(|entry| entry["request"]["url"] == "Patient/pt-1")
+            .unwrap();
+        assert_eq!(updated["request"]["ifMatch"], "W/\"1\"");
+
+        // Each file has its own outcome
+        // Reported once, to whoever next opens, closes or syncs the file
+        assert_eq!(fs.take_write_error(bad), Err(EINVAL));
+        assert_eq!(fs.take_write_error(bad), Ok(()));
+        assert_eq!(fs.take_write_error(ino), Ok(()));
+        assert!(text_file(&fs, dir, "bad.json.error")
+            .unwrap()
+            .contains("bad is bad"));
+        // The rejected file shows what it showed before it was written
+        assert_eq!(fs.inode_index.get_fhir_resource(bad).unwrap().content, "");
+        let updated = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(updated.version_id.as_deref(), Some("2"));
+        assert_eq!(
+            fs.file_handles.get(fh).unwrap().version_id.as_deref(),
+            Some("2")
+        );
+        fs.release_handle(fh).unwrap();
+        assert_eq!(requests(&server, None).len(), 2);
     }
-}
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    #[test]
+    fn test_write_behind_is_sent_before_deletes_and_renames() {
+        let server = fhir_server();
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                write_behind: Some(true),
+                batch_window_ms: Some(60_000),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+        edit(&mut fs, ino, SAVED);
+        fs.unlink_file(dir, "pt-1.json").unwrap();
+        // Nothing is left to recreate it
+        fs.flush_write_behind();
+        assert_eq!(
+            requests(&server, None),
+            vec!["POST /", "DELETE /Patient/pt-1"]
+        );
 
-    if args.len() != 3 {
-        eprintln!("Usage: {} <mountpoint> <fhir_base_url>", args[0]);
-        eprintln!("Example: {} /tmp/fhir http://localhost:8080/fhir", args[0]);
-        std::process::exit(1);
+        let ino = add_patient(&mut fs, PATIENT);
+        edit(&mut fs, ino, SAVED);
+        fs.rename_file(dir, "pt-1.json", dir, "pt-2.json").unwrap();
+        // The batch, then the rename's transaction, which carried the
+        // written content
+        let bundles: Vec<serde_json::Value> = requests(&server, Some("POST"))
+            .iter()
+            .map(|req| serde_json::from_str(&req.body).unwrap())
+            .collect();
+        assert_eq!(bundles.len(), 3);
+        assert_eq!(bundles[1]["type"], "batch");
+        assert_eq!(bundles[2]["type"], "transaction");
+        assert_eq!(bundles[2]["entry"][0]["resource"]["active"], true);
     }
 
-    let mountpoint = &args[1];
-    let fhir_base_url = &args[2];
+    /// Nothing listens on the discard port, so every request fails to connect
+    const UNREACHABLE: &str = "http://127.0.0.1:9";
+
+    #[test]
+    fn test_journal_replays_in_order_when_the_server_is_back() {
+        let server = fhir_server();
+        let path = journal_path("replay");
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                journal: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let root = fs.inode_allocator.root_inode;
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+        add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-3","meta":{"versionId":"4"}}"#,
+        );
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "Nothing pending\n"
+        );
 
-    println!("Mounting FHIR filesystem at: {}", mountpoint);
-    println!("FHIR server: {}", fhir_base_url);
+        fs.fhir_base_url = UNREACHABLE.to_string();
+        edit(&mut fs, ino, SAVED);
+        // Once something is pending, later changes wait behind it
+        fs.fhir_base_url = server.url("");
+        edit(&mut fs, ino, &SAVED.replace("true", "false"));
+        fs.unlink_file(dir, "pt-3.json").unwrap();
+        assert!(requests(&server, None).is_empty());
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "M Patient/pt-1.json\nM Patient/pt-1.json\nD Patient/pt-3.json\n"
+        );
+        assert!(fs
+            .inode_index
+            .get_fhir_resource(ino)
+            .unwrap()
+            .content
+            .contains("\"active\":false"));
+        assert!(fs
+            .inode_index
+            .find_child_by_name(dir, "pt-3.json")
+            .is_none());
+        // On disk for the next mount, should this one end first
+        assert_eq!(Journal::open(&path).unwrap().entries().len(), 3);
+
+        fs.replay_journal();
+        assert_eq!(
+            requests(&server, None),
+            vec![
+                "PUT /Patient/pt-1",
+                "PUT /Patient/pt-1",
+                "DELETE /Patient/pt-3"
+            ]
+        );
+        // The second edit builds on the version the first one stored
+        let if_match: Vec<_> = server
+            .requests()
+            .iter()
+            .filter_map(|req| req.header("If-Match").map(String::from))
+            .collect();
+        assert_eq!(if_match, vec!["W/\"1\"", "W/\"2\"", "W/\"4\""]);
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "Nothing pending\n"
+        );
+        assert!(Journal::open(&path).unwrap().is_empty());
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert_eq!(resource.version_id.as_deref(), Some("2"));
+        assert!(resource.content.contains("\"active\": false"));
+        std::fs::remove_file(&path).unwrap();
+    }
 
-    // Create tokio runtime
-    let runtime = Arc::new(
-        tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(4)
-            .enable_all()
-            .build()
-            .expect("Failed to create Tokio runtime"),
-    );
+    #[test]
+    fn test_journal_conflict_keeps_the_server_version() {
+        let server = fhir_server();
+        let path = journal_path("conflict");
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                journal: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let dir = fs.resource_directories["Patient"];
+        let root = fs.inode_allocator.root_inode;
+        let ino = add_patient(
+            &mut fs,
+            r#"{"resourceType":"Patient","id":"pt-7","meta":{"versionId":"7"}}"#,
+        );
 
-    // Create HTTP client
-    let http_client = Client::new();
+        fs.fhir_base_url = UNREACHABLE.to_string();
+        edit(
+            &mut fs,
+            ino,
+            r#"{"resourceType":"Patient","id":"pt-7","active":true}"#,
+        );
+        // Still unreachable: nothing is lost
+        fs.replay_journal();
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "M Patient/pt-7.json\n"
+        );
 
-    let fs = FhirFuse::new(fhir_base_url.clone(), http_client, runtime);
+        fs.fhir_base_url = server.url("");
+        fs.replay_journal();
+        assert_eq!(
+            requests(&server, None),
+            vec!["PUT /Patient/pt-7", "GET /Patient/pt-7?_pretty=true"]
+        );
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "Nothing pending\n"
+        );
+        assert!(text_file(&fs, dir, "pt-7.json.error")
+            .unwrap()
+            .contains("Version 8 is current"));
+        assert!(text_file(&fs, dir, "pt-7.json.conflict")
+            .unwrap()
+            .contains("\"versionId\":\"8\""));
+        // The change outlives the mount next to the journal
+        let mut rejected = path.clone().into_os_string();
+        rejected.push(".rejected");
+        let rejected = std::path::PathBuf::from(rejected);
+        let kept = std::fs::read_to_string(rejected.join("Patient/pt-7.json")).unwrap();
+        assert!(kept.contains("\"active\":true"));
+        std::fs::remove_dir_all(&rejected).unwrap();
+        // The file keeps the change, still against the version it was made
+        // on until it is merged
+        let resource = fs.inode_index.get_fhir_resource(ino).unwrap();
+        assert!(resource.content.contains("\"active\":true"));
+        assert_eq!(resource.version_id.as_deref(), Some("7"));
+        std::fs::remove_file(&path).unwrap();
+    }
 
-    let options = vec![
-        MountOption::RW,
-        MountOption::CUSTOM("direct_io".to_string()),
-        MountOption::CUSTOM("max_readahead=0".to_string()),
-        MountOption::CUSTOM("sync_read".to_string()),
-        MountOption::Sync,
-        MountOption::DirSync,
-        MountOption::FSName("fhir-fuse".to_string()),
-        MountOption::CUSTOM("noappledouble".to_string()),
-        MountOption::CUSTOM("noapplexattr".to_string()),
-        MountOption::AllowOther, // Allow other users/apps to access
-    ];
+    #[test]
+    fn test_unjournaled_changes_wait_for_the_journal() {
+        let server = fhir_server();
+        let path = journal_path("wait");
+        let mut fs = mount_with(
+            &server,
+            config::Settings {
+                journal: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let root = fs.inode_allocator.root_inode;
+        let dir = fs.resource_directories["Patient"];
+        let ino = add_patient(&mut fs, PATIENT);
+
+        fs.fhir_base_url = UNREACHABLE.to_string();
+        edit(&mut fs, ino, SAVED);
+        assert_eq!(
+            fs.rename_file(dir, "pt-1.json", dir, "pt-2.json"),
+            Err(EAGAIN)
+        );
+        let (patch, fh) = fs
+            .create_file(dir, "pt-1.json-patch", libc::O_WRONLY | libc::O_CREAT)
+            .unwrap();
+        let operations = br#"[{"op":"add","path":"/active","value":false}]"#;
+        fs.write_file(patch, fh, 0, operations).unwrap();
+        assert_eq!(fs.flush_file(patch, fh), Err(EAGAIN));
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "M Patient/pt-1.json\n"
+        );
 
-    match fuser::mount2(fs, mountpoint, &options) {
-        Ok(_) => println!("Filesystem unmounted"),
-        Err(e) => eprintln!("Failed to mount filesystem: {}", e),
+        // Back, and due for another try
+        fs.fhir_base_url = server.url("");
+        fs.journal_retry = None;
+        fs.rename_file(dir, "pt-1.json", dir, "pt-2.json").unwrap();
+        let writes = requests(&server, None);
+        assert_eq!(writes[0], "PUT /Patient/pt-1");
+        assert_eq!(writes[1], "HEAD /Patient/pt-2");
+        assert_eq!(
+            text_file(&fs, root, JOURNAL_STATUS).unwrap(),
+            "Nothing pending\n"
+        );
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,275 @@
+use crate::fhir::same_resource;
+use serde_json::{json, Value};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+
+/// Writes held back by `--staging` until they are committed as one
+/// transaction, keyed by `(resource type, id)`
+#[derive(Debug, Default)]
+pub struct Staging {
+    changes: BTreeMap<(String, String), Change>,
+}
+
+/// A staged resource, compared with what the server had when it was first
+/// staged
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Content on the server, `None` for a resource the server doesn't have
+    pub base: Option<String>,
+    /// Version of `base`, sent as `ifMatch`
+    pub version_id: Option<String>,
+    /// What the resource becomes, `None` when it is deleted
+    pub content: Option<String>,
+}
+
+impl Change {
+    fn status(&self) -> char {
+        match (&self.base, &self.content) {
+            (None, _) => 'A',
+            (Some(_), None) => 'D',
+            (Some(_), Some(_)) => 'M',
+        }
+    }
+
+    /// Unified diff of the change, between `a/<path>` and `b/<path>`
+    pub fn diff(&self, path: &str) -> String {
+        let old = self.base.as_deref().unwrap_or_default();
+        let new = self.content.as_deref().unwrap_or_default();
+        let old_header = match self.base {
+            Some(_) => format!("a/{}", path),
+            None => "/dev/null".to_string(),
+        };
+        let new_header = match self.content {
+            Some(_) => format!("b/{}", path),
+            None => "/dev/null".to_string(),
+        };
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(&old_header, &new_header)
+            .to_string()
+    }
+}
+
+impl Staging {
+    /// Stage new content for a resource. `version_id` and `base` describe the
+    /// server's copy and only count the first time the resource is staged.
+    /// Content that is back to the server's drops the change.
+    pub fn put(
+        &mut self,
+        resource_type: &str,
+        resource_id: &str,
+        content: String,
+        version_id: Option<String>,
+        base: Option<String>,
+    ) {
+        let key = (resource_type.to_string(), resource_id.to_string());
+        let change = self.changes.entry(key.clone()).or_insert(Change {
+            base,
+            version_id,
+            content: None,
+        });
+        if change
+            .base
+            .as_deref()
+            .is_some_and(|base| same_resource(base, &content))
+        {
+            self.changes.remove(&key);
+        } else {
+            change.content = Some(content);
+        }
+    }
+
+    /// Stage a delete. Deleting a resource that was only staged drops it.
+    pub fn delete(
+        &mut self,
+        resource_type: &str,
+        resource_id: &str,
+        version_id: Option<String>,
+        base: Option<String>,
+    ) {
+        let key = (resource_type.to_string(), resource_id.to_string());
+        let change = self.changes.entry(key.clone()).or_insert(Change {
+            base,
+            version_id,
+            content: None,
+        });
+        if change.base.is_none() {
+            self.changes.remove(&key);
+        } else {
+            change.content = None;
+        }
+    }
+
+    /// Staged changes as `(resource type, id, change)`, by type and id
+    pub fn changes(&self) -> impl Iterator<Item = (&str, &str, &Change)> {
+        self.changes
+            .iter()
+            .map(|((resource_type, id), change)| (resource_type.as_str(), id.as_str(), change))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.changes.clear();
+    }
+
+    /// One line per staged resource, `A` (created), `M` (modified) or `D`
+    /// (deleted) and its path
+    pub fn status(&self) -> String {
+        if self.is_empty() {
+            return "Nothing staged\n".to_string();
+        }
+        self.changes()
+            .map(|(resource_type, id, change)| {
+                format!("{} {}/{}.json\n", change.status(), resource_type, id)
+            })
+            .collect()
+    }
+
+    /// The staged changes as a `transaction` Bundle, in the order of
+    /// `changes`. Updates and deletes of resources the server has are
+    /// conditional on the version they were made against.
+    pub fn transaction(&self, fhir_base_url: &str) -> Value {
+        let entries: Vec<Value> = self
+            .changes()
+            .map(|(resource_type, id, change)| {
+                let url = format!("{}/{}", resource_type, id);
+                let mut request = match change.content {
+                    Some(_) => json!({"method": "PUT", "url": url}),
+                    None => json!({"method": "DELETE", "url": url}),
+                };
+                if let (Some(_), Some(version_id)) = (&change.base, &change.version_id) {
+                    request["ifMatch"] = json!(format!("W/\"{}\"", version_id));
+                }
+                let mut entry = json!({"fullUrl": format!("{}/{}", fhir_base_url, url)});
+                if let Some(content) = &change.content {
+                    entry["resource"] = serde_json::from_str(content).unwrap_or(Value::Null);
+                }
+                entry["request"] = request;
+                entry
+            })
+            .collect();
+        json!({"resourceType": "Bundle", "type": "transaction", "entry": entries})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str =
+        "{\n  \"resourceType\": \"Patient\",\n  \"id\": \"pt-1\",\n  \"active\": true\n}\n";
+    const EDITED: &str =
+        "{\n  \"resourceType\": \"Patient\",\n  \"id\": \"pt-1\",\n  \"active\": false\n}\n";
+
+    impl Staging {
+        fn get(&self, resource_type: &str, resource_id: &str) -> Option<&Change> {
+            self.changes
+                .get(&(resource_type.to_string(), resource_id.to_string()))
+        }
+    }
+
+    fn staging() -> Staging {
+        let mut staging = Staging::default();
+        staging.put(
+            "Patient",
+            "pt-1",
+            EDITED.to_string(),
+            Some("3".to_string()),
+            Some(SERVER.to_string()),
+        );
+        staging.put(
+            "Patient",
+            "pt-2",
+            "{\"resourceType\": \"Patient\", \"id\": \"pt-2\"}".to_string(),
+            None,
+            None,
+        );
+        staging.delete(
+            "Observation",
+            "obs-1",
+            Some("1".to_string()),
+            Some("{}".to_string()),
+        );
+        staging
+    }
+
+    #[test]
+    fn test_status_and_diff() {
+        let staging = staging();
+        assert_eq!(
+            staging.status(),
+            "D Observation/obs-1.json\nM Patient/pt-1.json\nA Patient/pt-2.json\n"
+        );
+        assert_eq!(
+            staging
+                .get("Patient", "pt-1")
+                .unwrap()
+                .diff("Patient/pt-1.json"),
+            "--- a/Patient/pt-1.json\n+++ b/Patient/pt-1.json\n@@ -1,5 +1,5 @@\n \
+             {\n   \"resourceType\": \"Patient\",\n   \"id\": \"pt-1\",\n-  \"active\": true\n\
+             +  \"active\": false\n }\n"
+        );
+        assert!(staging
+            .get("Patient", "pt-2")
+            .unwrap()
+            .diff("Patient/pt-2.json")
+            .starts_with("--- /dev/null\n+++ b/Patient/pt-2.json\n"));
+        assert_eq!(Staging::default().status(), "Nothing staged\n");
+    }
+
+    #[test]
+    fn test_restaging_keeps_the_server_version() {
+        let mut staging = staging();
+        let edited_again = EDITED.replace("false", "null");
+        staging.put(
+            "Patient",
+            "pt-1",
+            edited_again.clone(),
+            Some("4".into()),
+            None,
+        );
+        let change = staging.get("Patient", "pt-1").unwrap();
+        assert_eq!(change.version_id.as_deref(), Some("3"));
+        assert_eq!(change.base.as_deref(), Some(SERVER));
+        assert_eq!(change.content, Some(edited_again));
+
+        // Back to what the server has: nothing left to send
+        staging.put("Patient", "pt-1", SERVER.to_string(), None, None);
+        assert!(staging.get("Patient", "pt-1").is_none());
+        // A created resource that is deleted again was never there
+        staging.delete("Patient", "pt-2", None, None);
+        assert!(staging.get("Patient", "pt-2").is_none());
+        assert_eq!(staging.changes().count(), 1);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let bundle = staging().transaction("http://fhir.test");
+        assert_eq!(
+            bundle,
+            json!({
+                "resourceType": "Bundle",
+                "type": "transaction",
+                "entry": [
+                    {
+                        "fullUrl": "http://fhir.test/Observation/obs-1",
+                        "request": {"method": "DELETE", "url": "Observation/obs-1", "ifMatch": "W/\"1\""}
+                    },
+                    {
+                        "fullUrl": "http://fhir.test/Patient/pt-1",
+                        "resource": {"resourceType": "Patient", "id": "pt-1", "active": false},
+                        "request": {"method": "PUT", "url": "Patient/pt-1", "ifMatch": "W/\"3\""}
+                    },
+                    {
+                        "fullUrl": "http://fhir.test/Patient/pt-2",
+                        "resource": {"resourceType": "Patient", "id": "pt-2"},
+                        "request": {"method": "PUT", "url": "Patient/pt-2"}
+                    }
+                ]
+            })
+        );
+    }
+}
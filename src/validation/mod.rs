@@ -0,0 +1,158 @@
+//! Checks run on a resource before it leaves the machine, and the
+//! resource templates built from the same StructureDefinitions
+
+pub mod schema;
+pub mod structure;
+pub mod template;
+
+pub use schema::FhirSchema;
+pub use structure::{base_url, StructureDefinitions};
+
+use crate::config::ValidationSettings;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// A problem found in a resource, at a FHIRPath-like location such as
+/// `Patient.name[0].given`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Issue {
+    pub location: String,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn new(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.location.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.location, self.message)
+        }
+    }
+}
+
+/// `--validate local`: the base JSON schema, then the StructureDefinitions
+/// of the configured packages
+pub struct LocalValidator {
+    schema: Option<FhirSchema>,
+}
+
+impl LocalValidator {
+    pub fn load(settings: &ValidationSettings) -> anyhow::Result<Self> {
+        let schema = match &settings.fhir_schema {
+            Some(path) => Some(FhirSchema::load(path)?),
+            None => None,
+        };
+        if schema.is_some() {
+            println!("[Validate] Loaded the FHIR JSON schema");
+        }
+        Ok(Self { schema })
+    }
+
+    pub fn validate(&self, resource: &Value, definitions: &StructureDefinitions) -> Vec<Issue> {
+        let mut issues = match &self.schema {
+            Some(schema) => schema.validate(resource),
+            None => Vec::new(),
+        };
+        issues.extend(definitions.validate(resource));
+
+        // A profile repeats the checks of the definition it constrains
+        let mut seen = HashSet::new();
+        issues.retain(|issue| seen.insert(issue.clone()));
+        issues
+    }
+}
+
+/// Issues of `error` or `fatal` severity in an OperationOutcome, as
+/// returned by `$validate`
+pub fn outcome_issues(outcome: &Value) -> Vec<Issue> {
+    outcome["issue"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|issue| matches!(issue["severity"].as_str(), Some("error" | "fatal")))
+        .map(|issue| {
+            let location = issue["expression"][0]
+                .as_str()
+                .or_else(|| issue["location"][0].as_str())
+                .unwrap_or_default();
+            let message = issue["diagnostics"]
+                .as_str()
+                .or_else(|| issue["details"]["text"].as_str())
+                .or_else(|| issue["code"].as_str())
+                .unwrap_or("invalid");
+            Issue::new(location, message)
+        })
+        .collect()
+}
+
+/// What goes into the `.error` file of a resource that failed validation
+pub fn report(issues: &[Issue]) -> String {
+    let lines: Vec<String> = issues.iter().map(|issue| format!("- {}", issue)).collect();
+    format!("The resource is not valid:\n{}", lines.join("\n"))
+}
+
+/// `/name/0/given` -> `Patient.name[0].given`
+fn location(resource_type: &str, pointer: &str) -> String {
+    let mut location = resource_type.to_string();
+    for segment in pointer.split('/').skip(1) {
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+        if segment.parse::<usize>().is_ok() {
+            location.push_str(&format!("[{}]", segment));
+        } else {
+            location.push('.');
+            location.push_str(&segment);
+        }
+    }
+    location
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_outcome_issues() {
+        let outcome = json!({
+            "resourceType": "OperationOutcome",
+            "issue": [
+                {"severity": "warning", "code": "informational", "diagnostics": "No narrative"},
+                {"severity": "error", "code": "required", "expression": ["Patient.gender"],
+                 "diagnostics": "minimum required = 1, but only found 0"},
+                {"severity": "fatal", "code": "structure", "details": {"text": "Unknown element"}}
+            ]
+        });
+        let issues = outcome_issues(&outcome);
+        assert_eq!(
+            issues,
+            vec![
+                Issue::new("Patient.gender", "minimum required = 1, but only found 0"),
+                Issue::new("", "Unknown element")
+            ]
+        );
+        assert_eq!(
+            report(&issues),
+            "The resource is not valid:\n\
+             - Patient.gender: minimum required = 1, but only found 0\n\
+             - Unknown element"
+        );
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!(location("Patient", ""), "Patient");
+        assert_eq!(
+            location("Patient", "/name/0/given/1"),
+            "Patient.name[0].given[1]"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use super::{location, Issue};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The FHIR JSON schema (`fhir.schema.json` from the specification's
+/// downloads). It covers element names, value types, required elements
+/// and code enumerations, but not cardinality beyond that.
+pub struct FhirSchema {
+    schema: Value,
+    /// One validator per resource type, compiled when first needed: the
+    /// whole schema only reports "not valid under any of the schemas"
+    validators: Mutex<HashMap<String, Arc<jsonschema::Validator>>>,
+}
+
+impl FhirSchema {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let schema = serde_json::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("Invalid JSON schema {}: {}", path.display(), e))?;
+        Self::from_value(schema)
+            .map_err(|e| anyhow::anyhow!("Invalid JSON schema {}: {}", path.display(), e))
+    }
+
+    pub fn from_value(schema: Value) -> anyhow::Result<Self> {
+        if !schema["definitions"].is_object() {
+            return Err(anyhow::anyhow!("no \"definitions\""));
+        }
+        Ok(Self {
+            schema,
+            validators: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn validate(&self, resource: &Value) -> Vec<Issue> {
+        let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+        let validator = match self.validator(resource_type) {
+            Ok(validator) => validator,
+            Err(message) => return vec![Issue::new(resource_type, message)],
+        };
+        validator
+            .iter_errors(resource)
+            .map(|error| {
+                Issue::new(
+                    location(resource_type, error.instance_path.as_str()),
+                    error.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn validator(&self, resource_type: &str) -> Result<Arc<jsonschema::Validator>, String> {
+        let mut validators = self.validators.lock().unwrap();
+        if let Some(validator) = validators.get(resource_type) {
+            return Ok(validator.clone());
+        }
+        if !self.schema["definitions"][resource_type].is_object() {
+            return Err(format!(
+                "{} is not a resource type of the FHIR JSON schema",
+                resource_type
+            ));
+        }
+
+        // The same schema, with the resource's definition as its root
+        let mut schema = self.schema.clone();
+        if let Some(root) = schema.as_object_mut() {
+            root.remove("oneOf");
+            root.remove("discriminator");
+            root.insert(
+                "$ref".to_string(),
+                Value::String(format!("#/definitions/{}", resource_type)),
+            );
+        }
+        let validator = Arc::new(
+            jsonschema::validator_for(&schema)
+                .map_err(|e| format!("The FHIR JSON schema can't be compiled: {}", e))?,
+        );
+        validators.insert(resource_type.to_string(), validator.clone());
+        Ok(validator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The shape of `fhir.schema.json`, cut down to two types
+    fn schema() -> FhirSchema {
+        FhirSchema::from_value(json!({
+            "$schema": "http://json-schema.org/draft-06/schema#",
+            "id": "http://hl7.org/fhir/json-schema/4.0",
+            "discriminator": {"propertyName": "resourceType", "mapping": {
+                "Patient": "#/definitions/Patient"
+            }},
+            "oneOf": [{"$ref": "#/definitions/Patient"}],
+            "definitions": {
+                "boolean": {"pattern": "^true|false$", "type": "boolean"},
+                "string": {"pattern": "^[ \\r\\n\\t\\S]+$", "type": "string"},
+                "HumanName": {
+                    "properties": {
+                        "family": {"$ref": "#/definitions/string"},
+                        "given": {"items": {"$ref": "#/definitions/string"}, "type": "array"}
+                    },
+                    "additionalProperties": false
+                },
+                "Patient": {
+                    "properties": {
+                        "resourceType": {"const": "Patient"},
+                        "id": {"$ref": "#/definitions/string"},
+                        "active": {"$ref": "#/definitions/boolean"},
+                        "gender": {"enum": ["male", "female", "other", "unknown"]},
+                        "name": {"items": {"$ref": "#/definitions/HumanName"}, "type": "array"}
+                    },
+                    "additionalProperties": false,
+                    "required": ["resourceType"]
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_valid_resource() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "active": true,
+            "gender": "other",
+            "name": [{"family": "Example", "given": ["Ann"]}]
+        });
+        assert_eq!(schema().validate(&patient), vec![]);
+    }
+
+    #[test]
+    fn test_schema_issues() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "active": "yes",
+            "gender": "f",
+            "name": [{"family": "Example", "given": "Ann"}],
+            "birthdate": "1974-12-25"
+        });
+        let issues = schema().validate(&patient);
+        let mut locations: Vec<&str> = issues.iter().map(|i| i.location.as_str()).collect();
+        locations.sort();
+        assert_eq!(
+            locations,
+            vec![
+                "Patient",
+                // Both the type and the pattern of boolean
+                "Patient.active",
+                "Patient.active",
+                "Patient.gender",
+                "Patient.name[0].given"
+            ]
+        );
+        let reported = |location: &str, text: &str| {
+            issues
+                .iter()
+                .any(|issue| issue.location == location && issue.message.contains(text))
+        };
+        assert!(reported("Patient", "birthdate"));
+        assert!(reported("Patient.active", "\"boolean\""));
+
+        let issues = schema().validate(&json!({"resourceType": "Patiënt"}));
+        assert_eq!(
+            issues,
+            vec![Issue::new(
+                "Patiënt",
+                "Patiënt is not a resource type of the FHIR JSON schema"
+            )]
+        );
+    }
+}
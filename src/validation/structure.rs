@@ -0,0 +1,580 @@
+use super::Issue;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Canonical url prefix of the definitions in the FHIR specification
+const BASE_URL: &str = "http://hl7.org/fhir/StructureDefinition/";
+
+/// StructureDefinitions with a snapshot, from FHIR package directories
+/// (e.g. `~/.fhir/packages/hl7.fhir.r4.core#4.0.1/package`), by canonical
+/// url
+#[derive(Debug, Default)]
+pub struct StructureDefinitions {
+    by_url: HashMap<String, Value>,
+}
+
+impl StructureDefinitions {
+    /// Every StructureDefinition in the directories and their
+    /// subdirectories. Other files are skipped.
+    pub fn load(directories: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut definitions = Self::default();
+        for directory in directories {
+            definitions
+                .load_directory(directory)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", directory.display(), e))?;
+        }
+        if !directories.is_empty() {
+            match definitions.is_empty() {
+                true => println!("[Packages] No StructureDefinitions found in the packages"),
+                false => println!(
+                    "[Packages] Loaded {} StructureDefinitions",
+                    definitions.len()
+                ),
+            }
+        }
+        Ok(definitions)
+    }
+
+    fn load_directory(&mut self, directory: &Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.load_directory(&path)?;
+            } else if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let definition = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|text| serde_json::from_str(&text).ok());
+                if let Some(definition) = definition {
+                    self.insert(definition);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, definition: Value) {
+        if definition["resourceType"] != "StructureDefinition"
+            || !definition["snapshot"]["element"].is_array()
+        {
+            return;
+        }
+        if let Some(url) = definition["url"].as_str() {
+            self.by_url.insert(url.to_string(), definition);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_url.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_url.is_empty()
+    }
+
+    /// Definition by canonical url; a `|version` suffix is ignored
+    pub fn get(&self, url: &str) -> Option<&Value> {
+        self.by_url.get(url.split('|').next().unwrap_or(url))
+    }
+
+    /// Definition of a resource or data type in the FHIR specification
+    pub fn base(&self, type_name: &str) -> Option<&Value> {
+        self.get(&base_url(type_name))
+    }
+
+    /// Check a resource against the definition of its type and the profiles
+    /// it claims in `meta.profile`: unknown elements, cardinality, value
+    /// types and fixed and pattern values. Slices aren't checked.
+    pub fn validate(&self, resource: &Value) -> Vec<Issue> {
+        let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+        let mut definitions: Vec<&Value> = self.base(resource_type).into_iter().collect();
+        let profiles = resource["meta"]["profile"].as_array().into_iter().flatten();
+        for profile in profiles.filter_map(Value::as_str) {
+            match self.get(profile) {
+                Some(definition) => definitions.push(definition),
+                None => println!(
+                    "[Validate] {}: profile {} is not in the packages, not checking it",
+                    resource_type, profile
+                ),
+            }
+        }
+
+        let mut issues = Vec::new();
+        for definition in definitions {
+            if definition["type"] != resource_type {
+                issues.push(Issue::new(
+                    resource_type,
+                    format!(
+                        "{} is a profile of {}",
+                        definition["url"].as_str().unwrap_or_default(),
+                        definition["type"].as_str().unwrap_or_default()
+                    ),
+                ));
+                continue;
+            }
+            let snapshot = Snapshot::of(definition);
+            self.check_object(
+                &snapshot,
+                resource_type,
+                resource,
+                resource_type,
+                &mut issues,
+            );
+        }
+        issues
+    }
+
+    /// Check the members of `object`, an instance of the element at `path`
+    fn check_object(
+        &self,
+        snapshot: &Snapshot,
+        path: &str,
+        object: &Value,
+        location: &str,
+        issues: &mut Vec<Issue>,
+    ) {
+        let object = match object.as_object() {
+            Some(object) => object,
+            None => return,
+        };
+        let children = snapshot.children(path);
+
+        for key in object.keys() {
+            let name = key.strip_prefix('_').unwrap_or(key);
+            let is_resource_type = key == "resourceType" && !path.contains('.');
+            if !is_resource_type
+                && !children
+                    .iter()
+                    .any(|child| member_type(child, name).is_some())
+            {
+                issues.push(Issue::new(
+                    format!("{}.{}", location, key),
+                    format!("is not an element of {}", path),
+                ));
+            }
+        }
+
+        for child in children {
+            self.check_element(snapshot, child, object, location, issues);
+        }
+    }
+
+    /// Cardinality of one element in `object`, then each of its values
+    fn check_element(
+        &self,
+        snapshot: &Snapshot,
+        element: &Value,
+        object: &Map<String, Value>,
+        location: &str,
+        issues: &mut Vec<Issue>,
+    ) {
+        let name = element_name(element);
+        let min = element["min"].as_u64().unwrap_or(0) as usize;
+        let max = element["max"].as_str().unwrap_or("*");
+        let repeats = max != "0" && max != "1";
+
+        let mut count = 0;
+        for (key, value) in object {
+            let type_code = match member_type(element, key) {
+                Some(type_code) => type_code,
+                None => continue,
+            };
+            let member_location = format!("{}.{}", location, key);
+            match value {
+                Value::Array(items) => {
+                    if !repeats {
+                        issues.push(Issue::new(
+                            &member_location,
+                            "must be a single value, not an array",
+                        ));
+                    }
+                    for (index, item) in items.iter().enumerate() {
+                        let item_location = format!("{}[{}]", member_location, index);
+                        self.check_value(
+                            snapshot,
+                            element,
+                            &type_code,
+                            item,
+                            &item_location,
+                            issues,
+                        );
+                    }
+                    count += items.len();
+                }
+                value => {
+                    if repeats {
+                        issues.push(Issue::new(&member_location, "must be an array"));
+                    }
+                    self.check_value(
+                        snapshot,
+                        element,
+                        &type_code,
+                        value,
+                        &member_location,
+                        issues,
+                    );
+                    count += 1;
+                }
+            }
+        }
+        // A primitive can be there through its extensions alone
+        if count == 0 && object.contains_key(&format!("_{}", name)) {
+            count = 1;
+        }
+
+        let element_location = format!("{}.{}", location, name);
+        if count < min {
+            let message = match count {
+                0 => "is required".to_string(),
+                _ => format!("needs at least {} values, found {}", min, count),
+            };
+            issues.push(Issue::new(&element_location, message));
+        }
+        if let Ok(max) = max.parse::<usize>() {
+            if count > max {
+                let message = match max {
+                    0 => "is not allowed".to_string(),
+                    _ => format!("allows at most {} values, found {}", max, count),
+                };
+                issues.push(Issue::new(&element_location, message));
+            }
+        }
+    }
+
+    fn check_value(
+        &self,
+        snapshot: &Snapshot,
+        element: &Value,
+        type_code: &str,
+        value: &Value,
+        location: &str,
+        issues: &mut Vec<Issue>,
+    ) {
+        if let Some(expected) = json_kind(type_code) {
+            let found = kind_of(value);
+            if found != expected && !(expected == "a number" && found == "an integer") {
+                issues.push(Issue::new(
+                    location,
+                    format!("must be {} ({}), found {}", expected, type_code, found),
+                ));
+                return;
+            }
+        }
+
+        let constraints = element.as_object().into_iter().flatten();
+        for (key, constraint) in constraints {
+            if key.starts_with("fixed") && value != constraint {
+                issues.push(Issue::new(location, format!("must be {}", constraint)));
+            } else if key.starts_with("pattern") && !matches_pattern(value, constraint) {
+                issues.push(Issue::new(location, format!("must match {}", constraint)));
+            }
+        }
+
+        if !value.is_object() {
+            return;
+        }
+        let path = element["path"].as_str().unwrap_or_default();
+        if !snapshot.children(path).is_empty() {
+            self.check_object(snapshot, path, value, location, issues);
+        } else if let Some(reference) = element["contentReference"].as_str() {
+            // `#Questionnaire.item`: the same content as another element
+            let path = reference.rsplit('#').next().unwrap_or_default();
+            self.check_object(snapshot, path, value, location, issues);
+        } else if type_code == "Resource" {
+            let resource_type = value["resourceType"].as_str().unwrap_or_default();
+            if let Some(definition) = self.base(resource_type) {
+                let snapshot = Snapshot::of(definition);
+                self.check_object(&snapshot, resource_type, value, location, issues);
+            }
+        } else if let Some(definition) = self.base(type_code) {
+            let snapshot = Snapshot::of(definition);
+            self.check_object(&snapshot, type_code, value, location, issues);
+        }
+    }
+}
+
+/// Canonical url of a resource or data type in the FHIR specification
+pub fn base_url(type_name: &str) -> String {
+    format!("{}{}", BASE_URL, type_name)
+}
+
+/// The elements of a snapshot, without slices
+pub(super) struct Snapshot<'a> {
+    elements: Vec<&'a Value>,
+}
+
+impl<'a> Snapshot<'a> {
+    pub(super) fn of(definition: &'a Value) -> Self {
+        let elements = definition["snapshot"]["element"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|element| !element["id"].as_str().unwrap_or_default().contains(':'))
+            .collect();
+        Self { elements }
+    }
+
+    /// Elements directly below `path`
+    pub(super) fn children(&self, path: &str) -> Vec<&'a Value> {
+        self.elements
+            .iter()
+            .copied()
+            .filter(|element| {
+                element["path"]
+                    .as_str()
+                    .and_then(|child| child.strip_prefix(path))
+                    .and_then(|rest| rest.strip_prefix('.'))
+                    .is_some_and(|name| !name.is_empty() && !name.contains('.'))
+            })
+            .collect()
+    }
+}
+
+/// `Patient.deceased[x]` -> `deceased[x]`
+pub(super) fn element_name(element: &Value) -> &str {
+    let path = element["path"].as_str().unwrap_or_default();
+    path.rsplit('.').next().unwrap_or(path)
+}
+
+/// The type of the element a JSON member holds, if it holds this one:
+/// `deceasedBoolean` is `deceased[x]` as a `boolean`
+fn member_type(element: &Value, key: &str) -> Option<String> {
+    let name = element_name(element);
+    let codes = element["type"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|element_type| element_type["code"].as_str());
+    match name.strip_suffix("[x]") {
+        Some(prefix) => {
+            let suffix = key.strip_prefix(prefix)?;
+            codes
+                .map(String::from)
+                .find(|code| capitalize(code) == suffix)
+        }
+        None if key == name => Some(codes.map(String::from).next().unwrap_or_default()),
+        None => None,
+    }
+}
+
+pub(super) fn capitalize(code: &str) -> String {
+    let mut chars = code.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// What JSON holds a value of a FHIR type; `None` when it can't be told
+pub(super) fn json_kind(type_code: &str) -> Option<&'static str> {
+    let type_code = type_code
+        .strip_prefix("http://hl7.org/fhirpath/System.")
+        .unwrap_or(type_code);
+    match type_code {
+        "" => None,
+        "boolean" | "Boolean" => Some("true or false"),
+        "integer" | "positiveInt" | "unsignedInt" | "Integer" => Some("an integer"),
+        "decimal" | "Decimal" => Some("a number"),
+        "String" | "Date" | "DateTime" | "Time" => Some("a string"),
+        code if code.starts_with(|c: char| c.is_ascii_lowercase()) => Some("a string"),
+        _ => Some("an object"),
+    }
+}
+
+fn kind_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "true or false",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "an integer",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Whether `value` has everything `pattern` has: objects may have more
+/// members and arrays more items
+fn matches_pattern(value: &Value, pattern: &Value) -> bool {
+    match (value, pattern) {
+        (Value::Object(value), Value::Object(pattern)) => pattern.iter().all(|(key, pattern)| {
+            value
+                .get(key)
+                .is_some_and(|value| matches_pattern(value, pattern))
+        }),
+        (Value::Array(values), Value::Array(patterns)) => patterns
+            .iter()
+            .all(|pattern| values.iter().any(|value| matches_pattern(value, pattern))),
+        _ => value == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn element(path: &str, min: u64, max: &str, types: &[&str]) -> Value {
+        let types: Vec<Value> = types.iter().map(|code| json!({"code": code})).collect();
+        json!({"id": path, "path": path, "min": min, "max": max, "type": types})
+    }
+
+    fn definition(url: &str, type_name: &str, elements: Vec<Value>) -> Value {
+        json!({
+            "resourceType": "StructureDefinition",
+            "url": url,
+            "type": type_name,
+            "snapshot": {"element": elements}
+        })
+    }
+
+    /// A few elements of Patient and HumanName, as in the core package
+    fn definitions() -> StructureDefinitions {
+        let mut definitions = StructureDefinitions::default();
+        definitions.insert(definition(
+            "http://hl7.org/fhir/StructureDefinition/Patient",
+            "Patient",
+            vec![
+                element("Patient", 0, "*", &[]),
+                element(
+                    "Patient.id",
+                    0,
+                    "1",
+                    &["http://hl7.org/fhirpath/System.String"],
+                ),
+                element("Patient.meta", 0, "1", &["Meta"]),
+                element("Patient.active", 0, "1", &["boolean"]),
+                element("Patient.name", 0, "*", &["HumanName"]),
+                element("Patient.gender", 0, "1", &["code"]),
+                element("Patient.birthDate", 0, "1", &["date"]),
+                element("Patient.deceased[x]", 0, "1", &["boolean", "dateTime"]),
+                element("Patient.contact", 0, "*", &["BackboneElement"]),
+                element("Patient.contact.name", 0, "1", &["HumanName"]),
+                element("Patient.contact.gender", 0, "1", &["code"]),
+            ],
+        ));
+        definitions.insert(definition(
+            "http://hl7.org/fhir/StructureDefinition/HumanName",
+            "HumanName",
+            vec![
+                element("HumanName", 0, "*", &[]),
+                element("HumanName.family", 0, "1", &["string"]),
+                element("HumanName.given", 0, "*", &["string"]),
+            ],
+        ));
+        let mut active = element("Patient.active", 1, "1", &["boolean"]);
+        active["fixedBoolean"] = json!(true);
+        let mut name = element("Patient.name", 1, "*", &["HumanName"]);
+        name["patternHumanName"] = json!({"family": "Example"});
+        definitions.insert(definition(
+            "http://example.org/StructureDefinition/example-patient",
+            "Patient",
+            vec![
+                element("Patient", 0, "*", &[]),
+                element(
+                    "Patient.id",
+                    0,
+                    "1",
+                    &["http://hl7.org/fhirpath/System.String"],
+                ),
+                element("Patient.meta", 0, "1", &["Meta"]),
+                active,
+                name,
+                element("Patient.name:official", 0, "1", &["HumanName"]),
+                element("Patient.gender", 1, "1", &["code"]),
+                element("Patient.birthDate", 0, "1", &["date"]),
+                element("Patient.deceased[x]", 0, "0", &["boolean", "dateTime"]),
+                element("Patient.contact", 0, "*", &["BackboneElement"]),
+                element("Patient.contact.name", 0, "1", &["HumanName"]),
+                element("Patient.contact.gender", 0, "1", &["code"]),
+            ],
+        ));
+        definitions
+    }
+
+    #[test]
+    fn test_valid_resource() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "pt-1",
+            "name": [{"family": "Example", "given": ["Ann"]}],
+            "_birthDate": {"extension": []},
+            "deceasedDateTime": "2024-01-01",
+            "contact": [{"name": {"family": "Other"}}]
+        });
+        assert_eq!(definitions().validate(&patient), vec![]);
+    }
+
+    #[test]
+    fn test_base_definition_issues() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "active": "true",
+            "name": {"family": "Example"},
+            "deceasedString": "no",
+            "contact": [{"name": {"family": ["Other"]}, "relationship": []}]
+        });
+        assert_eq!(
+            definitions().validate(&patient),
+            vec![
+                Issue::new("Patient.deceasedString", "is not an element of Patient"),
+                Issue::new(
+                    "Patient.active",
+                    "must be true or false (boolean), found a string"
+                ),
+                Issue::new("Patient.name", "must be an array"),
+                Issue::new(
+                    "Patient.contact[0].relationship",
+                    "is not an element of Patient.contact"
+                ),
+                Issue::new(
+                    "Patient.contact[0].name.family",
+                    "must be a single value, not an array"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_issues() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "meta": {"profile": ["http://example.org/StructureDefinition/example-patient|1.0"]},
+            "active": false,
+            "name": [{"family": "Other"}],
+            "deceasedBoolean": false
+        });
+        let issues = definitions().validate(&patient);
+        assert_eq!(
+            issues,
+            vec![
+                Issue::new("Patient.active", "must be true"),
+                Issue::new("Patient.name[0]", "must match {\"family\":\"Example\"}"),
+                Issue::new("Patient.gender", "is required"),
+                Issue::new("Patient.deceased[x]", "is not allowed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_of_other_type() {
+        let mut definitions = definitions();
+        definitions.insert(definition(
+            "http://example.org/StructureDefinition/vitals",
+            "Observation",
+            vec![element("Observation", 0, "*", &[])],
+        ));
+        let patient = json!({
+            "resourceType": "Patient",
+            "meta": {"profile": ["http://example.org/StructureDefinition/vitals"]}
+        });
+        let issues = definitions.validate(&patient);
+        assert!(issues.contains(&Issue::new(
+            "Patient",
+            "http://example.org/StructureDefinition/vitals is a profile of Observation"
+        )));
+    }
+}
@@ -0,0 +1,376 @@
+use super::structure::{capitalize, element_name, json_kind, Snapshot};
+use super::{base_url, StructureDefinitions};
+use serde_json::Value;
+
+/// Members of every element, left out unless a definition requires them
+const ELEMENT_BASE: [&str; 3] = ["id", "extension", "modifierExtension"];
+/// Members of every resource, also left out
+const RESOURCE_BASE: [&str; 5] = ["meta", "implicitRules", "language", "text", "contained"];
+
+/// Data types nested deeper than this are left as `{}`
+const MAX_DEPTH: usize = 3;
+
+/// A resource to fill in, built from the snapshot of a StructureDefinition
+pub struct Template {
+    /// JSON with blanks (`""`, `null`, `{}`) for the values and a
+    /// `// min..max short description` comment on each element
+    pub content: String,
+    /// Data types a definition was needed for but not available; they are
+    /// left as `{}`
+    pub missing: Vec<String>,
+}
+
+/// The skeleton has the required elements, the ones a profile marks
+/// must-support, fixed and pattern values, and the summary elements of the
+/// resource. Inside data types optional elements are kept when they are
+/// primitive or repeat (a CodeableConcept's codings), so not every Period and
+/// Reference is spelled out.
+pub fn build(definition: &Value, definitions: &StructureDefinitions) -> Template {
+    let resource_type = definition["type"].as_str().unwrap_or_default();
+    let mut builder = Builder {
+        definitions,
+        missing: Vec::new(),
+    };
+
+    let mut members = vec![Member::new(
+        "resourceType",
+        Node::Value(resource_type.into()),
+    )];
+    let url = definition["url"].as_str().unwrap_or_default();
+    if url != base_url(resource_type) {
+        let profile = Node::Array(Box::new(Node::Value(url.into())));
+        members.push(Member::new(
+            "meta",
+            Node::Object(vec![Member::new("profile", profile)]),
+        ));
+    }
+    let snapshot = Snapshot::of(definition);
+    members.extend(builder.members(&snapshot, resource_type, 0, true));
+
+    let mut content = String::new();
+    render(&mut content, &Node::Object(members), 0, "", "");
+    Template {
+        content,
+        missing: builder.missing,
+    }
+}
+
+struct Builder<'a> {
+    definitions: &'a StructureDefinitions,
+    missing: Vec<String>,
+}
+
+impl Builder<'_> {
+    /// Members of an instance of the element at `path`
+    fn members(
+        &mut self,
+        snapshot: &Snapshot,
+        path: &str,
+        depth: usize,
+        resource: bool,
+    ) -> Vec<Member> {
+        snapshot
+            .children(path)
+            .into_iter()
+            .filter_map(|element| self.member(snapshot, element, depth, resource))
+            .collect()
+    }
+
+    fn member(
+        &mut self,
+        snapshot: &Snapshot,
+        element: &Value,
+        depth: usize,
+        resource: bool,
+    ) -> Option<Member> {
+        let name = element_name(element);
+        let min = element["min"].as_u64().unwrap_or(0);
+        let max = element["max"].as_str().unwrap_or("*");
+        if max == "0" {
+            return None;
+        }
+        let repeats = max != "1";
+        let constraint = element.as_object().and_then(|element| {
+            element
+                .iter()
+                .find(|(key, _)| key.starts_with("fixed") || key.starts_with("pattern"))
+                .map(|(_, value)| value.clone())
+        });
+        let codes: Vec<&str> = element["type"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|element_type| element_type["code"].as_str())
+            .collect();
+        let code = codes.first().copied().unwrap_or_default();
+        let primitive = json_kind(code).is_some_and(|kind| kind != "an object");
+
+        if min == 0 && constraint.is_none() {
+            let base = ELEMENT_BASE.contains(&name) || (resource && RESOURCE_BASE.contains(&name));
+            let summary = element["isSummary"] == true && (depth == 0 || primitive || repeats);
+            if base || !(element["mustSupport"] == true || summary) {
+                return None;
+            }
+        }
+
+        let node = match constraint {
+            Some(value) => Node::Value(value),
+            None => self.value(snapshot, element, code, depth),
+        };
+        let node = match repeats {
+            true => Node::Array(Box::new(node)),
+            false => node,
+        };
+
+        let key = match name.strip_suffix("[x]") {
+            Some(prefix) => format!("{}{}", prefix, capitalize(code)),
+            None => name.to_string(),
+        };
+        let mut comment = format!("{}..{}", min, max);
+        if name.ends_with("[x]") {
+            comment.push_str(&format!(" {}:", codes.join(" | ")));
+        }
+        if let Some(short) = element["short"].as_str() {
+            comment.push(' ');
+            comment.push_str(short);
+        }
+        Some(Member { key, node, comment })
+    }
+
+    /// A blank value of the element's type
+    fn value(&mut self, snapshot: &Snapshot, element: &Value, code: &str, depth: usize) -> Node {
+        match json_kind(code) {
+            Some("a string") => return Node::Value("".into()),
+            Some("an object") => {}
+            _ => return Node::Value(Value::Null),
+        }
+
+        let path = element["path"].as_str().unwrap_or_default();
+        if !snapshot.children(path).is_empty() {
+            // A BackboneElement: its elements follow in the same snapshot
+            return Node::Object(self.members(snapshot, path, depth, false));
+        }
+        if code == "Resource" || depth + 1 >= MAX_DEPTH {
+            return Node::Object(Vec::new());
+        }
+        match self.definitions.base(code) {
+            Some(definition) => {
+                let snapshot = Snapshot::of(definition);
+                Node::Object(self.members(&snapshot, code, depth + 1, false))
+            }
+            None => {
+                if !self.missing.iter().any(|missing| missing == code) {
+                    self.missing.push(code.to_string());
+                }
+                Node::Object(Vec::new())
+            }
+        }
+    }
+}
+
+enum Node {
+    Value(Value),
+    Object(Vec<Member>),
+    Array(Box<Node>),
+}
+
+struct Member {
+    key: String,
+    node: Node,
+    comment: String,
+}
+
+impl Member {
+    fn new(key: &str, node: Node) -> Self {
+        Self {
+            key: key.to_string(),
+            node,
+            comment: String::new(),
+        }
+    }
+}
+
+/// Two-space indentation like the resource files; a member's comment goes at
+/// the end of its first line
+fn render(out: &mut String, node: &Node, indent: usize, comma: &str, comment: &str) {
+    let comment = match comment {
+        "" => String::new(),
+        comment => format!(" // {}", comment),
+    };
+    let padding = "  ".repeat(indent);
+    match node {
+        Node::Object(members) if !members.is_empty() => {
+            out.push_str(&format!("{{{}\n", comment));
+            for (index, member) in members.iter().enumerate() {
+                let comma = if index + 1 < members.len() { "," } else { "" };
+                out.push_str(&format!("{}  \"{}\": ", padding, member.key));
+                render(out, &member.node, indent + 1, comma, &member.comment);
+            }
+            out.push_str(&format!("{}}}{}\n", padding, comma));
+        }
+        Node::Array(item) if matches!(**item, Node::Object(ref members) if !members.is_empty()) => {
+            out.push_str(&format!("[{}\n{}  ", comment, padding));
+            render(out, item, indent + 1, "", "");
+            out.push_str(&format!("{}]{}\n", padding, comma));
+        }
+        node => out.push_str(&format!("{}{}{}\n", inline(node, indent), comma, comment)),
+    }
+}
+
+/// Values, `{}` and arrays of them on the member's line
+fn inline(node: &Node, indent: usize) -> String {
+    match node {
+        Node::Value(value) => {
+            let text = serde_json::to_string_pretty(value).unwrap_or_default();
+            text.replace('\n', &format!("\n{}", "  ".repeat(indent)))
+        }
+        Node::Array(item) => format!("[{}]", inline(item, indent)),
+        Node::Object(_) => "{}".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn element(path: &str, min: u64, max: &str, types: &[&str], short: &str) -> Value {
+        let types: Vec<Value> = types.iter().map(|code| json!({"code": code})).collect();
+        json!({"id": path, "path": path, "min": min, "max": max, "type": types, "short": short})
+    }
+
+    fn summary(mut element: Value) -> Value {
+        element["isSummary"] = json!(true);
+        element
+    }
+
+    fn definition(url: &str, type_name: &str, elements: Vec<Value>) -> Value {
+        json!({
+            "resourceType": "StructureDefinition",
+            "url": url,
+            "type": type_name,
+            "snapshot": {"element": elements}
+        })
+    }
+
+    fn patient(url: &str, profiled: bool) -> Value {
+        let mut active = summary(element("Patient.active", 0, "1", &["boolean"], "In use"));
+        let mut gender = summary(element(
+            "Patient.gender",
+            0,
+            "1",
+            &["code"],
+            "male | female",
+        ));
+        if profiled {
+            active["fixedBoolean"] = json!(true);
+            gender["min"] = json!(1);
+        }
+        let mut birth_date = element("Patient.birthDate", 0, "1", &["date"], "Born");
+        birth_date["mustSupport"] = json!(profiled);
+        definition(
+            url,
+            "Patient",
+            vec![
+                element("Patient", 0, "*", &[], "Patient"),
+                summary(element("Patient.id", 0, "1", &["id"], "Logical id")),
+                summary(element("Patient.meta", 0, "1", &["Meta"], "Metadata")),
+                element("Patient.text", 0, "1", &["Narrative"], "Narrative"),
+                element("Patient.extension", 0, "*", &["Extension"], "Extensions"),
+                summary(element(
+                    "Patient.identifier",
+                    0,
+                    "*",
+                    &["Identifier"],
+                    "Ids",
+                )),
+                active,
+                summary(element("Patient.name", 0, "*", &["HumanName"], "Names")),
+                gender,
+                birth_date,
+                summary(element(
+                    "Patient.deceased[x]",
+                    0,
+                    "1",
+                    &["boolean", "dateTime"],
+                    "Deceased",
+                )),
+                element("Patient.photo", 0, "*", &["Attachment"], "Photos"),
+                element("Patient.contact", 0, "*", &["BackboneElement"], "Contacts"),
+                element("Patient.contact.name", 0, "1", &["HumanName"], "Name"),
+            ],
+        )
+    }
+
+    fn definitions() -> StructureDefinitions {
+        let mut definitions = StructureDefinitions::default();
+        definitions.insert(definition(
+            "http://hl7.org/fhir/StructureDefinition/HumanName",
+            "HumanName",
+            vec![
+                element("HumanName", 0, "*", &[], "Name"),
+                element("HumanName.id", 0, "1", &["string"], "Element id"),
+                summary(element(
+                    "HumanName.family",
+                    0,
+                    "1",
+                    &["string"],
+                    "Family name",
+                )),
+                summary(element(
+                    "HumanName.given",
+                    0,
+                    "*",
+                    &["string"],
+                    "Given names",
+                )),
+                summary(element("HumanName.period", 0, "1", &["Period"], "In use")),
+            ],
+        ));
+        definitions
+    }
+
+    #[test]
+    fn test_base_template() {
+        let definition = patient("http://hl7.org/fhir/StructureDefinition/Patient", false);
+        let template = build(&definition, &definitions());
+        assert_eq!(
+            template.content,
+            r#"{
+  "resourceType": "Patient",
+  "identifier": [{}], // 0..* Ids
+  "active": null, // 0..1 In use
+  "name": [ // 0..* Names
+    {
+      "family": "", // 0..1 Family name
+      "given": [""] // 0..* Given names
+    }
+  ],
+  "gender": "", // 0..1 male | female
+  "deceasedBoolean": null // 0..1 boolean | dateTime: Deceased
+}
+"#
+        );
+        assert_eq!(template.missing, vec!["Identifier"]);
+
+        // What an author fills in is read as JSON with comments
+        let strict = crate::fhir::json::strict_json(&template.content).unwrap();
+        let resource: Value = serde_json::from_str(&strict).unwrap();
+        assert_eq!(resource["name"][0]["given"], json!([""]));
+    }
+
+    #[test]
+    fn test_profile_template() {
+        let url = "http://example.org/StructureDefinition/example-patient";
+        let template = build(&patient(url, true), &definitions());
+        let content = template.content;
+        assert!(content.starts_with(
+            "{\n  \"resourceType\": \"Patient\",\n  \"meta\": {\n    \
+             \"profile\": [\"http://example.org/StructureDefinition/example-patient\"]\n  },\n"
+        ));
+        assert!(content.contains("\"active\": true, // 0..1 In use\n"));
+        assert!(content.contains("\"gender\": \"\", // 1..1 male | female\n"));
+        assert!(content.contains("\"birthDate\": \"\", // 0..1 Born\n"));
+        assert!(!content.contains("photo"));
+    }
+}
@@ -247,6 +247,22 @@ impl InodeIndex {
         }
     }
 
+    pub fn get_fhir_resource_mut(&mut self, inode: u64) -> Option<&mut FHIRResource> {
+        if let Some(VFSEntry::FHIRResource(resource)) = self.entries.get_mut(&inode) {
+            Some(resource)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_text_file_mut(&mut self, inode: u64) -> Option<&mut TextFile> {
+        if let Some(VFSEntry::TextFile(text_file)) = self.entries.get_mut(&inode) {
+            Some(text_file)
+        } else {
+            None
+        }
+    }
+
     pub fn get_search_path(&self, inode: u64) -> Option<&SearchPath> {
         if let Some(VFSEntry::SearchPath(search)) = self.get(inode) {
             Some(search)
@@ -1,5 +1,7 @@
+use crate::fhir::{version_id_of, JsonFormat};
 use fuser::FileAttr;
-use std::time::SystemTime;
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 pub struct FHIRResource {
@@ -9,6 +11,8 @@ pub struct FHIRResource {
     pub filename: String,
     pub content: String,
     pub mtime: SystemTime,
+    /// `meta.versionId` the content was loaded with, sent as `If-Match` on update
+    pub version_id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +41,43 @@ impl FHIRResource {
             filename,
             content: content.into(),
             mtime: SystemTime::now(),
+            version_id: None,
+        }
+    }
+
+    /// Build from a resource as returned by the server
+    pub fn from_json(
+        inode: u64,
+        resource_type: impl Into<String>,
+        resource: &Value,
+        format: JsonFormat,
+    ) -> Self {
+        let id = resource["id"].as_str().unwrap_or("unknown");
+        let content = format.render(resource);
+        let mut entry = Self::new(inode, resource_type, id, content);
+        entry.apply_meta(resource);
+        entry
+    }
+
+    pub fn with_version_id(mut self, version_id: Option<String>) -> Self {
+        self.version_id = version_id;
+        self
+    }
+
+    /// Replace the content with the server's representation, keeping the inode
+    pub fn update_from_json(&mut self, resource: &Value, format: JsonFormat) {
+        self.content = format.render(resource);
+        self.apply_meta(resource);
+    }
+
+    /// Take `meta.versionId` and use `meta.lastUpdated` as mtime
+    fn apply_meta(&mut self, resource: &Value) {
+        self.version_id = version_id_of(resource);
+        if let Some(last_updated) = resource["meta"]["lastUpdated"]
+            .as_str()
+            .and_then(parse_instant)
+        {
+            self.mtime = last_updated;
         }
     }
 
@@ -121,3 +162,91 @@ impl ResourceVersion {
         }
     }
 }
+
+/// Parse a FHIR instant such as `2024-03-01T12:00:00.5+02:00`
+fn parse_instant(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.split_once('T')?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+
+    let (clock, offset_secs) = match time.strip_suffix('Z') {
+        Some(clock) => (clock, 0),
+        None => {
+            let split = time.rfind(['+', '-'])?;
+            let (clock, offset) = time.split_at(split);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let offset_secs = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (clock, sign * offset_secs)
+        }
+    };
+
+    let (hms, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+    let mut hms_parts = hms.splitn(3, ':');
+    let hours: i64 = hms_parts.next()?.parse().ok()?;
+    let minutes: i64 = hms_parts.next()?.parse().ok()?;
+    let seconds: i64 = hms_parts.next()?.parse().ok()?;
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        let digits: String = fraction.chars().take(9).collect();
+        format!("{:0<9}", digits).parse::<u32>().ok()?
+    };
+
+    let secs = days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds
+        - offset_secs;
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_instant() {
+        assert_eq!(parse_instant("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_instant("2024-03-01T12:00:00.5+02:00"),
+            Some(UNIX_EPOCH + Duration::new(1_709_287_200, 500_000_000))
+        );
+        assert_eq!(
+            parse_instant("2000-02-29T23:59:59Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(951_868_799))
+        );
+        assert_eq!(parse_instant("2024-03-01"), None);
+        assert_eq!(parse_instant("not a date"), None);
+    }
+
+    #[test]
+    fn test_update_from_json_keeps_inode() {
+        let mut resource = FHIRResource::new(7, "Patient", "pt-1", "{}");
+        resource.update_from_json(
+            &json!({
+                "resourceType": "Patient",
+                "id": "pt-1",
+                "meta": {"versionId": "2", "lastUpdated": "1970-01-01T00:01:00Z"}
+            }),
+            JsonFormat::Lossless,
+        );
+
+        assert_eq!(resource.inode, 7);
+        assert_eq!(resource.version_id, Some("2".to_string()));
+        assert_eq!(resource.mtime, UNIX_EPOCH + Duration::from_secs(60));
+        assert!(resource.content.contains("\"versionId\": \"2\""));
+    }
+}
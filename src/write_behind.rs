@@ -0,0 +1,348 @@
+use crate::config::BatchSettings;
+use crate::fhir::{post_batch, FhirClient, HttpError, WriteResponse};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+
+/// A closed file waiting to be sent by `--write-behind`
+#[derive(Debug, Clone)]
+pub struct QueuedWrite {
+    /// The file it was written through
+    pub inode: u64,
+    pub resource_type: String,
+    pub resource_id: String,
+    pub content: String,
+    /// Version the content was written against, sent as `ifMatch`
+    pub version_id: Option<String>,
+    /// What the file showed before, shown again if the write is rejected
+    pub base: Option<String>,
+}
+
+impl QueuedWrite {
+    fn key(&self) -> (String, String) {
+        (self.resource_type.clone(), self.resource_id.clone())
+    }
+
+    fn entry(&self) -> Value {
+        let url = format!("{}/{}", self.resource_type, self.resource_id);
+        let mut request = json!({"method": "PUT", "url": url});
+        if let Some(version_id) = &self.version_id {
+            request["ifMatch"] = json!(format!("W/\"{}\"", version_id));
+        }
+        json!({
+            "resource": serde_json::from_str::<Value>(&self.content).unwrap_or(Value::Null),
+            "request": request
+        })
+    }
+}
+
+/// What the server made of a queued write
+pub struct Outcome {
+    pub write: QueuedWrite,
+    pub result: anyhow::Result<WriteResponse>,
+}
+
+/// Writes gathered into `batch` Bundles and sent in the background. The
+/// first write after a quiet spell opens a window; what is queued when it
+/// closes goes out in Bundles of `batch_size` entries, sent concurrently.
+pub struct WriteBehind {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    settings: BatchSettings,
+    client: FhirClient,
+    fhir_base_url: String,
+    queue: Mutex<Queue>,
+    /// A write was queued
+    queued: Notify,
+    /// Bundles were answered
+    sent: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<QueuedWrite>,
+    /// Resources whose write is being sent. A newer write of the same
+    /// resource waits, and is then sent against the version that write
+    /// stored.
+    in_flight: HashSet<(String, String)>,
+    outcomes: Vec<Outcome>,
+}
+
+impl WriteBehind {
+    /// Start the background sender on `runtime`
+    pub fn start(
+        runtime: &Runtime,
+        settings: BatchSettings,
+        client: FhirClient,
+        fhir_base_url: String,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            settings,
+            client,
+            fhir_base_url,
+            queue: Mutex::new(Queue::default()),
+            queued: Notify::new(),
+            sent: Notify::new(),
+        });
+        let sender = shared.clone();
+        runtime.spawn(async move {
+            loop {
+                sender.queued.notified().await;
+                tokio::time::sleep(sender.settings.window).await;
+                sender.send_pending().await;
+            }
+        });
+        Self { shared }
+    }
+
+    /// Queue a write. A queued write of the same resource that hasn't gone
+    /// out yet is replaced, keeping the version it was made against and what
+    /// the file showed before it.
+    pub fn queue(&self, write: QueuedWrite) {
+        let mut queue = self.shared.lock();
+        let key = write.key();
+        match queue.pending.iter_mut().find(|queued| queued.key() == key) {
+            Some(queued) => {
+                queued.inode = write.inode;
+                queued.content = write.content;
+            }
+            None => queue.pending.push(write),
+        }
+        drop(queue);
+        self.shared.queued.notify_one();
+    }
+
+    /// Send everything queued now and wait until every write was answered
+    pub async fn flush(&self) {
+        loop {
+            // Registered before looking, so an answer in between isn't missed
+            let sent = self.shared.sent.notified();
+            {
+                let queue = self.shared.lock();
+                if queue.pending.is_empty() && queue.in_flight.is_empty() {
+                    return;
+                }
+            }
+            if self.shared.send_pending().await == 0 {
+                // What's left waits for Bundles already on their way
+                sent.await;
+            }
+        }
+    }
+
+    /// Outcomes of the writes answered since the last call
+    pub fn take_outcomes(&self) -> Vec<Outcome> {
+        std::mem::take(&mut self.shared.lock().outcomes)
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Send the queued writes that aren't waiting for an earlier write of
+    /// the same resource. Returns how many were sent.
+    async fn send_pending(&self) -> usize {
+        let writes = {
+            let mut queue = self.lock();
+            let Queue {
+                pending, in_flight, ..
+            } = &mut *queue;
+            let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(pending)
+                .into_iter()
+                .partition(|write| !in_flight.contains(&write.key()));
+            *pending = waiting;
+            in_flight.extend(ready.iter().map(QueuedWrite::key));
+            ready
+        };
+        if writes.is_empty() {
+            return 0;
+        }
+
+        let count = writes.len();
+        let batches = writes
+            .chunks(self.settings.batch_size)
+            .map(|batch| self.send_batch(batch.to_vec()));
+        let outcomes: Vec<Outcome> = futures::future::join_all(batches)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let waiting = {
+            let mut queue = self.lock();
+            for outcome in &outcomes {
+                let key = outcome.write.key();
+                queue.in_flight.remove(&key);
+                if let Ok(response) = &outcome.result {
+                    for write in queue.pending.iter_mut().filter(|write| write.key() == key) {
+                        write.version_id = response.version_id.clone();
+                    }
+                }
+            }
+            queue.outcomes.extend(outcomes);
+            !queue.pending.is_empty()
+        };
+        if waiting {
+            self.queued.notify_one();
+        }
+        self.sent.notify_waiters();
+        count
+    }
+
+    async fn send_batch(&self, writes: Vec<QueuedWrite>) -> Vec<Outcome> {
+        let entries: Vec<Value> = writes.iter().map(QueuedWrite::entry).collect();
+        let bundle = json!({"resourceType": "Bundle", "type": "batch", "entry": entries});
+        println!("[Batch] Sending {} write(s)", writes.len());
+
+        match post_batch(&self.client, &self.fhir_base_url, &bundle).await {
+            Ok(results) => {
+                let mut results = results.into_iter();
+                writes
+                    .into_iter()
+                    .map(|write| {
+                        let result = match results.next() {
+                            Some(result) => result.map_err(anyhow::Error::from),
+                            None => Err(anyhow::anyhow!(
+                                "The batch response has no entry for {}/{}",
+                                write.resource_type,
+                                write.resource_id
+                            )),
+                        };
+                        Outcome { write, result }
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                println!("[Batch] Sending {} write(s) failed: {}", writes.len(), e);
+                writes
+                    .into_iter()
+                    .map(|write| Outcome {
+                        write,
+                        result: Err(copy_error(&e)),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The error of a whole Bundle, for each of its entries
+fn copy_error(error: &anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<HttpError>() {
+        Some(http_error) => HttpError {
+            action: http_error.action.clone(),
+            status: http_error.status,
+            body: http_error.body.clone(),
+        }
+        .into(),
+        None => anyhow::anyhow!("{}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, MockServer};
+    use std::time::Duration;
+
+    fn write(resource_id: &str, content: &str, version_id: Option<&str>) -> QueuedWrite {
+        QueuedWrite {
+            inode: 7,
+            resource_type: "Patient".to_string(),
+            resource_id: resource_id.to_string(),
+            content: content.to_string(),
+            version_id: version_id.map(String::from),
+            base: None,
+        }
+    }
+
+    #[test]
+    fn test_flush_sends_batches_and_orders_writes_of_a_resource() {
+        // Every entry is stored as version 2
+        let server = MockServer::start(|req| {
+            let bundle: Value = serde_json::from_str(&req.body).unwrap();
+            let entries: Vec<Value> = bundle["entry"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|_| json!({"response": {"status": "200 OK", "etag": "W/\"2\""}}))
+                .collect();
+            MockResponse::json(200, &json!({"entry": entries}).to_string())
+        });
+        let runtime = Runtime::new().unwrap();
+        let settings = BatchSettings {
+            window: Duration::from_secs(60),
+            batch_size: 2,
+        };
+        let client = FhirClient::new(reqwest::Client::new());
+        let write_behind = WriteBehind::start(&runtime, settings, client, server.url(""));
+
+        write_behind.queue(write("a", r#"{"resourceType":"Patient"}"#, Some("1")));
+        write_behind.queue(write("b", "{}", None));
+        // Replaces the queued write of `a`, against the same version
+        write_behind.queue(write("a", r#"{"active":true}"#, Some("9")));
+        write_behind.queue(write("c", "{}", None));
+        runtime.block_on(write_behind.flush());
+
+        let bundles: Vec<Value> = server
+            .requests()
+            .iter()
+            .map(|req| serde_json::from_str(&req.body).unwrap())
+            .collect();
+        assert_eq!(bundles.len(), 2);
+        let first = bundles
+            .iter()
+            .flat_map(|bundle| bundle["entry"].as_array().unwrap())
+            .find(|entry| entry["request"]["url"] == "Patient/a")
+            .unwrap();
+        assert_eq!(first["resource"], json!({"active": true}));
+        assert_eq!(first["request"]["ifMatch"], "W/\"1\"");
+
+        let outcomes = write_behind.take_outcomes();
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.iter().all(|outcome| outcome
+            .result
+            .as_ref()
+            .unwrap()
+            .version_id
+            .as_deref()
+            == Some("2")));
+        assert!(write_behind.take_outcomes().is_empty());
+    }
+
+    #[test]
+    fn test_failed_bundle_fails_each_write() {
+        let server = MockServer::start(|_| MockResponse::json(503, "down"));
+        let runtime = Runtime::new().unwrap();
+        let settings = BatchSettings {
+            window: Duration::from_millis(10),
+            batch_size: 10,
+        };
+        let client = FhirClient::new(reqwest::Client::new());
+        let write_behind = WriteBehind::start(&runtime, settings, client, server.url(""));
+
+        write_behind.queue(write("a", "{}", None));
+        write_behind.queue(write("b", "{}", None));
+        // Sent by the background sender once the window closes
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let mut outcomes = Vec::new();
+        while outcomes.len() < 2 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            outcomes.extend(write_behind.take_outcomes());
+        }
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(outcomes.len(), 2);
+        for outcome in outcomes {
+            let error = outcome.result.unwrap_err();
+            assert_eq!(error.downcast_ref::<HttpError>().unwrap().status, 503);
+        }
+    }
+}